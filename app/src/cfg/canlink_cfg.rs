@@ -0,0 +1,15 @@
+// Wireless CAN adapter link, see `task::canlink`
+use embassy_time::Duration;
+
+/// TCP port SavvyCAN connects to for GVRET over the network
+pub const CAN_LINK_PORT: u16 = 23;
+/// Networks allowed to connect, as an address and a prefix length, e.g.
+/// `&[([192, 168, 1, 20], 32)]` for a single laptop
+///
+/// Empty lets in the hosts of the Wi-Fi subnet only.
+pub const CAN_LINK_PEERS: &[([u8; 4], u8)] = &[];
+/// Let the host transmit frames on the vehicle bus, the link only listens
+/// otherwise
+pub const CAN_LINK_TRANSMIT: bool = true;
+/// Pause after refusing a host before accepting another one
+pub const CAN_LINK_REFUSE_DELAY: Duration = Duration::from_secs(5);
@@ -0,0 +1,36 @@
+// Cloud provider of the Wi-Fi MQTT connection, see `svc::cloud`
+use embassy_time::Duration;
+
+use crate::svc::cloud::{aws, Provider};
+
+/// Broker the Wi-Fi uplink connects to
+///
+/// `Generic` is the broker of `net_cfg`, which the LTE uplink always uses. The
+/// MQTT client ID, `net_cfg::MQTT_CLIENT_ID`, is the AWS thing name or the Azure
+/// device ID.
+pub const CLOUD_PROVIDER: Provider = Provider::Generic;
+
+/// AWS IoT Core device data endpoint, `<prefix>-ats.iot.<region>.amazonaws.com`
+pub const AWS_ENDPOINT_PREFIX: &str = "Fill your endpoint prefix here";
+pub const AWS_REGION: &str = "eu-west-1";
+/// 8883, or 443 where only HTTPS gets through, ALPN `x-amzn-mqtt-ca`
+///
+/// The TLS session can't offer ALPN yet, the Wi-Fi uplink refuses to start on
+/// 443.
+pub const AWS_PORT: u16 = aws::MQTT_PORT;
+
+/// Azure IoT Hub name, the broker is `<hub>.azure-devices.net`
+///
+/// IoT Hub only takes device-to-cloud messages under
+/// `devices/<device>/messages/events/`, `topic_cfg::TOPIC_TEMPLATE` has to
+/// render below it. Commands and the twin only come on the hub's own topics,
+/// the Wi-Fi uplink does not subscribe to ours. Sparkplug B needs a broker that
+/// takes its namespace.
+pub const AZURE_HUB_NAME: &str = "Fill your hub name here";
+/// Base64 symmetric key of the device for SAS tokens, empty for X.509
+/// authentication with the device certificate
+pub const AZURE_DEVICE_KEY: &str = "";
+/// Lifetime of a SAS token
+pub const AZURE_SAS_TTL: Duration = Duration::from_secs(3600);
+/// The connection is made again with a new token this long before expiry
+pub const AZURE_SAS_RENEW_MARGIN: Duration = Duration::from_secs(300);
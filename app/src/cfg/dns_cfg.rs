@@ -0,0 +1,12 @@
+// DNS resolver configuration, see `svc::dns`
+use embassy_time::Duration;
+
+/// Servers asked when DHCP gives none, or once the DHCP ones failed
+pub const FALLBACK_DNS_SERVERS: &[[u8; 4]] = &[[1, 1, 1, 1], [8, 8, 8, 8]];
+/// Wait for the answer of one server, over UDP then over TCP
+pub const DNS_QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+/// Rounds over all the servers before giving up
+pub const DNS_ATTEMPTS: usize = 2;
+/// Bounds of how long an answer is kept, whatever its TTL
+pub const DNS_MIN_TTL: Duration = Duration::from_secs(30);
+pub const DNS_MAX_TTL: Duration = Duration::from_secs(3600);
@@ -1 +1,12 @@
+pub mod canlink_cfg;
+pub mod cloud_cfg;
+pub mod dns_cfg;
+pub mod mqtt_cfg;
 pub mod net_cfg;
+pub mod provision_cfg;
+pub mod sparkplug_cfg;
+pub mod time_cfg;
+pub mod topic_cfg;
+pub mod trust_cfg;
+pub mod uplink_cfg;
+pub mod vehicle_cfg;
@@ -0,0 +1,30 @@
+// MQTT client configuration, see `svc::mqtt` and `svc::supervisor`
+use embassy_time::Duration;
+
+use crate::svc::mqtt::packet::ProtocolVersion;
+
+/// Protocol version tried first on the Wi-Fi uplink
+///
+/// With MQTT 5 the client falls back to 3.1.1 when the broker refuses the
+/// protocol version. The LTE uplink uses the modem's MQTT stack, which only
+/// speaks 3.1.1.
+pub const MQTT_PROTOCOL: ProtocolVersion = ProtocolVersion::V5;
+
+/// How long an MQTT 5 broker keeps the session (subscriptions, queued QoS 1
+/// messages) once the device is offline
+pub const MQTT_SESSION_EXPIRY_SECS: u32 = 24 * 3600;
+
+/// First delay before connecting again, doubled on every failure
+pub const RECONNECT_BACKOFF_BASE: Duration = Duration::from_secs(1);
+/// Longest delay between two attempts
+pub const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(120);
+/// Consecutive failures of one stage (DNS, TCP, TLS, MQTT) before escalating
+pub const RECONNECT_ESCALATE_AFTER: u8 = 5;
+/// How long the Wi-Fi uplink stays away once escalated
+pub const RECONNECT_CIRCUIT_OPEN: Duration = Duration::from_secs(300);
+/// How long the Wi-Fi uplink waits for the broker to acknowledge a queued
+/// message before putting it back in the outbox and connecting again
+pub const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+/// Pause before reading the TLS credentials again when they are missing or
+/// invalid, on both uplinks
+pub const CREDENTIALS_RETRY: Duration = Duration::from_secs(60);
@@ -0,0 +1,16 @@
+// Fleet provisioning of the device certificate, see `svc::provision`
+use embassy_time::Duration;
+
+/// Replace the shared factory certificate with one made for this device
+///
+/// The Wi-Fi uplink then connects with the factory certificate as claim
+/// certificate until the broker has signed the device's CSR, both uplinks
+/// present the device certificate from then on. Needs a broker with the fleet
+/// provisioning API.
+pub const FLEET_PROVISIONING: bool = false;
+/// Provisioning template registering the device
+pub const PROVISIONING_TEMPLATE: &str = "Fill your template name here";
+/// How long to wait for each answer of the broker
+pub const PROVISIONING_TIMEOUT: Duration = Duration::from_secs(30);
+/// Pause before the next attempt after a failed one
+pub const PROVISIONING_RETRY: Duration = Duration::from_secs(60);
@@ -0,0 +1,17 @@
+// Sparkplug B edge node, see `svc::sparkplug`
+use embassy_time::Duration;
+
+use crate::cfg::net_cfg::MQTT_CLIENT_ID;
+
+/// Run the Wi-Fi MQTT connection as a Sparkplug B edge node
+///
+/// The NDEATH certificate takes the place of the offline status as the MQTT
+/// Last Will of that connection, the retained status is still published.
+pub const SPARKPLUG_ENABLED: bool = false;
+/// Group of the edge node, none of the IDs may contain `/`, `+` or `#`
+pub const SPARKPLUG_GROUP_ID: &str = "telematics";
+pub const SPARKPLUG_EDGE_NODE_ID: &str = MQTT_CLIENT_ID;
+/// Device carrying the CAN metrics
+pub const SPARKPLUG_DEVICE_ID: &str = "vehicle";
+/// How often the metrics are sampled, DDATA only goes out when one changed
+pub const SPARKPLUG_SCAN_INTERVAL: Duration = Duration::from_secs(1);
@@ -0,0 +1,23 @@
+// Wall clock and time sources, see `svc::clock`
+use embassy_time::Duration;
+
+/// Asked in turn over the Wi-Fi uplink until one answers
+pub const NTP_SERVERS: &[&str] = &["pool.ntp.org", "time.google.com"];
+/// Asked by the modem with AT+QNTP once the LTE uplink is connected
+pub const MODEM_NTP_SERVER: &str = "pool.ntp.org";
+/// Wait for the +QNTP result, skipped when the cell gave the time
+pub const MODEM_NTP_TIMEOUT: Duration = Duration::from_secs(15);
+/// Between two SNTP synchronizations, and after a failed one
+pub const NTP_INTERVAL: Duration = Duration::from_secs(3600);
+pub const NTP_RETRY: Duration = Duration::from_secs(60);
+/// Wait for the reply of one server
+pub const NTP_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How long a source keeps less trusted ones from setting the clock after its
+/// last sample
+///
+/// Longer than `NTP_INTERVAL`, so NITZ does not take over between two SNTP
+/// synchronizations.
+pub const CLOCK_HOLDOVER: Duration = Duration::from_secs(2 * 3600);
+/// Shortest time between two precise samples the drift is measured over
+pub const DRIFT_MIN_INTERVAL: Duration = Duration::from_secs(600);
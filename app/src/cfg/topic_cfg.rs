@@ -0,0 +1,28 @@
+// MQTT topic namespace, see `svc::topic` for the placeholders
+use crate::svc::payload::PayloadFormat;
+use crate::svc::topic::MessageType;
+
+/// Topic of every message type without an override
+///
+/// The outbox policies of `uplink_cfg` match topic suffixes, keep them in line
+/// when changing the layout.
+pub const TOPIC_TEMPLATE: &str = "channels/{device}/messages/{dir}/{type}";
+
+/// Message types published on a topic of their own, first match wins
+///
+/// For example `(MessageType::Can, "channels/{device}/messages/{dir}/{type}/{bus}")`
+/// gives each CAN bus its own topic.
+pub const TOPIC_OVERRIDES: &[(MessageType, &str)] = &[];
+
+/// Value of `{bus}` for the frames of the TWAI controller
+pub const CAN_BUS_NAME: &str = "can0";
+
+/// Payload format of the message types not listed in [`PAYLOAD_FORMATS`]
+pub const DEFAULT_PAYLOAD_FORMAT: PayloadFormat = PayloadFormat::Json;
+
+/// Payload format by message type, first match wins
+///
+/// Only CAN batches, trips, health and events have an encoder (`svc::payload`),
+/// for example `(MessageType::Trip, PayloadFormat::Cbor)` roughly halves the
+/// trips sent over LTE. The backend reads the formats from the schema manifest.
+pub const PAYLOAD_FORMATS: &[(MessageType, PayloadFormat)] = &[];
@@ -0,0 +1,21 @@
+// Trust store and key pinning, see `svc::credentials::trust`
+use embassy_time::Duration;
+
+/// Keys the broker's chain has to lead to, as `pin-sha256` values: base64 of
+/// the SHA-256 of a DER SubjectPublicKeyInfo
+///
+/// Empty trusts every CA of the trust store. Pin the key of a CA or an
+/// intermediate present in the trust store, not the broker's own: it is
+/// enforced by giving only those CAs to the TLS session, on both uplinks.
+///
+/// The Mender server is not covered, the Mender client runs a TLS session of
+/// its own with no way to check keys.
+pub const BROKER_PINS: &[&str] = &[];
+
+/// P-256 public key the trust bundles are signed with, uncompressed and in hex
+///
+/// Empty refuses every new trust bundle, the one written at the factory stays.
+pub const TRUST_BUNDLE_KEY: &str = "";
+
+/// How long to wait for the certificate of a server that failed the handshake
+pub const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
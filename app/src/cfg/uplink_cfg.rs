@@ -0,0 +1,58 @@
+// Outbound queue configuration, see `svc::uplink::outbox`
+use embassy_time::Duration;
+
+use crate::svc::uplink::outbox::TopicPolicy;
+
+/// Priority and expiry of the queued messages, by topic suffix (first match wins)
+///
+/// A lower priority value is published first, messages older than their
+/// `ttl` are dropped instead of being published late.
+pub const OUTBOX_TOPIC_POLICIES: &[TopicPolicy] = &[
+    TopicPolicy {
+        suffix: "/client/status",
+        priority: 0,
+        ttl: None,
+    },
+    TopicPolicy {
+        suffix: "/client/health",
+        priority: 0,
+        ttl: Some(Duration::from_secs(3600)),
+    },
+    TopicPolicy {
+        suffix: "/client/shadow/reported",
+        priority: 1,
+        ttl: None,
+    },
+    TopicPolicy {
+        suffix: "/client/event",
+        priority: 1,
+        ttl: None,
+    },
+    TopicPolicy {
+        suffix: "/client/ev/charge",
+        priority: 1,
+        ttl: None,
+    },
+    TopicPolicy {
+        suffix: "/client/trip",
+        priority: 2,
+        ttl: Some(Duration::from_secs(6 * 3600)),
+    },
+    TopicPolicy {
+        suffix: "/client/ev/curve",
+        priority: 3,
+        ttl: Some(Duration::from_secs(3600)),
+    },
+];
+
+/// Policy of the topics not listed above
+pub const OUTBOX_DEFAULT_POLICY: TopicPolicy = TopicPolicy {
+    suffix: "",
+    priority: 2,
+    ttl: Some(Duration::from_secs(3600)),
+};
+
+/// Start of the external flash region used to spill the outbox
+pub const OUTBOX_SPILL_BASE: u32 = 0x0010_0000;
+/// Number of messages the spill region holds, two per 4 KiB sector
+pub const OUTBOX_SPILL_SLOTS: u32 = 256;
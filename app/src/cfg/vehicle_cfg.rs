@@ -0,0 +1,43 @@
+// Vehicle specific CAN signals, fill in from the vehicle DBC
+//
+// Every signal is optional, the vehicle state falls back to bus activity and
+// GNSS speed for the ones left to `None`.
+use crate::svc::can::signal::SignalDef;
+
+/// Engine speed in rpm
+pub const ENGINE_RPM_SIGNAL: Option<SignalDef> = None;
+/// Selected gear, 0 is park or neutral, anything else is a drive gear
+pub const GEAR_SIGNAL: Option<SignalDef> = None;
+/// Charging in progress, any value but 0 means charging
+pub const CHARGING_SIGNAL: Option<SignalDef> = None;
+
+/// Example: J1939 EEC1 engine speed, 0.125 rpm/bit on bytes 4-5
+#[allow(dead_code)]
+pub const J1939_ENGINE_RPM: SignalDef = SignalDef {
+    id: 0x0CF0_0400,
+    extended: true,
+    start_bit: 24,
+    length: 16,
+    big_endian: false,
+    signed: false,
+    factor: 0.125,
+    offset: 0.0,
+    j1939: true,
+};
+
+// Battery management system signals, used by the EV charging analytics
+
+/// State of charge in %
+pub const BMS_SOC_SIGNAL: Option<SignalDef> = None;
+/// Pack voltage in V
+pub const BMS_PACK_VOLTAGE_SIGNAL: Option<SignalDef> = None;
+/// Pack current in A, positive while charging
+pub const BMS_PACK_CURRENT_SIGNAL: Option<SignalDef> = None;
+/// Lowest cell temperature in degC
+pub const BMS_CELL_TEMP_MIN_SIGNAL: Option<SignalDef> = None;
+/// Highest cell temperature in degC
+pub const BMS_CELL_TEMP_MAX_SIGNAL: Option<SignalDef> = None;
+/// Lowest cell voltage in V
+pub const BMS_CELL_VOLTAGE_MIN_SIGNAL: Option<SignalDef> = None;
+/// Highest cell voltage in V
+pub const BMS_CELL_VOLTAGE_MAX_SIGNAL: Option<SignalDef> = None;
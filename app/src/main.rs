@@ -11,18 +11,39 @@ mod util;
 // Import the necessary modules
 //use crate::hal::flash;
 use crate::svc::atcmd::Urc;
+use crate::svc::can::isotp::DiagLink;
+use crate::svc::clock::{ClockCell, WallClock};
+use crate::svc::config::{ConfigCell, DeviceConfig};
+use crate::svc::credentials::RotationLink;
+use crate::svc::ev::{Battery, BatteryCell};
+use crate::svc::rpc::RpcInbox;
+use crate::svc::shadow::ShadowLink;
+use crate::svc::sniff::{LiveSession, LiveSessionCell};
+use crate::svc::supervisor::WifiResetSignal;
+use crate::svc::uplink::Uplink;
+use crate::svc::vehicle::{Vehicle, VehicleCell};
 use task::can::*;
+use task::canlink::*;
+use task::ev::*;
 use task::lte::*;
 use task::mqtt::*;
 #[cfg(feature = "ota")]
 use task::ota::ota_handler;
+#[cfg(feature = "spill")]
+use task::outbox::outbox_spill;
+use task::rpc::*;
+use task::shadow::*;
+use task::sniff::*;
+use task::sntp::*;
+use task::vehicle::*;
 use task::wifi::*;
 
 // Import the necessary modules
 use atat::{ResponseSlot, UrcChannel};
+use core::cell::RefCell;
 use embassy_executor::Spawner;
 use embassy_net::{Stack, StackResources};
-use embassy_sync::channel::Channel;
+use embassy_sync::{blocking_mutex::Mutex, channel::Channel, signal::Signal};
 #[cfg(feature = "ota")]
 use embassy_time::Duration;
 use embassy_time::Timer;
@@ -89,7 +110,7 @@ async fn main(spawner: Spawner) -> ! {
     let (stack, runner) = embassy_net::new(
         wifi_interface,
         config,
-        mk_static!(StackResources<3>, StackResources::<3>::new()),
+        mk_static!(StackResources<5>, StackResources::<5>::new()),
         seed,
     );
     let stack = &*mk_static!(Stack, stack);
@@ -107,7 +128,8 @@ async fn main(spawner: Spawner) -> ! {
         .into_async();
     let (uart_rx, uart_tx) = uart0.split();
     static RES_SLOT: ResponseSlot<1024> = ResponseSlot::new();
-    static URC_CHANNEL: UrcChannel<Urc, 128, 3> = UrcChannel::new();
+    // +QMTRECV carries up to 256 bytes, keep the URC queue short
+    static URC_CHANNEL: UrcChannel<Urc, 8, 3> = UrcChannel::new();
     static INGRESS_BUF: StaticCell<[u8; 1024]> = StaticCell::new();
     let ingress = atat::Ingress::new(
         atat::AtDigester::<Urc>::default(),
@@ -125,6 +147,7 @@ async fn main(spawner: Spawner) -> ! {
 
     let can_tx_pin = peripherals.GPIO1;
     let can_rx_pin = peripherals.GPIO10;
+    // Keep in sync with CAN_BITRATE_BPS
     const CAN_BAUDRATE: twai::BaudRate = twai::BaudRate::B250K;
     let mut twai_config = twai::TwaiConfiguration::new(
         peripherals.TWAI0,
@@ -146,15 +169,98 @@ async fn main(spawner: Spawner) -> ! {
     let can = twai_config.start();
     static CHANNEL: StaticCell<TwaiOutbox> = StaticCell::new();
     let channel = &*CHANNEL.init(Channel::new());
-    let (can_rx, _can_tx) = can.split();
+    static SNIFF_CHANNEL: StaticCell<SniffOutbox> = StaticCell::new();
+    let sniff_channel = &*SNIFF_CHANNEL.init(Channel::new());
+    static LIVE_SESSION: StaticCell<LiveSessionCell> = StaticCell::new();
+    let live_session = &*LIVE_SESSION.init(Mutex::new(RefCell::new(LiveSession::new())));
+    static UPLINK: StaticCell<Uplink> = StaticCell::new();
+    let uplink = &*UPLINK.init(Uplink::new());
+    static CAPTURE_CHANNEL: StaticCell<CaptureOutbox> = StaticCell::new();
+    let capture_channel = &*CAPTURE_CHANNEL.init(Channel::new());
+    static CAN_TX_CHANNEL: StaticCell<CanTxInbox> = StaticCell::new();
+    let can_tx_channel = &*CAN_TX_CHANNEL.init(Channel::new());
+    static VEHICLE: StaticCell<VehicleCell> = StaticCell::new();
+    let vehicle = &*VEHICLE.init(Mutex::new(RefCell::new(Vehicle::new())));
+    static BATTERY: StaticCell<BatteryCell> = StaticCell::new();
+    let battery = &*BATTERY.init(Mutex::new(RefCell::new(Battery::new())));
+    static WIFI_RESET: StaticCell<WifiResetSignal> = StaticCell::new();
+    let wifi_reset = &*WIFI_RESET.init(Signal::new());
+    static RPC_INBOX: StaticCell<RpcInbox> = StaticCell::new();
+    let rpc_inbox = &*RPC_INBOX.init(Channel::new());
+    static CONFIG: StaticCell<ConfigCell> = StaticCell::new();
+    let config = &*CONFIG.init(Mutex::new(RefCell::new(DeviceConfig::new())));
+    static DIAG_LINK: StaticCell<DiagLink> = StaticCell::new();
+    let diag_link = &*DIAG_LINK.init(DiagLink::new());
+    static SHADOW_LINK: StaticCell<ShadowLink> = StaticCell::new();
+    let shadow_link = &*SHADOW_LINK.init(ShadowLink::new());
+    static ROTATION: StaticCell<RotationLink> = StaticCell::new();
+    let rotation = &*ROTATION.init(RotationLink::new());
+    static CLOCK: StaticCell<ClockCell> = StaticCell::new();
+    let clock = &*CLOCK.init(Mutex::new(RefCell::new(WallClock::new())));
+    // Private key of the device, should fleet provisioning be needed
+    let mut key_seed = [0u8; 32];
+    trng.read(&mut key_seed);
+    // Key of the DNS transaction IDs and source ports
+    let mut dns_seed = [0u8; 32];
+    trng.read(&mut dns_seed);
+    // Key of the SNTP nonces, its DNS queries and source ports
+    let mut ntp_seed = [0u8; 32];
+    trng.read(&mut ntp_seed);
+    let (can_rx, can_tx) = can.split();
 
-    spawner.spawn(can_receiver(can_rx, channel)).ok();
-    spawner.spawn(connection(controller)).ok();
+    spawner
+        .spawn(can_receiver(
+            can_rx,
+            channel,
+            sniff_channel,
+            live_session,
+            capture_channel,
+            vehicle,
+            battery,
+            diag_link,
+        ))
+        .ok();
+    spawner.spawn(vehicle_monitor(vehicle, uplink)).ok();
+    spawner.spawn(ev_monitor(battery, vehicle, uplink)).ok();
+    spawner.spawn(can_transmitter(can_tx, can_tx_channel)).ok();
+    spawner
+        .spawn(sniff_streamer(sniff_channel, live_session, uplink))
+        .ok();
+    spawner
+        .spawn(rpc_server(
+            rpc_inbox,
+            uplink,
+            config,
+            live_session,
+            can_tx_channel,
+            diag_link,
+            rotation,
+        ))
+        .ok();
+    // Restores the stored settings, ahead of the uplinks
+    spawner.spawn(shadow_sync(shadow_link, config, uplink)).ok();
+    spawner.spawn(connection(controller, wifi_reset)).ok();
     spawner.spawn(net_task(runner)).ok();
+    spawner.spawn(sntp_client(stack, clock, ntp_seed)).ok();
+    spawner
+        .spawn(can_link_server(stack, capture_channel, can_tx_channel))
+        .ok();
     spawner
         .spawn(mqtt_handler(
             stack,
             channel,
+            uplink,
+            vehicle,
+            battery,
+            live_session,
+            rpc_inbox,
+            shadow_link,
+            config,
+            clock,
+            rotation,
+            wifi_reset,
+            key_seed,
+            dns_seed,
             peripherals.SHA,
             peripherals.RSA,
         ))
@@ -166,8 +272,38 @@ async fn main(spawner: Spawner) -> ! {
             quectel_pen_pin,
             quectel_dtr_pin,
             &URC_CHANNEL,
+            uplink,
+            vehicle,
+            rpc_inbox,
+            shadow_link,
+            config,
+            clock,
+            rotation,
         ))
         .ok();
+    #[cfg(feature = "spill")]
+    {
+        use esp_hal::prelude::*;
+        use esp_hal::spi::{
+            master::{Config as SpiConfig, Spi},
+            Mode as SpiMode,
+        };
+        // External flash on the FSPI pins
+        let spi = Spi::new(
+            peripherals.SPI2,
+            SpiConfig::default()
+                .with_frequency(20.MHz())
+                .with_mode(SpiMode::_0),
+        )
+        .unwrap()
+        .with_sck(peripherals.GPIO6)
+        .with_mosi(peripherals.GPIO7)
+        .with_miso(peripherals.GPIO2);
+        let cs = Output::new(peripherals.GPIO16, esp_hal::gpio::Level::High);
+        spawner
+            .spawn(outbox_spill(uplink, hal::flash::W25Q128FVSG::new(spi, cs)))
+            .ok();
+    }
     #[cfg(feature = "ota")]
     //wait until wifi connected
     {
@@ -139,6 +139,17 @@ pub struct SetUeFunctionality {
     pub fun: FunctionalityLevelOfUE,
 }
 
+/// AT+QSCLK Configure Whether or Not to Enter Sleep Mode
+///
+/// With `mode` 1 the module enters sleep mode while DTR is high, pulling DTR low
+/// wakes it up.
+#[derive(Debug, PartialEq, Clone, AtatCmd)]
+#[at_cmd("+QSCLK", NoResponse, timeout_ms = 300)]
+pub struct ConfigureSleepMode {
+    #[at_arg(position = 0)]
+    pub mode: u8,
+}
+
 /// AT+QGMR Query Firmware Version
 ///
 /// This command is used to query the firmware version of the module.
@@ -445,9 +456,9 @@ pub struct MqttConnect {
     #[at_arg(position = 1)]
     pub tcp_connect_id: u8,
     /// <clientID>
-    /// String type. The client identifier. The maximum length is 23 bytes.
+    /// String type. The client identifier. The maximum length is 64 bytes here.
     #[at_arg(position = 2)]
-    pub client_id: String<23>,
+    pub client_id: String<64>,
     /// <username>
     /// String type. The username. The maximum length is 64 bytes.
     #[at_arg(position = 3)]
@@ -460,9 +471,11 @@ pub struct MqttConnect {
 
 /// AT+QMTPUBEX Publish an MQTT Message with Extended Parameters
 ///
-/// The command responds with OK. We need to get the response from the URC +QMTPUB.
+/// Data mode: the modem answers with the `>` prompt and takes `length` bytes of
+/// payload, sent with [`MqttPublishPayload`]. The result comes with the URC
+/// +QMTPUBEX.
 #[derive(Clone, AtatCmd)]
-#[at_cmd("+QMTPUB", NoResponse, timeout_ms = 300)]
+#[at_cmd("+QMTPUBEX", NoResponse, timeout_ms = 300)]
 pub struct MqttPublishExtended {
     /// <tcpconnectID>
     /// Integer type. MQTT socket identifier. The range is from 0 to 5.
@@ -491,12 +504,62 @@ pub struct MqttPublishExtended {
     /// The topic name must not include the wildcard characters + and #.
     #[at_arg(position = 5)]
     pub topic: String<128>,
-    /// <payload>
-    /// String type. The payload. The maximum length is 1024 bytes.
-    /// The payload must be a UTF-8 encoded string.
-    /// The payload must not include the null character.
+    /// <length>
+    /// Integer type. Length of the payload. The range is from 1 to 1024.
     #[at_arg(position = 6)]
-    pub payload: String<1024>,
+    pub length: u16,
+}
+
+/// Payload of an AT+QMTPUBEX, sent as is after the `>` prompt
+///
+/// Any bytes, quotes and binary formats included. The modem answers with OK
+/// once it has taken them.
+#[derive(Clone)]
+pub struct MqttPublishPayload {
+    pub payload: heapless::Vec<u8, 1024>,
+}
+
+impl AtatCmd for MqttPublishPayload {
+    type Response = NoResponse;
+
+    const MAX_LEN: usize = 1024;
+
+    fn write(&self, buf: &mut [u8]) -> usize {
+        buf[..self.payload.len()].copy_from_slice(&self.payload);
+        self.payload.len()
+    }
+
+    fn parse(
+        &self,
+        resp: Result<&[u8], atat::InternalError>,
+    ) -> Result<Self::Response, atat::Error> {
+        resp.map(|_| NoResponse).map_err(atat::Error::from)
+    }
+}
+
+/// AT+QMTSUB Subscribe to a Topic
+///
+/// The command responds with OK. We need to get the result from the URC +QMTSUB.
+/// Messages published on the topic are then reported by the URC +QMTRECV.
+#[derive(Clone, AtatCmd)]
+#[at_cmd("+QMTSUB", NoResponse, timeout_ms = 300)]
+pub struct MqttSubscribe {
+    /// <tcpconnectID>
+    /// Integer type. MQTT socket identifier. The range is from 0 to 5.
+    #[at_arg(position = 1)]
+    pub tcp_connect_id: u8,
+    /// <msgID>
+    /// Integer type. The packet identifier. The range is from 1 to 65535.
+    #[at_arg(position = 2)]
+    pub msg_id: u16,
+    /// <topic>
+    /// String type. The topic filter, wildcards allowed.
+    #[at_arg(position = 3)]
+    pub topic: String<128>,
+    /// <qos>
+    /// Integer type. The QoS level at which the client wants to receive the messages.
+    #[at_arg(position = 4)]
+    pub qos: u8,
 }
 
 /// AT+QMTDISC Disconnect a MQTT Connection
@@ -571,6 +634,42 @@ pub struct MqttConfig {
     pub param_3: Option<u8>,
 }
 
+/// AT+QMTCFG="will" configure the Will Information
+///
+/// The command is used to configure the Last Will, to be set before AT+QMTCONN.
+#[derive(Clone, AtatCmd)]
+#[at_cmd("+QMTCFG", NoResponse)]
+pub struct MqttConfigWill {
+    /// config name
+    /// String type. Always "will"
+    #[at_arg(position = 1)]
+    pub name: String<12>,
+    /// <tcpconnectID>
+    /// Integer type. MQTT socket identifier. The range is from 0 to 5.
+    #[at_arg(position = 2)]
+    pub tcp_connect_id: u8,
+    /// <will_fg>
+    /// Integer type. 0: Ignore the Will Flag configuration, 1: Require the Will Flag configuration
+    #[at_arg(position = 3)]
+    pub will_flag: u8,
+    /// <will_qos>
+    /// Integer type. The QoS level of the will message. The range is from 0 to 2.
+    #[at_arg(position = 4)]
+    pub will_qos: u8,
+    /// <will_retain>
+    /// Integer type. 0: The will message is not retained, 1: The will message is retained
+    #[at_arg(position = 5)]
+    pub will_retain: u8,
+    /// <will_topic>
+    /// String type. The will topic. The maximum length is 256 bytes.
+    #[at_arg(position = 6)]
+    pub will_topic: String<128>,
+    /// <will_msg>
+    /// String type. The will message. The maximum length is 256 bytes.
+    #[at_arg(position = 7)]
+    pub will_msg: String<256>,
+}
+
 /// AT+QFDEL delete file path
 ///
 /// The command is used to delete the specified file <filename> in UFS.
@@ -500,7 +500,6 @@ pub struct MqttOpenResponse {
 
 /// URC +QMTSTAT response
 #[derive(Clone, Debug, AtatResp)]
-#[allow(dead_code)]
 pub struct MqttStatusResponse {
     /// <tcpconnectID>
     /// Integer type. The MQTT socket identifier from 0 to 5.
@@ -546,7 +545,7 @@ pub struct MqttConnectResponse {
     pub ret_code: u8,
 }
 
-/// URC +QMTPUB response
+/// URC +QMTPUBEX response
 #[derive(Clone, Debug, AtatResp)]
 #[allow(dead_code)]
 pub struct MqttPublishResponse {
@@ -573,6 +572,57 @@ pub struct MqttPublishResponse {
     pub value: Option<u8>,
 }
 
+/// URC +QMTSUB response
+#[derive(Clone, Debug, AtatResp)]
+#[allow(dead_code)]
+pub struct MqttSubscribeResponse {
+    /// <tcpconnectID>
+    /// Integer type. The MQTT socket identifier from 0 to 5.
+    #[at_arg(position = 1)]
+    pub tcpconnect_id: u8,
+    /// <msgID>
+    /// Integer type. The packet identifier.
+    #[at_arg(position = 2)]
+    pub message_id: u16,
+    /// <result>
+    /// Integer type. The result of the operation.
+    /// 0: Sent packet successfully and received ACK from server.
+    /// 1: Packet retransmission.
+    /// 2: Failed to send packet.
+    #[at_arg(position = 3)]
+    pub result: u8,
+    /// <value>
+    /// Integer type.
+    /// If result is 0, the granted QoS level.
+    /// If result is 1, the number of retransmissions.
+    #[at_arg(position = 4)]
+    pub value: Option<u8>,
+}
+
+/// URC +QMTRECV response
+///
+/// The payload is hex encoded, see the receive data format of AT+QMTCFG="dataformat".
+#[derive(Clone, Debug, AtatResp)]
+#[allow(dead_code)]
+pub struct MqttReceiveResponse {
+    /// <tcpconnectID>
+    /// Integer type. The MQTT socket identifier from 0 to 5.
+    #[at_arg(position = 1)]
+    pub tcpconnect_id: u8,
+    /// <msgID>
+    /// Integer type. The packet identifier, 0 for QoS 0 messages.
+    #[at_arg(position = 2)]
+    pub message_id: u16,
+    /// <topic>
+    /// String type. The topic the message was published on.
+    #[at_arg(position = 3)]
+    pub topic: String<128>,
+    /// <payload>
+    /// String type. The payload, two hex digits per byte.
+    #[at_arg(position = 4)]
+    pub payload: String<512>,
+}
+
 /// URC +QMTDISC response
 #[derive(Clone, Debug, AtatResp)]
 #[allow(dead_code)]
@@ -27,7 +27,6 @@ pub enum Urc {
     /// MQTT status URC
     /// +QMTSTAT: <link_id>,<status> where <link_id> is the link identifier and <status> is the status of the MQTT connection.
     #[at_urc("+QMTSTAT")]
-    #[allow(dead_code)]
     MqttStatus(MqttStatusResponse),
 
     /// MQTT connection URC
@@ -36,11 +35,20 @@ pub enum Urc {
     MqttConnect(MqttConnectResponse),
 
     /// MQTT publish URC
-    /// +QMTPUB: <tcpconnectID>,<messageID>,<result>[,<value>]
-    #[at_urc("+QMTPUB")]
-    #[allow(dead_code)]
+    /// +QMTPUBEX: <tcpconnectID>,<messageID>,<result>[,<value>]
+    #[at_urc("+QMTPUBEX")]
     MqttPublish(MqttPublishResponse),
 
+    /// MQTT subscribe URC
+    /// +QMTSUB: <tcpconnectID>,<msgID>,<result>[,<value>]
+    #[at_urc("+QMTSUB")]
+    MqttSubscribe(MqttSubscribeResponse),
+
+    /// MQTT message received URC
+    /// +QMTRECV: <tcpconnectID>,<msgID>,<topic>,<payload>
+    #[at_urc("+QMTRECV")]
+    MqttReceive(MqttReceiveResponse),
+
     /// MQTT Disconnection URC
     /// +QMTDISC: <tcpconnectID>,<result>
     #[at_urc("+QMTDISC")]
@@ -0,0 +1,74 @@
+use serde::Deserialize;
+
+/// Maximum number of acceptance filters in one filter set
+pub const MAX_FILTERS: usize = 16;
+
+const STANDARD_ID_MASK: u32 = 0x7FF;
+const EXTENDED_ID_MASK: u32 = 0x1FFF_FFFF;
+
+/// Software acceptance filter for a single CAN identifier range
+///
+/// A frame matches when `(frame_id & mask) == (id & mask)`. When `mask` is omitted
+/// the identifier has to match exactly. `ext` restricts the filter to standard
+/// (`false`) or extended (`true`) frames, both are accepted when it is omitted.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub struct CanFilter {
+    pub id: u32,
+    pub mask: Option<u32>,
+    pub ext: Option<bool>,
+}
+
+impl CanFilter {
+    pub fn matches(&self, id: u32, extended: bool) -> bool {
+        if let Some(ext) = self.ext {
+            if ext != extended {
+                return false;
+            }
+        }
+        let id_mask = if extended {
+            EXTENDED_ID_MASK
+        } else {
+            STANDARD_ID_MASK
+        };
+        let mask = self.mask.unwrap_or(id_mask) & id_mask;
+        (id & mask) == (self.id & mask)
+    }
+}
+
+/// Set of acceptance filters, an empty set accepts every frame
+#[derive(Debug, Clone, Default)]
+pub struct FilterSet {
+    filters: heapless::Vec<CanFilter, MAX_FILTERS>,
+}
+
+impl FilterSet {
+    pub const fn new() -> Self {
+        Self {
+            filters: heapless::Vec::new(),
+        }
+    }
+
+    pub fn from_slice(filters: &[CanFilter]) -> Self {
+        let mut set = Self::new();
+        for filter in filters.iter().take(MAX_FILTERS) {
+            let _ = set.filters.push(*filter);
+        }
+        set
+    }
+
+    pub fn clear(&mut self) {
+        self.filters.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.filters.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+    }
+
+    pub fn matches(&self, id: u32, extended: bool) -> bool {
+        self.is_empty() || self.filters.iter().any(|f| f.matches(id, extended))
+    }
+}
@@ -0,0 +1,17 @@
+/// CAN frame as captured from or sent to the TWAI controller
+#[derive(Debug, Clone, PartialEq)]
+pub struct CanFrame {
+    pub id: u32,
+    pub extended: bool,
+    pub len: u8,
+    pub data: [u8; 8],
+    /// Reception time in microseconds since boot
+    pub timestamp_us: u64,
+}
+
+impl CanFrame {
+    /// Data bytes actually carried by the frame
+    pub fn payload(&self) -> &[u8] {
+        &self.data[..(self.len as usize).min(8)]
+    }
+}
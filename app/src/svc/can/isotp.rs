@@ -0,0 +1,197 @@
+//! ISO-TP (ISO 15765-2) transport of the diagnostic requests
+//!
+//! Only what a tester needs: requests fit a single frame, responses come as a
+//! single frame or as a first frame followed by consecutive frames once the
+//! device sent a flow control frame. Normal addressing, classic 8 byte frames.
+use core::cell::Cell;
+
+use embassy_sync::{
+    blocking_mutex::{raw::NoopRawMutex, Mutex},
+    channel::Channel,
+};
+use embassy_time::Instant;
+
+use super::CanFrame;
+
+/// Longest response reassembled
+pub const MAX_MESSAGE_LEN: usize = 128;
+/// Filler of the unused bytes, frames are always sent with 8 bytes
+const PADDING: u8 = 0xAA;
+
+const SINGLE_FRAME: u8 = 0x0;
+const FIRST_FRAME: u8 = 0x1;
+const CONSECUTIVE_FRAME: u8 = 0x2;
+const FLOW_CONTROL: u8 = 0x3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IsoTpError {
+    /// Consecutive frame without first frame, or malformed frame
+    Unexpected,
+    /// Consecutive frame out of sequence
+    Sequence,
+    /// Response longer than [`MAX_MESSAGE_LEN`]
+    TooLong,
+}
+
+/// Hands the frames of the ECU being queried from the CAN receiver over to the
+/// diagnostic request in progress
+pub struct DiagLink {
+    listen: Mutex<NoopRawMutex, Cell<Option<(u32, bool)>>>,
+    frames: Channel<NoopRawMutex, CanFrame, 8>,
+}
+
+impl Default for DiagLink {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DiagLink {
+    pub const fn new() -> Self {
+        Self {
+            listen: Mutex::new(Cell::new(None)),
+            frames: Channel::new(),
+        }
+    }
+
+    /// Start collecting the frames sent with `id`, whatever was left over is dropped
+    pub fn listen(&self, id: u32, extended: bool) {
+        self.listen.lock(|l| l.set(Some((id, extended))));
+        while self.frames.try_receive().is_ok() {}
+    }
+
+    pub fn stop(&self) {
+        self.listen.lock(|l| l.set(None));
+    }
+
+    /// Called by the CAN receiver for every frame
+    pub fn offer(&self, frame: &CanFrame) {
+        if self.listen.lock(|l| l.get()) == Some((frame.id, frame.extended)) {
+            let _ = self.frames.try_send(frame.clone());
+        }
+    }
+
+    pub async fn receive(&self) -> CanFrame {
+        self.frames.receive().await
+    }
+}
+
+/// `payload` as a single frame, `None` when it does not fit one
+pub fn single_frame(id: u32, extended: bool, payload: &[u8]) -> Option<CanFrame> {
+    if payload.is_empty() || payload.len() > 7 {
+        return None;
+    }
+    let mut data = [PADDING; 8];
+    data[0] = (SINGLE_FRAME << 4) | payload.len() as u8;
+    data[1..1 + payload.len()].copy_from_slice(payload);
+    Some(frame(id, extended, data))
+}
+
+/// Clear to send, no block size limit nor separation time
+pub fn flow_control(id: u32, extended: bool) -> CanFrame {
+    let mut data = [PADDING; 8];
+    data[0] = FLOW_CONTROL << 4;
+    data[1] = 0;
+    data[2] = 0;
+    frame(id, extended, data)
+}
+
+fn frame(id: u32, extended: bool, data: [u8; 8]) -> CanFrame {
+    CanFrame {
+        id,
+        extended,
+        len: 8,
+        data,
+        timestamp_us: Instant::now().as_micros(),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Progress {
+    /// The message is complete, see [`Reassembler::message`]
+    Complete,
+    /// First frame received, a flow control frame has to be sent
+    FlowControl,
+    Pending,
+}
+
+/// Reassembles a response from its frames
+#[derive(Debug, Default)]
+pub struct Reassembler {
+    buf: heapless::Vec<u8, MAX_MESSAGE_LEN>,
+    expected: usize,
+    next_seq: u8,
+}
+
+impl Reassembler {
+    pub const fn new() -> Self {
+        Self {
+            buf: heapless::Vec::new(),
+            expected: 0,
+            next_seq: 0,
+        }
+    }
+
+    pub fn on_frame(&mut self, data: &[u8]) -> Result<Progress, IsoTpError> {
+        let pci = *data.first().ok_or(IsoTpError::Unexpected)?;
+        match pci >> 4 {
+            SINGLE_FRAME => {
+                let len = (pci & 0x0F) as usize;
+                let payload = data.get(1..1 + len).ok_or(IsoTpError::Unexpected)?;
+                if len == 0 {
+                    return Err(IsoTpError::Unexpected);
+                }
+                self.start(len)?;
+                self.push(payload);
+                Ok(Progress::Complete)
+            }
+            FIRST_FRAME => {
+                let low = *data.get(1).ok_or(IsoTpError::Unexpected)?;
+                let len = (((pci & 0x0F) as usize) << 8) | low as usize;
+                if len < 8 {
+                    return Err(IsoTpError::Unexpected);
+                }
+                self.start(len)?;
+                self.push(&data[2..]);
+                self.next_seq = 1;
+                Ok(Progress::FlowControl)
+            }
+            CONSECUTIVE_FRAME => {
+                if self.expected == 0 || self.buf.len() >= self.expected {
+                    return Err(IsoTpError::Unexpected);
+                }
+                if pci & 0x0F != self.next_seq {
+                    return Err(IsoTpError::Sequence);
+                }
+                self.next_seq = (self.next_seq + 1) & 0x0F;
+                self.push(&data[1..]);
+                Ok(if self.buf.len() == self.expected {
+                    Progress::Complete
+                } else {
+                    Progress::Pending
+                })
+            }
+            // Flow control of another tester, nothing to do with the response
+            _ => Ok(Progress::Pending),
+        }
+    }
+
+    pub fn message(&self) -> &[u8] {
+        &self.buf
+    }
+
+    fn start(&mut self, len: usize) -> Result<(), IsoTpError> {
+        if len > MAX_MESSAGE_LEN {
+            return Err(IsoTpError::TooLong);
+        }
+        self.buf.clear();
+        self.expected = len;
+        Ok(())
+    }
+
+    /// Append up to the announced length, the padding is left out
+    fn push(&mut self, bytes: &[u8]) {
+        let take = (self.expected - self.buf.len()).min(bytes.len());
+        let _ = self.buf.extend_from_slice(&bytes[..take]);
+    }
+}
@@ -0,0 +1,5 @@
+pub mod filter;
+pub mod frame;
+pub mod isotp;
+pub mod signal;
+pub use frame::CanFrame;
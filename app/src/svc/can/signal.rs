@@ -0,0 +1,134 @@
+use crate::svc::can::CanFrame;
+
+/// Physical signal packed in a CAN frame, as described by a DBC `SG_` line
+///
+/// `physical = raw * factor + offset`
+#[derive(Debug, Clone, Copy)]
+pub struct SignalDef {
+    pub id: u32,
+    pub extended: bool,
+    pub start_bit: u8,
+    pub length: u8,
+    /// Motorola byte order (`@0` in the DBC), `start_bit` is then the most
+    /// significant bit, the least significant one otherwise (Intel, `@1`)
+    pub big_endian: bool,
+    pub signed: bool,
+    pub factor: f32,
+    pub offset: f32,
+    /// SAE J1939-71 parameter, the raw values above its valid range (error,
+    /// not available) decode as missing
+    pub j1939: bool,
+}
+
+impl SignalDef {
+    /// Decode the signal from `frame`, `None` if the frame does not carry it
+    pub fn decode(&self, frame: &CanFrame) -> Option<f32> {
+        if frame.id != self.id || frame.extended != self.extended {
+            return None;
+        }
+        let length = self.length as u32;
+        if length == 0 || length > 64 {
+            return None;
+        }
+        let raw = if self.big_endian {
+            // Position of the most significant bit in the big endian bit stream
+            let msb = (self.start_bit as u32 / 8) * 8 + (7 - self.start_bit as u32 % 8);
+            if msb + length > frame.len as u32 * 8 {
+                return None;
+            }
+            u64::from_be_bytes(frame.data) >> (64 - msb - length)
+        } else {
+            if self.start_bit as u32 + length > frame.len as u32 * 8 {
+                return None;
+            }
+            u64::from_le_bytes(frame.data) >> self.start_bit
+        };
+        let mask = if length == 64 {
+            u64::MAX
+        } else {
+            (1u64 << length) - 1
+        };
+        let raw = raw & mask;
+        if self.j1939 && !j1939_valid(raw, length) {
+            return None;
+        }
+        let value = if self.signed && length < 64 && raw & (1 << (length - 1)) != 0 {
+            (raw | !mask) as i64 as f32
+        } else if self.signed {
+            raw as i64 as f32
+        } else {
+            raw as f32
+        };
+        Some(value * self.factor + self.offset)
+    }
+}
+
+/// Whether `raw` is in the valid signal range of SAE J1939-71
+///
+/// Parameters of whole bytes are valid up to 0xFA in their most significant
+/// byte, 0xFE is the error indicator and 0xFF "not available". Shorter ones
+/// end with the error and "not available" values, `10` and `11` for 2 bits.
+fn j1939_valid(raw: u64, length: u32) -> bool {
+    if length.is_multiple_of(8) {
+        raw >> (length - 8) <= 0xFA
+    } else {
+        raw < (1 << length) - 2
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ENGINE_RPM: SignalDef = SignalDef {
+        id: 0x0CF0_0400,
+        extended: true,
+        start_bit: 24,
+        length: 16,
+        big_endian: false,
+        signed: false,
+        factor: 0.125,
+        offset: 0.0,
+        j1939: true,
+    };
+
+    fn eec1(rpm: [u8; 2]) -> CanFrame {
+        CanFrame {
+            id: 0x0CF0_0400,
+            extended: true,
+            len: 8,
+            data: [0xFF, 0xFF, 0xFF, rpm[0], rpm[1], 0xFF, 0xFF, 0xFF],
+            timestamp_us: 0,
+        }
+    }
+
+    #[test]
+    fn decodes_j1939_engine_speed() {
+        assert_eq!(ENGINE_RPM.decode(&eec1([0x00, 0x00])), Some(0.0));
+        assert_eq!(ENGINE_RPM.decode(&eec1([0x40, 0x1F])), Some(1000.0));
+        // Top of the valid range
+        assert_eq!(ENGINE_RPM.decode(&eec1([0xFF, 0xFA])), Some(8031.875));
+    }
+
+    #[test]
+    fn j1939_error_and_not_available_are_missing() {
+        for rpm in [[0x00, 0xFB], [0x00, 0xFE], [0xFF, 0xFE], [0xFF, 0xFF]] {
+            assert_eq!(ENGINE_RPM.decode(&eec1(rpm)), None, "{rpm:02X?}");
+        }
+        // Taken as is outside of J1939
+        let raw = SignalDef {
+            j1939: false,
+            ..ENGINE_RPM
+        };
+        assert_eq!(raw.decode(&eec1([0xFF, 0xFF])), Some(8191.875));
+    }
+
+    #[test]
+    fn j1939_discrete_states() {
+        assert!(j1939_valid(0b00, 2) && j1939_valid(0b01, 2));
+        // Error, not available
+        assert!(!j1939_valid(0b10, 2) && !j1939_valid(0b11, 2));
+        assert!(j1939_valid(13, 4) && !j1939_valid(14, 4) && !j1939_valid(15, 4));
+        assert!(j1939_valid(0xFA, 8) && !j1939_valid(0xFB, 8));
+    }
+}
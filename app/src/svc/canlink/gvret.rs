@@ -0,0 +1,427 @@
+//! GVRET binary protocol, as spoken by SavvyCAN
+//!
+//! The host switches the link to binary mode by sending `0xE7 0xE7`. Every command
+//! then starts with `0xF1` followed by the command code and its arguments. Frames
+//! are exchanged as `F1 00 <ts:u32 LE> <id:u32 LE, bit31 = extended> <bus << 4 | len>
+//! <data..> <checksum>`.
+use crate::svc::can::CanFrame;
+
+const ENABLE_BINARY: u8 = 0xE7;
+const COMMAND_START: u8 = 0xF1;
+const EXTENDED_FLAG: u32 = 1 << 31;
+
+const BUILD_CAN_FRAME: u8 = 0x00;
+const TIME_SYNC: u8 = 0x01;
+const GET_DIG_INPUTS: u8 = 0x02;
+const GET_ANALOG_INPUTS: u8 = 0x03;
+const SET_DIG_OUTPUTS: u8 = 0x04;
+const SETUP_CANBUS: u8 = 0x05;
+const GET_CANBUS_PARAMS: u8 = 0x06;
+const GET_DEVICE_INFO: u8 = 0x07;
+const SET_SINGLEWIRE_MODE: u8 = 0x08;
+const KEEP_ALIVE: u8 = 0x09;
+const SET_SYSTEM_TYPE: u8 = 0x0A;
+const ECHO_CAN_FRAME: u8 = 0x0B;
+const GET_NUM_BUSES: u8 = 0x0C;
+const GET_EXT_BUSES: u8 = 0x0D;
+const SET_EXT_BUSES: u8 = 0x0E;
+
+/// Firmware build number reported to SavvyCAN
+const BUILD_NUMBER: u16 = 343;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum GvretCommand {
+    EnableBinary,
+    /// Transmit a frame on `bus`, `echo` asks to loop it back to the host as well
+    Transmit {
+        frame: CanFrame,
+        bus: u8,
+        echo: bool,
+    },
+    TimeSync,
+    GetDigitalInputs,
+    GetAnalogInputs,
+    SetupCanBus {
+        can0_speed: u32,
+        can1_speed: u32,
+    },
+    GetCanBusParams,
+    GetDeviceInfo,
+    KeepAlive,
+    GetNumBuses,
+    GetExtBuses,
+    /// Command understood but without effect on this device
+    Ignored(u8),
+    /// Frame longer than 8 bytes or with an identifier out of range, dropped
+    Invalid,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Idle,
+    Command,
+    Arguments { cmd: u8, expected: usize },
+}
+
+/// Incremental decoder, fed with the bytes received from the TCP stream
+pub struct GvretDecoder {
+    state: State,
+    args: heapless::Vec<u8, 16>,
+}
+
+impl Default for GvretDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl GvretDecoder {
+    pub const fn new() -> Self {
+        Self {
+            state: State::Idle,
+            args: heapless::Vec::new(),
+        }
+    }
+
+    /// Push one byte, returns the decoded command once it is complete
+    pub fn push(&mut self, byte: u8) -> Option<GvretCommand> {
+        match self.state {
+            State::Idle => match byte {
+                ENABLE_BINARY => Some(GvretCommand::EnableBinary),
+                COMMAND_START => {
+                    self.state = State::Command;
+                    None
+                }
+                _ => None,
+            },
+            State::Command => {
+                self.args.clear();
+                let expected = match byte {
+                    // id, bus and length first, the data length is known afterwards
+                    BUILD_CAN_FRAME | ECHO_CAN_FRAME => 6,
+                    SET_DIG_OUTPUTS | SET_SINGLEWIRE_MODE | SET_SYSTEM_TYPE => 1,
+                    SETUP_CANBUS => 8,
+                    SET_EXT_BUSES => 12,
+                    _ => 0,
+                };
+                if expected == 0 {
+                    self.state = State::Idle;
+                    Some(Self::decode(byte, &[]))
+                } else {
+                    self.state = State::Arguments {
+                        cmd: byte,
+                        expected,
+                    };
+                    None
+                }
+            }
+            State::Arguments { cmd, expected } => {
+                let _ = self.args.push(byte);
+                if matches!(cmd, BUILD_CAN_FRAME | ECHO_CAN_FRAME) && self.args.len() == 6 {
+                    let len = (self.args[5] & 0x0F) as usize;
+                    if len > 8 {
+                        self.state = State::Idle;
+                        return Some(GvretCommand::Invalid);
+                    }
+                    // data bytes plus the trailing checksum, which SavvyCAN
+                    // leaves at zero and GVRET never checks
+                    self.state = State::Arguments {
+                        cmd,
+                        expected: 6 + len + 1,
+                    };
+                    return None;
+                }
+                if self.args.len() < expected {
+                    return None;
+                }
+                self.state = State::Idle;
+                Some(Self::decode(cmd, &self.args))
+            }
+        }
+    }
+
+    fn decode(cmd: u8, args: &[u8]) -> GvretCommand {
+        match cmd {
+            BUILD_CAN_FRAME | ECHO_CAN_FRAME => {
+                let raw_id = u32::from_le_bytes([args[0], args[1], args[2], args[3]]);
+                let extended = raw_id & EXTENDED_FLAG != 0;
+                let id = raw_id & !EXTENDED_FLAG;
+                let max_id = if extended { 0x1FFF_FFFF } else { 0x7FF };
+                if id > max_id {
+                    return GvretCommand::Invalid;
+                }
+                let len = args[5] & 0x0F;
+                let mut data = [0u8; 8];
+                data[..len as usize].copy_from_slice(&args[6..6 + len as usize]);
+                GvretCommand::Transmit {
+                    frame: CanFrame {
+                        id,
+                        extended,
+                        len,
+                        data,
+                        timestamp_us: 0,
+                    },
+                    bus: args[4] & 0x03,
+                    echo: cmd == ECHO_CAN_FRAME,
+                }
+            }
+            TIME_SYNC => GvretCommand::TimeSync,
+            GET_DIG_INPUTS => GvretCommand::GetDigitalInputs,
+            GET_ANALOG_INPUTS => GvretCommand::GetAnalogInputs,
+            SETUP_CANBUS => GvretCommand::SetupCanBus {
+                can0_speed: u32::from_le_bytes([args[0], args[1], args[2], args[3]]),
+                can1_speed: u32::from_le_bytes([args[4], args[5], args[6], args[7]]),
+            },
+            GET_CANBUS_PARAMS => GvretCommand::GetCanBusParams,
+            GET_DEVICE_INFO => GvretCommand::GetDeviceInfo,
+            KEEP_ALIVE => GvretCommand::KeepAlive,
+            GET_NUM_BUSES => GvretCommand::GetNumBuses,
+            GET_EXT_BUSES => GvretCommand::GetExtBuses,
+            other => GvretCommand::Ignored(other),
+        }
+    }
+}
+
+/// Encode a received frame for the host, always reported on bus 0
+///
+/// Returns `false` when `out` is too small.
+pub fn encode_frame<const N: usize>(
+    frame: &CanFrame,
+    timestamp_us: u32,
+    out: &mut heapless::Vec<u8, N>,
+) -> bool {
+    let raw_id = if frame.extended {
+        frame.id | EXTENDED_FLAG
+    } else {
+        frame.id
+    };
+    let payload = frame.payload();
+    out.extend_from_slice(&[COMMAND_START, BUILD_CAN_FRAME])
+        .is_ok()
+        && out.extend_from_slice(&timestamp_us.to_le_bytes()).is_ok()
+        && out.extend_from_slice(&raw_id.to_le_bytes()).is_ok()
+        && out.push(payload.len() as u8).is_ok()
+        && out.extend_from_slice(payload).is_ok()
+        && out.push(0).is_ok()
+}
+
+/// Encode the answer to a command, commands without answer leave `out` untouched
+pub fn encode_response<const N: usize>(
+    cmd: &GvretCommand,
+    timestamp_us: u32,
+    bitrate: u32,
+    out: &mut heapless::Vec<u8, N>,
+) -> bool {
+    let res = match cmd {
+        GvretCommand::TimeSync => out
+            .extend_from_slice(&[COMMAND_START, TIME_SYNC])
+            .and_then(|_| out.extend_from_slice(&timestamp_us.to_le_bytes())),
+        GvretCommand::GetDigitalInputs => {
+            out.extend_from_slice(&[COMMAND_START, GET_DIG_INPUTS, 0, 0])
+        }
+        GvretCommand::GetAnalogInputs => out
+            .extend_from_slice(&[COMMAND_START, GET_ANALOG_INPUTS])
+            .and_then(|_| out.extend_from_slice(&[0; 15])),
+        GvretCommand::GetCanBusParams => out
+            .extend_from_slice(&[COMMAND_START, GET_CANBUS_PARAMS, 0x01])
+            .and_then(|_| out.extend_from_slice(&bitrate.to_le_bytes()))
+            .and_then(|_| out.push(0).map_err(|_| ()))
+            .and_then(|_| out.extend_from_slice(&0u32.to_le_bytes())),
+        GvretCommand::GetDeviceInfo => out
+            .extend_from_slice(&[COMMAND_START, GET_DEVICE_INFO])
+            .and_then(|_| out.extend_from_slice(&BUILD_NUMBER.to_le_bytes()))
+            .and_then(|_| out.extend_from_slice(&[0x20, 0, 0, 0])),
+        GvretCommand::KeepAlive => out.extend_from_slice(&[COMMAND_START, KEEP_ALIVE, 0xDE, 0xAD]),
+        GvretCommand::GetNumBuses => out.extend_from_slice(&[COMMAND_START, GET_NUM_BUSES, 1]),
+        GvretCommand::GetExtBuses => out
+            .extend_from_slice(&[COMMAND_START, GET_EXT_BUSES])
+            .and_then(|_| out.extend_from_slice(&[0; 15])),
+        GvretCommand::Transmit { frame, echo, .. } => {
+            return !*echo || encode_frame(frame, timestamp_us, out);
+        }
+        GvretCommand::EnableBinary
+        | GvretCommand::SetupCanBus { .. }
+        | GvretCommand::Ignored(_)
+        | GvretCommand::Invalid => Ok(()),
+    };
+    res.is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    /// What SavvyCAN sends once connected: binary mode, then the bus queries
+    const SAVVYCAN_CONNECT: &[u8] = &[
+        0xE7, 0xE7, 0xF1, 0x0C, 0xF1, 0x06, 0xF1, 0x07, 0xF1, 0x09, 0xF1, 0x01,
+    ];
+    /// Standard frame 0x123 `AA BB` on bus 0
+    const STANDARD_FRAME: &[u8] = &[
+        0xF1, 0x00, 0x23, 0x01, 0x00, 0x00, 0x00, 0x02, 0xAA, 0xBB, 0x00,
+    ];
+    /// Extended frame 0x18DAF110 with 8 bytes, echoed
+    const EXTENDED_ECHO: &[u8] = &[
+        0xF1, 0x0B, 0x10, 0xF1, 0xDA, 0x98, 0x00, 0x08, 0x02, 0x10, 0x03, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00,
+    ];
+
+    fn decode(chunks: &[&[u8]]) -> Vec<GvretCommand> {
+        let mut decoder = GvretDecoder::new();
+        chunks
+            .iter()
+            .flat_map(|chunk| chunk.iter())
+            .filter_map(|byte| decoder.push(*byte))
+            .collect()
+    }
+
+    fn frame(id: u32, extended: bool, payload: &[u8]) -> CanFrame {
+        let mut data = [0; 8];
+        data[..payload.len()].copy_from_slice(payload);
+        CanFrame {
+            id,
+            extended,
+            len: payload.len() as u8,
+            data,
+            timestamp_us: 0,
+        }
+    }
+
+    #[test]
+    fn decodes_savvycan_connect() {
+        assert_eq!(
+            decode(&[SAVVYCAN_CONNECT]),
+            [
+                GvretCommand::EnableBinary,
+                GvretCommand::EnableBinary,
+                GvretCommand::GetNumBuses,
+                GvretCommand::GetCanBusParams,
+                GvretCommand::GetDeviceInfo,
+                GvretCommand::KeepAlive,
+                GvretCommand::TimeSync,
+            ]
+        );
+    }
+
+    #[test]
+    fn decodes_standard_frame() {
+        assert_eq!(
+            decode(&[STANDARD_FRAME]),
+            [GvretCommand::Transmit {
+                frame: frame(0x123, false, &[0xAA, 0xBB]),
+                bus: 0,
+                echo: false,
+            }]
+        );
+    }
+
+    #[test]
+    fn decodes_extended_echo() {
+        assert_eq!(
+            decode(&[EXTENDED_ECHO]),
+            [GvretCommand::Transmit {
+                frame: frame(0x18DA_F110, true, &[0x02, 0x10, 0x03, 0, 0, 0, 0, 0]),
+                bus: 0,
+                echo: true,
+            }]
+        );
+    }
+
+    #[test]
+    fn frames_split_across_reads() {
+        let stream = [STANDARD_FRAME, EXTENDED_ECHO, &[0xF1, 0x09]].concat();
+        let whole = decode(&[&stream]);
+        assert_eq!(whole.len(), 3);
+        for split in 1..stream.len() {
+            let (head, tail) = stream.split_at(split);
+            assert_eq!(decode(&[head, tail]), whole, "split at {split}");
+        }
+        let bytes: Vec<&[u8]> = stream.chunks(1).collect();
+        assert_eq!(decode(&bytes), whole);
+    }
+
+    #[test]
+    fn rejects_length_over_8_and_resynchronizes() {
+        let stream = [
+            &[0xF1, 0x00, 0x23, 0x01, 0x00, 0x00, 0x00, 0x09][..],
+            &[0xF1, 0x09],
+        ];
+        assert_eq!(
+            decode(&stream),
+            [GvretCommand::Invalid, GvretCommand::KeepAlive]
+        );
+    }
+
+    #[test]
+    fn rejects_identifier_out_of_range() {
+        // Standard 0x800, extended 0x20000000
+        let standard = [0xF1, 0x00, 0x00, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00];
+        let extended = [0xF1, 0x00, 0x00, 0x00, 0x00, 0xA0, 0x00, 0x00, 0x00];
+        assert_eq!(decode(&[&standard]), [GvretCommand::Invalid]);
+        assert_eq!(decode(&[&extended]), [GvretCommand::Invalid]);
+    }
+
+    #[test]
+    fn checksum_is_consumed_not_checked() {
+        let mut stream = STANDARD_FRAME.to_vec();
+        *stream.last_mut().unwrap() = 0x5A;
+        stream.extend_from_slice(&[0xF1, 0x09]);
+        let commands = decode(&[&stream]);
+        assert!(matches!(commands[0], GvretCommand::Transmit { .. }));
+        assert_eq!(commands[1], GvretCommand::KeepAlive);
+    }
+
+    #[test]
+    fn skips_setup_arguments() {
+        // 250 kbit/s on can0 with the enable bit, can1 off
+        let setup = [
+            0xF1, 0x05, 0x90, 0xD0, 0x03, 0x80, 0x00, 0x00, 0x00, 0x00, 0xF1, 0x09,
+        ];
+        assert_eq!(
+            decode(&[&setup]),
+            [
+                GvretCommand::SetupCanBus {
+                    can0_speed: 0x8003_D090,
+                    can1_speed: 0,
+                },
+                GvretCommand::KeepAlive,
+            ]
+        );
+    }
+
+    #[test]
+    fn encodes_received_frames() {
+        let mut out = heapless::Vec::<u8, 32>::new();
+        assert!(encode_frame(
+            &frame(0x18DA_F110, true, &[1, 2, 3]),
+            0x0102_0304,
+            &mut out
+        ));
+        assert_eq!(
+            out,
+            [0xF1, 0x00, 0x04, 0x03, 0x02, 0x01, 0x10, 0xF1, 0xDA, 0x98, 0x03, 1, 2, 3, 0]
+        );
+        let mut small = heapless::Vec::<u8, 8>::new();
+        assert!(!encode_frame(&frame(0x123, false, &[1, 2]), 0, &mut small));
+    }
+
+    #[test]
+    fn answers_queries() {
+        let mut out = heapless::Vec::<u8, 64>::new();
+        for cmd in [
+            GvretCommand::GetNumBuses,
+            GvretCommand::KeepAlive,
+            GvretCommand::GetCanBusParams,
+            GvretCommand::EnableBinary,
+        ] {
+            assert!(encode_response(&cmd, 0, 250_000, &mut out));
+        }
+        assert_eq!(
+            out,
+            [
+                0xF1, 0x0C, 1, 0xF1, 0x09, 0xDE, 0xAD, 0xF1, 0x06, 0x01, 0x90, 0xD0, 0x03, 0x00, 0,
+                0, 0, 0, 0
+            ]
+        );
+    }
+}
@@ -0,0 +1,334 @@
+//! Wireless CAN adapter protocols (GVRET for SavvyCAN, SLCAN for `slcand`)
+//!
+//! Both codecs are pure byte-stream state machines so they can be checked on the
+//! host against recorded captures. The TCP plumbing lives in `task::canlink`.
+//!
+//! The link is plain TCP, neither protocol authenticates the host: it is only
+//! served to the allowed networks, and frames the host asks to transmit only
+//! reach the bus when transmitting is enabled.
+pub mod gvret;
+pub mod slcan;
+
+use log::{info, warn};
+
+use crate::svc::can::CanFrame;
+use gvret::{GvretCommand, GvretDecoder};
+use slcan::{SlcanCommand, SlcanDecoder, SLCAN_ERROR};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LinkProtocol {
+    /// Nothing received yet
+    Unknown,
+    Gvret,
+    Slcan,
+}
+
+/// Device properties reported to the host
+pub struct LinkContext {
+    /// Current time in microseconds, truncated to 32 bits as GVRET expects
+    pub timestamp_us: u32,
+    /// Bitrate the TWAI controller runs at
+    pub bitrate: u32,
+    /// Serial number reported to SLCAN hosts
+    pub serial: u16,
+}
+
+/// Whether the host at `peer` may use the link
+///
+/// `allowed` lists networks as an address and a prefix length. When it is
+/// empty, only the hosts of `subnet`, the network the device joined, are let
+/// in: GVRET and SLCAN have no way to authenticate a host, a routed peer is
+/// refused.
+pub fn peer_allowed(peer: [u8; 4], subnet: ([u8; 4], u8), allowed: &[([u8; 4], u8)]) -> bool {
+    if allowed.is_empty() {
+        return in_network(peer, subnet);
+    }
+    allowed.iter().any(|network| in_network(peer, *network))
+}
+
+fn in_network(addr: [u8; 4], (network, prefix_len): ([u8; 4], u8)) -> bool {
+    let mask = u32::MAX
+        .checked_shl(32 - u32::from(prefix_len.min(32)))
+        .unwrap_or(0);
+    u32::from_be_bytes(addr) & mask == u32::from_be_bytes(network) & mask
+}
+
+/// Protocol state of one connected host
+pub struct LinkSession {
+    protocol: LinkProtocol,
+    gvret: GvretDecoder,
+    slcan: SlcanDecoder,
+    /// Frames of the host may go on the bus
+    transmit: bool,
+    streaming: bool,
+    listen_only: bool,
+    timestamps: bool,
+}
+
+impl LinkSession {
+    /// `transmit` lets the host put frames on the bus, it only listens otherwise
+    pub const fn new(transmit: bool) -> Self {
+        Self {
+            protocol: LinkProtocol::Unknown,
+            gvret: GvretDecoder::new(),
+            slcan: SlcanDecoder::new(),
+            transmit,
+            streaming: false,
+            listen_only: false,
+            timestamps: false,
+        }
+    }
+
+    /// Whether received bus traffic has to be forwarded to the host
+    pub fn is_streaming(&self) -> bool {
+        self.streaming
+    }
+
+    /// Handle one byte from the host
+    ///
+    /// Answers are appended to `out`, a frame the host asked to transmit on the bus
+    /// is returned.
+    pub fn on_byte<const N: usize>(
+        &mut self,
+        byte: u8,
+        ctx: &LinkContext,
+        out: &mut heapless::Vec<u8, N>,
+    ) -> Option<CanFrame> {
+        if self.protocol == LinkProtocol::Unknown {
+            // Line ends a host sends to flush the link say nothing of the protocol
+            if byte == b'\r' || byte == b'\n' {
+                return None;
+            }
+            self.protocol = match byte {
+                0xE7 | 0xF1 => LinkProtocol::Gvret,
+                _ => LinkProtocol::Slcan,
+            };
+            info!("CAN link: host speaks {:?}", self.protocol);
+        }
+        match self.protocol {
+            LinkProtocol::Gvret => self.on_gvret_byte(byte, ctx, out),
+            _ => self.on_slcan_byte(byte, ctx, out),
+        }
+    }
+
+    /// Encode a frame received on the bus for the host
+    ///
+    /// Returns `false`, leaving `out` untouched, when the frame does not fit.
+    pub fn encode_frame<const N: usize>(
+        &self,
+        frame: &CanFrame,
+        out: &mut heapless::Vec<u8, N>,
+    ) -> bool {
+        let start = out.len();
+        let fits = match self.protocol {
+            LinkProtocol::Gvret => gvret::encode_frame(frame, frame.timestamp_us as u32, out),
+            LinkProtocol::Slcan => {
+                let timestamp_ms = self
+                    .timestamps
+                    .then_some(((frame.timestamp_us / 1000) % 60_000) as u16);
+                slcan::encode_frame(frame, timestamp_ms, out)
+            }
+            LinkProtocol::Unknown => true,
+        };
+        if !fits {
+            out.truncate(start);
+        }
+        fits
+    }
+
+    fn on_gvret_byte<const N: usize>(
+        &mut self,
+        byte: u8,
+        ctx: &LinkContext,
+        out: &mut heapless::Vec<u8, N>,
+    ) -> Option<CanFrame> {
+        let cmd = self.gvret.push(byte)?;
+        if !gvret::encode_response(&cmd, ctx.timestamp_us, ctx.bitrate, out) {
+            warn!("CAN link: GVRET response dropped, output buffer full");
+        }
+        match cmd {
+            GvretCommand::EnableBinary => {
+                self.streaming = true;
+                None
+            }
+            GvretCommand::SetupCanBus { can0_speed, .. } => {
+                if can0_speed & 0xFFFFF != 0 && can0_speed & 0xFFFFF != ctx.bitrate {
+                    warn!("CAN link: bitrate change to {can0_speed} not supported");
+                }
+                None
+            }
+            // Echoed frames are only looped back to the host
+            GvretCommand::Transmit { echo: true, .. } => None,
+            GvretCommand::Transmit { .. } if !self.transmit => {
+                warn!("CAN link: listen-only, frame of the host dropped");
+                None
+            }
+            GvretCommand::Transmit { frame, bus: 0, .. } => Some(frame),
+            GvretCommand::Transmit { bus, .. } => {
+                warn!("CAN link: no CAN bus {bus}");
+                None
+            }
+            GvretCommand::Invalid => {
+                warn!("CAN link: invalid GVRET frame dropped");
+                None
+            }
+            _ => None,
+        }
+    }
+
+    fn on_slcan_byte<const N: usize>(
+        &mut self,
+        byte: u8,
+        ctx: &LinkContext,
+        out: &mut heapless::Vec<u8, N>,
+    ) -> Option<CanFrame> {
+        let cmd = match self.slcan.push(byte)? {
+            Ok(cmd) => cmd,
+            Err(e) => {
+                warn!("CAN link: invalid SLCAN command: {e:?}");
+                let _ = out.push(SLCAN_ERROR);
+                return None;
+            }
+        };
+        let accepted = match &cmd {
+            SlcanCommand::Open { listen_only } => {
+                self.listen_only = *listen_only;
+                self.streaming = true;
+                true
+            }
+            SlcanCommand::Close => {
+                self.streaming = false;
+                true
+            }
+            // The controller is configured once at boot, only its own bitrate is accepted
+            SlcanCommand::SetBitrate(bitrate) => *bitrate == ctx.bitrate,
+            SlcanCommand::Timestamp(enabled) => {
+                self.timestamps = *enabled;
+                true
+            }
+            SlcanCommand::Transmit(_) => self.transmit && self.streaming && !self.listen_only,
+            _ => true,
+        };
+        if !accepted {
+            let _ = out.push(SLCAN_ERROR);
+            return None;
+        }
+        if !slcan::encode_response(&cmd, ctx.serial, out) {
+            warn!("CAN link: SLCAN response dropped, output buffer full");
+        }
+        match cmd {
+            SlcanCommand::Transmit(frame) => Some(frame),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use slcan::SLCAN_OK;
+    use std::vec::Vec;
+
+    const CTX: LinkContext = LinkContext {
+        timestamp_us: 0,
+        bitrate: 250_000,
+        serial: 0x1234,
+    };
+
+    /// Frames the session hands to the bus, and its answers
+    fn run(session: &mut LinkSession, stream: &[u8]) -> (Vec<CanFrame>, Vec<u8>) {
+        let mut out = heapless::Vec::<u8, 256>::new();
+        let frames = stream
+            .iter()
+            .filter_map(|byte| session.on_byte(*byte, &CTX, &mut out))
+            .collect();
+        (frames, out.to_vec())
+    }
+
+    #[test]
+    fn serves_the_allowed_networks_only() {
+        let subnet = ([192, 168, 4, 17], 24);
+        // Nothing configured, the hosts of the subnet only
+        assert!(peer_allowed([192, 168, 4, 2], subnet, &[]));
+        assert!(peer_allowed([192, 168, 4, 255], subnet, &[]));
+        assert!(!peer_allowed([192, 168, 5, 2], subnet, &[]));
+        assert!(!peer_allowed([10, 0, 0, 2], subnet, &[]));
+
+        let allowed = [([10, 1, 0, 0], 16), ([172, 16, 0, 9], 32)];
+        assert!(peer_allowed([10, 1, 200, 3], subnet, &allowed));
+        assert!(peer_allowed([172, 16, 0, 9], subnet, &allowed));
+        assert!(!peer_allowed([172, 16, 0, 8], subnet, &allowed));
+        assert!(!peer_allowed([10, 2, 0, 1], subnet, &allowed));
+        // The list replaces the subnet
+        assert!(!peer_allowed([192, 168, 4, 2], subnet, &allowed));
+        // A zero prefix is every host
+        assert!(peer_allowed([8, 8, 8, 8], subnet, &[([0, 0, 0, 0], 0)]));
+    }
+
+    #[test]
+    fn listen_only_drops_host_frames() {
+        let mut session = LinkSession::new(false);
+        let (frames, out) = run(&mut session, b"\nO\rt1230\r");
+        assert!(frames.is_empty());
+        assert_eq!(out, [SLCAN_OK, SLCAN_ERROR]);
+        assert!(session.is_streaming());
+
+        let mut session = LinkSession::new(false);
+        let (frames, _) = run(
+            &mut session,
+            &[0xE7, 0xE7, 0xF1, 0x00, 0x23, 0x01, 0, 0, 0, 0, 0],
+        );
+        assert!(frames.is_empty());
+    }
+
+    #[test]
+    fn transmits_when_allowed() {
+        let mut session = LinkSession::new(true);
+        // Not before the channel is open, nor when it is open listen-only
+        let (frames, out) = run(&mut session, b"t1230\rO\rt1231AA\rC\rL\rt1230\r");
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].id, 0x123);
+        assert_eq!(frames[0].payload(), [0xAA]);
+        assert_eq!(out, *b"\x07\rz\r\r\r\x07");
+
+        let mut session = LinkSession::new(true);
+        let (frames, _) = run(
+            &mut session,
+            &[
+                0xE7, 0xE7, 0xF1, 0x00, 0x23, 0x01, 0, 0, 0, 0, 0, 0xF1, 0x00, 0x23, 0x01, 0, 0, 1,
+                0, 0,
+            ],
+        );
+        // The second one is for a bus the device does not have
+        assert_eq!(frames.len(), 1);
+    }
+
+    #[test]
+    fn refuses_other_bitrates() {
+        let mut session = LinkSession::new(true);
+        let (_, out) = run(&mut session, b"S5\rS6\r");
+        assert_eq!(out, [SLCAN_OK, SLCAN_ERROR]);
+    }
+
+    #[test]
+    fn streams_in_the_host_protocol() {
+        let frame = CanFrame {
+            id: 0x7E8,
+            extended: false,
+            len: 1,
+            data: [0x41, 0, 0, 0, 0, 0, 0, 0],
+            timestamp_us: 5_000,
+        };
+        let mut out = heapless::Vec::<u8, 64>::new();
+        let mut session = LinkSession::new(false);
+        run(&mut session, b"Z1\rO\r");
+        assert!(session.encode_frame(&frame, &mut out));
+        assert_eq!(out, *b"t7E81410005\r");
+
+        out.clear();
+        let mut session = LinkSession::new(false);
+        run(&mut session, &[0xE7, 0xE7]);
+        assert!(session.encode_frame(&frame, &mut out));
+        assert_eq!(out[..2], [0xF1, 0x00]);
+    }
+}
@@ -0,0 +1,371 @@
+//! Lawicel SLCAN ASCII protocol
+//!
+//! Commands are ASCII lines terminated by `\r`. The device answers `\r` on success
+//! and `\x07` (BELL) on error, received frames are sent as `tiiildd..[tttt]\r`
+//! (standard) or `Tiiiiiiiildd..[tttt]\r` (extended).
+use crate::svc::can::CanFrame;
+
+pub const SLCAN_OK: u8 = b'\r';
+pub const SLCAN_ERROR: u8 = 0x07;
+
+/// Longest valid command line: `T` + 8 id + 1 dlc + 16 data digits
+const MAX_LINE_LEN: usize = 26;
+
+/// Bitrates selected by the `S0`..`S8` commands
+const BITRATES: [u32; 9] = [
+    10_000, 20_000, 50_000, 100_000, 125_000, 250_000, 500_000, 800_000, 1_000_000,
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SlcanCommand {
+    /// `O` open the channel, `L` open it in listen-only mode
+    Open { listen_only: bool },
+    /// `C` close the channel
+    Close,
+    /// `Sn` select one of the standard bitrates
+    SetBitrate(u32),
+    /// `V` hardware and software version
+    Version,
+    /// `N` serial number
+    SerialNumber,
+    /// `F` status flags
+    Status,
+    /// `Zn` enable or disable timestamps on received frames
+    Timestamp(bool),
+    /// `t`/`T` transmit a frame
+    Transmit(CanFrame),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SlcanError {
+    UnknownCommand,
+    /// `r`/`R` remote frames, only data frames are transmitted
+    Unsupported,
+    Malformed,
+    LineTooLong,
+}
+
+/// Incremental decoder, fed with the bytes received from the TCP stream
+pub struct SlcanDecoder {
+    line: heapless::Vec<u8, MAX_LINE_LEN>,
+    overflow: bool,
+}
+
+impl Default for SlcanDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SlcanDecoder {
+    pub const fn new() -> Self {
+        Self {
+            line: heapless::Vec::new(),
+            overflow: false,
+        }
+    }
+
+    /// Push one byte, returns the decoded command once a line is complete
+    pub fn push(&mut self, byte: u8) -> Option<Result<SlcanCommand, SlcanError>> {
+        match byte {
+            b'\r' => {
+                let res = if self.overflow {
+                    Err(SlcanError::LineTooLong)
+                } else {
+                    parse_line(&self.line)
+                };
+                self.line.clear();
+                self.overflow = false;
+                Some(res)
+            }
+            // slcand may send `\n` after `\r`, ignore it
+            b'\n' => None,
+            _ => {
+                if self.line.push(byte).is_err() {
+                    self.overflow = true;
+                }
+                None
+            }
+        }
+    }
+}
+
+fn parse_line(line: &[u8]) -> Result<SlcanCommand, SlcanError> {
+    let (&cmd, args) = line.split_first().ok_or(SlcanError::UnknownCommand)?;
+    match cmd {
+        b'O' => Ok(SlcanCommand::Open { listen_only: false }),
+        b'L' => Ok(SlcanCommand::Open { listen_only: true }),
+        b'C' => Ok(SlcanCommand::Close),
+        b'V' => Ok(SlcanCommand::Version),
+        b'N' => Ok(SlcanCommand::SerialNumber),
+        b'F' => Ok(SlcanCommand::Status),
+        b'S' => {
+            let idx = parse_hex(args)? as usize;
+            BITRATES
+                .get(idx)
+                .map(|bitrate| SlcanCommand::SetBitrate(*bitrate))
+                .ok_or(SlcanError::Malformed)
+        }
+        b'Z' => match args {
+            b"0" => Ok(SlcanCommand::Timestamp(false)),
+            b"1" => Ok(SlcanCommand::Timestamp(true)),
+            _ => Err(SlcanError::Malformed),
+        },
+        b't' => parse_frame(args, false).map(SlcanCommand::Transmit),
+        b'T' => parse_frame(args, true).map(SlcanCommand::Transmit),
+        b'r' | b'R' => Err(SlcanError::Unsupported),
+        _ => Err(SlcanError::UnknownCommand),
+    }
+}
+
+fn parse_frame(args: &[u8], extended: bool) -> Result<CanFrame, SlcanError> {
+    let id_len = if extended { 8 } else { 3 };
+    if args.len() < id_len + 1 {
+        return Err(SlcanError::Malformed);
+    }
+    let id = parse_hex(&args[..id_len])?;
+    let max_id = if extended { 0x1FFF_FFFF } else { 0x7FF };
+    if id > max_id {
+        return Err(SlcanError::Malformed);
+    }
+    let len = parse_hex(&args[id_len..id_len + 1])? as usize;
+    let data_digits = &args[id_len + 1..];
+    if len > 8 || data_digits.len() != len * 2 {
+        return Err(SlcanError::Malformed);
+    }
+    let mut data = [0u8; 8];
+    for (i, byte) in data.iter_mut().take(len).enumerate() {
+        *byte = parse_hex(&data_digits[i * 2..i * 2 + 2])? as u8;
+    }
+    Ok(CanFrame {
+        id,
+        extended,
+        len: len as u8,
+        data,
+        timestamp_us: 0,
+    })
+}
+
+fn parse_hex(digits: &[u8]) -> Result<u32, SlcanError> {
+    if digits.is_empty() || digits.len() > 8 {
+        return Err(SlcanError::Malformed);
+    }
+    digits.iter().try_fold(0u32, |acc, &d| {
+        let nibble = match d {
+            b'0'..=b'9' => d - b'0',
+            b'a'..=b'f' => d - b'a' + 10,
+            b'A'..=b'F' => d - b'A' + 10,
+            _ => return Err(SlcanError::Malformed),
+        };
+        Ok((acc << 4) | nibble as u32)
+    })
+}
+
+fn push_hex<const N: usize>(out: &mut heapless::Vec<u8, N>, value: u32, digits: usize) -> bool {
+    const HEX: &[u8; 16] = b"0123456789ABCDEF";
+    (0..digits)
+        .rev()
+        .all(|i| out.push(HEX[((value >> (i * 4)) & 0xF) as usize]).is_ok())
+}
+
+/// Encode a received frame, `timestamp_ms` is appended when timestamps are enabled
+///
+/// Returns `false` when `out` is too small.
+pub fn encode_frame<const N: usize>(
+    frame: &CanFrame,
+    timestamp_ms: Option<u16>,
+    out: &mut heapless::Vec<u8, N>,
+) -> bool {
+    let (tag, id_digits) = if frame.extended { (b'T', 8) } else { (b't', 3) };
+    let payload = frame.payload();
+    out.push(tag).is_ok()
+        && push_hex(out, frame.id, id_digits)
+        && push_hex(out, payload.len() as u32, 1)
+        && payload.iter().all(|b| push_hex(out, *b as u32, 2))
+        && timestamp_ms.is_none_or(|ts| push_hex(out, ts as u32 % 60_000, 4))
+        && out.push(b'\r').is_ok()
+}
+
+/// Encode the answer to a command, `Transmit` is acknowledged with `z`/`Z`
+pub fn encode_response<const N: usize>(
+    cmd: &SlcanCommand,
+    serial: u16,
+    out: &mut heapless::Vec<u8, N>,
+) -> bool {
+    match cmd {
+        SlcanCommand::Version => out.extend_from_slice(b"V1013\r").is_ok(),
+        SlcanCommand::SerialNumber => {
+            out.push(b'N').is_ok() && push_hex(out, serial as u32, 4) && out.push(b'\r').is_ok()
+        }
+        SlcanCommand::Status => out.extend_from_slice(b"F00\r").is_ok(),
+        SlcanCommand::Transmit(frame) => {
+            let ack: &[u8] = if frame.extended { b"Z\r" } else { b"z\r" };
+            out.extend_from_slice(ack).is_ok()
+        }
+        _ => out.push(SLCAN_OK).is_ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    fn decode(stream: &[u8]) -> Vec<Result<SlcanCommand, SlcanError>> {
+        let mut decoder = SlcanDecoder::new();
+        stream
+            .iter()
+            .filter_map(|byte| decoder.push(*byte))
+            .collect()
+    }
+
+    fn frame(id: u32, extended: bool, payload: &[u8]) -> CanFrame {
+        let mut data = [0; 8];
+        data[..payload.len()].copy_from_slice(payload);
+        CanFrame {
+            id,
+            extended,
+            len: payload.len() as u8,
+            data,
+            timestamp_us: 0,
+        }
+    }
+
+    #[test]
+    fn decodes_slcand_session() {
+        // slcand -o -s5 -t hw, then a frame each way and close
+        assert_eq!(
+            decode(b"C\rS5\rV\rN\rO\rF\rZ1\rt1232AABB\rT18DAF1103021003\rZ0\rC\r"),
+            [
+                Ok(SlcanCommand::Close),
+                Ok(SlcanCommand::SetBitrate(250_000)),
+                Ok(SlcanCommand::Version),
+                Ok(SlcanCommand::SerialNumber),
+                Ok(SlcanCommand::Open { listen_only: false }),
+                Ok(SlcanCommand::Status),
+                Ok(SlcanCommand::Timestamp(true)),
+                Ok(SlcanCommand::Transmit(frame(0x123, false, &[0xAA, 0xBB]))),
+                Ok(SlcanCommand::Transmit(frame(
+                    0x18DA_F110,
+                    true,
+                    &[0x02, 0x10, 0x03]
+                ))),
+                Ok(SlcanCommand::Timestamp(false)),
+                Ok(SlcanCommand::Close),
+            ]
+        );
+    }
+
+    #[test]
+    fn decodes_every_bitrate_and_listen_only() {
+        for (i, bitrate) in BITRATES.iter().enumerate() {
+            let line = [b'S', b'0' + i as u8, b'\r'];
+            assert_eq!(decode(&line), [Ok(SlcanCommand::SetBitrate(*bitrate))]);
+        }
+        assert_eq!(decode(b"S9\r"), [Err(SlcanError::Malformed)]);
+        assert_eq!(
+            decode(b"L\r"),
+            [Ok(SlcanCommand::Open { listen_only: true })]
+        );
+    }
+
+    #[test]
+    fn lines_split_across_reads() {
+        let stream = b"O\rt7FF0\rT1FFFFFFF81122334455667700\r\n";
+        let whole = decode(stream);
+        assert_eq!(whole.len(), 3);
+        let mut decoder = SlcanDecoder::new();
+        for split in 1..stream.len() {
+            let (head, tail) = stream.split_at(split);
+            let split_decoded: Vec<_> = head
+                .iter()
+                .chain(tail)
+                .filter_map(|byte| decoder.push(*byte))
+                .collect();
+            assert_eq!(split_decoded, whole, "split at {split}");
+        }
+        assert_eq!(
+            whole[1],
+            Ok(SlcanCommand::Transmit(frame(0x7FF, false, &[])))
+        );
+        assert_eq!(
+            whole[2],
+            Ok(SlcanCommand::Transmit(frame(
+                0x1FFF_FFFF,
+                true,
+                &[0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x00]
+            )))
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_frames() {
+        for line in [
+            &b"t8000\r"[..],              // standard id over 0x7FF
+            b"T200000000\r",              // extended id over 29 bits
+            b"t1239001122334455667788\r", // length over 8
+            b"t1232AA\r",                 // fewer data digits than the length
+            b"t1231AABB\r",               // more data digits than the length
+            b"t12G1AA\r",                 // not hex
+            b"t12\r",                     // no length
+            b"Z2\r",
+        ] {
+            assert_eq!(decode(line), [Err(SlcanError::Malformed)], "{line:?}");
+        }
+    }
+
+    #[test]
+    fn refuses_remote_and_unknown_commands() {
+        assert_eq!(decode(b"r1230\r"), [Err(SlcanError::Unsupported)]);
+        assert_eq!(decode(b"R18DAF1100\r"), [Err(SlcanError::Unsupported)]);
+        assert_eq!(decode(b"X\r"), [Err(SlcanError::UnknownCommand)]);
+        assert_eq!(decode(b"\r"), [Err(SlcanError::UnknownCommand)]);
+    }
+
+    #[test]
+    fn recovers_after_a_long_line() {
+        let mut stream = [b'x'; MAX_LINE_LEN + 4].to_vec();
+        stream.extend_from_slice(b"\rO\r");
+        assert_eq!(
+            decode(&stream),
+            [
+                Err(SlcanError::LineTooLong),
+                Ok(SlcanCommand::Open { listen_only: false })
+            ]
+        );
+    }
+
+    #[test]
+    fn encodes_received_frames() {
+        let mut out = heapless::Vec::<u8, 64>::new();
+        assert!(encode_frame(&frame(0x12, false, &[0xAB]), None, &mut out));
+        assert!(encode_frame(
+            &frame(0x18DA_F110, true, &[]),
+            Some(61_234),
+            &mut out
+        ));
+        assert_eq!(out, *b"t0121AB\rT18DAF110004D2\r");
+        let mut small = heapless::Vec::<u8, 4>::new();
+        assert!(!encode_frame(
+            &frame(0x12, false, &[0xAB]),
+            None,
+            &mut small
+        ));
+    }
+
+    #[test]
+    fn answers_commands() {
+        let mut out = heapless::Vec::<u8, 64>::new();
+        for cmd in [
+            SlcanCommand::Version,
+            SlcanCommand::SerialNumber,
+            SlcanCommand::Status,
+            SlcanCommand::Open { listen_only: false },
+            SlcanCommand::Transmit(frame(0x123, false, &[])),
+            SlcanCommand::Transmit(frame(0x123, true, &[])),
+        ] {
+            assert!(encode_response(&cmd, 0xBEEF, &mut out));
+        }
+        assert_eq!(out, *b"V1013\rNBEEF\rF00\r\rz\rZ\r");
+    }
+}
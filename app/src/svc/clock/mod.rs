@@ -0,0 +1,327 @@
+//! Wall clock
+//!
+//! UTC is unknown until a source sets it. Sources are ranked: GNSS fixes of the
+//! LTE modem, SNTP over Wi-Fi, the network time of the cell (NITZ), then the NTP
+//! client of the modem. A sample is taken unless a better source gave one
+//! within `cfg::time_cfg::CLOCK_HOLDOVER`. Between samples the time runs from
+//! the last one with `Instant`, corrected by the drift measured between two
+//! precise samples (GNSS or SNTP). A sample a little behind the time already
+//! shown holds the clock until it catches up rather than stepping it back.
+pub mod sntp;
+
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::{raw::NoopRawMutex, Mutex};
+use embassy_time::Instant;
+
+use crate::cfg::time_cfg::{CLOCK_HOLDOVER, DRIFT_MIN_INTERVAL};
+use crate::util::time::{civil_to_unix_secs, UtcTime};
+
+pub type ClockCell = Mutex<NoopRawMutex, RefCell<WallClock>>;
+
+/// Largest drift believed, anything beyond is a step of the time
+const MAX_DRIFT_PPB: i64 = 500_000;
+/// Largest correction back the clock is held over, it steps back beyond
+const MAX_HOLD_MS: i64 = 10_000;
+
+/// Where a sample comes from, least trusted first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TimeSource {
+    /// AT+QNTP of the modem, to the second
+    ModemNtp,
+    /// AT+QLTS, the time of the cell, to the second
+    Nitz,
+    /// SNTP over the Wi-Fi uplink
+    Ntp,
+    /// RMC sentence of a GNSS fix
+    Gnss,
+}
+
+impl TimeSource {
+    /// Good to a few milliseconds, enough to measure the drift
+    fn is_precise(self) -> bool {
+        matches!(self, TimeSource::Ntp | TimeSource::Gnss)
+    }
+}
+
+/// UTC at an `Instant`
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    source: TimeSource,
+    unix_ms: u64,
+    at: Instant,
+}
+
+pub struct WallClock {
+    /// The time runs from it
+    reference: Option<Sample>,
+    /// Start of the next drift measurement
+    precise: Option<Sample>,
+    /// How much faster UTC runs than `Instant`, parts per billion
+    drift_ppb: Option<i64>,
+    /// Time shown when the last sample stepped back, until it catches up
+    floor_ms: u64,
+}
+
+impl Default for WallClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WallClock {
+    pub const fn new() -> Self {
+        Self {
+            reference: None,
+            precise: None,
+            drift_ppb: None,
+            floor_ms: 0,
+        }
+    }
+
+    /// UTC was `unix_ms` at `at` according to `source`, returns the correction
+    /// in milliseconds, `None` when a better source set the clock too recently
+    pub fn set(&mut self, source: TimeSource, unix_ms: u64, at: Instant) -> Option<i64> {
+        let estimate = self.ms_at(at);
+        let correction = match (self.reference, estimate) {
+            (Some(current), _)
+                if source < current.source
+                    && at.saturating_duration_since(current.at) < CLOCK_HOLDOVER =>
+            {
+                return None;
+            }
+            (_, Some(estimate)) => unix_ms as i64 - estimate as i64,
+            _ => 0,
+        };
+        let sample = Sample {
+            source,
+            unix_ms,
+            at,
+        };
+        if source.is_precise() {
+            match self.precise {
+                Some(start) if at.saturating_duration_since(start.at) < DRIFT_MIN_INTERVAL => {}
+                Some(start) => {
+                    self.measure_drift(start, sample);
+                    self.precise = Some(sample);
+                }
+                None => self.precise = Some(sample),
+            }
+        }
+        self.floor_ms = match estimate {
+            Some(estimate) if correction >= -MAX_HOLD_MS => estimate,
+            _ => 0,
+        };
+        self.reference = Some(sample);
+        Some(correction)
+    }
+
+    /// Source of the last sample taken
+    pub fn source(&self) -> Option<TimeSource> {
+        self.reference.map(|sample| sample.source)
+    }
+
+    pub fn drift_ppb(&self) -> Option<i64> {
+        self.drift_ppb
+    }
+
+    /// Milliseconds since the Unix epoch, `None` until a source set the clock
+    pub fn now_ms(&self) -> Option<u64> {
+        self.ms_at(Instant::now())
+    }
+
+    pub fn now_secs(&self) -> Option<u64> {
+        self.now_ms().map(|ms| ms / 1000)
+    }
+
+    pub fn now_utc(&self) -> Option<UtcTime> {
+        self.now_ms().map(UtcTime::from_unix_ms)
+    }
+
+    fn ms_at(&self, at: Instant) -> Option<u64> {
+        let reference = self.reference?;
+        let elapsed = at.as_millis() as i64 - reference.at.as_millis() as i64;
+        let drift = elapsed * self.drift_ppb.unwrap_or(0) / 1_000_000_000;
+        let ms = u64::try_from(reference.unix_ms as i64 + elapsed + drift).ok()?;
+        Some(ms.max(self.floor_ms))
+    }
+
+    /// Drift between two precise samples, smoothed over the measurements
+    fn measure_drift(&mut self, start: Sample, end: Sample) {
+        let elapsed = (end.at.as_millis() - start.at.as_millis()) as i64;
+        let utc_elapsed = end.unix_ms as i64 - start.unix_ms as i64;
+        let measured = (utc_elapsed - elapsed) * 1_000_000_000 / elapsed;
+        if measured.abs() > MAX_DRIFT_PPB {
+            return;
+        }
+        self.drift_ppb = Some(match self.drift_ppb {
+            Some(drift) => (3 * drift + measured) / 4,
+            None => measured,
+        });
+    }
+}
+
+/// Unix milliseconds of the modem's `yy/MM/dd,hh:mm:ss±zz` (or a 4-digit
+/// year), `±zz` the offset of local time in quarters of an hour, taken off
+/// when the time is `local`
+pub fn parse_modem_time(text: &str, local: bool) -> Option<u64> {
+    let (date, rest) = text.trim_matches('"').split_once(',')?;
+    // A DST flag may follow
+    let time = rest.split(',').next()?;
+    let (time, zone) = match time.find(['+', '-']) {
+        Some(at) => (&time[..at], time[at..].parse::<i64>().ok()?),
+        None => (time, 0),
+    };
+    let mut date = date.split('/');
+    let year = match date.next()?.parse::<u16>().ok()? {
+        year @ 0..=99 => 2000 + year,
+        year => year,
+    };
+    let month = date.next()?.parse().ok()?;
+    let day = date.next()?.parse().ok()?;
+    let mut time = time.split(':');
+    let hour = time.next()?.parse().ok()?;
+    let minute = time.next()?.parse().ok()?;
+    let second = time.next()?.parse().ok()?;
+    let secs = civil_to_unix_secs(year, month, day, hour, minute, second)? as i64;
+    let secs = if local { secs - zone * 900 } else { secs };
+    u64::try_from(secs * 1000).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_time::Duration;
+
+    use super::*;
+
+    /// 2025-01-01T00:00:00Z
+    const UTC: u64 = 1_735_689_600_000;
+
+    fn at(ms: u64) -> Instant {
+        Instant::from_millis(100_000 + ms)
+    }
+
+    #[test]
+    fn runs_from_the_first_sample() {
+        let mut clock = WallClock::new();
+        assert_eq!(clock.ms_at(at(0)), None);
+        assert_eq!(clock.set(TimeSource::ModemNtp, UTC, at(0)), Some(0));
+        assert_eq!(clock.source(), Some(TimeSource::ModemNtp));
+        assert_eq!(clock.ms_at(at(1500)), Some(UTC + 1500));
+    }
+
+    #[test]
+    fn better_sources_take_over() {
+        let mut clock = WallClock::new();
+        clock.set(TimeSource::ModemNtp, UTC, at(0));
+        assert_eq!(clock.set(TimeSource::Nitz, UTC + 1200, at(1000)), Some(200));
+        assert_eq!(clock.set(TimeSource::Ntp, UTC + 2000, at(2000)), Some(-200));
+        assert_eq!(clock.set(TimeSource::Gnss, UTC + 3030, at(3000)), Some(30));
+        assert_eq!(clock.source(), Some(TimeSource::Gnss));
+        assert_eq!(clock.ms_at(at(4000)), Some(UTC + 4030));
+    }
+
+    #[test]
+    fn worse_sources_wait_out_the_holdover() {
+        let mut clock = WallClock::new();
+        clock.set(TimeSource::Ntp, UTC, at(0));
+        let holdover = CLOCK_HOLDOVER.as_millis();
+        for source in [TimeSource::ModemNtp, TimeSource::Nitz] {
+            assert_eq!(clock.set(source, UTC + 5000, at(holdover - 1)), None);
+        }
+        assert_eq!(clock.source(), Some(TimeSource::Ntp));
+        assert_eq!(clock.ms_at(at(holdover)), Some(UTC + holdover));
+
+        // The same source is always taken, and renews the holdover
+        assert_eq!(clock.set(TimeSource::Ntp, UTC + 20, at(10)), Some(10));
+        assert_eq!(clock.set(TimeSource::Nitz, UTC, at(holdover)), None);
+
+        // A stale source gives way
+        let stale = 10 + holdover;
+        assert_eq!(
+            clock.set(TimeSource::Nitz, UTC + stale + 2010, at(stale)),
+            Some(2000)
+        );
+        assert_eq!(clock.source(), Some(TimeSource::Nitz));
+    }
+
+    #[test]
+    fn holds_the_time_over_a_step_back() {
+        let mut clock = WallClock::new();
+        clock.set(TimeSource::Nitz, UTC, at(0));
+        assert_eq!(
+            clock.set(TimeSource::Gnss, UTC + 9500, at(10_000)),
+            Some(-500)
+        );
+        assert_eq!(clock.source(), Some(TimeSource::Gnss));
+        let mut last = 0;
+        for ms in (0..12_000).step_by(100) {
+            let now = clock.ms_at(at(ms)).unwrap();
+            assert!(now >= last, "{now} after {last}");
+            last = now;
+        }
+        assert_eq!(clock.ms_at(at(10_400)), Some(UTC + 10_000));
+        assert_eq!(clock.ms_at(at(11_000)), Some(UTC + 10_500));
+
+        // Held from the time shown, not from the sample behind it
+        assert_eq!(
+            clock.set(TimeSource::Gnss, UTC + 9800, at(10_100)),
+            Some(-200)
+        );
+        assert_eq!(clock.ms_at(at(10_200)), Some(UTC + 10_000));
+        assert_eq!(clock.ms_at(at(10_300)), Some(UTC + 10_000));
+        assert_eq!(clock.ms_at(at(10_400)), Some(UTC + 10_100));
+    }
+
+    #[test]
+    fn steps_back_far_beyond_the_hold() {
+        let mut clock = WallClock::new();
+        clock.set(TimeSource::Nitz, UTC + 3_600_000, at(0));
+        assert_eq!(clock.set(TimeSource::Gnss, UTC, at(0)), Some(-3_600_000));
+        assert_eq!(clock.ms_at(at(0)), Some(UTC));
+        assert_eq!(clock.ms_at(at(100)), Some(UTC + 100));
+    }
+
+    #[test]
+    fn measures_the_drift_between_precise_samples() {
+        let mut clock = WallClock::new();
+        let interval = DRIFT_MIN_INTERVAL.as_millis();
+        clock.set(TimeSource::Gnss, UTC, at(0));
+        // Too soon to measure over
+        clock.set(TimeSource::Gnss, UTC + interval / 2 + 50, at(interval / 2));
+        assert_eq!(clock.drift_ppb(), None);
+        let later = 2 * interval;
+        clock.set(TimeSource::Gnss, UTC + later, at(later));
+        assert_eq!(clock.drift_ppb(), Some(0));
+
+        // 100 ppm fast over the next interval, smoothed with the first
+        let utc = UTC + later + interval + interval / 10_000;
+        clock.set(TimeSource::Gnss, utc, at(later + interval));
+        assert_eq!(clock.drift_ppb(), Some(25_000));
+        let after = Duration::from_secs(1000).as_millis();
+        assert_eq!(
+            clock.ms_at(at(later + interval + after)),
+            Some(utc + after + after / 40_000)
+        );
+    }
+
+    #[test]
+    fn ignores_a_step_as_drift() {
+        let mut clock = WallClock::new();
+        let interval = DRIFT_MIN_INTERVAL.as_millis();
+        clock.set(TimeSource::Ntp, UTC, at(0));
+        clock.set(TimeSource::Ntp, UTC + interval + 60_000, at(interval));
+        assert_eq!(clock.drift_ppb(), None);
+    }
+
+    #[test]
+    fn parses_the_modem_time() {
+        // Local time two hours ahead of UTC, with the DST flag of AT+QLTS=2
+        let local = parse_modem_time("\"25/01/01,02:00:00+08,0\"", true);
+        assert_eq!(local, Some(UTC));
+        assert_eq!(parse_modem_time("2025/01/01,00:00:00+08", false), Some(UTC));
+        assert_eq!(parse_modem_time("24/12/31,23:00:00-04", true), Some(UTC));
+        assert_eq!(parse_modem_time("25/13/01,00:00:00", false), None);
+        assert_eq!(parse_modem_time("garbage", false), None);
+    }
+}
@@ -0,0 +1,77 @@
+//! SNTP (RFC 4330) request and reply
+//!
+//! The transmit timestamp of a request is a random nonce rather than the time,
+//! the server echoes it as originate timestamp and anything else is not the
+//! reply. The time is the server's transmit timestamp plus half the round
+//! trip, less the time the server held the request.
+
+pub const PACKET_LEN: usize = 48;
+/// Seconds from 1900, the NTP epoch, to 1970
+const UNIX_OFFSET: u64 = 2_208_988_800;
+/// LI 0, version 4, mode 3 (client)
+const CLIENT_REQUEST: u8 = 0x23;
+const MODE_SERVER: u8 = 4;
+const MODE_MASK: u8 = 0x07;
+const LEAP_UNSYNCHRONIZED: u8 = 0xC0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SntpError {
+    /// Shorter than a packet
+    Short,
+    /// Not a server reply to the request
+    Unrelated,
+    /// The server has no time to give
+    Unsynchronized,
+    /// Stratum 0, the server asks the client to go away
+    KissOfDeath,
+}
+
+pub fn request(nonce: [u8; 8]) -> [u8; PACKET_LEN] {
+    let mut packet = [0; PACKET_LEN];
+    packet[0] = CLIENT_REQUEST;
+    packet[40..].copy_from_slice(&nonce);
+    packet
+}
+
+/// Unix milliseconds when the reply was received, `sent_ms` and
+/// `received_ms` read off `Instant`
+pub fn parse_reply(
+    reply: &[u8],
+    nonce: [u8; 8],
+    sent_ms: u64,
+    received_ms: u64,
+) -> Result<u64, SntpError> {
+    let reply = reply.get(..PACKET_LEN).ok_or(SntpError::Short)?;
+    if reply[0] & MODE_MASK != MODE_SERVER || reply[24..32] != nonce {
+        return Err(SntpError::Unrelated);
+    }
+    if reply[0] & LEAP_UNSYNCHRONIZED == LEAP_UNSYNCHRONIZED {
+        return Err(SntpError::Unsynchronized);
+    }
+    match reply[1] {
+        0 => return Err(SntpError::KissOfDeath),
+        1..=15 => {}
+        _ => return Err(SntpError::Unsynchronized),
+    }
+    let received = timestamp_ms(&reply[32..40]).ok_or(SntpError::Unsynchronized)?;
+    let transmitted = timestamp_ms(&reply[40..48]).ok_or(SntpError::Unsynchronized)?;
+    let held = transmitted.saturating_sub(received);
+    let round_trip = received_ms.saturating_sub(sent_ms).saturating_sub(held);
+    Ok(transmitted + round_trip / 2)
+}
+
+/// Unix milliseconds of an NTP timestamp, `None` for zero
+fn timestamp_ms(bytes: &[u8]) -> Option<u64> {
+    let secs = u32::from_be_bytes(bytes[..4].try_into().ok()?) as u64;
+    let fraction = u32::from_be_bytes(bytes[4..8].try_into().ok()?) as u64;
+    if secs == 0 && fraction == 0 {
+        return None;
+    }
+    // Era 1 from 2036 on, the seconds wrapped
+    let secs = if secs < 1 << 31 {
+        secs + (1 << 32)
+    } else {
+        secs
+    };
+    Some((secs - UNIX_OFFSET) * 1000 + ((fraction * 1000) >> 32))
+}
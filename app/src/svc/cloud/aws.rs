@@ -0,0 +1,14 @@
+//! AWS IoT Core
+//!
+//! The device authenticates with its certificate, the client ID has to be the
+//! thing name the certificate is attached to. No username nor password.
+use core::fmt::Write;
+
+pub const MQTT_PORT: u16 = 8883;
+/// MQTT over TLS on 443 is told apart from HTTPS by this ALPN protocol
+pub const ALPN_MQTT_CA: &str = "x-amzn-mqtt-ca";
+
+/// `<prefix>-ats.iot.<region>.amazonaws.com`, the ATS signed endpoint
+pub fn endpoint<W: Write>(out: &mut W, prefix: &str, region: &str) -> core::fmt::Result {
+    write!(out, "{prefix}-ats.iot.{region}.amazonaws.com")
+}
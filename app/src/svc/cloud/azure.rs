@@ -0,0 +1,112 @@
+//! Azure IoT Hub
+//!
+//! The client ID is the device ID and the username
+//! `<hub>.azure-devices.net/<device>/?api-version=<version>`. The device
+//! authenticates either with its certificate or with a SAS token as password,
+//! signed with its symmetric key. IoT Hub closes the connection when the token
+//! expires, the client has to connect again with a new one before that.
+use core::fmt::Write;
+
+use crate::util::{base64, sha256};
+
+pub const MQTT_PORT: u16 = 8883;
+pub const API_VERSION: &str = "2021-04-12";
+pub const HOST_SUFFIX: &str = ".azure-devices.net";
+/// Longest SAS token
+pub const SAS_LEN: usize = 256;
+
+pub type SasToken = heapless::String<SAS_LEN>;
+
+pub fn host<W: Write>(out: &mut W, hub: &str) -> core::fmt::Result {
+    write!(out, "{hub}{HOST_SUFFIX}")
+}
+
+pub fn username<W: Write>(out: &mut W, host: &str, device_id: &str) -> core::fmt::Result {
+    write!(out, "{host}/{device_id}/?api-version={API_VERSION}")
+}
+
+/// SAS token for `device_id`, valid until `expiry` (Unix seconds)
+///
+/// `SharedAccessSignature sr=<resource>&sig=<signature>&se=<expiry>`, the
+/// signature being the HMAC-SHA256 of `<resource>\n<expiry>` with the device
+/// key, the resource `<host>/devices/<device>` URL-encoded.
+pub fn sas_token(host: &str, device_id: &str, key: &[u8], expiry: u64) -> Option<SasToken> {
+    let mut resource = heapless::String::<128>::new();
+    url_encode(&mut resource, host).ok()?;
+    url_encode(&mut resource, "/devices/").ok()?;
+    url_encode(&mut resource, device_id).ok()?;
+    let mut se = heapless::String::<20>::new();
+    write!(&mut se, "{expiry}").ok()?;
+    let mac = sha256::hmac(key, &[resource.as_bytes(), b"\n", se.as_bytes()]);
+    let mut signature = heapless::String::<44>::new();
+    base64::encode(&mut signature, &mac).ok()?;
+
+    let mut token = SasToken::new();
+    write!(&mut token, "SharedAccessSignature sr={resource}&sig=").ok()?;
+    url_encode(&mut token, &signature).ok()?;
+    write!(&mut token, "&se={se}").ok()?;
+    Some(token)
+}
+
+/// Percent-encode everything but the unreserved characters of RFC 3986
+fn url_encode<W: Write>(out: &mut W, text: &str) -> core::fmt::Result {
+    for byte in text.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                out.write_char(byte as char)?
+            }
+            _ => write!(out, "%{byte:02X}")?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Primary key of the device, the bytes 0 to 31
+    const DEVICE_KEY: &str = "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+
+    fn token(device_id: &str, expiry: u64) -> SasToken {
+        let key = base64::decode::<32>(DEVICE_KEY).unwrap();
+        sas_token("myhub.azure-devices.net", device_id, &key, expiry).unwrap()
+    }
+
+    // Expected tokens from `generate_sas_token`, the Python sample of the IoT Hub
+    // access control documentation, given the same resource URI, key and expiry
+    #[test]
+    fn sas_token_known_answers() {
+        assert_eq!(
+            token("device1", 1_700_000_000),
+            "SharedAccessSignature sr=myhub.azure-devices.net%2Fdevices%2Fdevice1\
+             &sig=gFSfLGMXDOvf906%2FiaX3KwzLLNFHgaFZyCbsGjxtd1Y%3D&se=1700000000"
+        );
+        // Device IDs may hold characters outside the unreserved set
+        assert_eq!(
+            token("truck:7+a@b", 4_102_444_800),
+            "SharedAccessSignature sr=myhub.azure-devices.net%2Fdevices%2Ftruck%3A7%2Ba%40b\
+             &sig=x2HZ%2FAfdI6NJVYNykgFPdQZOWIQu2W7u5Q5xOWb%2BLI0%3D&se=4102444800"
+        );
+    }
+
+    #[test]
+    fn sas_token_changes_with_the_expiry() {
+        assert_ne!(
+            token("device1", 1_700_000_000),
+            token("device1", 1_700_000_001)
+        );
+    }
+
+    #[test]
+    fn username() {
+        let mut host_name = heapless::String::<64>::new();
+        host(&mut host_name, "myhub").unwrap();
+        let mut out = heapless::String::<128>::new();
+        super::username(&mut out, &host_name, "device1").unwrap();
+        assert_eq!(
+            out,
+            "myhub.azure-devices.net/device1/?api-version=2021-04-12"
+        );
+    }
+}
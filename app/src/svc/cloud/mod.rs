@@ -0,0 +1,196 @@
+//! Cloud provider connection profiles
+//!
+//! A profile says how the Wi-Fi uplink reaches the broker of
+//! `cfg::cloud_cfg::CLOUD_PROVIDER`: host and port, ALPN protocol, client ID,
+//! username and password, and the highest MQTT version the broker speaks.
+//! Only our own topics are used, not the provider ones (AWS shadow and jobs,
+//! Azure twin and direct methods).
+pub mod aws;
+pub mod azure;
+
+use core::ffi::CStr;
+
+use embassy_time::Duration;
+
+use crate::cfg::cloud_cfg::*;
+use crate::cfg::mqtt_cfg::MQTT_PROTOCOL;
+use crate::cfg::net_cfg::{
+    MQTT_CLIENT_ID, MQTT_SERVER_NAME, MQTT_SERVER_PORT, MQTT_USR_NAME, MQTT_USR_PASS,
+};
+use crate::svc::mqtt::packet::ProtocolVersion;
+use crate::util::base64;
+
+pub const HOST_LEN: usize = 128;
+pub const USERNAME_LEN: usize = 192;
+/// Longest decoded Azure device key, they are 32 or 64 bytes
+const KEY_LEN: usize = 64;
+
+pub type Password = heapless::Vec<u8, { azure::SAS_LEN }>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Provider {
+    /// The broker of `cfg::net_cfg`
+    Generic,
+    AwsIotCore,
+    AzureIotHub,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProfileError {
+    /// The host name or username does not fit
+    TooLong,
+    /// The Azure device key is not base64
+    InvalidKey,
+    /// A SAS token needs the time, no source set the clock yet
+    NoTime,
+}
+
+/// How the device proves who it is, on top of its TLS certificate
+#[derive(Debug, Clone, PartialEq)]
+pub enum Auth {
+    /// Nothing more than the certificate
+    Certificate,
+    Password(&'static [u8]),
+    /// Azure SAS token signed with the device key
+    Sas(heapless::Vec<u8, KEY_LEN>),
+}
+
+pub struct Profile {
+    pub provider: Provider,
+    /// Host name and a NUL, for the TLS server name
+    host: heapless::String<HOST_LEN>,
+    pub port: u16,
+    pub alpn: Option<&'static str>,
+    username: Option<heapless::String<USERNAME_LEN>>,
+    auth: Auth,
+    /// The broker refuses anything newer
+    pub protocol: ProtocolVersion,
+}
+
+impl Profile {
+    /// Profile of `cfg::cloud_cfg`
+    pub fn from_config() -> Result<Self, ProfileError> {
+        let mut host = heapless::String::new();
+        let mut profile = match CLOUD_PROVIDER {
+            Provider::Generic => {
+                host.push_str(MQTT_SERVER_NAME)
+                    .map_err(|_| ProfileError::TooLong)?;
+                Self {
+                    provider: CLOUD_PROVIDER,
+                    port: MQTT_SERVER_PORT,
+                    alpn: None,
+                    username: Some(
+                        heapless::String::try_from(MQTT_USR_NAME)
+                            .map_err(|_| ProfileError::TooLong)?,
+                    ),
+                    auth: Auth::Password(&MQTT_USR_PASS),
+                    protocol: MQTT_PROTOCOL,
+                    host,
+                }
+            }
+            Provider::AwsIotCore => {
+                aws::endpoint(&mut host, AWS_ENDPOINT_PREFIX, AWS_REGION)
+                    .map_err(|_| ProfileError::TooLong)?;
+                Self {
+                    provider: CLOUD_PROVIDER,
+                    port: AWS_PORT,
+                    alpn: (AWS_PORT == 443).then_some(aws::ALPN_MQTT_CA),
+                    username: None,
+                    auth: Auth::Certificate,
+                    protocol: MQTT_PROTOCOL,
+                    host,
+                }
+            }
+            Provider::AzureIotHub => {
+                azure::host(&mut host, AZURE_HUB_NAME).map_err(|_| ProfileError::TooLong)?;
+                let mut username = heapless::String::new();
+                azure::username(&mut username, &host, MQTT_CLIENT_ID)
+                    .map_err(|_| ProfileError::TooLong)?;
+                let auth = if AZURE_DEVICE_KEY.is_empty() {
+                    Auth::Certificate
+                } else {
+                    Auth::Sas(base64::decode(AZURE_DEVICE_KEY).ok_or(ProfileError::InvalidKey)?)
+                };
+                Self {
+                    provider: CLOUD_PROVIDER,
+                    port: azure::MQTT_PORT,
+                    alpn: None,
+                    username: Some(username),
+                    auth,
+                    // IoT Hub speaks MQTT 3.1.1 only
+                    protocol: ProtocolVersion::V311,
+                    host,
+                }
+            }
+        };
+        profile.host.push('\0').map_err(|_| ProfileError::TooLong)?;
+        Ok(profile)
+    }
+
+    pub fn host(&self) -> &str {
+        self.host.trim_end_matches('\0')
+    }
+
+    pub fn server_name(&self) -> &CStr {
+        // Built with a single NUL at the end
+        CStr::from_bytes_with_nul(self.host.as_bytes()).unwrap_or(c"")
+    }
+
+    /// The device ID, AWS expects the thing name and Azure the device ID
+    pub fn client_id(&self) -> &'static str {
+        MQTT_CLIENT_ID
+    }
+
+    pub fn username(&self) -> Option<&str> {
+        self.username.as_deref()
+    }
+
+    /// Password of a connection made at `now_secs` (Unix time)
+    ///
+    /// A new SAS token for Azure, valid for `AZURE_SAS_TTL`.
+    pub fn password(&self, now_secs: Option<u64>) -> Result<Option<Password>, ProfileError> {
+        match &self.auth {
+            Auth::Certificate => Ok(None),
+            Auth::Password(password) => Password::from_slice(password)
+                .map(Some)
+                .map_err(|_| ProfileError::TooLong),
+            Auth::Sas(key) => {
+                let now = now_secs.ok_or(ProfileError::NoTime)?;
+                let expiry = now + AZURE_SAS_TTL.as_secs();
+                let token = azure::sas_token(self.host(), MQTT_CLIENT_ID, key, expiry)
+                    .ok_or(ProfileError::TooLong)?;
+                Ok(Some(
+                    Password::from_slice(token.as_bytes()).map_err(|_| ProfileError::TooLong)?,
+                ))
+            }
+        }
+    }
+
+    /// How long after connecting the client has to connect again with new
+    /// credentials, `None` when they don't expire
+    pub fn renew_after(&self) -> Option<Duration> {
+        match self.auth {
+            Auth::Sas(_) => AZURE_SAS_TTL.checked_sub(AZURE_SAS_RENEW_MARGIN),
+            _ => None,
+        }
+    }
+
+    /// Whether the broker takes our own topic namespace
+    ///
+    /// IoT Hub only knows its own topics, the subscriptions to commands, RPC
+    /// requests and the desired shadow are left to the LTE uplink.
+    pub fn custom_topics(&self) -> bool {
+        self.provider != Provider::AzureIotHub
+    }
+}
+
+impl core::fmt::Debug for Profile {
+    // Leaves the credentials out
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{:?} {}:{}", self.provider, self.host(), self.port)?;
+        if let Some(alpn) = self.alpn {
+            write!(f, " ALPN {alpn}")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,145 @@
+//! Settings the backend can change at run time
+//!
+//! Reporting intervals default to the policy of the vehicle state, a non-zero
+//! interval set here overrides it whatever the state. The settings persist
+//! through the device shadow, see `svc::shadow`.
+use core::cell::RefCell;
+use core::fmt::Write;
+
+use embassy_sync::blocking_mutex::{raw::NoopRawMutex, Mutex};
+use embassy_time::Duration;
+use serde::Deserialize;
+
+use crate::svc::can::filter::CanFilter;
+use crate::svc::vehicle::{ReportPolicy, VehicleCell};
+
+/// Longest reporting interval the backend can ask for
+pub const MAX_INTERVAL_SECS: u32 = 24 * 3600;
+/// Number of CAN telemetry filters
+pub const MAX_TELEMETRY_FILTERS: usize = 4;
+
+pub type TelemetryFilters = heapless::Vec<CanFilter, MAX_TELEMETRY_FILTERS>;
+
+pub type ConfigCell = Mutex<NoopRawMutex, RefCell<DeviceConfig>>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceConfig {
+    /// Interval between two CAN telemetry messages, 0 follows the vehicle state
+    pub telemetry_interval_s: u32,
+    /// Interval between two trip messages, 0 follows the vehicle state
+    pub trip_interval_s: u32,
+    /// Whether the Wi-Fi uplink reports the CAN telemetry at all
+    pub can_telemetry: bool,
+    /// Frames reported as CAN telemetry, all of them when empty
+    pub can_filters: TelemetryFilters,
+}
+
+impl Default for DeviceConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Partial update, the fields left out keep their value
+///
+/// `{"telemetry_interval_s":10,"trip_interval_s":0,"can_telemetry":true,
+///   "can_filters":[{"id":256,"mask":1792,"ext":false}]}`
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ConfigPatch {
+    pub telemetry_interval_s: Option<u32>,
+    pub trip_interval_s: Option<u32>,
+    pub can_telemetry: Option<bool>,
+    pub can_filters: Option<TelemetryFilters>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConfigError {
+    /// The named setting is out of range
+    OutOfRange(&'static str),
+}
+
+impl DeviceConfig {
+    pub const fn new() -> Self {
+        Self {
+            telemetry_interval_s: 0,
+            trip_interval_s: 0,
+            can_telemetry: true,
+            can_filters: heapless::Vec::new(),
+        }
+    }
+
+    /// Apply `patch` as a whole, nothing changes when a field is out of range
+    pub fn apply(&mut self, patch: &ConfigPatch) -> Result<(), ConfigError> {
+        let mut next = self.clone();
+        if let Some(secs) = patch.telemetry_interval_s {
+            next.telemetry_interval_s = check_interval("telemetry_interval_s", secs)?;
+        }
+        if let Some(secs) = patch.trip_interval_s {
+            next.trip_interval_s = check_interval("trip_interval_s", secs)?;
+        }
+        if let Some(enabled) = patch.can_telemetry {
+            next.can_telemetry = enabled;
+        }
+        if let Some(filters) = &patch.can_filters {
+            next.can_filters = filters.clone();
+        }
+        *self = next;
+        Ok(())
+    }
+
+    /// `policy` with the configured intervals
+    pub fn report_policy(&self, mut policy: ReportPolicy) -> ReportPolicy {
+        if self.telemetry_interval_s > 0 {
+            policy.telemetry_interval = Duration::from_secs(self.telemetry_interval_s as u64);
+        }
+        if self.trip_interval_s > 0 {
+            policy.trip_interval = Duration::from_secs(self.trip_interval_s as u64);
+        }
+        policy
+    }
+
+    /// Whether a frame is reported as CAN telemetry
+    pub fn telemetry_accepts(&self, id: u32, extended: bool) -> bool {
+        self.can_telemetry
+            && (self.can_filters.is_empty()
+                || self.can_filters.iter().any(|f| f.matches(id, extended)))
+    }
+
+    /// Settings as a JSON object, the format [`ConfigPatch`] reads back
+    pub fn write_json<W: Write>(&self, out: &mut W) -> core::fmt::Result {
+        write!(
+            out,
+            "{{\"telemetry_interval_s\":{},\"trip_interval_s\":{},\"can_telemetry\":{},\"can_filters\":[",
+            self.telemetry_interval_s, self.trip_interval_s, self.can_telemetry
+        )?;
+        for (idx, filter) in self.can_filters.iter().enumerate() {
+            write!(
+                out,
+                "{}{{\"id\":{}",
+                if idx > 0 { "," } else { "" },
+                filter.id
+            )?;
+            if let Some(mask) = filter.mask {
+                write!(out, ",\"mask\":{mask}")?;
+            }
+            if let Some(ext) = filter.ext {
+                write!(out, ",\"ext\":{ext}")?;
+            }
+            out.write_char('}')?;
+        }
+        out.write_str("]}")
+    }
+}
+
+fn check_interval(name: &'static str, secs: u32) -> Result<u32, ConfigError> {
+    if secs > MAX_INTERVAL_SECS {
+        return Err(ConfigError::OutOfRange(name));
+    }
+    Ok(secs)
+}
+
+/// Reporting policy of the current vehicle state with the configured overrides
+pub fn report_policy(vehicle: &VehicleCell, config: &ConfigCell) -> ReportPolicy {
+    let policy = vehicle.lock(|v| v.borrow().policy());
+    config.lock(|c| c.borrow().report_policy(policy))
+}
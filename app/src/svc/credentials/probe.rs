@@ -0,0 +1,181 @@
+//! Certificate a server presents, to report a failed handshake with it
+//!
+//! The TLS session does not tell which certificate it refused. A TLS 1.2
+//! `ClientHello` on a connection of its own gets the server's chain in the
+//! clear, the server's own certificate first. Nothing is negotiated past it.
+use crate::util::sha256::{self, DIGEST_LEN};
+
+use super::trust;
+
+/// Handshake kept until the server's certificate is complete
+pub const PROBE_LEN: usize = 2560;
+pub const HELLO_LEN: usize = 384;
+
+const HANDSHAKE: u8 = 22;
+const ALERT: u8 = 21;
+const CLIENT_HELLO: u8 = 1;
+const SERVER_HELLO: u8 = 2;
+const CERTIFICATE: u8 = 11;
+const RECORD_HEADER_LEN: usize = 5;
+
+/// ECDHE with ECDSA or RSA, then plain RSA key exchange
+const CIPHER_SUITES: [u16; 12] = [
+    0xC02B, 0xC02F, 0xC02C, 0xC030, 0xC023, 0xC027, 0xC009, 0xC013, 0x009C, 0x009D, 0x003C, 0x002F,
+];
+/// secp256r1 and secp384r1, uncompressed points, ECDSA, RSA-PSS and RSA
+/// PKCS#1 signatures
+const EXTENSIONS: &[u8] = &[
+    0x00, 0x0A, 0x00, 0x06, 0x00, 0x04, 0x00, 0x17, 0x00, 0x18, //
+    0x00, 0x0B, 0x00, 0x02, 0x01, 0x00, //
+    0x00, 0x0D, 0x00, 0x10, 0x00, 0x0E, 0x04, 0x03, 0x05, 0x03, 0x08, 0x04, 0x08, 0x05, 0x04, 0x01,
+    0x05, 0x01, 0x02, 0x01,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProbeError {
+    /// The server refused the `ClientHello`, TLS 1.3 only for one
+    Alert,
+    /// Anything but a server hello and certificate
+    Unexpected,
+    /// A server name or certificate longer than the buffers
+    TooLarge,
+    /// The connection failed or closed before the certificate
+    Closed,
+}
+
+/// Fingerprints of the server's certificate
+pub struct Presented {
+    /// SHA-256 of the certificate DER
+    pub fingerprint: [u8; DIGEST_LEN],
+    /// `pin-sha256` of its key, `None` when it doesn't parse
+    pub pin: Option<[u8; DIGEST_LEN]>,
+}
+
+/// Handshake records of the server, read as they come
+pub struct Probe {
+    /// Handshake messages, record headers stripped
+    handshake: heapless::Vec<u8, PROBE_LEN>,
+    header: [u8; RECORD_HEADER_LEN],
+    header_len: usize,
+    /// Bytes left in the current record
+    left: usize,
+}
+
+impl Default for Probe {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Probe {
+    pub const fn new() -> Self {
+        Self {
+            handshake: heapless::Vec::new(),
+            header: [0; RECORD_HEADER_LEN],
+            header_len: 0,
+            left: 0,
+        }
+    }
+
+    /// `ClientHello` record offering TLS 1.2 only, with SNI
+    pub fn client_hello(server_name: &str) -> Result<heapless::Vec<u8, HELLO_LEN>, ProbeError> {
+        let name = server_name.as_bytes();
+        let mut body = heapless::Vec::<u8, HELLO_LEN>::new();
+        put(&mut body, &[0x03, 0x03])?;
+        // Nothing is negotiated past the certificate, the random doesn't matter
+        put(&mut body, &[0x5A; 32])?;
+        put(&mut body, &[0x00])?;
+        put_u16(&mut body, 2 * CIPHER_SUITES.len())?;
+        for suite in CIPHER_SUITES {
+            put(&mut body, &suite.to_be_bytes())?;
+        }
+        put(&mut body, &[0x01, 0x00])?;
+        put_u16(&mut body, 9 + name.len() + EXTENSIONS.len())?;
+        put(&mut body, &[0x00, 0x00])?;
+        put_u16(&mut body, name.len() + 5)?;
+        put_u16(&mut body, name.len() + 3)?;
+        put(&mut body, &[0x00])?;
+        put_u16(&mut body, name.len())?;
+        put(&mut body, name)?;
+        put(&mut body, EXTENSIONS)?;
+
+        let mut record = heapless::Vec::<u8, HELLO_LEN>::new();
+        put(&mut record, &[HANDSHAKE, 0x03, 0x01])?;
+        put_u16(&mut record, 4 + body.len())?;
+        put(&mut record, &[CLIENT_HELLO, 0x00])?;
+        put_u16(&mut record, body.len())?;
+        put(&mut record, &body)?;
+        Ok(record)
+    }
+
+    /// Take bytes received from the server, the fingerprints once its
+    /// certificate is complete
+    pub fn feed(&mut self, mut bytes: &[u8]) -> Result<Option<Presented>, ProbeError> {
+        while !bytes.is_empty() {
+            if self.left == 0 {
+                let take = (RECORD_HEADER_LEN - self.header_len).min(bytes.len());
+                self.header[self.header_len..self.header_len + take]
+                    .copy_from_slice(&bytes[..take]);
+                self.header_len += take;
+                bytes = &bytes[take..];
+                if self.header_len < RECORD_HEADER_LEN {
+                    break;
+                }
+                self.header_len = 0;
+                match self.header[0] {
+                    HANDSHAKE => {}
+                    ALERT => return Err(ProbeError::Alert),
+                    _ => return Err(ProbeError::Unexpected),
+                }
+                self.left = u16::from_be_bytes([self.header[3], self.header[4]]) as usize;
+                continue;
+            }
+            let take = self.left.min(bytes.len());
+            let room = PROBE_LEN - self.handshake.len();
+            let _ = self.handshake.extend_from_slice(&bytes[..take.min(room)]);
+            self.left -= take;
+            bytes = &bytes[take..];
+        }
+        self.presented()
+    }
+
+    fn presented(&self) -> Result<Option<Presented>, ProbeError> {
+        let mut messages = &self.handshake[..];
+        while let [kind, a, b, c, body @ ..] = messages {
+            let len = u32::from_be_bytes([0, *a, *b, *c]) as usize;
+            match *kind {
+                CERTIFICATE => {
+                    // The list length, then the server's own certificate
+                    let [_, _, _, a, b, c, certificates @ ..] = body else {
+                        break;
+                    };
+                    let len = u32::from_be_bytes([0, *a, *b, *c]) as usize;
+                    let Some(certificate) = certificates.get(..len) else {
+                        break;
+                    };
+                    return Ok(Some(Presented {
+                        fingerprint: sha256::digest(certificate),
+                        pin: trust::pin(certificate),
+                    }));
+                }
+                SERVER_HELLO if body.len() >= len => messages = &body[len..],
+                SERVER_HELLO => break,
+                _ => return Err(ProbeError::Unexpected),
+            }
+        }
+        if self.handshake.is_full() {
+            Err(ProbeError::TooLarge)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+fn put(out: &mut heapless::Vec<u8, HELLO_LEN>, bytes: &[u8]) -> Result<(), ProbeError> {
+    out.extend_from_slice(bytes)
+        .map_err(|_| ProbeError::TooLarge)
+}
+
+fn put_u16(out: &mut heapless::Vec<u8, HELLO_LEN>, value: usize) -> Result<(), ProbeError> {
+    put(out, &(value as u16).to_be_bytes())
+}
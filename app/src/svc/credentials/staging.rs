@@ -0,0 +1,173 @@
+//! Credentials of a rotation, staged until the broker accepted them
+//!
+//! `magic, format, state, 0, CA length, certificate length, key length (LE),
+//! checksum of the header (LE)`, then each part at an offset of its own. A part
+//! arrives in chunks written in order, a chunk at offset 0 starts it over.
+use super::Part;
+use crate::svc::mem::nvm::{BlockId, Nvm, NvsError};
+use crate::util::fnv::fnv1a;
+
+pub const STAGING_LEN: usize = 0x2000;
+const HEADER_LEN: usize = 16;
+const MAGIC: u8 = 0x57;
+const FORMAT: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum State {
+    /// Parts being written
+    Receiving = 0,
+    /// Waiting for the Wi-Fi uplink to try them
+    Staged = 1,
+    /// Accepted by the broker, being copied over the current ones
+    Committing = 2,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StagingError {
+    /// The chunk does not follow the part received so far
+    OutOfOrder,
+    /// The part is longer than [`Part::max_len`]
+    TooLarge,
+    /// No part to try
+    Empty,
+    /// The staged credentials are being committed
+    Busy,
+    Storage,
+}
+
+impl From<NvsError> for StagingError {
+    fn from(_: NvsError) -> Self {
+        StagingError::Storage
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Header {
+    state: State,
+    lens: [u16; 3],
+}
+
+impl Header {
+    const fn empty() -> Self {
+        Self {
+            state: State::Receiving,
+            lens: [0; 3],
+        }
+    }
+
+    fn read(nvm: &mut Nvm) -> Result<Option<Self>, StagingError> {
+        let mut raw = [0u8; HEADER_LEN];
+        nvm.nvs_read_at(BlockId::StagingId, 0, &mut raw)?;
+        let stored = u32::from_le_bytes([raw[12], raw[13], raw[14], raw[15]]);
+        if raw[0] != MAGIC || raw[1] != FORMAT || stored != fnv1a(&raw[..12]) {
+            return Ok(None);
+        }
+        let state = match raw[2] {
+            0 => State::Receiving,
+            1 => State::Staged,
+            2 => State::Committing,
+            _ => return Ok(None),
+        };
+        let len = |at: usize| u16::from_le_bytes([raw[at], raw[at + 1]]);
+        Ok(Some(Self {
+            state,
+            lens: [len(4), len(6), len(8)],
+        }))
+    }
+
+    fn write(&self, nvm: &mut Nvm) -> Result<(), StagingError> {
+        let mut raw = [0u8; HEADER_LEN];
+        raw[0] = MAGIC;
+        raw[1] = FORMAT;
+        raw[2] = self.state as u8;
+        for (idx, len) in self.lens.iter().enumerate() {
+            raw[4 + 2 * idx..6 + 2 * idx].copy_from_slice(&len.to_le_bytes());
+        }
+        let checksum = fnv1a(&raw[..12]);
+        raw[12..].copy_from_slice(&checksum.to_le_bytes());
+        nvm.nvs_write_at(BlockId::StagingId, 0, &raw)?;
+        Ok(())
+    }
+}
+
+/// Offset of `part` in the block
+const fn offset(part: Part) -> usize {
+    match part {
+        Part::CaChain => HEADER_LEN,
+        Part::Certificate => 0x1000,
+        Part::PrivateKey => 0x1800,
+    }
+}
+
+/// State of the rotation, `None` when there is none
+pub fn state(nvm: &mut Nvm) -> Option<State> {
+    Header::read(nvm).ok().flatten().map(|header| header.state)
+}
+
+/// Write a chunk of `part`, returns the length received so far
+///
+/// A chunk received while staged drops the pending rotation.
+pub fn write(nvm: &mut Nvm, part: Part, at: usize, chunk: &[u8]) -> Result<usize, StagingError> {
+    let mut header = Header::read(nvm)?.unwrap_or(Header::empty());
+    if header.state == State::Committing {
+        return Err(StagingError::Busy);
+    }
+    if header.state == State::Staged {
+        header = Header::empty();
+    }
+    let received = header.lens[part as usize] as usize;
+    if at != 0 && at != received {
+        return Err(StagingError::OutOfOrder);
+    }
+    if at + chunk.len() > part.max_len() {
+        return Err(StagingError::TooLarge);
+    }
+    nvm.nvs_write_at(BlockId::StagingId, offset(part) + at, chunk)?;
+    header.lens[part as usize] = (at + chunk.len()) as u16;
+    header.write(nvm)?;
+    Ok(at + chunk.len())
+}
+
+/// Mark the parts received ready to be tried
+pub fn seal(nvm: &mut Nvm) -> Result<(), StagingError> {
+    let mut header = Header::read(nvm)?.ok_or(StagingError::Empty)?;
+    match header.state {
+        State::Committing => Err(StagingError::Busy),
+        _ if header.lens.iter().all(|len| *len == 0) => Err(StagingError::Empty),
+        _ => {
+            header.state = State::Staged;
+            header.write(nvm)
+        }
+    }
+}
+
+/// Read the staged `part` into `out`, `false` when it isn't part of the rotation
+pub fn read<const N: usize>(
+    nvm: &mut Nvm,
+    part: Part,
+    out: &mut heapless::Vec<u8, N>,
+) -> Result<bool, StagingError> {
+    let Some(header) = Header::read(nvm)? else {
+        return Ok(false);
+    };
+    let len = header.lens[part as usize] as usize;
+    if len == 0 {
+        return Ok(false);
+    }
+    out.clear();
+    out.resize(len, 0).map_err(|_| StagingError::TooLarge)?;
+    nvm.nvs_read_at(BlockId::StagingId, offset(part), out)?;
+    Ok(true)
+}
+
+pub fn set_committing(nvm: &mut Nvm) -> Result<(), StagingError> {
+    let mut header = Header::read(nvm)?.ok_or(StagingError::Empty)?;
+    header.state = State::Committing;
+    header.write(nvm)
+}
+
+/// End the rotation, committed or dropped
+pub fn clear(nvm: &mut Nvm) -> Result<(), StagingError> {
+    nvm.nvs_write_at(BlockId::StagingId, 0, &[0u8; HEADER_LEN])?;
+    Ok(())
+}
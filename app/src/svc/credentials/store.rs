@@ -0,0 +1,43 @@
+//! Credential material in a block of its own
+//!
+//! `magic, format, length (LE), checksum (LE), material`, the material without
+//! the NUL mbedtls wants after a PEM.
+use crate::svc::mem::nvm::{BlockId, Nvm, NvsError};
+use crate::util::fnv::fnv1a;
+
+const MAGIC: u8 = 0xCE;
+const FORMAT: u8 = 1;
+const HEADER_LEN: usize = 8;
+
+/// Read the material of `id` into `out`, `false` when there is none or it is corrupt
+pub fn load<const N: usize>(nvm: &mut Nvm, id: BlockId, out: &mut heapless::Vec<u8, N>) -> bool {
+    out.clear();
+    let mut header = [0u8; HEADER_LEN];
+    if nvm.nvs_read_at(id, 0, &mut header).is_err() || header[0] != MAGIC || header[1] != FORMAT {
+        return false;
+    }
+    let len = u16::from_le_bytes([header[2], header[3]]) as usize;
+    let checksum = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    if len == 0 || out.resize(len, 0).is_err() {
+        return false;
+    }
+    if nvm.nvs_read_at(id, HEADER_LEN, out).is_err() || fnv1a(out) != checksum {
+        out.clear();
+        return false;
+    }
+    true
+}
+
+/// Replace the material of `id`
+///
+/// The header goes last, an interrupted write leaves a record that fails its
+/// checksum rather than a mix of old and new material.
+pub fn store(nvm: &mut Nvm, id: BlockId, material: &[u8]) -> Result<(), NvsError> {
+    let mut header = [0u8; HEADER_LEN];
+    header[0] = MAGIC;
+    header[1] = FORMAT;
+    header[2..4].copy_from_slice(&(material.len() as u16).to_le_bytes());
+    header[4..8].copy_from_slice(&fnv1a(material).to_le_bytes());
+    nvm.nvs_write_at(id, HEADER_LEN, material)?;
+    nvm.nvs_write_at(id, 0, &header)
+}
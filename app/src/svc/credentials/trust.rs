@@ -0,0 +1,240 @@
+//! Trust store of the uplinks and pinning of the broker's keys
+//!
+//! The trust store is the CA bundle of the `CrtPemId` record, any number of
+//! CA certificates. A new bundle comes as the `ca` part of a rotation and is
+//! only taken with a signature of `cfg::trust_cfg::TRUST_BUNDLE_KEY`, ECDSA
+//! P-256 over the SHA-256 of the bundle as uploaded.
+//!
+//! mbedtls does not tell which certificate the server presented, so pins are
+//! enforced through the trust store: with pins set, only the CAs with one of
+//! those keys go to the TLS session and the modem.
+use crate::cfg::trust_cfg::TRUST_BUNDLE_KEY;
+use crate::svc::mem::nvm::Nvm;
+use crate::util::p256::{Signature, VerifyingKey, PUBLIC_KEY_LEN, SCALAR_LEN};
+use crate::util::sha256::{self, DIGEST_LEN};
+use crate::util::{base64, hex};
+
+use super::{staging, x509, CredentialError, Part, CA_LEN, DER_LEN};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TrustError {
+    /// A new bundle without a signature
+    Unsigned,
+    /// Not signed with the bundle key, or no bundle key configured
+    BadSignature,
+    Storage,
+}
+
+/// SHA-256 of a DER `SubjectPublicKeyInfo`, the `pin-sha256` of the key
+pub fn pin(der: &[u8]) -> Option<[u8; DIGEST_LEN]> {
+    x509::spki(der).map(sha256::digest)
+}
+
+/// Keep the CAs of `bundle`, a PEM with its NUL, with a key of `pins`
+pub fn restrict<const N: usize>(
+    bundle: &mut heapless::Vec<u8, N>,
+    pins: &[&str],
+) -> Result<(), CredentialError> {
+    if pins.is_empty() {
+        return Ok(());
+    }
+    let mut kept = heapless::Vec::<u8, N>::new();
+    let mut der = heapless::Vec::<u8, DER_LEN>::new();
+    let mut rest = bundle.strip_suffix(&[0]).unwrap_or(&bundle[..]);
+    while let Some((_, next)) = base64::decode_pem(rest, &mut der) {
+        if pin(&der).is_some_and(|key| is_pinned(&key, pins)) {
+            // The block as it came, from its BEGIN line to its END line
+            let block = &rest[..rest.len() - next.len()];
+            let start = block
+                .windows(11)
+                .position(|window| window == b"-----BEGIN ")
+                .unwrap_or(0);
+            let _ = kept.extend_from_slice(&block[start..]);
+            let _ = kept.push(b'\n');
+        }
+        rest = next;
+    }
+    if kept.is_empty() {
+        return Err(CredentialError::Unpinned);
+    }
+    kept.push(0).map_err(|_| CredentialError::TooLarge)?;
+    *bundle = kept;
+    Ok(())
+}
+
+fn is_pinned(key: &[u8; DIGEST_LEN], pins: &[&str]) -> bool {
+    pins.iter()
+        .filter_map(|pin| base64::decode::<DIGEST_LEN>(pin))
+        .any(|pin| pin[..] == key[..])
+}
+
+/// Check the signature of the staged CA bundle, if the rotation carries one
+///
+/// `signature` is the base64 of `r || s`.
+pub fn verify_staged(nvm: &mut Nvm, signature: Option<&str>) -> Result<(), TrustError> {
+    let mut bundle = heapless::Vec::<u8, CA_LEN>::new();
+    if !staging::read(nvm, Part::CaChain, &mut bundle).map_err(|_| TrustError::Storage)? {
+        return Ok(());
+    }
+    let signature = signature.ok_or(TrustError::Unsigned)?;
+    verify(&bundle, signature, TRUST_BUNDLE_KEY)
+}
+
+/// Check `signature`, the base64 of `r || s`, of `bundle` with `key` in hex
+fn verify(bundle: &[u8], signature: &str, key: &str) -> Result<(), TrustError> {
+    let signature = base64::decode::<{ 2 * SCALAR_LEN }>(signature)
+        .and_then(|raw| {
+            Some(Signature {
+                r: raw.get(..SCALAR_LEN)?.try_into().ok()?,
+                s: raw.get(SCALAR_LEN..)?.try_into().ok()?,
+            })
+        })
+        .ok_or(TrustError::BadSignature)?;
+    let key = hex::decode::<PUBLIC_KEY_LEN>(key)
+        .and_then(|key| VerifyingKey::from_bytes(&key))
+        .ok_or(TrustError::BadSignature)?;
+    if key.verify(&sha256::digest(bundle), &signature) {
+        Ok(())
+    } else {
+        Err(TrustError::BadSignature)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::p256::SecretKey;
+
+    const CA: &[u8] = include_bytes!("testdata/ca.der");
+    /// Issuer of nothing the device trusts
+    const OTHER_CA: &[u8] = include_bytes!("testdata/other_ca.der");
+    /// `openssl x509 -pubkey -noout | openssl pkey -pubin -outform der |
+    /// openssl dgst -sha256 -binary | base64`
+    const CA_PIN: &str = "EQlQK5RZln6cyih3BMKX1E3U5Fvz0/4vBx0hxldmOcE=";
+    const OTHER_CA_PIN: &str = "71Oq0ogtrl0x5NQcjcvcGgXi+DNXZEbd8vnwVAL7iXE=";
+
+    fn pem(der: &[u8]) -> std::string::String {
+        let mut out = std::string::String::new();
+        base64::encode_pem(&mut out, "CERTIFICATE", der).unwrap();
+        out
+    }
+
+    /// Both CAs as a bundle with its NUL
+    fn bundle() -> heapless::Vec<u8, CA_LEN> {
+        let mut bundle = heapless::Vec::new();
+        bundle.extend_from_slice(pem(OTHER_CA).as_bytes()).unwrap();
+        bundle.extend_from_slice(pem(CA).as_bytes()).unwrap();
+        bundle.push(0).unwrap();
+        bundle
+    }
+
+    fn encoded_pin(der: &[u8]) -> std::string::String {
+        let mut out = std::string::String::new();
+        base64::encode(&mut out, &pin(der).unwrap()).unwrap();
+        out
+    }
+
+    #[test]
+    fn pins_match_openssl() {
+        assert_eq!(encoded_pin(CA), CA_PIN);
+        assert_eq!(encoded_pin(OTHER_CA), OTHER_CA_PIN);
+    }
+
+    #[test]
+    fn keeps_only_the_pinned_issuer() {
+        let mut bundle = bundle();
+        restrict(&mut bundle, &[CA_PIN]).unwrap();
+        let expected = std::format!("{}\n\0", pem(CA).trim_end());
+        assert_eq!(core::str::from_utf8(&bundle).unwrap(), expected);
+
+        let mut bundle = self::bundle();
+        restrict(&mut bundle, &["not base64", OTHER_CA_PIN]).unwrap();
+        assert!(bundle.starts_with(pem(OTHER_CA).trim_end().as_bytes()));
+        assert_eq!(bundle.iter().filter(|b| **b == 0).count(), 1);
+    }
+
+    #[test]
+    fn bundle_without_a_pinned_issuer_is_refused() {
+        let mut bundle = heapless::Vec::<u8, CA_LEN>::new();
+        bundle.extend_from_slice(pem(OTHER_CA).as_bytes()).unwrap();
+        bundle.push(0).unwrap();
+        let before = bundle.clone();
+        assert_eq!(
+            restrict(&mut bundle, &[CA_PIN]),
+            Err(CredentialError::Unpinned)
+        );
+        assert_eq!(bundle, before);
+    }
+
+    #[test]
+    fn no_pins_trust_the_whole_bundle() {
+        let mut bundle = bundle();
+        restrict(&mut bundle, &[]).unwrap();
+        assert_eq!(bundle, self::bundle());
+    }
+
+    fn signer(seed: u8) -> (SecretKey, std::string::String) {
+        let key = SecretKey::from_seed(&[seed; 32]);
+        let mut public = std::string::String::new();
+        hex::encode(&mut public, &key.public_key()).unwrap();
+        (key, public)
+    }
+
+    fn sign(key: &SecretKey, bundle: &[u8]) -> std::string::String {
+        let signature = key.sign(&sha256::digest(bundle));
+        let mut raw = [0u8; 2 * SCALAR_LEN];
+        raw[..SCALAR_LEN].copy_from_slice(&signature.r);
+        raw[SCALAR_LEN..].copy_from_slice(&signature.s);
+        let mut out = std::string::String::new();
+        base64::encode(&mut out, &raw).unwrap();
+        out
+    }
+
+    #[test]
+    fn bundle_signatures() {
+        let bundle = pem(CA);
+        let (key, public) = signer(1);
+        let signature = sign(&key, bundle.as_bytes());
+        assert_eq!(verify(bundle.as_bytes(), &signature, &public), Ok(()));
+
+        // Signed by another key, or another bundle
+        let (other, _) = signer(2);
+        let forged = sign(&other, bundle.as_bytes());
+        assert_eq!(
+            verify(bundle.as_bytes(), &forged, &public),
+            Err(TrustError::BadSignature)
+        );
+        let tampered = pem(OTHER_CA);
+        assert_eq!(
+            verify(tampered.as_bytes(), &signature, &public),
+            Err(TrustError::BadSignature)
+        );
+        // Malformed, or no bundle key configured
+        assert_eq!(
+            verify(bundle.as_bytes(), &signature[..40], &public),
+            Err(TrustError::BadSignature)
+        );
+        assert_eq!(
+            verify(bundle.as_bytes(), &signature, ""),
+            Err(TrustError::BadSignature)
+        );
+    }
+
+    #[test]
+    fn staged_bundle_needs_a_signature() {
+        let mut nvm = Nvm::init();
+        assert_eq!(verify_staged(&mut nvm, None), Ok(()));
+
+        let bundle = pem(CA);
+        staging::write(&mut nvm, Part::CaChain, 0, bundle.as_bytes()).unwrap();
+        staging::seal(&mut nvm).unwrap();
+        assert_eq!(verify_staged(&mut nvm, None), Err(TrustError::Unsigned));
+        let (key, _) = signer(1);
+        let signature = sign(&key, bundle.as_bytes());
+        // TRUST_BUNDLE_KEY is not set, every new bundle is refused
+        assert_eq!(
+            verify_staged(&mut nvm, Some(&signature)),
+            Err(TrustError::BadSignature)
+        );
+    }
+}
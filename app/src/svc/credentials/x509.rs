@@ -0,0 +1,360 @@
+//! Just enough DER to check credentials before handing them to mbedtls
+//!
+//! Reads the validity period and public key of a certificate, and the public
+//! key of an RSA (PKCS #1), EC (SEC1) or PKCS #8 private key. RSA keys compare
+//! by their modulus, EC keys by their point, derived from the scalar for P-256.
+use crate::util::p256::{SecretKey, SCALAR_LEN};
+use crate::util::time::civil_to_unix_secs;
+
+const INTEGER: u8 = 0x02;
+const BIT_STRING: u8 = 0x03;
+const OCTET_STRING: u8 = 0x04;
+const OID: u8 = 0x06;
+const UTC_TIME: u8 = 0x17;
+const GENERALIZED_TIME: u8 = 0x18;
+const SEQUENCE: u8 = 0x30;
+/// `[0]` and `[1]`, constructed
+const CONTEXT_0: u8 = 0xA0;
+const CONTEXT_1: u8 = 0xA1;
+
+/// 1.2.840.113549.1.1.1
+const RSA_ENCRYPTION: &[u8] = &[0x2A, 0x86, 0x48, 0x86, 0xF7, 0x0D, 0x01, 0x01, 0x01];
+/// 1.2.840.10045.2.1
+const EC_PUBLIC_KEY: &[u8] = &[0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x02, 0x01];
+/// 1.2.840.10045.3.1.7
+const PRIME256V1: &[u8] = &[0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x03, 0x01, 0x07];
+
+/// Longest RSA modulus, 4096 bits
+const MODULUS_LEN: usize = 512;
+const CURVE_LEN: usize = 16;
+/// Longest uncompressed point, P-521
+const POINT_LEN: usize = 133;
+
+// Parsed once per connection and compared right away, the size does not matter
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, PartialEq)]
+pub enum PublicKey {
+    /// Modulus without leading zeros
+    Rsa(heapless::Vec<u8, MODULUS_LEN>),
+    Ec {
+        curve: heapless::Vec<u8, CURVE_LEN>,
+        point: heapless::Vec<u8, POINT_LEN>,
+    },
+}
+
+pub struct Certificate {
+    /// Seconds since the Unix epoch
+    pub not_before: u64,
+    pub not_after: u64,
+    /// `None` for an algorithm other than RSA and EC
+    pub key: Option<PublicKey>,
+}
+
+impl Certificate {
+    pub fn parse(der: &[u8]) -> Option<Self> {
+        let (validity, spki) = tbs_fields(der)?;
+        let mut validity = Reader::new(validity);
+        let not_before = time(validity.next()?)?;
+        let not_after = time(validity.next()?)?;
+        let key = Reader::new(spki).expect(SEQUENCE).and_then(public_key);
+        Some(Self {
+            not_before,
+            not_after,
+            key,
+        })
+    }
+
+    pub fn is_valid_at(&self, now: u64) -> bool {
+        (self.not_before..=self.not_after).contains(&now)
+    }
+}
+
+/// Whole DER `SubjectPublicKeyInfo` of a certificate, what a pin hashes
+pub fn spki(der: &[u8]) -> Option<&[u8]> {
+    tbs_fields(der).map(|(_, spki)| spki)
+}
+
+pub struct PrivateKey {
+    /// PEM label of the encoding
+    pub label: &'static str,
+    pub public: PublicKey,
+}
+
+impl PrivateKey {
+    /// Unencrypted key, `None` for an unknown algorithm
+    pub fn parse(der: &[u8]) -> Option<Self> {
+        let mut key = Reader::new(Reader::new(der).expect(SEQUENCE)?);
+        let version = key.expect(INTEGER)?;
+        let (label, public) = match key.next()? {
+            (INTEGER, modulus) if version == [0] => ("RSA PRIVATE KEY", rsa_key(modulus)?),
+            (OCTET_STRING, scalar) if version == [1] => {
+                ("EC PRIVATE KEY", ec_key(scalar, key, None)?)
+            }
+            (SEQUENCE, algorithm) if version == [0] => (
+                "PRIVATE KEY",
+                pkcs8_key(algorithm, key.expect(OCTET_STRING)?)?,
+            ),
+            _ => return None,
+        };
+        Some(Self { label, public })
+    }
+}
+
+/// Walks the elements of a DER encoding
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+
+    /// Tag and contents of the next element, `None` at the end or when cut short
+    fn next(&mut self) -> Option<(u8, &'a [u8])> {
+        let [tag, first, rest @ ..] = self.bytes else {
+            return None;
+        };
+        let (len, rest) = match *first {
+            len @ 0..=0x7F => (len as usize, rest),
+            0x81 => (*rest.first()? as usize, rest.get(1..)?),
+            0x82 => (
+                u16::from_be_bytes([*rest.first()?, *rest.get(1)?]) as usize,
+                rest.get(2..)?,
+            ),
+            _ => return None,
+        };
+        let contents = rest.get(..len)?;
+        self.bytes = &rest[len..];
+        Some((*tag, contents))
+    }
+
+    /// The next element whole, tag and length included
+    fn next_raw(&mut self) -> Option<&'a [u8]> {
+        let start = self.bytes;
+        self.next()?;
+        Some(&start[..start.len() - self.bytes.len()])
+    }
+
+    fn expect(&mut self, tag: u8) -> Option<&'a [u8]> {
+        self.next()
+            .filter(|(found, _)| *found == tag)
+            .map(|(_, contents)| contents)
+    }
+}
+
+/// Contents of the validity and whole `SubjectPublicKeyInfo` of a certificate
+fn tbs_fields(der: &[u8]) -> Option<(&[u8], &[u8])> {
+    let mut certificate = Reader::new(Reader::new(der).expect(SEQUENCE)?);
+    let mut tbs = Reader::new(certificate.expect(SEQUENCE)?);
+    let (mut tag, _) = tbs.next()?;
+    if tag == CONTEXT_0 {
+        // Version
+        (tag, _) = tbs.next()?;
+    }
+    if tag != INTEGER {
+        return None;
+    }
+    tbs.expect(SEQUENCE)?; // Signature algorithm
+    tbs.expect(SEQUENCE)?; // Issuer
+    let validity = tbs.expect(SEQUENCE)?;
+    tbs.expect(SEQUENCE)?; // Subject
+    Some((validity, tbs.next_raw()?))
+}
+
+/// Contents of a `SubjectPublicKeyInfo`
+fn public_key(spki: &[u8]) -> Option<PublicKey> {
+    let mut spki = Reader::new(spki);
+    let mut algorithm = Reader::new(spki.expect(SEQUENCE)?);
+    let oid = algorithm.expect(OID)?;
+    let key = bit_string(spki.expect(BIT_STRING)?)?;
+    if oid == RSA_ENCRYPTION {
+        let mut rsa = Reader::new(Reader::new(key).expect(SEQUENCE)?);
+        rsa_key(rsa.expect(INTEGER)?)
+    } else if oid == EC_PUBLIC_KEY {
+        Some(PublicKey::Ec {
+            curve: heapless::Vec::from_slice(algorithm.expect(OID)?).ok()?,
+            point: heapless::Vec::from_slice(key).ok()?,
+        })
+    } else {
+        None
+    }
+}
+
+fn rsa_key(modulus: &[u8]) -> Option<PublicKey> {
+    let start = modulus.iter().position(|b| *b != 0)?;
+    heapless::Vec::from_slice(&modulus[start..])
+        .ok()
+        .map(PublicKey::Rsa)
+}
+
+/// SEC1 key from its scalar on, the curve comes from PKCS #8 when wrapped
+fn ec_key(scalar: &[u8], mut rest: Reader, curve: Option<&[u8]>) -> Option<PublicKey> {
+    let mut curve = curve;
+    let mut point = None;
+    while let Some((tag, contents)) = rest.next() {
+        match tag {
+            CONTEXT_0 => curve = Some(Reader::new(contents).expect(OID)?),
+            CONTEXT_1 => point = Some(bit_string(Reader::new(contents).expect(BIT_STRING)?)?),
+            _ => return None,
+        }
+    }
+    let curve = curve?;
+    let point = if curve == PRIME256V1 {
+        // Derived rather than trusted, the point stored with it may be anything
+        let mut bytes = [0u8; SCALAR_LEN];
+        let at = SCALAR_LEN.checked_sub(scalar.len())?;
+        bytes[at..].copy_from_slice(scalar);
+        heapless::Vec::from_slice(&SecretKey::from_bytes(&bytes)?.public_key()).ok()?
+    } else {
+        heapless::Vec::from_slice(point?).ok()?
+    };
+    Some(PublicKey::Ec {
+        curve: heapless::Vec::from_slice(curve).ok()?,
+        point,
+    })
+}
+
+/// `PrivateKeyInfo` from its algorithm on
+fn pkcs8_key(algorithm: &[u8], inner: &[u8]) -> Option<PublicKey> {
+    let mut algorithm = Reader::new(algorithm);
+    let oid = algorithm.expect(OID)?;
+    let mut key = Reader::new(Reader::new(inner).expect(SEQUENCE)?);
+    let version = key.expect(INTEGER)?;
+    if oid == RSA_ENCRYPTION && version == [0] {
+        rsa_key(key.expect(INTEGER)?)
+    } else if oid == EC_PUBLIC_KEY && version == [1] {
+        let curve = algorithm.expect(OID)?;
+        ec_key(key.expect(OCTET_STRING)?, key, Some(curve))
+    } else {
+        None
+    }
+}
+
+/// Contents of a BIT STRING without unused bits
+fn bit_string(contents: &[u8]) -> Option<&[u8]> {
+    let (unused, bits) = contents.split_first()?;
+    (*unused == 0).then_some(bits)
+}
+
+/// `UTCTime` or `GeneralizedTime`, both in UTC
+fn time((tag, text): (u8, &[u8])) -> Option<u64> {
+    let (year, rest) = match (tag, text.len()) {
+        (UTC_TIME, 13) => match digits(&text[..2])? {
+            year @ 50.. => (1900 + year, &text[2..]),
+            year => (2000 + year, &text[2..]),
+        },
+        (GENERALIZED_TIME, 15) => (digits(&text[..4])?, &text[4..]),
+        _ => return None,
+    };
+    if rest[10] != b'Z' {
+        return None;
+    }
+    if year < 1970 {
+        return Some(0);
+    }
+    let field = |at: usize| digits(&rest[at..at + 2]).map(|n| n as u8);
+    civil_to_unix_secs(year, field(0)?, field(2)?, field(4)?, field(6)?, field(8)?)
+}
+
+fn digits(text: &[u8]) -> Option<u16> {
+    text.iter().try_fold(0u16, |n, c| {
+        c.is_ascii_digit().then(|| n * 10 + (c - b'0') as u16)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // P-256 fixtures made with openssl: a root CA, a device certificate it
+    // issued with its key, the same key certified for 2020 only, and by
+    // another CA
+    const CA: &[u8] = include_bytes!("testdata/ca.der");
+    const DEVICE: &[u8] = include_bytes!("testdata/device.der");
+    const DEVICE_KEY: &[u8] = include_bytes!("testdata/device_key.der");
+    const EXPIRED: &[u8] = include_bytes!("testdata/expired.der");
+    const WRONG_ISSUER: &[u8] = include_bytes!("testdata/wrong_issuer.der");
+    const OTHER_KEY: &[u8] = include_bytes!("testdata/other_key.der");
+
+    const JAN_2020: u64 = 1_577_836_800;
+    const JAN_2021: u64 = 1_609_459_200;
+    const JAN_2024: u64 = 1_704_067_200;
+    const JAN_2034: u64 = 2_019_686_400;
+    const JAN_2050: u64 = 2_524_608_000;
+
+    #[test]
+    fn parses_the_validity_period() {
+        let device = Certificate::parse(DEVICE).unwrap();
+        assert_eq!((device.not_before, device.not_after), (JAN_2024, JAN_2034));
+        let expired = Certificate::parse(EXPIRED).unwrap();
+        assert_eq!(
+            (expired.not_before, expired.not_after),
+            (JAN_2020, JAN_2021)
+        );
+        // GeneralizedTime from 2050 on
+        let ca = Certificate::parse(CA).unwrap();
+        assert_eq!((ca.not_before, ca.not_after), (JAN_2024, JAN_2050));
+    }
+
+    #[test]
+    fn validity_bounds_are_inclusive() {
+        let device = Certificate::parse(DEVICE).unwrap();
+        assert!(!device.is_valid_at(JAN_2024 - 1));
+        assert!(device.is_valid_at(JAN_2024));
+        assert!(device.is_valid_at(JAN_2034));
+        assert!(!device.is_valid_at(JAN_2034 + 1));
+        let expired = Certificate::parse(EXPIRED).unwrap();
+        assert!(!expired.is_valid_at(JAN_2024));
+    }
+
+    #[test]
+    fn key_belongs_to_its_certificates() {
+        let key = PrivateKey::parse(DEVICE_KEY).unwrap();
+        assert_eq!(key.label, "EC PRIVATE KEY");
+        let PublicKey::Ec { curve, point } = &key.public else {
+            panic!("not an EC key");
+        };
+        assert_eq!(&curve[..], PRIME256V1);
+        assert_eq!((point.len(), point[0]), (65, 0x04));
+        for der in [DEVICE, EXPIRED, WRONG_ISSUER] {
+            assert_eq!(
+                Certificate::parse(der).unwrap().key.as_ref(),
+                Some(&key.public)
+            );
+        }
+        let other = PrivateKey::parse(OTHER_KEY).unwrap();
+        assert_ne!(Certificate::parse(DEVICE).unwrap().key, Some(other.public));
+    }
+
+    #[test]
+    fn spki_is_the_whole_element() {
+        let spki = spki(CA).unwrap();
+        assert_eq!(spki[0], SEQUENCE);
+        assert_eq!(spki.len(), 91);
+        assert!(CA.windows(spki.len()).any(|window| window == spki));
+    }
+
+    #[test]
+    fn rejects_truncated_or_malformed_der() {
+        for len in [0, 1, 4, DEVICE.len() / 2, DEVICE.len() - 1] {
+            assert!(Certificate::parse(&DEVICE[..len]).is_none(), "{len}");
+        }
+        let mut malformed = DEVICE.to_vec();
+        malformed[0] = OCTET_STRING;
+        assert!(Certificate::parse(&malformed).is_none());
+        assert!(PrivateKey::parse(DEVICE).is_none());
+        assert!(PrivateKey::parse(&DEVICE_KEY[..DEVICE_KEY.len() - 1]).is_none());
+    }
+
+    #[test]
+    fn time_formats() {
+        assert_eq!(time((UTC_TIME, b"240101000000Z")), Some(JAN_2024));
+        assert_eq!(time((UTC_TIME, b"491231235959Z")), Some(2_524_607_999));
+        assert_eq!(time((UTC_TIME, b"500101000000Z")), Some(0));
+        assert_eq!(time((GENERALIZED_TIME, b"20500101000000Z")), Some(JAN_2050));
+        assert_eq!(time((UTC_TIME, b"240101000000+0100")), None);
+        assert_eq!(time((UTC_TIME, b"241301000000Z")), None);
+        assert_eq!(time((GENERALIZED_TIME, b"240101000000Z")), None);
+    }
+}
@@ -0,0 +1,499 @@
+//! DNS messages (RFC 1035), address queries and their answers
+//!
+//! Every read of a response is checked against its end. Names are compared in
+//! wire format, lowercased, after following compression pointers; pointers
+//! only lead back in the message, so a pointer loop is rejected.
+use core::net::{IpAddr, Ipv4Addr};
+
+pub const HEADER_LEN: usize = 12;
+/// Longest name, dots included
+pub const NAME_LEN: usize = 253;
+/// Longest name in wire format, label lengths and root included
+const WIRE_NAME_LEN: usize = NAME_LEN + 2;
+/// Header, name, type and class
+pub const QUERY_LEN: usize = HEADER_LEN + WIRE_NAME_LEN + 4;
+/// Longest answer over UDP without EDNS
+pub const UDP_LEN: usize = 512;
+/// Addresses kept from one answer
+pub const MAX_ADDRS: usize = 4;
+/// CNAMEs followed from the name asked for
+const MAX_CNAMES: usize = 8;
+const LABEL_LEN: usize = 63;
+
+const TYPE_A: u16 = 1;
+const TYPE_CNAME: u16 = 5;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_TRUNCATED: u16 = 0x0200;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+const RCODE_MASK: u16 = 0x000F;
+const RCODE_SERVER_FAILURE: u16 = 2;
+const RCODE_NAME_ERROR: u16 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DnsError {
+    /// Empty, with an empty label or longer than DNS allows
+    InvalidName,
+    /// Not the answer to the query: another ID, or not a response
+    Unrelated,
+    /// Cut short or inconsistent
+    Malformed,
+    /// Did not fit the UDP datagram, asked again over TCP
+    Truncated,
+    /// A TCP answer longer than the buffer
+    TooLarge,
+    /// NXDOMAIN, the name does not exist
+    NameError,
+    /// SERVFAIL, the server could not resolve the name
+    ServerFailure,
+    /// RCODE of a query refused or failed otherwise
+    Server(u8),
+    /// No address for the name, CNAMEs followed
+    NoAddress,
+    /// No server from DHCP nor configured
+    NoServer,
+    /// The socket failed or the server closed the connection
+    Network,
+    /// No server answered in time
+    Timeout,
+}
+
+/// Name in wire format, lowercased
+type Name = heapless::Vec<u8, WIRE_NAME_LEN>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecordType {
+    A,
+    Aaaa,
+}
+
+impl RecordType {
+    fn code(self) -> u16 {
+        match self {
+            RecordType::A => TYPE_A,
+            RecordType::Aaaa => TYPE_AAAA,
+        }
+    }
+}
+
+/// Addresses of a name and how long they can be kept
+#[derive(Debug, Clone, PartialEq)]
+pub struct Answer {
+    /// A and AAAA records of the name, at the end of its CNAME chain
+    pub addrs: heapless::Vec<IpAddr, MAX_ADDRS>,
+    /// Seconds, the lowest TTL of the records used, CNAMEs included
+    pub ttl: u32,
+}
+
+impl Answer {
+    pub fn ipv4(&self) -> Option<Ipv4Addr> {
+        self.addrs.iter().find_map(|addr| match addr {
+            IpAddr::V4(addr) => Some(*addr),
+            IpAddr::V6(_) => None,
+        })
+    }
+}
+
+/// Recursive query for the `kind` records of `host`
+pub fn query(
+    id: u16,
+    host: &str,
+    kind: RecordType,
+) -> Result<heapless::Vec<u8, QUERY_LEN>, DnsError> {
+    let mut query = heapless::Vec::new();
+    for field in [id, FLAG_RECURSION_DESIRED, 1, 0, 0, 0] {
+        let _ = query.extend_from_slice(&field.to_be_bytes());
+    }
+    let _ = query.extend_from_slice(&encode_name(host)?);
+    let _ = query.extend_from_slice(&kind.code().to_be_bytes());
+    let _ = query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(query)
+}
+
+/// Addresses in the answer to query `id` for the `kind` records of `host`
+pub fn parse_response(
+    id: u16,
+    host: &str,
+    kind: RecordType,
+    response: &[u8],
+) -> Result<Answer, DnsError> {
+    let mut reader = Reader::new(response);
+    if reader.u16()? != id {
+        return Err(DnsError::Unrelated);
+    }
+    let flags = reader.u16()?;
+    let questions = reader.u16()?;
+    let answers = reader.u16()?;
+    reader.take(4)?; // Authority and additional counts
+    if flags & FLAG_RESPONSE == 0 || questions != 1 {
+        return Err(DnsError::Unrelated);
+    }
+    // The question comes back as asked, or it is not the answer to it
+    let asked = encode_name(host)?;
+    if reader.name()? != asked || reader.u16()? != kind.code() || reader.u16()? != CLASS_IN {
+        return Err(DnsError::Unrelated);
+    }
+    if flags & FLAG_TRUNCATED != 0 {
+        return Err(DnsError::Truncated);
+    }
+    match flags & RCODE_MASK {
+        0 => {}
+        RCODE_NAME_ERROR => return Err(DnsError::NameError),
+        RCODE_SERVER_FAILURE => return Err(DnsError::ServerFailure),
+        rcode => return Err(DnsError::Server(rcode as u8)),
+    }
+
+    let mut ttl = u32::MAX;
+    let mut name = asked;
+    for _ in 0..MAX_CNAMES {
+        let mut records = reader.clone();
+        let mut target = None;
+        for _ in 0..answers {
+            let record = records.record()?;
+            if record.kind == TYPE_CNAME && record.class == CLASS_IN && record.name == name {
+                target = Some(Reader::at(response, record.data_at).name()?);
+                ttl = ttl.min(record.ttl);
+            }
+        }
+        match target {
+            Some(target) => name = target,
+            None => break,
+        }
+    }
+    let mut addrs = heapless::Vec::new();
+    for _ in 0..answers {
+        let record = reader.record()?;
+        if record.class != CLASS_IN || record.name != name {
+            continue;
+        }
+        let addr = match record.kind {
+            TYPE_A => <[u8; 4]>::try_from(record.data).map(IpAddr::from),
+            TYPE_AAAA => <[u8; 16]>::try_from(record.data).map(IpAddr::from),
+            _ => continue,
+        };
+        let addr = addr.map_err(|_| DnsError::Malformed)?;
+        ttl = ttl.min(record.ttl);
+        let _ = addrs.push(addr);
+    }
+    if addrs.is_empty() {
+        return Err(DnsError::NoAddress);
+    }
+    Ok(Answer { addrs, ttl })
+}
+
+/// `host` in wire format, a trailing dot allowed
+fn encode_name(host: &str) -> Result<Name, DnsError> {
+    let host = host.strip_suffix('.').unwrap_or(host);
+    if host.is_empty() || host.len() > NAME_LEN {
+        return Err(DnsError::InvalidName);
+    }
+    let mut name = Name::new();
+    for label in host.split('.') {
+        if label.is_empty() || label.len() > LABEL_LEN {
+            return Err(DnsError::InvalidName);
+        }
+        let _ = name.push(label.len() as u8);
+        for byte in label.bytes() {
+            let _ = name.push(byte.to_ascii_lowercase());
+        }
+    }
+    let _ = name.push(0);
+    Ok(name)
+}
+
+/// Resource record of the answer section
+struct Record<'a> {
+    name: Name,
+    kind: u16,
+    class: u16,
+    /// Seconds
+    ttl: u32,
+    data: &'a [u8],
+    /// Offset of the data in the message, for the names it holds
+    data_at: usize,
+}
+
+/// Walks a message, every read checked against its end
+#[derive(Clone)]
+struct Reader<'a> {
+    message: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(message: &'a [u8]) -> Self {
+        Self::at(message, 0)
+    }
+
+    fn at(message: &'a [u8], pos: usize) -> Self {
+        Self { message, pos }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], DnsError> {
+        let end = self.pos.checked_add(len).ok_or(DnsError::Malformed)?;
+        let taken = self.message.get(self.pos..end).ok_or(DnsError::Malformed)?;
+        self.pos = end;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, DnsError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, DnsError> {
+        Ok(u16::from_be_bytes([self.u8()?, self.u8()?]))
+    }
+
+    fn u32(&mut self) -> Result<u32, DnsError> {
+        Ok((self.u16()? as u32) << 16 | self.u16()? as u32)
+    }
+
+    /// Name at the position, compression pointers followed
+    fn name(&mut self) -> Result<Name, DnsError> {
+        let mut name = Name::new();
+        let mut at = self.pos;
+        // Where the name ends in the message, before the first pointer
+        let mut end = None;
+        loop {
+            let len = *self.message.get(at).ok_or(DnsError::Malformed)?;
+            match len {
+                0 => {
+                    name.push(0).map_err(|_| DnsError::Malformed)?;
+                    self.pos = end.unwrap_or(at + 1);
+                    return Ok(name);
+                }
+                1..=0x3F => {
+                    let label = self
+                        .message
+                        .get(at + 1..at + 1 + len as usize)
+                        .ok_or(DnsError::Malformed)?;
+                    name.push(len).map_err(|_| DnsError::Malformed)?;
+                    for byte in label {
+                        name.push(byte.to_ascii_lowercase())
+                            .map_err(|_| DnsError::Malformed)?;
+                    }
+                    at += 1 + len as usize;
+                }
+                0xC0.. => {
+                    let low = *self.message.get(at + 1).ok_or(DnsError::Malformed)?;
+                    let target = u16::from_be_bytes([len & 0x3F, low]) as usize;
+                    // To an earlier name only
+                    if target >= at {
+                        return Err(DnsError::Malformed);
+                    }
+                    end.get_or_insert(at + 2);
+                    at = target;
+                }
+                _ => return Err(DnsError::Malformed),
+            }
+        }
+    }
+
+    fn record(&mut self) -> Result<Record<'a>, DnsError> {
+        let name = self.name()?;
+        let kind = self.u16()?;
+        let class = self.u16()?;
+        // A TTL with the top bit set is taken as zero (RFC 2181)
+        let ttl = match self.u32()? {
+            ttl @ 0..=0x7FFF_FFFF => ttl,
+            _ => 0,
+        };
+        let len = self.u16()? as usize;
+        let data_at = self.pos;
+        let data = self.take(len)?;
+        Ok(Record {
+            name,
+            kind,
+            class,
+            ttl,
+            data,
+            data_at,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use proptest::prelude::*;
+
+    use super::*;
+
+    const ID: u16 = 0x1234;
+    const HOST: &str = "broker.example.com";
+    /// Compression pointer to the name of the question
+    const QUESTION: [u8; 2] = [0xC0, 0x0C];
+
+    /// Header and question of the answer to the A query for `HOST`
+    fn response(answers: u16) -> Vec<u8> {
+        let mut message = query(ID, HOST, RecordType::A).unwrap().to_vec();
+        // Response, recursion desired and available
+        message[2..4].copy_from_slice(&0x8180u16.to_be_bytes());
+        message[6..8].copy_from_slice(&answers.to_be_bytes());
+        message
+    }
+
+    fn push_record(message: &mut Vec<u8>, name: &[u8], kind: u16, ttl: u32, data: &[u8]) {
+        message.extend_from_slice(name);
+        message.extend_from_slice(&kind.to_be_bytes());
+        message.extend_from_slice(&CLASS_IN.to_be_bytes());
+        message.extend_from_slice(&ttl.to_be_bytes());
+        message.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        message.extend_from_slice(data);
+    }
+
+    /// `HOST` is a CNAME of `edge.example.net`, which has two addresses
+    fn cname_response() -> Vec<u8> {
+        let mut message = response(3);
+        let target = b"\x04edge\x07example\x03net\x00";
+        push_record(&mut message, &QUESTION, TYPE_CNAME, 300, target);
+        let target_at = (message.len() - target.len()) as u8;
+        push_record(
+            &mut message,
+            &[0xC0, target_at],
+            TYPE_A,
+            60,
+            &[192, 0, 2, 1],
+        );
+        push_record(
+            &mut message,
+            &[0xC0, target_at],
+            TYPE_A,
+            120,
+            &[192, 0, 2, 2],
+        );
+        message
+    }
+
+    fn parse(message: &[u8]) -> Result<Answer, DnsError> {
+        parse_response(ID, HOST, RecordType::A, message)
+    }
+
+    #[test]
+    fn parses_addresses() {
+        let mut message = response(1);
+        push_record(&mut message, &QUESTION, TYPE_A, 30, &[192, 0, 2, 7]);
+        let answer = parse(&message).unwrap();
+        assert_eq!(answer.ipv4(), Some(Ipv4Addr::new(192, 0, 2, 7)));
+        assert_eq!(answer.ttl, 30);
+    }
+
+    #[test]
+    fn follows_cnames() {
+        let answer = parse(&cname_response()).unwrap();
+        let addrs: Vec<IpAddr> = answer.addrs.iter().copied().collect();
+        assert_eq!(addrs, [[192, 0, 2, 1], [192, 0, 2, 2]].map(IpAddr::from));
+        assert_eq!(answer.ttl, 60);
+    }
+
+    #[test]
+    fn rejects_pointer_loops() {
+        // The question pointing at itself
+        let mut message = response(0);
+        message.truncate(HEADER_LEN);
+        message.extend_from_slice(&QUESTION);
+        message.extend_from_slice(&[0, 1, 0, 1]);
+        assert_eq!(parse(&message), Err(DnsError::Malformed));
+
+        // A record name pointing at itself, then forward
+        for pointer in [0, 2] {
+            let mut message = response(1);
+            let at = message.len() as u8;
+            push_record(
+                &mut message,
+                &[0xC0, at + pointer],
+                TYPE_A,
+                30,
+                &[192, 0, 2, 7],
+            );
+            assert_eq!(parse(&message), Err(DnsError::Malformed));
+        }
+
+        // Two names pointing at each other
+        let mut message = response(2);
+        let first = message.len() as u8;
+        push_record(
+            &mut message,
+            &[0xC0, first + 16],
+            TYPE_A,
+            30,
+            &[192, 0, 2, 7],
+        );
+        push_record(
+            &mut message,
+            &[0x01, b'a', 0xC0, first],
+            TYPE_A,
+            30,
+            &[192, 0, 2, 8],
+        );
+        assert_eq!(message[first as usize + 16], 0x01);
+        assert_eq!(parse(&message), Err(DnsError::Malformed));
+    }
+
+    #[test]
+    fn rejects_names_grown_by_pointers() {
+        // Each name is a full label followed by a pointer to the previous one
+        let mut message = response(5);
+        let mut previous = QUESTION;
+        for _ in 0..5 {
+            let at = message.len() as u16;
+            let mut name = std::vec![LABEL_LEN as u8];
+            name.extend_from_slice(&[b'a'; LABEL_LEN]);
+            name.extend_from_slice(&previous);
+            push_record(&mut message, &name, TYPE_A, 30, &[192, 0, 2, 7]);
+            previous = (0xC000 | at).to_be_bytes();
+        }
+        assert_eq!(parse(&message), Err(DnsError::Malformed));
+    }
+
+    #[test]
+    fn stops_on_cname_loops() {
+        let mut message = response(2);
+        let loop_at = message.len() as u8 + 12;
+        push_record(&mut message, &QUESTION, TYPE_CNAME, 30, b"\x04loop\x00");
+        push_record(&mut message, &[0xC0, loop_at], TYPE_CNAME, 30, &QUESTION);
+        assert_eq!(parse(&message), Err(DnsError::NoAddress));
+    }
+
+    proptest! {
+        #[test]
+        fn survives_any_bytes(bytes in proptest::collection::vec(any::<u8>(), 0..UDP_LEN)) {
+            let _ = parse(&bytes);
+            let _ = parse_response(ID, HOST, RecordType::Aaaa, &bytes);
+        }
+
+        /// Past the header and question, where the records are parsed
+        #[test]
+        fn survives_any_records(
+            answers in any::<u16>(),
+            records in proptest::collection::vec(any::<u8>(), 0..UDP_LEN),
+        ) {
+            let mut message = response(answers);
+            message.extend_from_slice(&records);
+            if let Ok(answer) = parse(&message) {
+                prop_assert!(!answer.addrs.is_empty());
+            }
+        }
+
+        #[test]
+        fn survives_corrupted_answers(
+            edits in proptest::collection::vec((any::<prop::sample::Index>(), any::<u8>()), 1..8),
+        ) {
+            let mut message = cname_response();
+            for (at, byte) in edits {
+                let at = at.index(message.len());
+                message[at] = byte;
+            }
+            if let Ok(answer) = parse(&message) {
+                prop_assert!(!answer.addrs.is_empty() && answer.addrs.len() <= MAX_ADDRS);
+            }
+        }
+
+        #[test]
+        fn rejects_cut_answers(len in 0..cname_response().len()) {
+            prop_assert!(parse(&cname_response()[..len]).is_err());
+        }
+    }
+}
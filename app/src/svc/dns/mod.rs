@@ -1,2 +1,11 @@
-pub mod builder; // Declare builder.rs as a module
-pub use builder::*; // Re-export the builder module
+//! Name resolution of the Wi-Fi uplink
+//!
+//! Address queries go over UDP to the servers given by DHCP, then to
+//! `cfg::dns_cfg::FALLBACK_DNS_SERVERS`, and again over TCP when the answer is
+//! truncated. Transaction IDs and source ports are random, an answer from
+//! another server or to another ID is ignored. Answers are cached for their
+//! TTL, within bounds, so a reconnection does not resolve the broker again.
+pub mod message;
+pub mod resolver;
+
+pub use message::DnsError;
@@ -0,0 +1,239 @@
+//! Resolver of the Wi-Fi uplink, UDP with TCP fallback and a TTL cache
+use embassy_net::{
+    tcp::TcpSocket,
+    udp::{PacketMetadata, UdpSocket},
+    IpAddress, IpEndpoint, Ipv4Address, Stack,
+};
+use embassy_time::{with_timeout, Duration, Instant};
+use embedded_io_async::{Read, Write};
+use log::{info, warn};
+
+use crate::cfg::dns_cfg::{
+    DNS_ATTEMPTS, DNS_MAX_TTL, DNS_MIN_TTL, DNS_QUERY_TIMEOUT, FALLBACK_DNS_SERVERS,
+};
+use crate::util::random::Nonces;
+
+use super::message::{self, Answer, RecordType, NAME_LEN, QUERY_LEN, UDP_LEN};
+use super::DnsError;
+
+const DNS_PORT: u16 = 53;
+/// Longest answer over TCP
+const TCP_LEN: usize = 1024;
+/// DHCP servers, up to 3, then the configured ones
+const MAX_SERVERS: usize = 6;
+const CACHE_LEN: usize = 4;
+/// Source ports are picked above it
+const EPHEMERAL_PORTS: u16 = 49152;
+
+struct Entry {
+    host: heapless::String<NAME_LEN>,
+    addr: Ipv4Address,
+    expires: Instant,
+}
+
+pub struct Resolver {
+    cache: heapless::Vec<Entry, CACHE_LEN>,
+    /// Transaction IDs and source ports
+    nonces: Nonces,
+}
+
+impl Resolver {
+    pub fn new(seed: [u8; 32]) -> Self {
+        Self {
+            cache: heapless::Vec::new(),
+            nonces: Nonces::new(seed),
+        }
+    }
+
+    /// IPv4 address of `host`, an address literal as is
+    pub async fn resolve(&mut self, stack: Stack<'_>, host: &str) -> Result<Ipv4Address, DnsError> {
+        if let Ok(addr) = host.parse() {
+            return Ok(addr);
+        }
+        if let Some(addr) = self.cached(host) {
+            return Ok(addr);
+        }
+        let servers = servers(stack);
+        let mut error = DnsError::NoServer;
+        for _ in 0..DNS_ATTEMPTS {
+            for server in &servers {
+                match self.ask(stack, *server, host).await {
+                    Ok((addr, ttl)) => {
+                        info!("Resolved {host} to {addr} with {server}, TTL {ttl} s");
+                        self.store(host, addr, ttl);
+                        return Ok(addr);
+                    }
+                    // The name does not exist, no other server will say otherwise
+                    Err(e @ (DnsError::InvalidName | DnsError::NameError)) => return Err(e),
+                    Err(e) => {
+                        warn!("DNS query for {host} to {server} failed: {e:?}");
+                        error = e;
+                    }
+                }
+            }
+        }
+        Err(error)
+    }
+
+    /// Drop the cached address of `host`, after a connection to it failed
+    pub fn forget(&mut self, host: &str) {
+        self.cache.retain(|entry| entry.host != host);
+    }
+
+    fn cached(&mut self, host: &str) -> Option<Ipv4Address> {
+        let now = Instant::now();
+        self.cache.retain(|entry| entry.expires > now);
+        self.cache
+            .iter()
+            .find(|entry| entry.host == host)
+            .map(|entry| entry.addr)
+    }
+
+    fn store(&mut self, host: &str, addr: Ipv4Address, ttl: u32) {
+        let Ok(host) = heapless::String::try_from(host) else {
+            return;
+        };
+        let ttl = Duration::from_secs(ttl as u64).clamp(DNS_MIN_TTL, DNS_MAX_TTL);
+        self.forget(&host);
+        if self.cache.is_full() {
+            // The one expiring first
+            if let Some(soonest) = (0..self.cache.len()).min_by_key(|i| self.cache[*i].expires) {
+                self.cache.swap_remove(soonest);
+            }
+        }
+        let _ = self.cache.push(Entry {
+            host,
+            addr,
+            expires: Instant::now() + ttl,
+        });
+    }
+
+    /// Address and TTL from one query to `server`, over TCP too when the UDP
+    /// answer is truncated
+    async fn ask(
+        &mut self,
+        stack: Stack<'_>,
+        server: Ipv4Address,
+        host: &str,
+    ) -> Result<(Ipv4Address, u32), DnsError> {
+        let random = self.nonces.next();
+        let id = u16::from_be_bytes([random[0], random[1]]);
+        let port = EPHEMERAL_PORTS + u16::from_be_bytes([random[2], random[3]]) % 16384;
+        let query = message::query(id, host, RecordType::A)?;
+        let server = IpEndpoint::new(IpAddress::Ipv4(server), DNS_PORT);
+        let udp = with_timeout(
+            DNS_QUERY_TIMEOUT,
+            ask_udp(stack, server, port, id, host, &query),
+        );
+        let answer = match udp.await {
+            Ok(Err(DnsError::Truncated)) => {
+                with_timeout(DNS_QUERY_TIMEOUT, ask_tcp(stack, server, id, host, &query))
+                    .await
+                    .unwrap_or(Err(DnsError::Timeout))
+            }
+            Ok(result) => result,
+            Err(_) => Err(DnsError::Timeout),
+        }?;
+        // An AAAA record at the end of the chain is no use over IPv4
+        let addr = answer.ipv4().ok_or(DnsError::NoAddress)?;
+        Ok((Ipv4Address::from(addr.octets()), answer.ttl))
+    }
+}
+
+/// DHCP servers first, then the configured ones
+fn servers(stack: Stack<'_>) -> heapless::Vec<Ipv4Address, MAX_SERVERS> {
+    let mut servers = heapless::Vec::new();
+    if let Some(config) = stack.config_v4() {
+        let _ = servers.extend_from_slice(&config.dns_servers);
+    }
+    for server in FALLBACK_DNS_SERVERS
+        .iter()
+        .map(|addr| Ipv4Address::from(*addr))
+    {
+        if !servers.contains(&server) {
+            let _ = servers.push(server);
+        }
+    }
+    servers
+}
+
+async fn ask_udp(
+    stack: Stack<'_>,
+    server: IpEndpoint,
+    port: u16,
+    id: u16,
+    host: &str,
+    query: &[u8],
+) -> Result<Answer, DnsError> {
+    let mut rx_meta = [PacketMetadata::EMPTY; 2];
+    let mut rx_buffer = [0; UDP_LEN];
+    let mut tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut tx_buffer = [0; QUERY_LEN];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    socket.bind(port).map_err(|_| DnsError::Network)?;
+    socket
+        .send_to(query, server)
+        .await
+        .map_err(|_| DnsError::Network)?;
+    let mut response = [0; UDP_LEN];
+    loop {
+        let (len, meta) = socket
+            .recv_from(&mut response)
+            .await
+            .map_err(|_| DnsError::Network)?;
+        // Anything else is stray or spoofed, keep waiting for the answer
+        if meta.endpoint != server {
+            continue;
+        }
+        match message::parse_response(id, host, RecordType::A, &response[..len]) {
+            Err(DnsError::Unrelated) => continue,
+            result => return result,
+        }
+    }
+}
+
+/// Same query with the 2-byte length prefix of DNS over TCP
+async fn ask_tcp(
+    stack: Stack<'_>,
+    server: IpEndpoint,
+    id: u16,
+    host: &str,
+    query: &[u8],
+) -> Result<Answer, DnsError> {
+    let mut rx_buffer = [0; TCP_LEN];
+    let mut tx_buffer = [0; QUERY_LEN + 2];
+    let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+    socket
+        .connect(server)
+        .await
+        .map_err(|_| DnsError::Network)?;
+    socket
+        .write_all(&(query.len() as u16).to_be_bytes())
+        .await
+        .map_err(|_| DnsError::Network)?;
+    socket
+        .write_all(query)
+        .await
+        .map_err(|_| DnsError::Network)?;
+    let mut len = [0; 2];
+    socket
+        .read_exact(&mut len)
+        .await
+        .map_err(|_| DnsError::Network)?;
+    let mut response = [0; TCP_LEN];
+    let response = response
+        .get_mut(..u16::from_be_bytes(len) as usize)
+        .ok_or(DnsError::TooLarge)?;
+    socket
+        .read_exact(response)
+        .await
+        .map_err(|_| DnsError::Network)?;
+    socket.close();
+    message::parse_response(id, host, RecordType::A, response)
+}
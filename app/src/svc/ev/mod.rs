@@ -0,0 +1,279 @@
+//! EV battery and charging session analytics
+//!
+//! [`Battery`] keeps the last BMS values decoded from the bus, [`ChargeTracker`]
+//! turns the periodic snapshots into charging sessions: start and stop SoC,
+//! energy delivered, peak power, duration, location and a per-minute charge curve.
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::{raw::NoopRawMutex, Mutex};
+use embassy_time::{Duration, Instant};
+
+use crate::cfg::vehicle_cfg::*;
+use crate::svc::can::signal::SignalDef;
+use crate::svc::can::CanFrame;
+use crate::svc::vehicle::Position;
+
+pub type BatteryCell = Mutex<NoopRawMutex, RefCell<Battery>>;
+
+/// BMS values older than that are not used
+const BATTERY_MAX_AGE: Duration = Duration::from_secs(5);
+/// Pack current above which the pack is considered charging
+const CHARGE_MIN_CURRENT_A: f32 = 1.0;
+/// How long charging has to be seen before a session starts
+const START_DEBOUNCE: Duration = Duration::from_secs(10);
+/// How long charging has to stop before the session is closed
+const STOP_DEBOUNCE: Duration = Duration::from_secs(30);
+/// Interval between two points of the charge curve
+const CURVE_INTERVAL: Duration = Duration::from_secs(60);
+/// Longest gap integrated at once, longer gaps are missing BMS data
+const MAX_INTEGRATION_STEP: Duration = Duration::from_secs(5);
+
+/// Last decoded BMS values, `None` for the signals not configured or not seen yet
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct BatterySnapshot {
+    pub soc: Option<f32>,
+    pub pack_voltage: Option<f32>,
+    pub pack_current: Option<f32>,
+    pub cell_temp_min: Option<f32>,
+    pub cell_temp_max: Option<f32>,
+    pub cell_voltage_min: Option<f32>,
+    pub cell_voltage_max: Option<f32>,
+}
+
+impl BatterySnapshot {
+    /// Charging power in kW, negative while discharging
+    pub fn power_kw(&self) -> Option<f32> {
+        Some(self.pack_voltage? * self.pack_current? / 1000.0)
+    }
+}
+
+/// BMS values fed by the CAN receiver
+pub struct Battery {
+    snapshot: BatterySnapshot,
+    updated_at: Option<Instant>,
+}
+
+impl Default for Battery {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Battery {
+    pub const fn new() -> Self {
+        Self {
+            snapshot: BatterySnapshot {
+                soc: None,
+                pack_voltage: None,
+                pack_current: None,
+                cell_temp_min: None,
+                cell_temp_max: None,
+                cell_voltage_min: None,
+                cell_voltage_max: None,
+            },
+            updated_at: None,
+        }
+    }
+
+    /// Decode the BMS signals carried by `frame`
+    pub fn on_frame(&mut self, frame: &CanFrame, now: Instant) {
+        let mut updated = false;
+        let mut decode = |signal: &Option<SignalDef>, value: &mut Option<f32>| {
+            if let Some(decoded) = signal.as_ref().and_then(|s| s.decode(frame)) {
+                *value = Some(decoded);
+                updated = true;
+            }
+        };
+        let s = &mut self.snapshot;
+        decode(&BMS_SOC_SIGNAL, &mut s.soc);
+        decode(&BMS_PACK_VOLTAGE_SIGNAL, &mut s.pack_voltage);
+        decode(&BMS_PACK_CURRENT_SIGNAL, &mut s.pack_current);
+        decode(&BMS_CELL_TEMP_MIN_SIGNAL, &mut s.cell_temp_min);
+        decode(&BMS_CELL_TEMP_MAX_SIGNAL, &mut s.cell_temp_max);
+        decode(&BMS_CELL_VOLTAGE_MIN_SIGNAL, &mut s.cell_voltage_min);
+        decode(&BMS_CELL_VOLTAGE_MAX_SIGNAL, &mut s.cell_voltage_max);
+        if updated {
+            self.updated_at = Some(now);
+        }
+    }
+
+    /// Current values, `None` when the BMS has been silent for too long
+    pub fn snapshot(&self, now: Instant) -> Option<BatterySnapshot> {
+        self.updated_at
+            .filter(|at| now.saturating_duration_since(*at) <= BATTERY_MAX_AGE)
+            .map(|_| self.snapshot)
+    }
+}
+
+/// Summary of a finished charging session
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChargeSummary {
+    pub session: u32,
+    pub start_soc: Option<f32>,
+    pub end_soc: Option<f32>,
+    pub energy_kwh: f32,
+    pub peak_power_kw: f32,
+    pub duration: Duration,
+    pub position: Option<Position>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChargeEvent {
+    Started {
+        session: u32,
+        soc: Option<f32>,
+        position: Option<Position>,
+    },
+    /// One point of the charge curve
+    Curve {
+        session: u32,
+        minute: u32,
+        battery: BatterySnapshot,
+    },
+    Finished(ChargeSummary),
+}
+
+struct ChargeSession {
+    id: u32,
+    started_at: Instant,
+    last_charging_at: Instant,
+    last_update: Instant,
+    last_curve: Instant,
+    minute: u32,
+    start_soc: Option<f32>,
+    end_soc: Option<f32>,
+    energy_wh: f32,
+    peak_power_kw: f32,
+    position: Option<Position>,
+}
+
+/// Charging session detection, updated periodically with the battery snapshot
+pub struct ChargeTracker {
+    next_id: u32,
+    charging_since: Option<Instant>,
+    idle_since: Option<Instant>,
+    session: Option<ChargeSession>,
+}
+
+impl Default for ChargeTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ChargeTracker {
+    pub const fn new() -> Self {
+        Self {
+            next_id: 1,
+            charging_since: None,
+            idle_since: None,
+            session: None,
+        }
+    }
+
+    /// Feed the latest snapshot
+    ///
+    /// `charging_hint` is the charging state reported by the vehicle, the pack
+    /// current alone is enough to detect charging when it is not available.
+    pub fn update(
+        &mut self,
+        now: Instant,
+        battery: Option<BatterySnapshot>,
+        charging_hint: bool,
+        position: Option<Position>,
+    ) -> Option<ChargeEvent> {
+        let charging = charging_hint
+            || battery
+                .and_then(|b| b.pack_current)
+                .is_some_and(|current| current >= CHARGE_MIN_CURRENT_A);
+
+        let Some(session) = self.session.as_mut() else {
+            if !charging {
+                self.charging_since = None;
+                return None;
+            }
+            let since = *self.charging_since.get_or_insert(now);
+            if now.saturating_duration_since(since) < START_DEBOUNCE {
+                return None;
+            }
+            let soc = battery.and_then(|b| b.soc);
+            let id = self.next_id;
+            self.next_id = self.next_id.wrapping_add(1);
+            self.charging_since = None;
+            self.idle_since = None;
+            self.session = Some(ChargeSession {
+                id,
+                started_at: since,
+                last_charging_at: now,
+                last_update: now,
+                last_curve: now,
+                minute: 0,
+                start_soc: soc,
+                end_soc: soc,
+                energy_wh: 0.0,
+                peak_power_kw: 0.0,
+                position,
+            });
+            return Some(ChargeEvent::Started {
+                session: id,
+                soc,
+                position,
+            });
+        };
+
+        let step = now
+            .saturating_duration_since(session.last_update)
+            .min(MAX_INTEGRATION_STEP);
+        session.last_update = now;
+        if let Some(battery) = battery {
+            if let Some(power_kw) = battery.power_kw() {
+                if power_kw > 0.0 {
+                    session.energy_wh += power_kw * 1000.0 * step.as_millis() as f32 / 3_600_000.0;
+                }
+                session.peak_power_kw = session.peak_power_kw.max(power_kw);
+            }
+            if battery.soc.is_some() {
+                session.end_soc = battery.soc;
+            }
+        }
+        if session.position.is_none() {
+            session.position = position;
+        }
+
+        if charging {
+            self.idle_since = None;
+            session.last_charging_at = now;
+        } else {
+            let since = *self.idle_since.get_or_insert(now);
+            if now.saturating_duration_since(since) >= STOP_DEBOUNCE {
+                let summary = ChargeSummary {
+                    session: session.id,
+                    start_soc: session.start_soc,
+                    end_soc: session.end_soc,
+                    energy_kwh: session.energy_wh / 1000.0,
+                    peak_power_kw: session.peak_power_kw,
+                    duration: session
+                        .last_charging_at
+                        .saturating_duration_since(session.started_at),
+                    position: session.position,
+                };
+                self.session = None;
+                self.idle_since = None;
+                return Some(ChargeEvent::Finished(summary));
+            }
+        }
+
+        if now.saturating_duration_since(session.last_curve) >= CURVE_INTERVAL {
+            session.last_curve = now;
+            session.minute += 1;
+            if let Some(battery) = battery {
+                return Some(ChargeEvent::Curve {
+                    session: session.id,
+                    minute: session.minute,
+                    battery,
+                });
+            }
+        }
+        None
+    }
+}
@@ -4,12 +4,22 @@ use esp_storage::FlashStorage;
 #[derive(Copy, Clone)]
 #[allow(dead_code)]
 pub enum BlockId {
+    /// CA chain as a `svc::credentials::store` record
     CrtPemId = 0x0,
+    /// Factory device certificate as a `svc::credentials::store` record
     DvtCrtId = 0x1,
+    /// Factory private key as a `svc::credentials::store` record
     DvtKeyId = 0x2,
-    BlockNum = 0x3,
+    /// Applied device shadow, see `svc::shadow`
+    ShadowId = 0x3,
+    /// Provisioned device certificate and key, see `svc::provision`
+    IdentityId = 0x4,
+    /// Credentials of a rotation, see `svc::credentials::staging`
+    StagingId = 0x5,
+    BlockNum = 0x6,
 }
 
+#[derive(Debug)]
 pub enum NvsError {
     IdInvalid,
     LenInvalid,
@@ -24,8 +34,8 @@ struct NvmConf {
 }
 
 #[allow(dead_code)]
-struct Nvm {
-    blocks: [NvmConf; 0x03],
+pub struct Nvm {
+    blocks: [NvmConf; 0x06],
     storage: FlashStorage,
 }
 
@@ -36,15 +46,28 @@ impl Nvm {
             blocks: [
                 NvmConf {
                     addr: 0x9000,
-                    size: 2574,
+                    size: 0x1000,
                 },
                 NvmConf {
                     addr: 0xA000,
-                    size: 1268,
+                    size: 0x1000,
                 },
                 NvmConf {
                     addr: 0xB000,
-                    size: 1678,
+                    size: 0x1000,
+                },
+                NvmConf {
+                    addr: 0xC000,
+                    size: crate::svc::shadow::RECORD_LEN,
+                },
+                // `key_data` partition
+                NvmConf {
+                    addr: 0x610000,
+                    size: crate::svc::provision::identity::IDENTITY_LEN,
+                },
+                NvmConf {
+                    addr: 0x611000,
+                    size: crate::svc::credentials::staging::STAGING_LEN,
                 },
             ],
             storage: FlashStorage::new(),
@@ -68,6 +91,34 @@ impl Nvm {
         }
     }
 
+    /// Write `buf` at `offset` into the block, the rest of it is kept
+    pub fn nvs_write_at(&mut self, id: BlockId, offset: usize, buf: &[u8]) -> Result<(), NvsError> {
+        let addr = self.span(id, offset, buf.len())?;
+        self.storage
+            .write(addr, buf)
+            .map_err(|_| NvsError::WriteErr)
+    }
+
+    /// Read `buf.len()` bytes at `offset` of the block
+    pub fn nvs_read_at(
+        &mut self,
+        id: BlockId,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> Result<(), NvsError> {
+        let addr = self.span(id, offset, buf.len())?;
+        self.storage.read(addr, buf).map_err(|_| NvsError::ReadErr)
+    }
+
+    /// Flash address of `len` bytes at `offset` of the block
+    fn span(&self, id: BlockId, offset: usize, len: usize) -> Result<u32, NvsError> {
+        let block = self.blocks.get(id as usize).ok_or(NvsError::IdInvalid)?;
+        if offset + len > block.size {
+            return Err(NvsError::LenInvalid);
+        }
+        Ok(block.addr + offset as u32)
+    }
+
     pub fn nvs_read(&mut self, id: BlockId, buf: &mut [u8]) -> Result<(), NvsError> {
         if id as usize >= self.blocks.len() {
             return Err(NvsError::IdInvalid);
@@ -1,6 +1,24 @@
 // svc/mod.rs
 pub mod atcmd;
+pub mod can;
+pub mod canlink;
+pub mod clock;
+pub mod cloud;
+pub mod config;
+pub mod credentials;
 pub mod dns;
+pub mod ev;
 pub mod mem;
 //pub mod mender;
 pub mod mqtt;
+pub mod payload;
+pub mod presence;
+pub mod provision;
+pub mod rpc;
+pub mod shadow;
+pub mod sniff;
+pub mod sparkplug;
+pub mod supervisor;
+pub mod topic;
+pub mod uplink;
+pub mod vehicle;
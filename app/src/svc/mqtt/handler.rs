@@ -0,0 +1,221 @@
+//! Dispatch of inbound messages to the handlers registered per topic filter
+use super::packet::InboundPublish;
+
+/// Longest topic filter a handler can be registered for
+pub const MAX_FILTER_LEN: usize = 128;
+/// Number of handlers the registry holds
+pub const MAX_HANDLERS: usize = 8;
+
+/// Receives the messages published on the topics matching its filter
+pub trait MessageHandler {
+    fn on_message(&self, topic: &str, payload: &[u8]);
+
+    /// Whole message, for the handlers that need its MQTT 5 properties
+    /// (response topic, correlation data, user properties)
+    fn on_publish(&self, publish: &InboundPublish<'_>) {
+        self.on_message(publish.topic, publish.payload);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RegistryError {
+    InvalidFilter,
+    FilterTooLong,
+    Full,
+}
+
+/// Whether `filter` is a valid topic filter: `#` only as the last level, `+` and
+/// `#` only as whole levels
+pub fn is_valid_filter(filter: &str) -> bool {
+    if filter.is_empty() {
+        return false;
+    }
+    let mut levels = filter.split('/').peekable();
+    while let Some(level) = levels.next() {
+        match level {
+            "#" if levels.peek().is_some() => return false,
+            "#" | "+" => {}
+            _ if level.contains(['#', '+']) => return false,
+            _ => {}
+        }
+    }
+    true
+}
+
+/// Whether `topic` matches `filter`, with the `+` (one level) and `#` (any number
+/// of levels, parent included) wildcards
+///
+/// Topics starting with `$` are not matched by a leading wildcard.
+pub fn matches(filter: &str, topic: &str) -> bool {
+    if topic.starts_with('$') && filter.starts_with(['+', '#']) {
+        return false;
+    }
+    let mut filter_levels = filter.split('/');
+    let mut topic_levels = topic.split('/');
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => {}
+            (Some(f), Some(t)) if f == t => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+/// Handlers keyed by topic filter
+pub struct HandlerRegistry<'a> {
+    entries:
+        heapless::Vec<(heapless::String<MAX_FILTER_LEN>, &'a dyn MessageHandler), MAX_HANDLERS>,
+}
+
+impl Default for HandlerRegistry<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> HandlerRegistry<'a> {
+    pub const fn new() -> Self {
+        Self {
+            entries: heapless::Vec::new(),
+        }
+    }
+
+    /// Register `handler` for `filter`, replacing the handler already registered for it
+    pub fn register(
+        &mut self,
+        filter: &str,
+        handler: &'a dyn MessageHandler,
+    ) -> Result<(), RegistryError> {
+        if !is_valid_filter(filter) {
+            return Err(RegistryError::InvalidFilter);
+        }
+        if let Some(entry) = self.entries.iter_mut().find(|(f, _)| f == filter) {
+            entry.1 = handler;
+            return Ok(());
+        }
+        let mut key = heapless::String::new();
+        key.push_str(filter)
+            .map_err(|_| RegistryError::FilterTooLong)?;
+        self.entries
+            .push((key, handler))
+            .map_err(|_| RegistryError::Full)
+    }
+
+    /// Remove the handler of `filter`, returns whether one was registered
+    pub fn remove(&mut self, filter: &str) -> bool {
+        match self.entries.iter().position(|(f, _)| f == filter) {
+            Some(idx) => {
+                self.entries.swap_remove(idx);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Hand the message to every handler whose filter matches, returns how many did
+    pub fn dispatch(&self, publish: &InboundPublish<'_>) -> usize {
+        let mut count = 0;
+        for (filter, handler) in self.entries.iter() {
+            if matches(filter, publish.topic) {
+                handler.on_publish(publish);
+                count += 1;
+            }
+        }
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_topics() {
+        let cases = [
+            ("a/b", "a/b", true),
+            ("a/b", "a", false),
+            ("a/b", "a/b/c", false),
+            ("a/b", "a/b/", false),
+            // `#` matches the parent level too
+            ("a/#", "a", true),
+            ("a/#", "a/", true),
+            ("a/#", "a/b/c", true),
+            ("a/#", "ab", false),
+            ("#", "a/b", true),
+            ("+", "a", true),
+            ("+", "a/b", false),
+            ("a/+", "a", false),
+            ("a/+/c", "a/b/c", true),
+            ("a/+/c", "a/b/d", false),
+            // `+` matches an empty level
+            ("a/+", "a/", true),
+            ("+/b", "/b", true),
+            ("a/+/c", "a//c", true),
+            ("+", "/", false),
+            ("+/+", "/", true),
+            // `$` topics are left out of leading wildcards only
+            ("#", "$SYS/broker/uptime", false),
+            ("+/broker/uptime", "$SYS/broker/uptime", false),
+            ("$SYS/#", "$SYS/broker/uptime", true),
+            ("$SYS/+/uptime", "$SYS/broker/uptime", true),
+            ("a/#", "a/$b", true),
+        ];
+        for (filter, topic, expected) in cases {
+            assert_eq!(matches(filter, topic), expected, "{filter} / {topic}");
+        }
+    }
+
+    #[test]
+    fn validates_filters() {
+        let cases = [
+            ("a/b", true),
+            ("#", true),
+            ("+", true),
+            ("a/#", true),
+            ("+/+/#", true),
+            ("/", true),
+            ("a//b", true),
+            ("$SYS/#", true),
+            ("", false),
+            ("a/#/b", false),
+            ("#/", false),
+            ("a#", false),
+            ("a/b#", false),
+            ("a+", false),
+            ("a/+b/c", false),
+            ("++", false),
+        ];
+        for (filter, expected) in cases {
+            assert_eq!(is_valid_filter(filter), expected, "{filter}");
+        }
+    }
+
+    #[test]
+    fn registry_rejects_invalid_and_too_many_filters() {
+        struct Nop;
+        impl MessageHandler for Nop {
+            fn on_message(&self, _: &str, _: &[u8]) {}
+        }
+        let mut registry = HandlerRegistry::new();
+        assert_eq!(
+            registry.register("a/#/b", &Nop),
+            Err(RegistryError::InvalidFilter)
+        );
+        let long = "a".repeat(MAX_FILTER_LEN + 1);
+        assert_eq!(
+            registry.register(&long, &Nop),
+            Err(RegistryError::FilterTooLong)
+        );
+        for idx in 0..MAX_HANDLERS {
+            registry.register(&std::format!("f{idx}"), &Nop).unwrap();
+        }
+        // Replacing a handler takes no slot
+        assert_eq!(registry.register("f0", &Nop), Ok(()));
+        assert_eq!(registry.register("g", &Nop), Err(RegistryError::Full));
+        assert!(registry.remove("f0"));
+        assert!(!registry.remove("f0"));
+        assert_eq!(registry.register("g", &Nop), Ok(()));
+    }
+}
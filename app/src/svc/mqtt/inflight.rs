@@ -0,0 +1,217 @@
+//! Packet identifiers and QoS 1/2 state in both directions
+//!
+//! The window outlives a connection so unacknowledged messages are sent again,
+//! flagged DUP, once the client is connected back. With MQTT 3.1.1 they are
+//! also sent again on the live connection when not acknowledged in time.
+use embassy_time::{Duration, Instant};
+
+/// Outbound QoS 1/2 messages waiting for their acknowledgement
+pub const INFLIGHT_WINDOW: usize = 4;
+/// Inbound QoS 2 messages waiting for their PUBREL
+const INBOUND_QOS2_WINDOW: usize = 8;
+/// Longest topic kept for retransmission
+pub const MAX_INFLIGHT_TOPIC_LEN: usize = 128;
+/// Longest payload kept for retransmission
+pub const MAX_INFLIGHT_PAYLOAD_LEN: usize = 1024;
+/// Longest encoded MQTT 5 property list kept for retransmission
+pub const MAX_INFLIGHT_PROPERTIES_LEN: usize = 256;
+
+/// Acknowledgement an outbound message is waiting for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stage {
+    /// QoS 1 PUBLISH sent
+    AwaitPuback,
+    /// QoS 2 PUBLISH sent
+    AwaitPubrec,
+    /// QoS 2 PUBREL sent
+    AwaitPubcomp,
+}
+
+pub struct OutboundMessage {
+    pub pid: u16,
+    pub qos: u8,
+    pub retain: bool,
+    pub stage: Stage,
+    pub topic: heapless::String<MAX_INFLIGHT_TOPIC_LEN>,
+    /// Encoded MQTT 5 properties, topic alias excluded
+    pub properties: heapless::Vec<u8, MAX_INFLIGHT_PROPERTIES_LEN>,
+    pub payload: heapless::Vec<u8, MAX_INFLIGHT_PAYLOAD_LEN>,
+    pub sent_at: Instant,
+    pub attempts: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InFlightError {
+    /// No slot left in the window
+    Full,
+    /// Topic or payload too long to be kept for retransmission
+    TooLarge,
+}
+
+pub struct InFlightWindow {
+    outbound: heapless::Vec<OutboundMessage, INFLIGHT_WINDOW>,
+    inbound_qos2: heapless::Vec<u16, INBOUND_QOS2_WINDOW>,
+    next_pid: u16,
+}
+
+impl Default for InFlightWindow {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InFlightWindow {
+    pub const fn new() -> Self {
+        Self {
+            outbound: heapless::Vec::new(),
+            inbound_qos2: heapless::Vec::new(),
+            next_pid: 1,
+        }
+    }
+
+    /// Next packet identifier not used by an outbound message in flight
+    pub fn allocate_pid(&mut self) -> u16 {
+        loop {
+            let pid = self.next_pid;
+            self.next_pid = self.next_pid.checked_add(1).unwrap_or(1);
+            if !self.is_pending(pid) {
+                return pid;
+            }
+        }
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.outbound.is_full()
+    }
+
+    /// Whether the message sent with `pid` is still waiting for an acknowledgement
+    pub fn is_pending(&self, pid: u16) -> bool {
+        self.outbound.iter().any(|m| m.pid == pid)
+    }
+
+    /// Track a QoS 1/2 message that has just been sent
+    pub fn insert(
+        &mut self,
+        pid: u16,
+        qos: u8,
+        retain: bool,
+        topic: &str,
+        properties: &[u8],
+        payload: &[u8],
+    ) -> Result<(), InFlightError> {
+        if self.outbound.is_full() {
+            return Err(InFlightError::Full);
+        }
+        let mut msg = OutboundMessage {
+            pid,
+            qos,
+            retain,
+            stage: if qos == 2 {
+                Stage::AwaitPubrec
+            } else {
+                Stage::AwaitPuback
+            },
+            topic: heapless::String::new(),
+            properties: heapless::Vec::new(),
+            payload: heapless::Vec::new(),
+            sent_at: Instant::now(),
+            attempts: 1,
+        };
+        msg.topic
+            .push_str(topic)
+            .map_err(|_| InFlightError::TooLarge)?;
+        msg.properties
+            .extend_from_slice(properties)
+            .map_err(|_| InFlightError::TooLarge)?;
+        msg.payload
+            .extend_from_slice(payload)
+            .map_err(|_| InFlightError::TooLarge)?;
+        self.outbound.push(msg).map_err(|_| InFlightError::Full)
+    }
+
+    /// PUBACK received, returns whether it completed a QoS 1 message
+    pub fn on_puback(&mut self, pid: u16) -> bool {
+        self.complete(pid, Stage::AwaitPuback)
+    }
+
+    /// PUBREC received, returns whether a PUBREL has to be sent
+    pub fn on_pubrec(&mut self, pid: u16) -> bool {
+        match self.outbound.iter_mut().find(|m| m.pid == pid) {
+            Some(msg) if msg.stage != Stage::AwaitPuback => {
+                msg.stage = Stage::AwaitPubcomp;
+                msg.sent_at = Instant::now();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// PUBACK or PUBREC with a failure reason code (MQTT 5), the broker won't take
+    /// the message, returns whether it was in flight
+    pub fn on_rejected(&mut self, pid: u16) -> bool {
+        self.remove(pid)
+    }
+
+    /// Forget the message sent with `pid`, returns whether it was in flight
+    pub fn remove(&mut self, pid: u16) -> bool {
+        match self.outbound.iter().position(|m| m.pid == pid) {
+            Some(idx) => {
+                self.outbound.remove(idx);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// PUBCOMP received, returns whether it completed a QoS 2 message
+    pub fn on_pubcomp(&mut self, pid: u16) -> bool {
+        self.complete(pid, Stage::AwaitPubcomp)
+    }
+
+    /// Inbound QoS 2 PUBLISH, returns `false` for a duplicate already delivered
+    pub fn on_inbound_qos2(&mut self, pid: u16) -> bool {
+        if self.inbound_qos2.contains(&pid) {
+            return false;
+        }
+        if self.inbound_qos2.is_full() {
+            self.inbound_qos2.remove(0);
+        }
+        let _ = self.inbound_qos2.push(pid);
+        true
+    }
+
+    /// Inbound PUBREL, the broker won't send the message with `pid` again
+    pub fn on_inbound_pubrel(&mut self, pid: u16) {
+        self.inbound_qos2.retain(|p| *p != pid);
+    }
+
+    /// Messages waiting longer than `timeout`, oldest first
+    pub fn expired(
+        &mut self,
+        now: Instant,
+        timeout: Duration,
+    ) -> impl Iterator<Item = &mut OutboundMessage> {
+        self.outbound
+            .iter_mut()
+            .filter(move |m| now.saturating_duration_since(m.sent_at) >= timeout)
+    }
+
+    /// Every message in flight, to be sent again after a reconnection
+    pub fn pending(&mut self) -> impl Iterator<Item = &mut OutboundMessage> {
+        self.outbound.iter_mut()
+    }
+
+    fn complete(&mut self, pid: u16, stage: Stage) -> bool {
+        match self
+            .outbound
+            .iter()
+            .position(|m| m.pid == pid && m.stage == stage)
+        {
+            Some(idx) => {
+                self.outbound.remove(idx);
+                true
+            }
+            None => false,
+        }
+    }
+}
@@ -1,139 +1,1558 @@
 #![allow(clippy::uninlined_format_args)]
-use embassy_net::{tcp::TcpSocket, IpEndpoint};
-use embassy_time::Instant;
-use esp_mbedtls::asynch::Session;
-use log::{error, info, warn};
+pub mod handler;
+pub mod inflight;
+pub mod packet;
+pub mod properties;
+#[cfg(test)]
+pub(crate) mod script;
+
+use embassy_time::{with_timeout, Duration, Instant};
+use embedded_io_async::{Read, Write};
+use log::{debug, error, info, warn};
 use mqttrust::{
-    encoding::v4::{encode_slice, Connect, Protocol},
-    MqttError, Packet, Publish, QoS,
+    encoding::v4::{encode_slice, Connect, LastWill, Protocol},
+    Packet, QoS,
+};
+
+use handler::{HandlerRegistry, MessageHandler, RegistryError};
+use inflight::{
+    InFlightError, InFlightWindow, OutboundMessage, Stage, MAX_INFLIGHT_PROPERTIES_LEN,
+    MAX_INFLIGHT_TOPIC_LEN,
 };
+use packet::{
+    Ack, ConnectV5, Inbound, PacketError, ProtocolVersion, PublishFlags, Will, REASON_FAILURE,
+    SUBACK_FAILURE,
+};
+use properties::{Properties, Property};
+
+/// Size of the reassembly buffer, inbound packets longer than that are dropped
+///
+/// The certificate answered by fleet provisioning, see `svc::provision`, takes
+/// close to 2 KiB alone.
+const RECV_BUFFER_LEN: usize = 3072;
+/// Size of the encoding buffer, bounds the size of a published message
+const SEND_BUFFER_LEN: usize = 4096;
+/// How long to wait for the CONNACK, SUBACK or UNSUBACK of a request
+const ACK_TIMEOUT: Duration = Duration::from_secs(10);
+/// QoS 1/2 messages not acknowledged within that delay are sent again (MQTT 3.1.1)
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+/// Topic aliases used per connection (MQTT 5), fewer when the broker allows less
+const MAX_TOPIC_ALIASES: usize = 8;
+/// Properties of a PUBLISH, one is kept for the topic alias
+const MAX_PUBLISH_PROPERTIES: usize = 10;
+
+type PublishProperties<'o> = heapless::Vec<Property<'o>, MAX_PUBLISH_PROPERTIES>;
+
+/// Reason the broker refused the connection, from the CONNACK return code
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConnectRefused {
+    UnacceptableProtocolVersion,
+    IdentifierRejected,
+    ServerUnavailable,
+    BadCredentials,
+    NotAuthorized,
+    /// Code defined by neither MQTT 3.1.1 nor MQTT 5
+    Unknown(u8),
+}
+
+impl ConnectRefused {
+    /// `None` for an accepted connection
+    ///
+    /// Takes the MQTT 3.1.1 return codes as well as the MQTT 5 reason codes, a
+    /// 3.1.1 broker answers an MQTT 5 CONNECT with a 3.1.1 CONNACK.
+    pub fn from_return_code(code: u8) -> Option<Self> {
+        match code {
+            0 => None,
+            1 | 0x84 => Some(Self::UnacceptableProtocolVersion),
+            2 | 0x85 => Some(Self::IdentifierRejected),
+            3 | 0x88 | 0x89 => Some(Self::ServerUnavailable),
+            4 | 0x86 => Some(Self::BadCredentials),
+            5 | 0x87 => Some(Self::NotAuthorized),
+            other => Some(Self::Unknown(other)),
+        }
+    }
+}
+
+/// MQTT 5 reason code of a refused request or of a DISCONNECT from the broker
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReasonCode {
+    NormalDisconnection,
+    UnspecifiedError,
+    MalformedPacket,
+    ProtocolError,
+    ImplementationSpecificError,
+    NotAuthorized,
+    ServerBusy,
+    ServerShuttingDown,
+    KeepAliveTimeout,
+    SessionTakenOver,
+    TopicFilterInvalid,
+    TopicNameInvalid,
+    PacketIdentifierInUse,
+    TopicAliasInvalid,
+    PacketTooLarge,
+    QuotaExceeded,
+    PayloadFormatInvalid,
+    Other(u8),
+}
+
+impl ReasonCode {
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            0x00 => Self::NormalDisconnection,
+            0x80 => Self::UnspecifiedError,
+            0x81 => Self::MalformedPacket,
+            0x82 => Self::ProtocolError,
+            0x83 => Self::ImplementationSpecificError,
+            0x87 => Self::NotAuthorized,
+            0x89 => Self::ServerBusy,
+            0x8B => Self::ServerShuttingDown,
+            0x8D => Self::KeepAliveTimeout,
+            0x8E => Self::SessionTakenOver,
+            0x8F => Self::TopicFilterInvalid,
+            0x90 => Self::TopicNameInvalid,
+            0x91 => Self::PacketIdentifierInUse,
+            0x94 => Self::TopicAliasInvalid,
+            0x95 => Self::PacketTooLarge,
+            0x97 => Self::QuotaExceeded,
+            0x99 => Self::PayloadFormatInvalid,
+            other => Self::Other(other),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MqttClientError {
+    /// Read or write on the transport failed
+    Io,
+    /// Connection closed by the broker
+    ConnectionClosed,
+    /// Not connected, or the connection was declared dead
+    NotConnected,
+    /// CONNACK with a non-zero return code
+    ConnectionRefused(ConnectRefused),
+    /// No CONNACK, SUBACK, UNSUBACK or delivery acknowledgement in time
+    Timeout,
+    /// Nothing received from the broker for 1.5 times the keep-alive
+    KeepAliveTimeout,
+    /// Packet could not be encoded
+    Encoding(PacketError),
+    /// Malformed packet received
+    Decoding(PacketError),
+    /// In-flight window still full after waiting for acknowledgements
+    InFlightFull,
+    /// Topic or payload too long to be kept for retransmission
+    MessageTooLarge,
+    /// Topic filter invalid, too long, or no handler slot left
+    Registry(RegistryError),
+    /// SUBACK return code 0x80
+    SubscriptionRejected,
+    /// MQTT 5 reason code of 0x80 or above on a PUBACK, PUBREC, SUBACK or UNSUBACK
+    Rejected(ReasonCode),
+    /// DISCONNECT sent by an MQTT 5 broker
+    ServerDisconnect(ReasonCode),
+}
+
+impl MqttClientError {
+    /// The message itself cannot be published, sending it again won't help
+    pub fn is_message_error(&self) -> bool {
+        matches!(
+            self,
+            Self::MessageTooLarge | Self::Encoding(_) | Self::Rejected(_)
+        )
+    }
+}
+
+impl From<InFlightError> for MqttClientError {
+    fn from(e: InFlightError) -> Self {
+        match e {
+            InFlightError::Full => Self::InFlightFull,
+            InFlightError::TooLarge => Self::MessageTooLarge,
+        }
+    }
+}
+
+/// MQTT 5 features of a published message, ignored with MQTT 3.1.1
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PublishOptions<'o> {
+    pub retain: bool,
+    /// The broker drops the message when it could not deliver it within that delay
+    pub message_expiry_secs: Option<u32>,
+    pub content_type: Option<&'o str>,
+    /// Where the receiver should publish its response
+    pub response_topic: Option<&'o str>,
+    /// Sent back with the response to match it with the request
+    pub correlation_data: Option<&'o [u8]>,
+    pub user_properties: &'o [(&'o str, &'o str)],
+}
+
+impl<'o> PublishOptions<'o> {
+    /// `None` when there are too many user properties
+    fn properties(&self) -> Option<PublishProperties<'o>> {
+        let mut props = PublishProperties::new();
+        if let Some(secs) = self.message_expiry_secs {
+            props
+                .push(Property::U32(properties::MESSAGE_EXPIRY_INTERVAL, secs))
+                .ok()?;
+        }
+        if let Some(content_type) = self.content_type {
+            props
+                .push(Property::Str(properties::CONTENT_TYPE, content_type))
+                .ok()?;
+        }
+        if let Some(topic) = self.response_topic {
+            props
+                .push(Property::Str(properties::RESPONSE_TOPIC, topic))
+                .ok()?;
+        }
+        if let Some(data) = self.correlation_data {
+            props
+                .push(Property::Binary(properties::CORRELATION_DATA, data))
+                .ok()?;
+        }
+        for (name, value) in self.user_properties {
+            props
+                .push(Property::Pair(properties::USER_PROPERTY, name, value))
+                .ok()?;
+        }
+        // Room for the topic alias
+        (!props.is_full()).then_some(props)
+    }
+}
 
+/// Handle on a published message, resolved with [`MqttClient::wait_delivery`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Delivery {
+    /// `None` for QoS 0, delivered as soon as it is written
+    pid: Option<u16>,
+}
+
+/// MQTT client over any async byte stream: a TCP socket, a TLS session, a modem
+/// socket or an in-memory pipe
 #[allow(dead_code)]
-pub struct MqttClient<'a> {
+pub struct MqttClient<'a, T: Read + Write> {
     client_id: &'a str,
-    session: Session<'a, TcpSocket<'a>>,
+    transport: T,
     connection_state: bool,
-    recv_buffer: [u8; 512],
+    recv_buffer: [u8; RECV_BUFFER_LEN],
     recv_index: usize,
+    /// Bytes still to skip from an inbound packet too long for `recv_buffer`
+    recv_discard: usize,
     keep_alive_secs: Option<u16>,
+    protocol: ProtocolVersion,
+    clean_session: bool,
+    /// How long an MQTT 5 broker keeps the session when `clean_session` is false
+    session_expiry_secs: u32,
+    /// Topic aliases the broker accepts on this connection (MQTT 5)
+    topic_alias_max: u16,
+    /// Topics sent with an alias, the alias is the index plus one
+    topic_aliases: heapless::Vec<heapless::String<MAX_INFLIGHT_TOPIC_LEN>, MAX_TOPIC_ALIASES>,
+    /// Last delivery the broker refused, reported by [`MqttClient::wait_delivery`]
+    last_rejected: Option<(u16, ReasonCode)>,
+    last_will: Option<LastWill<'a>>,
     last_sent_millis: u64,
+    /// Last time anything was received, for the keep-alive watchdog
+    last_received_millis: u64,
+    /// PINGREQ sent, no PINGRESP yet
+    ping_outstanding: bool,
+    inflight: &'a mut InFlightWindow,
+    handlers: HandlerRegistry<'a>,
 }
+
+/// CONNACK, SUBACK or UNSUBACK seen while processing inbound packets
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RequestAck {
+    Connect {
+        session_present: bool,
+        return_code: u8,
+    },
+    Subscribe {
+        pid: u16,
+        return_code: u8,
+    },
+    Unsubscribe {
+        pid: u16,
+        reason_code: u8,
+    },
+}
+
 #[allow(dead_code)]
-impl<'a> MqttClient<'a> {
-    pub fn new(client_id: &'a str, session: Session<'a, TcpSocket<'a>>) -> Self {
+impl<'a, T: Read + Write> MqttClient<'a, T> {
+    /// `transport` must be open, TLS handshake included. `inflight` is kept by the
+    /// caller across connections, so that messages not acknowledged before a
+    /// disconnection are sent again on the next one.
+    pub fn new(client_id: &'a str, transport: T, inflight: &'a mut InFlightWindow) -> Self {
         MqttClient {
             client_id,
-            session,
+            transport,
             connection_state: false,
-            recv_buffer: [0u8; 512],
+            recv_buffer: [0u8; RECV_BUFFER_LEN],
             recv_index: 0,
+            recv_discard: 0,
             keep_alive_secs: None,
+            protocol: ProtocolVersion::V311,
+            clean_session: true,
+            session_expiry_secs: 0,
+            topic_alias_max: 0,
+            topic_aliases: heapless::Vec::new(),
+            last_rejected: None,
+            last_will: None,
             last_sent_millis: 0,
+            last_received_millis: 0,
+            ping_outstanding: false,
+            inflight,
+            handlers: HandlerRegistry::new(),
         }
     }
 
+    /// Ask the broker to keep the subscriptions and the QoS 1/2 messages across
+    /// connections, which requires a client ID stable for the device
+    pub fn set_clean_session(&mut self, clean_session: bool) {
+        self.clean_session = clean_session;
+    }
+
+    /// Protocol version of the next [`MqttClient::connect`], MQTT 3.1.1 by default
+    pub fn set_protocol(&mut self, protocol: ProtocolVersion) {
+        self.protocol = protocol;
+    }
+
+    pub fn protocol(&self) -> ProtocolVersion {
+        self.protocol
+    }
+
+    /// How long an MQTT 5 broker keeps the session after a disconnection, used
+    /// when the session is not clean. MQTT 3.1.1 brokers keep it indefinitely.
+    pub fn set_session_expiry(&mut self, secs: u32) {
+        self.session_expiry_secs = secs;
+    }
+
+    /// Message the broker publishes on `topic` if the connection drops without a
+    /// DISCONNECT, registered with the next [`MqttClient::connect`]
+    pub fn set_last_will(&mut self, topic: &'a str, message: &'a [u8], qos: QoS, retain: bool) {
+        self.last_will = Some(LastWill {
+            topic,
+            message,
+            qos,
+            retain,
+        });
+    }
+
+    /// Send the CONNECT and wait for the CONNACK
     pub async fn connect(
         &mut self,
-        end_point: IpEndpoint,
         keep_alive_secs: u16,
         username: Option<&'a str>,
         password: Option<&'a [u8]>,
-    ) -> Result<(), MqttError> {
+    ) -> Result<(), MqttClientError> {
         self.keep_alive_secs = Some(keep_alive_secs);
+        self.connection_state = false;
+        self.ping_outstanding = false;
+        self.recv_index = 0;
+        self.recv_discard = 0;
+        self.topic_alias_max = 0;
+        self.topic_aliases.clear();
 
-        // if self.session.state() != State::Closed {
-        //     self.disconnect();
-        // }
-        if let Err(e) = self.session.connect().await {
-            error!("Failed to connect to {end_point:?}: {e:?}");
-            return Err(MqttError::Overflow);
+        self.last_received_millis = self.current_millis();
+        match self.protocol {
+            ProtocolVersion::V311 => {
+                let conn_pkt = Packet::Connect(Connect {
+                    protocol: Protocol::MQTT311,
+                    keep_alive: keep_alive_secs,
+                    client_id: self.client_id,
+                    clean_session: self.clean_session,
+                    last_will: self.last_will.clone(),
+                    username,
+                    password,
+                });
+                self.send(conn_pkt).await?;
+            }
+            ProtocolVersion::V5 => {
+                self.send_connect_v5(keep_alive_secs, username, password)
+                    .await?
+            }
         }
 
-        let conn_pkt = Packet::Connect(Connect {
-            protocol: Protocol::MQTT311,
-            keep_alive: keep_alive_secs,
-            client_id: self.client_id,
-            clean_session: true,
-            last_will: None,
-            username,
-            password,
-        });
+        let start = Instant::now();
+        let (session_present, return_code) = loop {
+            let Some(remaining) = ACK_TIMEOUT.checked_sub(start.elapsed()) else {
+                error!("No CONNACK from the broker");
+                return Err(MqttClientError::Timeout);
+            };
+            let acks = self.receive_packets(remaining).await?;
+            let connack = acks.iter().find_map(|ack| match ack {
+                RequestAck::Connect {
+                    session_present,
+                    return_code,
+                } => Some((*session_present, *return_code)),
+                _ => None,
+            });
+            if let Some(connack) = connack {
+                break connack;
+            }
+        };
+        if let Some(refused) = ConnectRefused::from_return_code(return_code) {
+            error!("MQTT connection refused: {:?}", refused);
+            self.disconnect().await;
+            return Err(MqttClientError::ConnectionRefused(refused));
+        }
+        if !self.clean_session && !session_present {
+            info!(
+                "MQTT broker has no session for {}, starting a new one",
+                self.client_id
+            );
+        }
+        debug!(
+            "CONNACK received, session present: {}, topic aliases: {}",
+            session_present, self.topic_alias_max
+        );
 
-        self.last_sent_millis = self.current_millis();
         self.connection_state = true;
-        self.send(conn_pkt).await?;
-        Ok(())
+        self.resend_inflight().await
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connection_state
     }
 
+    /// End the MQTT connection, with a DISCONNECT when connected so the broker
+    /// discards the last will. Closing the transport is left to the caller.
     pub async fn disconnect(&mut self) {
-        let _ = self.session.close().await;
+        if self.connection_state {
+            let _ = self.send(Packet::Disconnect).await;
+            let _ = self.transport.flush().await;
+        }
         self.connection_state = false;
     }
 
+    /// Give the transport back, to close it
+    pub fn into_transport(self) -> T {
+        self.transport
+    }
+
+    /// Publish a message
+    ///
+    /// QoS 1 and 2 messages are kept until the broker acknowledges them, waiting
+    /// for room in the in-flight window first. The returned [`Delivery`] tells
+    /// when the broker has the message. Once in the window a message is never
+    /// reported as failed, it is sent again after a reconnection instead.
     pub async fn publish(
         &mut self,
         topic_name: &str,
         payload: &[u8],
         qos: QoS,
-    ) -> Result<(), MqttError> {
-        let pub_pkt = Packet::Publish(Publish {
-            dup: false,
+    ) -> Result<Delivery, MqttClientError> {
+        self.publish_with_options(topic_name, payload, qos, &PublishOptions::default())
+            .await
+    }
+
+    /// Publish a message the broker keeps for the future subscribers of `topic_name`
+    pub async fn publish_retained(
+        &mut self,
+        topic_name: &str,
+        payload: &[u8],
+        qos: QoS,
+    ) -> Result<Delivery, MqttClientError> {
+        let options = PublishOptions {
+            retain: true,
+            ..Default::default()
+        };
+        self.publish_with_options(topic_name, payload, qos, &options)
+            .await
+    }
+
+    /// Publish a message with MQTT 5 properties, see [`MqttClient::publish`]
+    ///
+    /// With MQTT 3.1.1 only `options.retain` is used.
+    pub async fn publish_with_options(
+        &mut self,
+        topic_name: &str,
+        payload: &[u8],
+        qos: QoS,
+        options: &PublishOptions<'_>,
+    ) -> Result<Delivery, MqttClientError> {
+        if !self.connection_state {
+            return Err(MqttClientError::NotConnected);
+        }
+        let props = match self.protocol {
+            ProtocolVersion::V311 => PublishProperties::new(),
+            ProtocolVersion::V5 => options.properties().ok_or_else(|| {
+                error!("Too many properties for a message on {}", topic_name);
+                MqttClientError::Encoding(PacketError::BufferTooSmall)
+            })?,
+        };
+        let retain = options.retain;
+        let qos = qos as u8;
+        if qos == 0 {
+            let flags = PublishFlags {
+                qos: 0,
+                pid: None,
+                retain,
+                dup: false,
+            };
+            self.send_publish(topic_name, payload, flags, props).await?;
+            return Ok(Delivery { pid: None });
+        }
+
+        let start = Instant::now();
+        while self.inflight.is_full() {
+            let Some(remaining) = ACK_TIMEOUT.checked_sub(start.elapsed()) else {
+                warn!(
+                    "MQTT in-flight window full, cannot publish on {}",
+                    topic_name
+                );
+                return Err(MqttClientError::InFlightFull);
+            };
+            self.receive_packets(remaining).await?;
+        }
+        let mut stored = [0u8; MAX_INFLIGHT_PROPERTIES_LEN];
+        let Ok(stored_len) = properties::encode_list(&props, &mut stored) else {
+            error!("Properties of the message on {} too long", topic_name);
+            return Err(MqttClientError::MessageTooLarge);
+        };
+        let pid = self.inflight.allocate_pid();
+        if let Err(e) =
+            self.inflight
+                .insert(pid, qos, retain, topic_name, &stored[..stored_len], payload)
+        {
+            error!("Cannot keep message on {} in flight: {:?}", topic_name, e);
+            return Err(e.into());
+        }
+        // Kept in flight even when the write fails, it goes again on reconnect
+        let flags = PublishFlags {
             qos,
-            pid: None,
-            retain: false,
-            topic_name,
-            payload,
-        });
+            pid: Some(pid),
+            retain,
+            dup: false,
+        };
+        if let Err(e) = self.send_publish(topic_name, payload, flags, props).await {
+            warn!("MQTT packet {} kept for the next connection: {:?}", pid, e);
+            self.connection_state = false;
+        }
+        Ok(Delivery { pid: Some(pid) })
+    }
 
-        self.send(pub_pkt).await?;
-        self.last_sent_millis = self.current_millis();
+    /// Wait until the broker acknowledged `delivery`, handling inbound traffic meanwhile
+    ///
+    /// Fails with [`MqttClientError::Rejected`] when an MQTT 5 broker refused it.
+    pub async fn wait_delivery(
+        &mut self,
+        delivery: Delivery,
+        timeout: Duration,
+    ) -> Result<(), MqttClientError> {
+        let Some(pid) = delivery.pid else {
+            return Ok(());
+        };
+        let start = Instant::now();
+        while self.inflight.is_pending(pid) {
+            let Some(remaining) = timeout.checked_sub(start.elapsed()) else {
+                return Err(MqttClientError::Timeout);
+            };
+            self.receive_packets(remaining).await?;
+        }
+        match self.last_rejected {
+            Some((rejected, reason)) if rejected == pid => Err(MqttClientError::Rejected(reason)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Stop sending `delivery` again, the caller queues the message itself
+    pub fn abandon(&mut self, delivery: Delivery) {
+        if let Some(pid) = delivery.pid {
+            self.inflight.remove(pid);
+        }
+    }
+
+    /// Subscribe to `filter` and route the matching messages to `handler`
+    ///
+    /// Waits for the SUBACK, messages received meanwhile are dispatched as usual.
+    pub async fn subscribe(
+        &mut self,
+        filter: &str,
+        qos: QoS,
+        handler: &'a dyn MessageHandler,
+    ) -> Result<(), MqttClientError> {
+        if let Err(e) = self.handlers.register(filter, handler) {
+            error!("Cannot register handler for {}: {:?}", filter, e);
+            return Err(MqttClientError::Registry(e));
+        }
+
+        let pid = self.inflight.allocate_pid();
+        let mut buffer = [0u8; 256];
+        let encoded =
+            packet::encode_subscribe(pid, &[(filter, qos as u8)], self.protocol, &mut buffer);
+        let len = match encoded {
+            Ok(len) => len,
+            Err(e) => {
+                error!("Failed to encode SUBSCRIBE: {e:?}");
+                self.handlers.remove(filter);
+                return Err(MqttClientError::Encoding(e));
+            }
+        };
+        if let Err(e) = self.send_raw(&buffer[..len]).await {
+            self.handlers.remove(filter);
+            return Err(e);
+        }
+
+        match self.wait_request_ack(pid).await {
+            Ok(RequestAck::Subscribe { return_code, .. }) if return_code < REASON_FAILURE => {
+                info!("Subscribed to {} with QoS {}", filter, return_code);
+                Ok(())
+            }
+            Ok(RequestAck::Subscribe { return_code, .. })
+                if self.protocol == ProtocolVersion::V5 =>
+            {
+                let reason = ReasonCode::from_code(return_code);
+                error!("Subscription to {} rejected: {:?}", filter, reason);
+                self.handlers.remove(filter);
+                Err(MqttClientError::Rejected(reason))
+            }
+            Ok(_) => {
+                error!("Subscription to {} rejected", filter);
+                self.handlers.remove(filter);
+                Err(MqttClientError::SubscriptionRejected)
+            }
+            Err(e) => {
+                self.handlers.remove(filter);
+                Err(e)
+            }
+        }
+    }
+
+    /// Unsubscribe from `filter` and drop its handler
+    pub async fn unsubscribe(&mut self, filter: &str) -> Result<(), MqttClientError> {
+        self.handlers.remove(filter);
+
+        let pid = self.inflight.allocate_pid();
+        let mut buffer = [0u8; 256];
+        let len = packet::encode_unsubscribe(pid, &[filter], self.protocol, &mut buffer).map_err(
+            |e| {
+                error!("Failed to encode UNSUBSCRIBE: {e:?}");
+                MqttClientError::Encoding(e)
+            },
+        )?;
+        self.send_raw(&buffer[..len]).await?;
+        match self.wait_request_ack(pid).await? {
+            RequestAck::Unsubscribe { reason_code, .. } if reason_code >= REASON_FAILURE => {
+                let reason = ReasonCode::from_code(reason_code);
+                error!("Unsubscription from {} rejected: {:?}", filter, reason);
+                Err(MqttClientError::Rejected(reason))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Read what the broker sends within `timeout` and dispatch the complete packets
+    ///
+    /// Returns `Ok` on timeout, an error when the connection is lost.
+    pub async fn receive(&mut self, timeout: Duration) -> Result<(), MqttClientError> {
+        self.receive_packets(timeout).await.map(|_| ())
+    }
+
+    /// Wait for bytes from the broker, kept until [`MqttClient::handle_packets`]
+    ///
+    /// Writes nothing, so it can be raced in a `select` as long as the transport
+    /// read itself is cancel-safe.
+    pub async fn read_packets(&mut self) -> Result<(), MqttClientError> {
+        let read = self
+            .transport
+            .read(&mut self.recv_buffer[self.recv_index..])
+            .await;
+        let len = match read {
+            Ok(0) => {
+                warn!("MQTT connection closed by the broker");
+                self.connection_state = false;
+                return Err(MqttClientError::ConnectionClosed);
+            }
+            Ok(len) => len,
+            Err(e) => {
+                error!("Failed to receive MQTT: {e:?}");
+                self.connection_state = false;
+                return Err(MqttClientError::Io);
+            }
+        };
+        self.last_received_millis = self.current_millis();
+
+        // Tail of a packet too long for the buffer
+        let skip = self.recv_discard.min(len);
+        if skip > 0 {
+            let start = self.recv_index;
+            self.recv_buffer
+                .copy_within(start + skip..start + len, start);
+            self.recv_discard -= skip;
+        }
+        self.recv_index += len - skip;
         Ok(())
     }
 
-    pub async fn poll(&mut self) {
-        // if self.session.state() == State::Closed {
-        //     self.connection_state = false;
-        //     warn!("socket state is closed");
-        //     return;
-        // }
+    /// Dispatch and acknowledge the complete packets from [`MqttClient::read_packets`]
+    pub async fn handle_packets(&mut self) -> Result<(), MqttClientError> {
+        loop {
+            self.process_packets().await?;
+            if !self.has_complete_packet() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Send the keep-alive ping when due and retransmit unacknowledged messages
+    ///
+    /// Messages are only sent again on a live connection with MQTT 3.1.1, MQTT 5
+    /// forbids it [MQTT-4.4.0-1] and waits for a reconnection. Fails once
+    /// nothing has been received for 1.5 times the keep-alive, the broker has
+    /// dropped the connection without the socket noticing.
+    pub async fn poll(&mut self) -> Result<(), MqttClientError> {
+        if !self.connection_state {
+            return Err(MqttClientError::NotConnected);
+        }
 
         if let Some(keep_alive_secs) = self.keep_alive_secs {
-            if (self.last_sent_millis + (keep_alive_secs * 1000) as u64) < self.current_millis() {
-                let ping_err = self.send(Packet::Pingreq).await;
-                match ping_err {
-                    Ok(()) => {
-                        info!("Ping success");
-                        self.last_sent_millis = self.current_millis();
-                    }
-                    Err(_) => warn!("Ping failed"),
+            let keep_alive_millis = keep_alive_secs as u64 * 1000;
+            let now_millis = self.current_millis();
+            let silent_millis = now_millis.saturating_sub(self.last_received_millis);
+            if silent_millis >= keep_alive_millis * 3 / 2 {
+                warn!("No MQTT traffic from the broker for {} ms", silent_millis);
+                self.connection_state = false;
+                return Err(MqttClientError::KeepAliveTimeout);
+            }
+            // Ping when idle either way, a broker that only receives never answers
+            let idle = now_millis.saturating_sub(self.last_sent_millis) >= keep_alive_millis
+                || silent_millis >= keep_alive_millis;
+            if idle && !self.ping_outstanding {
+                self.send(Packet::Pingreq).await?;
+                debug!("Ping sent");
+                self.ping_outstanding = true;
+            }
+        }
+
+        if self.protocol == ProtocolVersion::V311 {
+            let now = Instant::now();
+            let mut buffer = [0u8; SEND_BUFFER_LEN];
+            for msg in self.inflight.expired(now, RETRY_INTERVAL) {
+                warn!(
+                    "MQTT packet {} not acknowledged after {} attempts, sending again",
+                    msg.pid, msg.attempts
+                );
+                write_retransmission(&mut self.transport, msg, self.protocol, now, &mut buffer)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Send every message still in flight again, after a reconnection
+    async fn resend_inflight(&mut self) -> Result<(), MqttClientError> {
+        let now = Instant::now();
+        let mut buffer = [0u8; SEND_BUFFER_LEN];
+        for msg in self.inflight.pending() {
+            info!("Sending MQTT packet {} again after reconnection", msg.pid);
+            write_retransmission(&mut self.transport, msg, self.protocol, now, &mut buffer).await?;
+        }
+        Ok(())
+    }
+
+    /// Send a PUBLISH, with a topic alias when the broker accepts one
+    async fn send_publish(
+        &mut self,
+        topic: &str,
+        payload: &[u8],
+        flags: PublishFlags,
+        mut props: PublishProperties<'_>,
+    ) -> Result<(), MqttClientError> {
+        let alias = match self.protocol {
+            ProtocolVersion::V5 => self.topic_alias(topic),
+            ProtocolVersion::V311 => None,
+        };
+        let mut topic_field = topic;
+        if let Some((alias, known)) = alias {
+            let _ = props.push(Property::U16(properties::TOPIC_ALIAS, alias));
+            if known {
+                topic_field = "";
+            }
+        }
+
+        let mut buffer = [0u8; SEND_BUFFER_LEN];
+        let len = packet::encode_publish(
+            topic_field,
+            payload,
+            flags,
+            &props,
+            self.protocol,
+            &mut buffer,
+        )
+        .map_err(|e| {
+            error!("Failed to encode PUBLISH on {}: {:?}", topic, e);
+            MqttClientError::Encoding(e)
+        })?;
+        self.send_raw(&buffer[..len]).await?;
+
+        // The broker learns the alias with this first message
+        if let Some((_, false)) = alias {
+            let mut key = heapless::String::new();
+            let _ = key.push_str(topic);
+            let _ = self.topic_aliases.push(key);
+        }
+        Ok(())
+    }
+
+    /// Alias to send `topic` with and whether the broker already knows it
+    fn topic_alias(&self, topic: &str) -> Option<(u16, bool)> {
+        if let Some(idx) = self.topic_aliases.iter().position(|t| t == topic) {
+            return Some((idx as u16 + 1, true));
+        }
+        let limit = MAX_TOPIC_ALIASES.min(self.topic_alias_max as usize);
+        let fits = topic.len() <= MAX_INFLIGHT_TOPIC_LEN;
+        (self.topic_aliases.len() < limit && fits)
+            .then_some((self.topic_aliases.len() as u16 + 1, false))
+    }
+
+    async fn send_connect_v5(
+        &mut self,
+        keep_alive_secs: u16,
+        username: Option<&str>,
+        password: Option<&[u8]>,
+    ) -> Result<(), MqttClientError> {
+        let mut props: heapless::Vec<Property, 2> = heapless::Vec::new();
+        // Larger packets would be dropped by the reassembly buffer anyway
+        let _ = props.push(Property::U32(
+            properties::MAXIMUM_PACKET_SIZE,
+            RECV_BUFFER_LEN as u32,
+        ));
+        if !self.clean_session {
+            let _ = props.push(Property::U32(
+                properties::SESSION_EXPIRY_INTERVAL,
+                self.session_expiry_secs,
+            ));
+        }
+        let connect = ConnectV5 {
+            client_id: self.client_id,
+            keep_alive: keep_alive_secs,
+            clean_start: self.clean_session,
+            properties: &props,
+            will: self.last_will.as_ref().map(|will| Will {
+                topic: will.topic,
+                payload: will.message,
+                qos: will.qos as u8,
+                retain: will.retain,
+            }),
+            username,
+            password,
+        };
+        let mut buffer = [0u8; SEND_BUFFER_LEN];
+        let len = packet::encode_connect_v5(&connect, &mut buffer).map_err(|e| {
+            error!("Failed to encode CONNECT: {e:?}");
+            MqttClientError::Encoding(e)
+        })?;
+        self.send_raw(&buffer[..len]).await
+    }
+
+    async fn send(&mut self, packet: Packet<'_>) -> Result<(), MqttClientError> {
+        let mut buffer = [0u8; SEND_BUFFER_LEN];
+        let len = encode_slice(&packet, &mut buffer).map_err(|e| {
+            error!("Failed to encode MQTT packet: {e:?}");
+            MqttClientError::Encoding(PacketError::BufferTooSmall)
+        })?;
+        self.send_raw(&buffer[..len]).await
+    }
+
+    async fn send_raw(&mut self, data: &[u8]) -> Result<(), MqttClientError> {
+        write_packet(&mut self.transport, data).await?;
+        self.last_sent_millis = self.current_millis();
+        Ok(())
+    }
+
+    async fn wait_request_ack(&mut self, pid: u16) -> Result<RequestAck, MqttClientError> {
+        let start = Instant::now();
+        while start.elapsed() < ACK_TIMEOUT {
+            let remaining = ACK_TIMEOUT
+                .checked_sub(start.elapsed())
+                .unwrap_or(Duration::from_ticks(0));
+            let acks = self.receive_packets(remaining).await?;
+            let found = acks.iter().find(|ack| match ack {
+                RequestAck::Subscribe { pid: p, .. } | RequestAck::Unsubscribe { pid: p, .. } => {
+                    *p == pid
                 }
+                RequestAck::Connect { .. } => false,
+            });
+            if let Some(ack) = found {
+                return Ok(*ack);
             }
         }
+        error!("No acknowledgement for packet {}", pid);
+        Err(MqttClientError::Timeout)
     }
 
-    async fn send(&mut self, packet: Packet<'_>) -> Result<(), MqttError> {
-        let mut buffer = [0u8; 4096];
-        let len = encode_slice(&packet, &mut buffer).unwrap();
-        match self.session.write(&buffer[..len]).await {
-            Ok(_) => Ok(()),
-            Err(e) => {
-                error!("Failed to send MQTT: {e:?}");
-                Err(MqttError::Overflow)
+    /// One read from the transport, then decode and dispatch every complete packet
+    ///
+    /// Packets left over by the previous call are handled first, without reading.
+    async fn receive_packets(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<heapless::Vec<RequestAck, 4>, MqttClientError> {
+        if self.has_complete_packet() {
+            return self.process_packets().await;
+        }
+        match with_timeout(timeout, self.read_packets()).await {
+            Err(_) => Ok(heapless::Vec::new()),
+            Ok(read) => {
+                read?;
+                self.process_packets().await
             }
         }
     }
 
-    async fn receive(&mut self) -> Result<(), MqttError> {
-        match self.session.read(&mut self.recv_buffer).await {
-            Ok(len) => {
-                self.recv_index = len;
-                Ok(())
+    /// Whether the packets read so far hold a complete one
+    fn has_complete_packet(&self) -> bool {
+        let pending = &self.recv_buffer[..self.recv_index];
+        matches!(packet::packet_len(pending), Ok(Some(len)) if len <= pending.len())
+    }
+
+    /// Decode, dispatch and acknowledge the complete packets read so far
+    ///
+    /// Stops once `acks` is full, the packets left are handled by the next call.
+    async fn process_packets(&mut self) -> Result<heapless::Vec<RequestAck, 4>, MqttClientError> {
+        let mut acks = heapless::Vec::new();
+        let mut consumed = 0;
+        while !acks.is_full() {
+            let pending = &self.recv_buffer[consumed..self.recv_index];
+            let (inbound, used) = match packet::decode(pending, self.protocol) {
+                Ok(Some(decoded)) => decoded,
+                Ok(None) => {
+                    if consumed == 0 && self.recv_index == self.recv_buffer.len() {
+                        // A single packet does not fit, drop it
+                        let total = packet::packet_len(pending).ok().flatten().unwrap_or(0);
+                        warn!("Dropping inbound MQTT packet of {} bytes", total);
+                        self.recv_discard = total.saturating_sub(self.recv_index);
+                        consumed = self.recv_index;
+                    }
+                    break;
+                }
+                Err(e) => {
+                    error!("Malformed MQTT packet: {e:?}");
+                    self.recv_index = 0;
+                    return Err(MqttClientError::Decoding(e));
+                }
+            };
+            consumed += used;
+
+            let mut response = None;
+            match inbound {
+                Inbound::Publish(publish) => {
+                    debug!("MQTT message on {}", publish.topic);
+                    // A QoS 2 message is delivered once, however often the broker sends it
+                    let duplicate = match (publish.qos, publish.pid) {
+                        (2, Some(pid)) => !self.inflight.on_inbound_qos2(pid),
+                        _ => false,
+                    };
+                    if !duplicate && self.handlers.dispatch(&publish) == 0 {
+                        warn!("No handler for MQTT message on {}", publish.topic);
+                    }
+                    response = match (publish.qos, publish.pid) {
+                        (1, Some(pid)) => Some(Ack::Puback(pid)),
+                        (2, Some(pid)) => Some(Ack::Pubrec(pid)),
+                        _ => None,
+                    };
+                }
+                Inbound::Pubrel { pid, .. } => {
+                    self.inflight.on_inbound_pubrel(pid);
+                    response = Some(Ack::Pubcomp(pid));
+                }
+                Inbound::Puback { pid, reason } | Inbound::Pubrec { pid, reason }
+                    if reason >= REASON_FAILURE =>
+                {
+                    let reason = ReasonCode::from_code(reason);
+                    warn!("MQTT packet {} rejected by the broker: {:?}", pid, reason);
+                    if self.inflight.on_rejected(pid) {
+                        self.last_rejected = Some((pid, reason));
+                    }
+                }
+                Inbound::Puback { pid, .. } => {
+                    if !self.inflight.on_puback(pid) {
+                        warn!("Unexpected PUBACK for packet {}", pid);
+                    }
+                }
+                Inbound::Pubrec { pid, .. } => {
+                    if self.inflight.on_pubrec(pid) {
+                        response = Some(Ack::Pubrel(pid));
+                    } else {
+                        warn!("Unexpected PUBREC for packet {}", pid);
+                    }
+                }
+                Inbound::Pubcomp { pid, .. } => {
+                    if !self.inflight.on_pubcomp(pid) {
+                        warn!("Unexpected PUBCOMP for packet {}", pid);
+                    }
+                }
+                Inbound::Suback { pid, return_codes } => {
+                    let return_code = return_codes.first().copied().unwrap_or(SUBACK_FAILURE);
+                    let _ = acks.push(RequestAck::Subscribe { pid, return_code });
+                }
+                Inbound::Unsuback { pid, reason_codes } => {
+                    let reason_code = reason_codes.first().copied().unwrap_or(0);
+                    let _ = acks.push(RequestAck::Unsubscribe { pid, reason_code });
+                }
+                Inbound::Connack {
+                    session_present,
+                    return_code,
+                    properties: props,
+                } => {
+                    // Limits the MQTT 5 broker sets for this connection
+                    self.topic_alias_max = props.u16(properties::TOPIC_ALIAS_MAXIMUM).unwrap_or(0);
+                    if let Some(keep_alive_secs) = props.u16(properties::SERVER_KEEP_ALIVE) {
+                        info!("MQTT broker sets the keep-alive to {} s", keep_alive_secs);
+                        self.keep_alive_secs = Some(keep_alive_secs);
+                    }
+                    let _ = acks.push(RequestAck::Connect {
+                        session_present,
+                        return_code,
+                    });
+                }
+                Inbound::Pingresp => {
+                    debug!("MQTT ping response");
+                    self.ping_outstanding = false;
+                }
+                Inbound::Disconnect { reason } => {
+                    let reason = ReasonCode::from_code(reason);
+                    warn!("MQTT broker closed the connection: {:?}", reason);
+                    self.connection_state = false;
+                    self.recv_index = 0;
+                    return Err(MqttClientError::ServerDisconnect(reason));
+                }
+                other => debug!("Ignoring MQTT packet {:?}", other),
+            }
+            // Acknowledged as it is handled, however many packets the read held
+            if let Some(ack) = response {
+                let mut buffer = [0u8; 4];
+                if let Ok(len) = packet::encode_ack(ack, &mut buffer) {
+                    self.send_raw(&buffer[..len]).await?;
+                }
             }
-            Err(_) => Err(MqttError::Overflow),
         }
+        self.recv_buffer.copy_within(consumed..self.recv_index, 0);
+        self.recv_index -= consumed;
+        Ok(acks)
     }
 
     fn current_millis(&self) -> u64 {
         Instant::now().as_millis()
     }
 }
+
+async fn write_packet<T: Write>(transport: &mut T, data: &[u8]) -> Result<(), MqttClientError> {
+    // A single write may take only part of the packet
+    transport.write_all(data).await.map_err(|e| {
+        error!("Failed to send MQTT: {e:?}");
+        MqttClientError::Io
+    })
+}
+
+/// Send the PUBLISH (flagged DUP) or PUBREL an in-flight message is stuck at
+///
+/// The PUBLISH carries the full topic, the aliases of a previous connection are gone.
+async fn write_retransmission<T: Write>(
+    transport: &mut T,
+    msg: &mut OutboundMessage,
+    protocol: ProtocolVersion,
+    now: Instant,
+    buffer: &mut [u8],
+) -> Result<(), MqttClientError> {
+    let props: PublishProperties = Properties::new(&msg.properties)
+        .iter()
+        .flatten()
+        .take(MAX_PUBLISH_PROPERTIES)
+        .collect();
+    let flags = PublishFlags {
+        qos: msg.qos,
+        pid: Some(msg.pid),
+        retain: msg.retain,
+        dup: true,
+    };
+    let encoded = match msg.stage {
+        Stage::AwaitPuback | Stage::AwaitPubrec => {
+            packet::encode_publish(&msg.topic, &msg.payload, flags, &props, protocol, buffer)
+        }
+        Stage::AwaitPubcomp => packet::encode_ack(Ack::Pubrel(msg.pid), buffer),
+    };
+    let len = encoded.map_err(|e| {
+        error!("Failed to encode retransmission of {}: {:?}", msg.pid, e);
+        MqttClientError::Encoding(e)
+    })?;
+    write_packet(transport, &buffer[..len]).await?;
+    msg.sent_at = now;
+    msg.attempts = msg.attempts.saturating_add(1);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cell::Cell;
+
+    use super::script::{run, ScriptedTransport};
+    use super::*;
+
+    /// Client `dev`, keep-alive 60 s, clean session
+    const CONNECT: &[u8] = &[
+        0x10, 0x0F, 0x00, 0x04, b'M', b'Q', b'T', b'T', 0x04, 0x02, 0x00, 0x3C, 0x00, 0x03, b'd',
+        b'e', b'v',
+    ];
+    /// Same with MQTT 5, announcing a maximum packet size of 3072
+    const CONNECT_V5: &[u8] = &[
+        0x10, 0x15, 0x00, 0x04, b'M', b'Q', b'T', b'T', 0x05, 0x02, 0x00, 0x3C, 0x05, 0x27, 0x00,
+        0x00, 0x0C, 0x00, 0x00, 0x03, b'd', b'e', b'v',
+    ];
+    const CONNACK: &[u8] = &[0x20, 0x02, 0x00, 0x00];
+    /// `hi` on `t`, packet 1
+    const PUBLISH_QOS1: &[u8] = &[0x32, 0x07, 0x00, 0x01, b't', 0x00, 0x01, b'h', b'i'];
+    const PUBLISH_QOS2: &[u8] = &[0x34, 0x07, 0x00, 0x01, b't', 0x00, 0x01, b'h', b'i'];
+    const PUBACK: &[u8] = &[0x40, 0x02, 0x00, 0x01];
+    const PUBREC: &[u8] = &[0x50, 0x02, 0x00, 0x01];
+    const PUBREL: &[u8] = &[0x62, 0x02, 0x00, 0x01];
+    const PUBCOMP: &[u8] = &[0x70, 0x02, 0x00, 0x01];
+    const PINGREQ: &[u8] = &[0xC0, 0x00];
+    const PINGRESP: &[u8] = &[0xD0, 0x00];
+
+    /// `t` with QoS 1, packet 1
+    const SUBSCRIBE: &[u8] = &[0x82, 0x06, 0x00, 0x01, 0x00, 0x01, b't', 0x01];
+    const SUBACK: &[u8] = &[0x90, 0x03, 0x00, 0x01, 0x01];
+
+    #[derive(Default)]
+    struct Counter {
+        messages: Cell<usize>,
+    }
+
+    impl MessageHandler for Counter {
+        fn on_message(&self, topic: &str, payload: &[u8]) {
+            assert_eq!((topic, payload), ("t", &b"on"[..]));
+            self.messages.set(self.messages.get() + 1);
+        }
+    }
+
+    fn with_dup(packet: &[u8]) -> std::vec::Vec<u8> {
+        let mut packet = packet.to_vec();
+        packet[0] |= 0x08;
+        packet
+    }
+
+    #[test]
+    fn connects() {
+        run(async {
+            let mut inflight = InFlightWindow::new();
+            let transport = ScriptedTransport::new().expect(CONNECT).send(CONNACK);
+            let mut client = MqttClient::new("dev", transport, &mut inflight);
+            assert_eq!(client.connect(60, None, None).await, Ok(()));
+            assert!(client.is_connected());
+            client.into_transport().finish();
+        });
+    }
+
+    #[test]
+    fn connack_split_across_reads() {
+        run(async {
+            let mut inflight = InFlightWindow::new();
+            let transport = ScriptedTransport::new()
+                .expect(CONNECT)
+                .send(&CONNACK[..1])
+                .send(&CONNACK[1..]);
+            let mut client = MqttClient::new("dev", transport, &mut inflight);
+            assert_eq!(client.connect(60, None, None).await, Ok(()));
+            client.into_transport().finish();
+        });
+    }
+
+    #[test]
+    fn reports_refused_connections() {
+        use ConnectRefused::*;
+        for (code, refused) in [
+            (1, UnacceptableProtocolVersion),
+            (2, IdentifierRejected),
+            (3, ServerUnavailable),
+            (4, BadCredentials),
+            (5, NotAuthorized),
+            (6, Unknown(6)),
+        ] {
+            run(async {
+                let mut inflight = InFlightWindow::new();
+                let transport = ScriptedTransport::new()
+                    .expect(CONNECT)
+                    .send(&[0x20, 0x02, 0x00, code]);
+                let mut client = MqttClient::new("dev", transport, &mut inflight);
+                let result = client.connect(60, None, None).await;
+                assert_eq!(result, Err(MqttClientError::ConnectionRefused(refused)));
+                assert!(!client.is_connected());
+                // No DISCONNECT on a connection never accepted
+                client.into_transport().finish();
+            });
+        }
+    }
+
+    #[test]
+    fn reports_refused_v5_connections() {
+        run(async {
+            let mut inflight = InFlightWindow::new();
+            let transport = ScriptedTransport::new()
+                .expect(CONNECT_V5)
+                .send(&[0x20, 0x03, 0x00, 0x87, 0x00]);
+            let mut client = MqttClient::new("dev", transport, &mut inflight);
+            client.set_protocol(ProtocolVersion::V5);
+            let result = client.connect(60, None, None).await;
+            let refused = MqttClientError::ConnectionRefused(ConnectRefused::NotAuthorized);
+            assert_eq!(result, Err(refused));
+            client.into_transport().finish();
+        });
+    }
+
+    #[test]
+    fn times_out_without_connack() {
+        run(async {
+            let mut inflight = InFlightWindow::new();
+            let transport = ScriptedTransport::new().expect(CONNECT);
+            let mut client = MqttClient::new("dev", transport, &mut inflight);
+            let start = Instant::now();
+            let result = client.connect(60, None, None).await;
+            assert_eq!(result, Err(MqttClientError::Timeout));
+            assert!(start.elapsed() >= ACK_TIMEOUT);
+            client.into_transport().finish();
+        });
+    }
+
+    #[test]
+    fn delivers_qos1() {
+        run(async {
+            let mut inflight = InFlightWindow::new();
+            let transport = ScriptedTransport::new()
+                .expect(CONNECT)
+                .send(CONNACK)
+                .expect(PUBLISH_QOS1)
+                .send(PUBACK);
+            let mut client = MqttClient::new("dev", transport, &mut inflight);
+            client.connect(60, None, None).await.unwrap();
+            let delivery = client.publish("t", b"hi", QoS::AtLeastOnce).await.unwrap();
+            let result = client.wait_delivery(delivery, ACK_TIMEOUT).await;
+            assert_eq!(result, Ok(()));
+            client.into_transport().finish();
+            assert!(!inflight.is_pending(1));
+        });
+    }
+
+    #[test]
+    fn delivers_qos2() {
+        run(async {
+            let mut inflight = InFlightWindow::new();
+            let transport = ScriptedTransport::new()
+                .expect(CONNECT)
+                .send(CONNACK)
+                .expect(PUBLISH_QOS2)
+                .send(PUBREC)
+                .expect(PUBREL)
+                .send(PUBCOMP);
+            let mut client = MqttClient::new("dev", transport, &mut inflight);
+            client.connect(60, None, None).await.unwrap();
+            let delivery = client.publish("t", b"hi", QoS::ExactlyOnce).await.unwrap();
+            let result = client.wait_delivery(delivery, ACK_TIMEOUT).await;
+            assert_eq!(result, Ok(()));
+            client.into_transport().finish();
+        });
+    }
+
+    #[test]
+    fn times_out_without_puback() {
+        run(async {
+            let mut inflight = InFlightWindow::new();
+            let transport = ScriptedTransport::new()
+                .expect(CONNECT)
+                .send(CONNACK)
+                .expect(PUBLISH_QOS1)
+                .expect(&with_dup(PUBLISH_QOS1))
+                .send(PUBACK);
+            let mut client = MqttClient::new("dev", transport, &mut inflight);
+            client.connect(60, None, None).await.unwrap();
+            let delivery = client.publish("t", b"hi", QoS::AtLeastOnce).await.unwrap();
+            let result = client.wait_delivery(delivery, ACK_TIMEOUT).await;
+            assert_eq!(result, Err(MqttClientError::Timeout));
+            assert!(client.inflight.is_pending(1));
+            // Sent again on the same connection with MQTT 3.1.1
+            client.poll().await.unwrap();
+            client.receive(ACK_TIMEOUT).await.unwrap();
+            client.into_transport().finish();
+            assert!(!inflight.is_pending(1));
+        });
+    }
+
+    #[test]
+    fn keeps_unacknowledged_v5_messages_for_the_next_connection() {
+        run(async {
+            let mut inflight = InFlightWindow::new();
+            let transport = ScriptedTransport::new()
+                .expect(CONNECT_V5)
+                .send(&[0x20, 0x03, 0x00, 0x00, 0x00])
+                .expect(&[0x32, 0x08, 0x00, 0x01, b't', 0x00, 0x01, 0x00, b'h', b'i']);
+            let mut client = MqttClient::new("dev", transport, &mut inflight);
+            client.set_protocol(ProtocolVersion::V5);
+            client.connect(60, None, None).await.unwrap();
+            let delivery = client.publish("t", b"hi", QoS::AtLeastOnce).await.unwrap();
+            let result = client.wait_delivery(delivery, ACK_TIMEOUT).await;
+            assert_eq!(result, Err(MqttClientError::Timeout));
+            // MQTT 5 forbids sending it again on this connection
+            client.poll().await.unwrap();
+            client.into_transport().finish();
+            assert!(inflight.is_pending(1));
+        });
+    }
+
+    #[test]
+    fn stops_aliasing_at_the_broker_maximum() {
+        run(async {
+            let mut inflight = InFlightWindow::new();
+            let transport = ScriptedTransport::new()
+                .expect(CONNECT_V5)
+                // Topic Alias Maximum 1
+                .send(&[0x20, 0x06, 0x00, 0x00, 0x03, 0x22, 0x00, 0x01])
+                // `t` with alias 1, then the alias alone
+                .expect(&[
+                    0x30, 0x09, 0x00, 0x01, b't', 0x03, 0x23, 0x00, 0x01, b'h', b'i',
+                ])
+                .expect(&[0x30, 0x08, 0x00, 0x00, 0x03, 0x23, 0x00, 0x01, b'h', b'i'])
+                // No alias left for `u`
+                .expect(&[0x30, 0x06, 0x00, 0x01, b'u', 0x00, b'h', b'i']);
+            let mut client = MqttClient::new("dev", transport, &mut inflight);
+            client.set_protocol(ProtocolVersion::V5);
+            client.connect(60, None, None).await.unwrap();
+            for topic in ["t", "t", "u"] {
+                client.publish(topic, b"hi", QoS::AtMostOnce).await.unwrap();
+            }
+            client.into_transport().finish();
+        });
+    }
+
+    #[test]
+    fn keeps_to_its_own_alias_maximum() {
+        let mut inflight = InFlightWindow::new();
+        let mut client = MqttClient::new("dev", ScriptedTransport::new(), &mut inflight);
+        client.set_protocol(ProtocolVersion::V5);
+        client.topic_alias_max = u16::MAX;
+        for alias in 1..=MAX_TOPIC_ALIASES as u16 {
+            let topic = std::format!("t{alias}");
+            assert_eq!(client.topic_alias(&topic), Some((alias, false)));
+            client
+                .topic_aliases
+                .push(topic.as_str().try_into().unwrap())
+                .unwrap();
+            assert_eq!(client.topic_alias(&topic), Some((alias, true)));
+        }
+        assert_eq!(client.topic_alias("other"), None);
+        // Too long to be remembered
+        client.topic_aliases.clear();
+        let long = "t".repeat(MAX_INFLIGHT_TOPIC_LEN + 1);
+        assert_eq!(client.topic_alias(&long), None);
+        // No alias unless the broker allows some
+        client.topic_alias_max = 0;
+        assert_eq!(client.topic_alias("t"), None);
+    }
+
+    #[test]
+    fn does_not_resend_abandoned_messages() {
+        run(async {
+            let mut inflight = InFlightWindow::new();
+            let transport = ScriptedTransport::new()
+                .expect(CONNECT)
+                .send(CONNACK)
+                .expect(PUBLISH_QOS1);
+            let mut client = MqttClient::new("dev", transport, &mut inflight);
+            client.connect(60, None, None).await.unwrap();
+            let delivery = client.publish("t", b"hi", QoS::AtLeastOnce).await.unwrap();
+            let result = client.wait_delivery(delivery, ACK_TIMEOUT).await;
+            assert_eq!(result, Err(MqttClientError::Timeout));
+            client.abandon(delivery);
+            client.poll().await.unwrap();
+            client.into_transport().finish();
+            assert!(!inflight.is_pending(1));
+        });
+    }
+
+    #[test]
+    fn acknowledges_inbound_messages() {
+        let counter = Counter::default();
+        run(async {
+            let mut inflight = InFlightWindow::new();
+            let transport = ScriptedTransport::new()
+                .expect(CONNECT)
+                .send(CONNACK)
+                .expect(SUBSCRIBE)
+                .send(SUBACK)
+                // QoS 1, packet 8
+                .send(&[0x32, 0x07, 0x00, 0x01, b't', 0x00, 0x08, b'o', b'n'])
+                .expect(&[0x40, 0x02, 0x00, 0x08])
+                // QoS 2, packet 7, sent again before the PUBREL
+                .send(&[0x34, 0x07, 0x00, 0x01, b't', 0x00, 0x07, b'o', b'n'])
+                .expect(&[0x50, 0x02, 0x00, 0x07])
+                .send(&[0x3C, 0x07, 0x00, 0x01, b't', 0x00, 0x07, b'o', b'n'])
+                .expect(&[0x50, 0x02, 0x00, 0x07])
+                .send(&[0x62, 0x02, 0x00, 0x07])
+                .expect(&[0x70, 0x02, 0x00, 0x07]);
+            let mut client = MqttClient::new("dev", transport, &mut inflight);
+            client.connect(60, None, None).await.unwrap();
+            client
+                .subscribe("t", QoS::AtLeastOnce, &counter)
+                .await
+                .unwrap();
+            for _ in 0..4 {
+                client.receive(Duration::from_secs(1)).await.unwrap();
+            }
+            client.into_transport().finish();
+        });
+        // The QoS 2 message is delivered once
+        assert_eq!(counter.messages.get(), 2);
+    }
+
+    #[test]
+    fn acknowledges_every_message_of_a_read() {
+        let counter = Counter::default();
+        run(async {
+            let mut inflight = InFlightWindow::new();
+            let mut burst = std::vec::Vec::new();
+            let mut pubacks = std::vec::Vec::new();
+            for pid in 1..=12 {
+                burst.extend_from_slice(&[0x32, 0x07, 0x00, 0x01, b't', 0x00, pid, b'o', b'n']);
+                pubacks.extend_from_slice(&[0x40, 0x02, 0x00, pid]);
+            }
+            let transport = ScriptedTransport::new()
+                .expect(CONNECT)
+                .send(CONNACK)
+                .expect(SUBSCRIBE)
+                .send(SUBACK)
+                .send(&burst)
+                .expect(&pubacks);
+            let mut client = MqttClient::new("dev", transport, &mut inflight);
+            client.connect(60, None, None).await.unwrap();
+            client
+                .subscribe("t", QoS::AtLeastOnce, &counter)
+                .await
+                .unwrap();
+            client.read_packets().await.unwrap();
+            client.handle_packets().await.unwrap();
+            client.into_transport().finish();
+        });
+        assert_eq!(counter.messages.get(), 12);
+    }
+
+    #[test]
+    fn finds_the_suback_behind_other_acks() {
+        let counter = Counter::default();
+        run(async {
+            let mut inflight = InFlightWindow::new();
+            // Stray UNSUBACKs fill the acknowledgements of the read
+            let mut acks = std::vec::Vec::new();
+            for pid in 9..14 {
+                acks.extend_from_slice(&[0xB0, 0x02, 0x00, pid]);
+            }
+            acks.extend_from_slice(SUBACK);
+            let transport = ScriptedTransport::new()
+                .expect(CONNECT)
+                .send(CONNACK)
+                .expect(SUBSCRIBE)
+                .send(&acks);
+            let mut client = MqttClient::new("dev", transport, &mut inflight);
+            client.connect(60, None, None).await.unwrap();
+            let result = client.subscribe("t", QoS::AtLeastOnce, &counter).await;
+            assert_eq!(result, Ok(()));
+            client.into_transport().finish();
+        });
+    }
+
+    #[test]
+    fn handles_packets_after_reading_them() {
+        let counter = Counter::default();
+        run(async {
+            let mut inflight = InFlightWindow::new();
+            let publish = [0x32, 0x07, 0x00, 0x01, b't', 0x00, 0x08, b'o', b'n'];
+            let transport = ScriptedTransport::new()
+                .expect(CONNECT)
+                .send(CONNACK)
+                .expect(SUBSCRIBE)
+                .send(SUBACK)
+                .send(&publish[..4])
+                .send(&publish[4..])
+                .expect(&[0x40, 0x02, 0x00, 0x08]);
+            let mut client = MqttClient::new("dev", transport, &mut inflight);
+            client.connect(60, None, None).await.unwrap();
+            client
+                .subscribe("t", QoS::AtLeastOnce, &counter)
+                .await
+                .unwrap();
+
+            // Reads only buffer, the message is delivered and acknowledged once
+            // handled in full
+            client.read_packets().await.unwrap();
+            client.handle_packets().await.unwrap();
+            client.read_packets().await.unwrap();
+            assert_eq!(counter.messages.get(), 0);
+            client.handle_packets().await.unwrap();
+            assert_eq!(counter.messages.get(), 1);
+            client.into_transport().finish();
+        });
+    }
+
+    #[test]
+    fn pings_when_idle() {
+        run(async {
+            let mut inflight = InFlightWindow::new();
+            let mut connect = CONNECT.to_vec();
+            connect[11] = 2;
+            let transport = ScriptedTransport::new()
+                .expect(&connect)
+                .send(CONNACK)
+                .expect(PINGREQ)
+                .send(PINGRESP);
+            let mut client = MqttClient::new("dev", transport, &mut inflight);
+            client.connect(2, None, None).await.unwrap();
+            // Not due yet
+            client.poll().await.unwrap();
+            client.receive(Duration::from_secs(2)).await.unwrap();
+            client.poll().await.unwrap();
+            client.receive(Duration::from_secs(1)).await.unwrap();
+            assert!(client.is_connected());
+
+            // Silent for 1.5 times the keep-alive
+            client.receive(Duration::from_secs(3)).await.unwrap();
+            assert_eq!(client.poll().await, Err(MqttClientError::KeepAliveTimeout));
+            assert!(!client.is_connected());
+            client.into_transport().finish();
+        });
+    }
+
+    #[test]
+    fn resends_after_reconnection() {
+        run(async {
+            let mut inflight = InFlightWindow::new();
+            let transport = ScriptedTransport::new()
+                .expect(CONNECT)
+                .send(CONNACK)
+                .expect(PUBLISH_QOS1)
+                .expect(&[0x34, 0x07, 0x00, 0x01, b't', 0x00, 0x02, b'h', b'i'])
+                .send(&[0x50, 0x02, 0x00, 0x02])
+                .expect(&[0x62, 0x02, 0x00, 0x02])
+                .close();
+            let mut client = MqttClient::new("dev", transport, &mut inflight);
+            client.connect(60, None, None).await.unwrap();
+            let first = client.publish("t", b"hi", QoS::AtLeastOnce).await.unwrap();
+            let second = client.publish("t", b"hi", QoS::ExactlyOnce).await.unwrap();
+            client.receive(Duration::from_secs(1)).await.unwrap();
+            let result = client.receive(Duration::from_secs(1)).await;
+            assert_eq!(result, Err(MqttClientError::ConnectionClosed));
+            client.into_transport().finish();
+
+            // The PUBLISH goes again flagged DUP, the QoS 2 message resumes at its PUBREL
+            let transport = ScriptedTransport::new()
+                .expect(CONNECT)
+                .send(CONNACK)
+                .expect(&with_dup(PUBLISH_QOS1))
+                .expect(&[0x62, 0x02, 0x00, 0x02])
+                .send(PUBACK)
+                .send(&[0x70, 0x02, 0x00, 0x02]);
+            let mut client = MqttClient::new("dev", transport, &mut inflight);
+            client.connect(60, None, None).await.unwrap();
+            assert_eq!(client.wait_delivery(first, ACK_TIMEOUT).await, Ok(()));
+            assert_eq!(client.wait_delivery(second, ACK_TIMEOUT).await, Ok(()));
+            client.into_transport().finish();
+        });
+    }
+}
@@ -0,0 +1,673 @@
+//! MQTT packets not covered by `mqttrust`
+//!
+//! SUBSCRIBE/UNSUBSCRIBE, PUBLISH, the acknowledgements and the MQTT 5 CONNECT are
+//! encoded here, and everything the broker sends is decoded here, in both protocol
+//! versions. The decoder works on a reassembly buffer: it reports how many bytes a
+//! complete packet used, or that more bytes are needed.
+use super::properties::{self, Properties, Property};
+
+const CONNECT: u8 = 1;
+
+const CONNACK: u8 = 2;
+const PUBLISH: u8 = 3;
+const PUBACK: u8 = 4;
+const PUBREC: u8 = 5;
+const PUBREL: u8 = 6;
+const PUBCOMP: u8 = 7;
+const SUBSCRIBE: u8 = 8;
+const SUBACK: u8 = 9;
+const UNSUBSCRIBE: u8 = 10;
+const UNSUBACK: u8 = 11;
+const PINGRESP: u8 = 13;
+const DISCONNECT: u8 = 14;
+
+/// SUBACK return code of a rejected subscription
+pub const SUBACK_FAILURE: u8 = 0x80;
+/// Reason codes from that value on report a failure (MQTT 5)
+pub const REASON_FAILURE: u8 = 0x80;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProtocolVersion {
+    V311,
+    V5,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PacketError {
+    /// Remaining length longer than 4 bytes
+    MalformedLength,
+    /// Packet content does not match its type
+    Malformed,
+    /// Output buffer too small for the encoded packet
+    BufferTooSmall,
+}
+
+/// PUBLISH received from the broker
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InboundPublish<'a> {
+    pub topic: &'a str,
+    pub pid: Option<u16>,
+    pub qos: u8,
+    pub retain: bool,
+    pub dup: bool,
+    /// Always empty with MQTT 3.1.1
+    pub properties: Properties<'a>,
+    pub payload: &'a [u8],
+}
+
+impl<'a> InboundPublish<'a> {
+    /// Topic the sender expects a response on (MQTT 5)
+    pub fn response_topic(&self) -> Option<&'a str> {
+        self.properties.str(properties::RESPONSE_TOPIC)
+    }
+
+    /// Data to send back with the response (MQTT 5)
+    pub fn correlation_data(&self) -> Option<&'a [u8]> {
+        self.properties.binary(properties::CORRELATION_DATA)
+    }
+
+    pub fn user_properties(&self) -> impl Iterator<Item = (&'a str, &'a str)> {
+        self.properties.user_properties()
+    }
+}
+
+/// Packet received from the broker
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Inbound<'a> {
+    Connack {
+        session_present: bool,
+        /// CONNACK return code, or reason code with MQTT 5
+        return_code: u8,
+        properties: Properties<'a>,
+    },
+    Publish(InboundPublish<'a>),
+    /// Acknowledgements carry a reason code with MQTT 5, 0 (success) otherwise
+    Puback {
+        pid: u16,
+        reason: u8,
+    },
+    Pubrec {
+        pid: u16,
+        reason: u8,
+    },
+    Pubrel {
+        pid: u16,
+        reason: u8,
+    },
+    Pubcomp {
+        pid: u16,
+        reason: u8,
+    },
+    Suback {
+        pid: u16,
+        return_codes: &'a [u8],
+    },
+    Unsuback {
+        pid: u16,
+        /// Always empty with MQTT 3.1.1
+        reason_codes: &'a [u8],
+    },
+    Pingresp,
+    /// Sent by an MQTT 5 broker before closing the connection
+    Disconnect {
+        reason: u8,
+    },
+    /// Packet a client never receives from a broker
+    Unexpected(u8),
+}
+
+/// Acknowledgements sent by the client
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Ack {
+    Puback(u16),
+    Pubrec(u16),
+    Pubrel(u16),
+    Pubcomp(u16),
+}
+
+/// Length of the complete packet starting at `buf[0]`, `Ok(None)` while the fixed
+/// header itself is incomplete
+pub fn packet_len(buf: &[u8]) -> Result<Option<usize>, PacketError> {
+    Ok(fixed_header(buf)?.map(|(header_len, remaining)| header_len + remaining))
+}
+
+/// Length of the fixed header and value of the remaining length field
+fn fixed_header(buf: &[u8]) -> Result<Option<(usize, usize)>, PacketError> {
+    let mut remaining = 0usize;
+    for i in 0..4 {
+        let Some(&byte) = buf.get(1 + i) else {
+            return Ok(None);
+        };
+        remaining |= ((byte & 0x7F) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(Some((2 + i, remaining)));
+        }
+    }
+    Err(PacketError::MalformedLength)
+}
+
+/// Decode the packet at the start of `buf`
+///
+/// Returns the packet and the number of bytes it used, `Ok(None)` when `buf` does
+/// not hold a complete packet yet.
+pub fn decode(
+    buf: &[u8],
+    version: ProtocolVersion,
+) -> Result<Option<(Inbound<'_>, usize)>, PacketError> {
+    let Some((header_len, remaining)) = fixed_header(buf)? else {
+        return Ok(None);
+    };
+    let total = header_len + remaining;
+    if buf.len() < total {
+        return Ok(None);
+    }
+    let header = buf[0];
+    let body = &buf[header_len..total];
+    let v5 = version == ProtocolVersion::V5;
+    let pid = |body: &[u8]| -> Result<u16, PacketError> {
+        match body {
+            [hi, lo, ..] => Ok(u16::from_be_bytes([*hi, *lo])),
+            _ => Err(PacketError::Malformed),
+        }
+    };
+    // Packet identifier, reason code (success when omitted) and properties
+    let ack = |body: &[u8]| -> Result<(u16, u8), PacketError> {
+        let pid = pid(body)?;
+        match body.get(2) {
+            Some(reason) if v5 => Ok((pid, *reason)),
+            _ => Ok((pid, 0)),
+        }
+    };
+
+    let packet = match header >> 4 {
+        CONNACK => match body {
+            [flags, code, rest @ ..] => Inbound::Connack {
+                session_present: flags & 0x01 != 0,
+                return_code: *code,
+                properties: if v5 && !rest.is_empty() {
+                    properties::split_block(rest)?.0
+                } else {
+                    Properties::empty()
+                },
+            },
+            _ => return Err(PacketError::Malformed),
+        },
+        PUBLISH => {
+            let qos = (header >> 1) & 0x03;
+            if qos == 3 {
+                return Err(PacketError::Malformed);
+            }
+            let topic_len = pid(body)? as usize;
+            let topic_end = 2 + topic_len;
+            let topic = body
+                .get(2..topic_end)
+                .and_then(|t| core::str::from_utf8(t).ok())
+                .ok_or(PacketError::Malformed)?;
+            let (pid, rest_start) = if qos > 0 {
+                (
+                    Some(pid(body.get(topic_end..).unwrap_or_default())?),
+                    topic_end + 2,
+                )
+            } else {
+                (None, topic_end)
+            };
+            let rest = body.get(rest_start..).ok_or(PacketError::Malformed)?;
+            let (properties, payload) = if v5 {
+                properties::split_block(rest)?
+            } else {
+                (Properties::empty(), rest)
+            };
+            Inbound::Publish(InboundPublish {
+                topic,
+                pid,
+                qos,
+                retain: header & 0x01 != 0,
+                dup: header & 0x08 != 0,
+                properties,
+                payload,
+            })
+        }
+        PUBACK => {
+            let (pid, reason) = ack(body)?;
+            Inbound::Puback { pid, reason }
+        }
+        PUBREC => {
+            let (pid, reason) = ack(body)?;
+            Inbound::Pubrec { pid, reason }
+        }
+        PUBREL => {
+            let (pid, reason) = ack(body)?;
+            Inbound::Pubrel { pid, reason }
+        }
+        PUBCOMP => {
+            let (pid, reason) = ack(body)?;
+            Inbound::Pubcomp { pid, reason }
+        }
+        SUBACK => Inbound::Suback {
+            pid: pid(body)?,
+            return_codes: after_pid(body, v5)?,
+        },
+        UNSUBACK => Inbound::Unsuback {
+            pid: pid(body)?,
+            reason_codes: after_pid(body, v5)?,
+        },
+        PINGRESP => Inbound::Pingresp,
+        DISCONNECT => Inbound::Disconnect {
+            reason: body.first().copied().unwrap_or(0),
+        },
+        other => Inbound::Unexpected(other),
+    };
+    Ok(Some((packet, total)))
+}
+
+/// What follows the packet identifier and, with MQTT 5, the properties
+fn after_pid(body: &[u8], v5: bool) -> Result<&[u8], PacketError> {
+    let rest = body.get(2..).ok_or(PacketError::Malformed)?;
+    if v5 {
+        Ok(properties::split_block(rest)?.1)
+    } else {
+        Ok(rest)
+    }
+}
+
+/// PUBLISH fixed header flags and packet identifier
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PublishFlags {
+    pub qos: u8,
+    /// Required for QoS 1 and 2
+    pub pid: Option<u16>,
+    pub retain: bool,
+    pub dup: bool,
+}
+
+/// Encode a PUBLISH packet
+///
+/// `properties` are only written with MQTT 5. With a topic alias property the
+/// topic may be empty, the broker uses the topic it was first sent with.
+pub fn encode_publish(
+    topic: &str,
+    payload: &[u8],
+    flags: PublishFlags,
+    properties: &[Property],
+    version: ProtocolVersion,
+    out: &mut [u8],
+) -> Result<usize, PacketError> {
+    let qos = flags.qos;
+    let pid = match (qos, flags.pid) {
+        (0, _) => None,
+        (1 | 2, Some(pid)) => Some(pid),
+        _ => return Err(PacketError::Malformed),
+    };
+    let props_len = match version {
+        ProtocolVersion::V5 => properties::block_len(properties),
+        ProtocolVersion::V311 => 0,
+    };
+    let remaining = 2 + topic.len() + pid.map_or(0, |_| 2) + props_len + payload.len();
+    let first = (PUBLISH << 4) | ((flags.dup as u8) << 3) | (qos << 1) | flags.retain as u8;
+    let mut w = Writer::new(out);
+    w.header(first, remaining)?;
+    w.str(topic)?;
+    if let Some(pid) = pid {
+        w.u16(pid)?;
+    }
+    if version == ProtocolVersion::V5 {
+        properties::write_block(&mut w, properties)?;
+    }
+    w.bytes(payload)?;
+    Ok(w.pos)
+}
+
+/// Encode a SUBSCRIBE packet for `filters`, each with its requested QoS
+///
+/// With MQTT 5 the QoS byte is the subscription options byte, the other options
+/// (no local, retain as published, retain handling) are left to their defaults.
+pub fn encode_subscribe(
+    pid: u16,
+    filters: &[(&str, u8)],
+    version: ProtocolVersion,
+    out: &mut [u8],
+) -> Result<usize, PacketError> {
+    let props_len = (version == ProtocolVersion::V5) as usize;
+    let remaining = 2
+        + props_len
+        + filters
+            .iter()
+            .map(|(filter, _)| 2 + filter.len() + 1)
+            .sum::<usize>();
+    let mut w = Writer::new(out);
+    w.header((SUBSCRIBE << 4) | 0x02, remaining)?;
+    w.u16(pid)?;
+    if version == ProtocolVersion::V5 {
+        properties::write_block(&mut w, &[])?;
+    }
+    for (filter, qos) in filters {
+        w.str(filter)?;
+        w.bytes(&[*qos])?;
+    }
+    Ok(w.pos)
+}
+
+/// Encode an UNSUBSCRIBE packet for `filters`
+pub fn encode_unsubscribe(
+    pid: u16,
+    filters: &[&str],
+    version: ProtocolVersion,
+    out: &mut [u8],
+) -> Result<usize, PacketError> {
+    let props_len = (version == ProtocolVersion::V5) as usize;
+    let remaining = 2 + props_len + filters.iter().map(|f| 2 + f.len()).sum::<usize>();
+    let mut w = Writer::new(out);
+    w.header((UNSUBSCRIBE << 4) | 0x02, remaining)?;
+    w.u16(pid)?;
+    if version == ProtocolVersion::V5 {
+        properties::write_block(&mut w, &[])?;
+    }
+    for filter in filters {
+        w.str(filter)?;
+    }
+    Ok(w.pos)
+}
+
+/// Last will of an MQTT 5 CONNECT
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Will<'a> {
+    pub topic: &'a str,
+    pub payload: &'a [u8],
+    pub qos: u8,
+    pub retain: bool,
+}
+
+/// Content of an MQTT 5 CONNECT
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConnectV5<'a> {
+    pub client_id: &'a str,
+    pub keep_alive: u16,
+    pub clean_start: bool,
+    pub properties: &'a [Property<'a>],
+    pub will: Option<Will<'a>>,
+    pub username: Option<&'a str>,
+    pub password: Option<&'a [u8]>,
+}
+
+/// Encode an MQTT 5 CONNECT, the 3.1.1 one is encoded by `mqttrust`
+pub fn encode_connect_v5(connect: &ConnectV5, out: &mut [u8]) -> Result<usize, PacketError> {
+    let mut flags = (connect.clean_start as u8) << 1;
+    let mut remaining = 2 + 4 + 1 + 1 + 2 + properties::block_len(connect.properties);
+    remaining += 2 + connect.client_id.len();
+    if let Some(will) = &connect.will {
+        flags |= 0x04 | (will.qos << 3) | ((will.retain as u8) << 5);
+        // No will properties
+        remaining += 1 + 2 + will.topic.len() + 2 + will.payload.len();
+    }
+    if let Some(username) = connect.username {
+        flags |= 0x80;
+        remaining += 2 + username.len();
+    }
+    if let Some(password) = connect.password {
+        flags |= 0x40;
+        remaining += 2 + password.len();
+    }
+
+    let mut w = Writer::new(out);
+    w.header(CONNECT << 4, remaining)?;
+    w.str("MQTT")?;
+    w.bytes(&[5, flags])?;
+    w.u16(connect.keep_alive)?;
+    properties::write_block(&mut w, connect.properties)?;
+    w.str(connect.client_id)?;
+    if let Some(will) = &connect.will {
+        properties::write_block(&mut w, &[])?;
+        w.str(will.topic)?;
+        w.binary(will.payload)?;
+    }
+    if let Some(username) = connect.username {
+        w.str(username)?;
+    }
+    if let Some(password) = connect.password {
+        w.binary(password)?;
+    }
+    Ok(w.pos)
+}
+
+/// Encode the acknowledgement of an inbound PUBLISH or of a QoS 2 step
+pub fn encode_ack(ack: Ack, out: &mut [u8]) -> Result<usize, PacketError> {
+    let (header, pid) = match ack {
+        Ack::Puback(pid) => (PUBACK << 4, pid),
+        Ack::Pubrec(pid) => (PUBREC << 4, pid),
+        // PUBREL has the reserved flags set like SUBSCRIBE
+        Ack::Pubrel(pid) => ((PUBREL << 4) | 0x02, pid),
+        Ack::Pubcomp(pid) => (PUBCOMP << 4, pid),
+    };
+    let mut w = Writer::new(out);
+    w.header(header, 2)?;
+    w.u16(pid)?;
+    Ok(w.pos)
+}
+
+pub(super) struct Writer<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> Writer<'a> {
+    pub(super) fn new(buf: &'a mut [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.pos
+    }
+
+    pub(super) fn bytes(&mut self, data: &[u8]) -> Result<(), PacketError> {
+        let end = self.pos + data.len();
+        self.buf
+            .get_mut(self.pos..end)
+            .ok_or(PacketError::BufferTooSmall)?
+            .copy_from_slice(data);
+        self.pos = end;
+        Ok(())
+    }
+
+    pub(super) fn u16(&mut self, value: u16) -> Result<(), PacketError> {
+        self.bytes(&value.to_be_bytes())
+    }
+
+    pub(super) fn str(&mut self, value: &str) -> Result<(), PacketError> {
+        self.binary(value.as_bytes())
+    }
+
+    /// Length-prefixed binary data
+    pub(super) fn binary(&mut self, value: &[u8]) -> Result<(), PacketError> {
+        let len = u16::try_from(value.len()).map_err(|_| PacketError::Malformed)?;
+        self.u16(len)?;
+        self.bytes(value)
+    }
+
+    /// Variable byte integer
+    pub(super) fn varint(&mut self, mut value: usize) -> Result<(), PacketError> {
+        if value > 268_435_455 {
+            return Err(PacketError::MalformedLength);
+        }
+        loop {
+            let mut byte = (value % 128) as u8;
+            value /= 128;
+            if value > 0 {
+                byte |= 0x80;
+            }
+            self.bytes(&[byte])?;
+            if value == 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    fn header(&mut self, first: u8, remaining: usize) -> Result<(), PacketError> {
+        self.bytes(&[first])?;
+        self.varint(remaining)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const V311: ProtocolVersion = ProtocolVersion::V311;
+    const V5: ProtocolVersion = ProtocolVersion::V5;
+
+    type PacketLen = Result<Option<usize>, PacketError>;
+
+    #[test]
+    fn reads_remaining_lengths() {
+        let cases: &[(&[u8], PacketLen)] = &[
+            (&[], Ok(None)),
+            (&[0x30], Ok(None)),
+            (&[0x30, 0x00], Ok(Some(2))),
+            (&[0x30, 0x7F], Ok(Some(2 + 127))),
+            (&[0x30, 0x80], Ok(None)),
+            (&[0x30, 0x80, 0x01], Ok(Some(3 + 128))),
+            (&[0x30, 0xFF, 0xFF, 0xFF], Ok(None)),
+            (&[0x30, 0xFF, 0xFF, 0xFF, 0x7F], Ok(Some(5 + 268_435_455))),
+            // A fifth length byte
+            (
+                &[0x30, 0xFF, 0xFF, 0xFF, 0xFF],
+                Err(PacketError::MalformedLength),
+            ),
+            (
+                &[0x30, 0x80, 0x80, 0x80, 0x80, 0x01],
+                Err(PacketError::MalformedLength),
+            ),
+        ];
+        for (buf, expected) in cases {
+            assert_eq!(packet_len(buf), *expected, "{buf:02X?}");
+        }
+        assert_eq!(
+            decode(&[0x30, 0xFF, 0xFF, 0xFF, 0xFF], V311),
+            Err(PacketError::MalformedLength)
+        );
+    }
+
+    #[test]
+    fn waits_for_the_whole_packet() {
+        let puback = [0x40, 0x02, 0x00, 0x07];
+        for len in 0..puback.len() {
+            assert_eq!(decode(&puback[..len], V311), Ok(None), "{len} bytes");
+        }
+        // The next packet is left in the buffer
+        let buf = [0x40, 0x02, 0x00, 0x07, 0xD0];
+        let expected = Inbound::Puback { pid: 7, reason: 0 };
+        assert_eq!(decode(&buf, V311), Ok(Some((expected, 4))));
+    }
+
+    #[test]
+    fn rejects_malformed_packets() {
+        let cases: &[(&str, &[u8], ProtocolVersion)] = &[
+            ("CONNACK without return code", &[0x20, 0x01, 0x00], V311),
+            (
+                "PUBACK without packet identifier",
+                &[0x40, 0x01, 0x00],
+                V311,
+            ),
+            ("PUBREC without packet identifier", &[0x50, 0x00], V5),
+            (
+                "SUBACK without packet identifier",
+                &[0x90, 0x01, 0x00],
+                V311,
+            ),
+            ("UNSUBACK without properties", &[0xB0, 0x02, 0x00, 0x01], V5),
+            (
+                "PUBLISH with QoS 3",
+                &[0x36, 0x05, 0x00, 0x01, b't', 0x00, 0x01],
+                V311,
+            ),
+            (
+                "topic length beyond the packet",
+                &[0x30, 0x03, 0x00, 0x05, b'a'],
+                V311,
+            ),
+            ("topic length cut short", &[0x30, 0x01, 0x00], V311),
+            (
+                "QoS 1 without packet identifier",
+                &[0x32, 0x03, 0x00, 0x01, b't'],
+                V311,
+            ),
+            ("topic not UTF-8", &[0x30, 0x03, 0x00, 0x01, 0xFF], V311),
+            (
+                "property block beyond the packet",
+                &[0x30, 0x05, 0x00, 0x01, b't', 0x05, 0x23],
+                V5,
+            ),
+            (
+                "PUBLISH without properties",
+                &[0x30, 0x03, 0x00, 0x01, b't'],
+                V5,
+            ),
+        ];
+        for (name, buf, version) in cases {
+            assert_eq!(decode(buf, *version), Err(PacketError::Malformed), "{name}");
+        }
+    }
+
+    #[test]
+    fn decodes_publish_in_both_versions() {
+        let v311 = [0x3B, 0x07, 0x00, 0x01, b't', 0x00, 0x09, b'h', b'i'];
+        let Ok(Some((Inbound::Publish(publish), 9))) = decode(&v311, V311) else {
+            panic!("not a PUBLISH");
+        };
+        assert_eq!(
+            (
+                publish.topic,
+                publish.pid,
+                publish.qos,
+                publish.retain,
+                publish.dup
+            ),
+            ("t", Some(9), 1, true, true)
+        );
+        assert_eq!(publish.payload, b"hi");
+
+        // Same with a response topic, and an empty payload
+        let v5 = [
+            0x30, 0x09, 0x00, 0x01, b't', 0x05, 0x08, 0x00, 0x02, b'r', b'/',
+        ];
+        let Ok(Some((Inbound::Publish(publish), 11))) = decode(&v5, V5) else {
+            panic!("not a PUBLISH");
+        };
+        assert_eq!((publish.pid, publish.payload), (None, &[][..]));
+        assert_eq!(publish.response_topic(), Some("r/"));
+    }
+
+    #[test]
+    fn reads_reason_codes_with_mqtt_5_only() {
+        let puback = [0x40, 0x03, 0x00, 0x01, 0x87];
+        let refused = Inbound::Puback {
+            pid: 1,
+            reason: 0x87,
+        };
+        assert_eq!(decode(&puback, V5), Ok(Some((refused, 5))));
+        let accepted = Inbound::Puback { pid: 1, reason: 0 };
+        assert_eq!(decode(&puback, V311), Ok(Some((accepted, 5))));
+        // Omitted, success
+        assert_eq!(
+            decode(&[0x40, 0x02, 0x00, 0x01], V5),
+            Ok(Some((accepted, 4)))
+        );
+
+        let suback = [0x90, 0x05, 0x00, 0x01, 0x00, 0x01, 0x80];
+        let Ok(Some((Inbound::Suback { pid, return_codes }, _))) = decode(&suback, V5) else {
+            panic!("not a SUBACK");
+        };
+        assert_eq!((pid, return_codes), (1, &[0x01, SUBACK_FAILURE][..]));
+    }
+
+    #[test]
+    fn reports_packets_a_broker_never_sends() {
+        assert_eq!(
+            decode(&[0x82, 0x00], V311),
+            Ok(Some((Inbound::Unexpected(SUBSCRIBE), 2)))
+        );
+        assert_eq!(
+            decode(&[0xE0, 0x00], V5),
+            Ok(Some((Inbound::Disconnect { reason: 0 }, 2)))
+        );
+    }
+}
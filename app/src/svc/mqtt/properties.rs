@@ -0,0 +1,442 @@
+//! MQTT 5 properties
+//!
+//! Properties are a length-prefixed list of `identifier, value` pairs carried by
+//! most MQTT 5 packets. The value type is implied by the identifier.
+use super::packet::{PacketError, Writer};
+
+pub const PAYLOAD_FORMAT_INDICATOR: u8 = 0x01;
+pub const MESSAGE_EXPIRY_INTERVAL: u8 = 0x02;
+pub const CONTENT_TYPE: u8 = 0x03;
+pub const RESPONSE_TOPIC: u8 = 0x08;
+pub const CORRELATION_DATA: u8 = 0x09;
+pub const SUBSCRIPTION_IDENTIFIER: u8 = 0x0B;
+pub const SESSION_EXPIRY_INTERVAL: u8 = 0x11;
+pub const ASSIGNED_CLIENT_IDENTIFIER: u8 = 0x12;
+pub const SERVER_KEEP_ALIVE: u8 = 0x13;
+pub const REASON_STRING: u8 = 0x1F;
+pub const RECEIVE_MAXIMUM: u8 = 0x21;
+pub const TOPIC_ALIAS_MAXIMUM: u8 = 0x22;
+pub const TOPIC_ALIAS: u8 = 0x23;
+pub const MAXIMUM_QOS: u8 = 0x24;
+pub const USER_PROPERTY: u8 = 0x26;
+pub const MAXIMUM_PACKET_SIZE: u8 = 0x27;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Property<'a> {
+    Byte(u8, u8),
+    U16(u8, u16),
+    U32(u8, u32),
+    VarInt(u8, u32),
+    Str(u8, &'a str),
+    Binary(u8, &'a [u8]),
+    /// User property, a name and a value
+    Pair(u8, &'a str, &'a str),
+}
+
+impl Property<'_> {
+    pub fn id(&self) -> u8 {
+        match self {
+            Property::Byte(id, _)
+            | Property::U16(id, _)
+            | Property::U32(id, _)
+            | Property::VarInt(id, _)
+            | Property::Str(id, _)
+            | Property::Binary(id, _)
+            | Property::Pair(id, _, _) => *id,
+        }
+    }
+
+    /// Encoded length, identifier included
+    pub fn encoded_len(&self) -> usize {
+        1 + match self {
+            Property::Byte(..) => 1,
+            Property::U16(..) => 2,
+            Property::U32(..) => 4,
+            Property::VarInt(_, value) => varint_len(*value as usize),
+            Property::Str(_, s) => 2 + s.len(),
+            Property::Binary(_, b) => 2 + b.len(),
+            Property::Pair(_, k, v) => 4 + k.len() + v.len(),
+        }
+    }
+
+    pub(super) fn write(&self, w: &mut Writer<'_>) -> Result<(), PacketError> {
+        w.bytes(&[self.id()])?;
+        match self {
+            Property::Byte(_, value) => w.bytes(&[*value]),
+            Property::U16(_, value) => w.u16(*value),
+            Property::U32(_, value) => w.bytes(&value.to_be_bytes()),
+            Property::VarInt(_, value) => w.varint(*value as usize),
+            Property::Str(_, s) => w.str(s),
+            Property::Binary(_, b) => w.binary(b),
+            Property::Pair(_, k, v) => {
+                w.str(k)?;
+                w.str(v)
+            }
+        }
+    }
+}
+
+/// Number of bytes of a variable byte integer
+pub fn varint_len(value: usize) -> usize {
+    match value {
+        0..=127 => 1,
+        128..=16_383 => 2,
+        16_384..=2_097_151 => 3,
+        _ => 4,
+    }
+}
+
+/// Length of the property list, without its own length prefix
+pub fn list_len(properties: &[Property]) -> usize {
+    properties.iter().map(Property::encoded_len).sum()
+}
+
+/// Length of the property list with its length prefix
+pub fn block_len(properties: &[Property]) -> usize {
+    let len = list_len(properties);
+    varint_len(len) + len
+}
+
+pub(super) fn write_block(w: &mut Writer<'_>, properties: &[Property]) -> Result<(), PacketError> {
+    w.varint(list_len(properties))?;
+    for property in properties {
+        property.write(w)?;
+    }
+    Ok(())
+}
+
+/// Encode the property list without its length prefix, read back with [`Properties::new`]
+pub fn encode_list(properties: &[Property], out: &mut [u8]) -> Result<usize, PacketError> {
+    let mut w = Writer::new(out);
+    for property in properties {
+        property.write(&mut w)?;
+    }
+    Ok(w.len())
+}
+
+/// Decode a variable byte integer, returns the value and its length
+pub fn read_varint(buf: &[u8]) -> Result<(usize, usize), PacketError> {
+    let mut value = 0usize;
+    for i in 0..4 {
+        let byte = *buf.get(i).ok_or(PacketError::Malformed)?;
+        value |= ((byte & 0x7F) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok((value, i + 1));
+        }
+    }
+    Err(PacketError::MalformedLength)
+}
+
+/// Split the property block at the start of `buf`, returns the properties and the rest
+pub fn split_block(buf: &[u8]) -> Result<(Properties<'_>, &[u8]), PacketError> {
+    let (len, used) = read_varint(buf)?;
+    let block = buf.get(used..used + len).ok_or(PacketError::Malformed)?;
+    Ok((Properties { buf: block }, &buf[used + len..]))
+}
+
+/// Received property list, iterated lazily
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Properties<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Properties<'a> {
+    pub const fn empty() -> Self {
+        Self { buf: &[] }
+    }
+
+    /// Property list without its length prefix
+    pub const fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    pub fn iter(&self) -> PropertyIter<'a> {
+        PropertyIter { buf: self.buf }
+    }
+
+    pub fn find(&self, id: u8) -> Option<Property<'a>> {
+        self.iter().flatten().find(|p| p.id() == id)
+    }
+
+    pub fn u16(&self, id: u8) -> Option<u16> {
+        match self.find(id)? {
+            Property::U16(_, value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn u32(&self, id: u8) -> Option<u32> {
+        match self.find(id)? {
+            Property::U32(_, value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn str(&self, id: u8) -> Option<&'a str> {
+        match self.find(id)? {
+            Property::Str(_, value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn binary(&self, id: u8) -> Option<&'a [u8]> {
+        match self.find(id)? {
+            Property::Binary(_, value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// User properties as name/value pairs
+    pub fn user_properties(&self) -> impl Iterator<Item = (&'a str, &'a str)> {
+        self.iter().flatten().filter_map(|p| match p {
+            Property::Pair(_, k, v) => Some((k, v)),
+            _ => None,
+        })
+    }
+}
+
+pub struct PropertyIter<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Iterator for PropertyIter<'a> {
+    type Item = Result<Property<'a>, PacketError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (&id, rest) = self.buf.split_first()?;
+        let res = decode_value(id, rest);
+        match res {
+            Ok((property, used)) => {
+                self.buf = &rest[used..];
+                Some(Ok(property))
+            }
+            Err(e) => {
+                // Stop at the first malformed property
+                self.buf = &[];
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+fn decode_value(id: u8, buf: &[u8]) -> Result<(Property<'_>, usize), PacketError> {
+    Ok(match id {
+        0x01 | 0x17 | 0x19 | 0x24 | 0x25 | 0x28 | 0x29 | 0x2A => (
+            Property::Byte(id, *buf.first().ok_or(PacketError::Malformed)?),
+            1,
+        ),
+        0x13 | 0x21 | 0x22 | 0x23 => match buf {
+            [hi, lo, ..] => (Property::U16(id, u16::from_be_bytes([*hi, *lo])), 2),
+            _ => return Err(PacketError::Malformed),
+        },
+        0x02 | 0x11 | 0x18 | 0x27 => match buf {
+            [a, b, c, d, ..] => (Property::U32(id, u32::from_be_bytes([*a, *b, *c, *d])), 4),
+            _ => return Err(PacketError::Malformed),
+        },
+        0x0B => {
+            let (value, used) = read_varint(buf)?;
+            (Property::VarInt(id, value as u32), used)
+        }
+        0x03 | 0x08 | 0x12 | 0x15 | 0x1A | 0x1C | 0x1F => {
+            let (value, used) = str_at(buf)?;
+            (Property::Str(id, value), used)
+        }
+        0x09 | 0x16 => {
+            let (value, used) = binary_at(buf)?;
+            (Property::Binary(id, value), used)
+        }
+        0x26 => {
+            let (key, used) = str_at(buf)?;
+            let (value, used_value) = str_at(&buf[used..])?;
+            (Property::Pair(id, key, value), used + used_value)
+        }
+        _ => return Err(PacketError::Malformed),
+    })
+}
+
+fn str_at(buf: &[u8]) -> Result<(&str, usize), PacketError> {
+    let (bytes, used) = binary_at(buf)?;
+    let value = core::str::from_utf8(bytes).map_err(|_| PacketError::Malformed)?;
+    Ok((value, used))
+}
+
+fn binary_at(buf: &[u8]) -> Result<(&[u8], usize), PacketError> {
+    match buf {
+        [hi, lo, rest @ ..] => {
+            let len = u16::from_be_bytes([*hi, *lo]) as usize;
+            let value = rest.get(..len).ok_or(PacketError::Malformed)?;
+            Ok((value, 2 + len))
+        }
+        _ => Err(PacketError::Malformed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EVERY_TYPE: &[Property<'static>] = &[
+        Property::Byte(PAYLOAD_FORMAT_INDICATOR, 1),
+        Property::U16(TOPIC_ALIAS, 3),
+        Property::U32(MESSAGE_EXPIRY_INTERVAL, 60),
+        Property::VarInt(SUBSCRIPTION_IDENTIFIER, 268_435_455),
+        Property::Str(CONTENT_TYPE, "application/json"),
+        Property::Binary(CORRELATION_DATA, &[0x00, 0xFF]),
+        Property::Pair(USER_PROPERTY, "k", "v"),
+    ];
+
+    fn varint(value: usize) -> Result<std::vec::Vec<u8>, PacketError> {
+        let mut buf = [0u8; 4];
+        let mut w = Writer::new(&mut buf);
+        w.varint(value)?;
+        let len = w.len();
+        Ok(buf[..len].to_vec())
+    }
+
+    #[test]
+    fn encodes_each_value_type() {
+        let mut buf = [0u8; 64];
+        let cases: &[(Property, &[u8])] = &[
+            (Property::Byte(MAXIMUM_QOS, 1), &[0x24, 0x01]),
+            (Property::U16(TOPIC_ALIAS, 3), &[0x23, 0x00, 0x03]),
+            (
+                Property::U32(MESSAGE_EXPIRY_INTERVAL, 60),
+                &[0x02, 0x00, 0x00, 0x00, 0x3C],
+            ),
+            (
+                Property::VarInt(SUBSCRIPTION_IDENTIFIER, 128),
+                &[0x0B, 0x80, 0x01],
+            ),
+            (Property::Str(CONTENT_TYPE, "a"), &[0x03, 0x00, 0x01, b'a']),
+            (
+                Property::Binary(CORRELATION_DATA, &[0xAB]),
+                &[0x09, 0x00, 0x01, 0xAB],
+            ),
+            (
+                Property::Pair(USER_PROPERTY, "k", "v"),
+                &[0x26, 0x00, 0x01, b'k', 0x00, 0x01, b'v'],
+            ),
+        ];
+        for (property, encoded) in cases {
+            let len = encode_list(core::slice::from_ref(property), &mut buf).unwrap();
+            assert_eq!(&buf[..len], *encoded, "{property:?}");
+            assert_eq!(property.encoded_len(), encoded.len(), "{property:?}");
+        }
+    }
+
+    #[test]
+    fn round_trips_a_property_list() {
+        let mut buf = [0u8; 64];
+        let len = encode_list(EVERY_TYPE, &mut buf).unwrap();
+        assert_eq!(len, list_len(EVERY_TYPE));
+        let decoded: std::vec::Vec<_> = Properties::new(&buf[..len]).iter().collect();
+        let expected: std::vec::Vec<_> = EVERY_TYPE.iter().map(|p| Ok(*p)).collect();
+        assert_eq!(decoded, expected);
+
+        let properties = Properties::new(&buf[..len]);
+        assert_eq!(properties.u16(TOPIC_ALIAS), Some(3));
+        assert_eq!(properties.u32(MESSAGE_EXPIRY_INTERVAL), Some(60));
+        assert_eq!(properties.str(CONTENT_TYPE), Some("application/json"));
+        assert_eq!(properties.binary(CORRELATION_DATA), Some(&[0x00, 0xFF][..]));
+        assert!(properties.user_properties().eq([("k", "v")]));
+        // Absent, or of another type
+        assert_eq!(properties.u16(RECEIVE_MAXIMUM), None);
+        assert_eq!(properties.u32(TOPIC_ALIAS), None);
+    }
+
+    #[test]
+    fn round_trips_a_property_block() {
+        let mut buf = [0u8; 256];
+        let mut w = Writer::new(&mut buf);
+        write_block(&mut w, EVERY_TYPE).unwrap();
+        w.bytes(b"rest").unwrap();
+        let len = w.len();
+        assert_eq!(len, block_len(EVERY_TYPE) + 4);
+
+        let (properties, rest) = split_block(&buf[..len]).unwrap();
+        assert_eq!(rest, b"rest");
+        assert_eq!(properties.iter().count(), EVERY_TYPE.len());
+        // Empty block
+        let (properties, rest) = split_block(&[0x00, 0xAA]).unwrap();
+        assert_eq!((properties, rest), (Properties::empty(), &[0xAA][..]));
+    }
+
+    #[test]
+    fn variable_byte_integer_boundaries() {
+        let cases: &[(usize, &[u8])] = &[
+            (0, &[0x00]),
+            (127, &[0x7F]),
+            (128, &[0x80, 0x01]),
+            (16_383, &[0xFF, 0x7F]),
+            (16_384, &[0x80, 0x80, 0x01]),
+            (2_097_151, &[0xFF, 0xFF, 0x7F]),
+            (2_097_152, &[0x80, 0x80, 0x80, 0x01]),
+            (268_435_455, &[0xFF, 0xFF, 0xFF, 0x7F]),
+        ];
+        for (value, encoded) in cases {
+            assert_eq!(varint(*value).unwrap(), *encoded, "{value}");
+            assert_eq!(varint_len(*value), encoded.len(), "{value}");
+            assert_eq!(read_varint(encoded), Ok((*value, encoded.len())));
+        }
+        assert_eq!(varint(268_435_456), Err(PacketError::MalformedLength));
+    }
+
+    #[test]
+    fn rejects_malformed_variable_byte_integers() {
+        // A fifth byte
+        let too_long = [0xFF, 0xFF, 0xFF, 0xFF, 0x01];
+        assert_eq!(read_varint(&too_long), Err(PacketError::MalformedLength));
+        // Continuation bit set on the last byte available
+        assert_eq!(read_varint(&[0x80]), Err(PacketError::Malformed));
+        assert_eq!(read_varint(&[]), Err(PacketError::Malformed));
+    }
+
+    #[test]
+    fn rejects_truncated_blocks() {
+        // Block length beyond the packet
+        assert_eq!(
+            split_block(&[0x05, 0x23, 0x00]),
+            Err(PacketError::Malformed)
+        );
+        assert_eq!(split_block(&[0x80]), Err(PacketError::Malformed));
+
+        // Each property cut short, then the iteration stops
+        let truncated: &[&[u8]] = &[
+            &[0x24],
+            &[0x23, 0x00],
+            &[0x02, 0x00, 0x00, 0x00],
+            &[0x0B, 0x80],
+            &[0x03, 0x00, 0x05, b'a', b'b'],
+            &[0x09, 0x00],
+            &[0x26, 0x00, 0x01, b'k', 0x00, 0x02, b'v'],
+        ];
+        for block in truncated {
+            let mut iter = Properties::new(block).iter();
+            assert!(matches!(iter.next(), Some(Err(_))), "{block:02X?}");
+            assert_eq!(iter.next(), None);
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_identifiers_and_invalid_strings() {
+        // Valid first property, then an unknown identifier
+        let block = [0x23, 0x00, 0x01, 0x7F, 0x00];
+        let mut iter = Properties::new(&block).iter();
+        assert_eq!(iter.next(), Some(Ok(Property::U16(TOPIC_ALIAS, 1))));
+        assert_eq!(iter.next(), Some(Err(PacketError::Malformed)));
+        assert_eq!(iter.next(), None);
+        // The valid one is still found
+        assert_eq!(Properties::new(&block).u16(TOPIC_ALIAS), Some(1));
+
+        let invalid_utf8 = [0x03, 0x00, 0x01, 0xFF];
+        let mut iter = Properties::new(&invalid_utf8).iter();
+        assert_eq!(iter.next(), Some(Err(PacketError::Malformed)));
+    }
+
+    #[test]
+    fn reports_a_full_output_buffer() {
+        let mut buf = [0u8; 3];
+        let result = encode_list(&[Property::U32(MESSAGE_EXPIRY_INTERVAL, 1)], &mut buf);
+        assert_eq!(result, Err(PacketError::BufferTooSmall));
+    }
+}
@@ -0,0 +1,125 @@
+//! In-memory transport playing a recorded broker session, for the client tests
+//!
+//! The script lists, in order, the bytes the client must write and the bytes the
+//! broker answers. A read with nothing to deliver moves the mock clock forward,
+//! so timeouts and the keep-alive run without waiting.
+use core::convert::Infallible;
+use core::future::{poll_fn, Future};
+use core::task::Poll;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::vec::Vec;
+
+use embassy_time::{Duration, MockDriver};
+use embedded_io_async::{ErrorType, Read, Write};
+
+/// Time passing on each read finding nothing to deliver
+const IDLE_STEP: Duration = Duration::from_millis(100);
+
+/// The mock clock is shared by the whole test binary
+static CLOCK: Mutex<()> = Mutex::new(());
+
+/// Run `session` to completion, alone on the mock clock
+pub fn run<F: Future>(session: F) -> F::Output {
+    let _clock = CLOCK
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    embassy_futures::block_on(session)
+}
+
+enum Step {
+    /// Bytes the client must write next
+    Expect(Vec<u8>),
+    /// Bytes the broker sends, taken by the next reads
+    Send(Vec<u8>),
+    /// The broker closes the connection
+    Close,
+}
+
+#[derive(Default)]
+pub struct ScriptedTransport {
+    steps: VecDeque<Step>,
+}
+
+impl ScriptedTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn expect(mut self, bytes: &[u8]) -> Self {
+        self.steps.push_back(Step::Expect(bytes.to_vec()));
+        self
+    }
+
+    pub fn send(mut self, bytes: &[u8]) -> Self {
+        self.steps.push_back(Step::Send(bytes.to_vec()));
+        self
+    }
+
+    pub fn close(mut self) -> Self {
+        self.steps.push_back(Step::Close);
+        self
+    }
+
+    /// Check the session went through the whole script
+    pub fn finish(self) {
+        if let Some(step) = self.steps.front() {
+            match step {
+                Step::Expect(bytes) => panic!("client never wrote {:02X?}", bytes),
+                Step::Send(bytes) => panic!("client never read {:02X?}", bytes),
+                Step::Close => panic!("client never saw the connection close"),
+            }
+        }
+    }
+}
+
+impl ErrorType for ScriptedTransport {
+    type Error = Infallible;
+}
+
+impl Read for ScriptedTransport {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Infallible> {
+        poll_fn(|_| match self.steps.front_mut() {
+            Some(Step::Send(bytes)) => {
+                let len = bytes.len().min(buf.len());
+                buf[..len].copy_from_slice(&bytes[..len]);
+                bytes.drain(..len);
+                if bytes.is_empty() {
+                    self.steps.pop_front();
+                }
+                Poll::Ready(Ok(len))
+            }
+            Some(Step::Close) => {
+                self.steps.pop_front();
+                Poll::Ready(Ok(0))
+            }
+            // The executor polls again right away, the clock drives the timeouts
+            _ => {
+                MockDriver::get().advance(IDLE_STEP);
+                Poll::Pending
+            }
+        })
+        .await
+    }
+}
+
+impl Write for ScriptedTransport {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Infallible> {
+        let Some(Step::Expect(expected)) = self.steps.front_mut() else {
+            panic!("unexpected write of {:02X?}", buf);
+        };
+        let len = buf.len().min(expected.len());
+        assert_eq!(
+            buf[..len],
+            expected[..len],
+            "client wrote {:02X?}, script expects {:02X?}",
+            buf,
+            expected
+        );
+        expected.drain(..len);
+        if expected.is_empty() {
+            self.steps.pop_front();
+        }
+        Ok(len)
+    }
+}
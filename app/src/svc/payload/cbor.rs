@@ -0,0 +1,253 @@
+//! CBOR (RFC 8949), messages are maps keyed by the field names
+use super::{EncodeError, Field, Output, PayloadEncoder, MAX_DEPTH};
+
+const MAJOR_UINT: u8 = 0;
+const MAJOR_NEGATIVE: u8 = 1;
+const MAJOR_BYTES: u8 = 2;
+const MAJOR_TEXT: u8 = 3;
+const MAJOR_ARRAY: u8 = 4;
+const MAJOR_MAP: u8 = 5;
+const FALSE: u8 = 0xF4;
+const TRUE: u8 = 0xF5;
+const FLOAT32: u8 = 0xFA;
+const FLOAT64: u8 = 0xFB;
+
+pub struct CborEncoder<'a> {
+    out: Output<'a>,
+    /// `true` for a map, `false` for an array
+    stack: heapless::Vec<bool, MAX_DEPTH>,
+}
+
+impl<'a> CborEncoder<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Self {
+            out: Output::new(buf),
+            stack: heapless::Vec::new(),
+        }
+    }
+
+    fn head(&mut self, major: u8, value: u64) -> Result<(), EncodeError> {
+        let major = major << 5;
+        match value {
+            0..=23 => self.out.byte(major | value as u8),
+            24..=0xFF => self.out.bytes(&[major | 24, value as u8]),
+            0x100..=0xFFFF => {
+                self.out.byte(major | 25)?;
+                self.out.bytes(&(value as u16).to_be_bytes())
+            }
+            0x1_0000..=0xFFFF_FFFF => {
+                self.out.byte(major | 26)?;
+                self.out.bytes(&(value as u32).to_be_bytes())
+            }
+            _ => {
+                self.out.byte(major | 27)?;
+                self.out.bytes(&value.to_be_bytes())
+            }
+        }
+    }
+
+    fn key(&mut self, field: Field) -> Result<(), EncodeError> {
+        if self.stack.last() == Some(&true) {
+            self.head(MAJOR_TEXT, field.name.len() as u64)?;
+            self.out.bytes(field.name.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn open(&mut self, field: Field, map: bool, len: usize) -> Result<(), EncodeError> {
+        self.key(field)?;
+        self.stack.push(map).map_err(|_| EncodeError::TooDeep)?;
+        self.head(if map { MAJOR_MAP } else { MAJOR_ARRAY }, len as u64)
+    }
+
+    fn close(&mut self, map: bool) -> Result<(), EncodeError> {
+        match self.stack.pop() {
+            Some(kind) if kind == map => Ok(()),
+            _ => Err(EncodeError::Unbalanced),
+        }
+    }
+}
+
+impl PayloadEncoder for CborEncoder<'_> {
+    fn begin_message(&mut self, field: Field, len: usize) -> Result<(), EncodeError> {
+        self.open(field, true, len)
+    }
+
+    fn end_message(&mut self) -> Result<(), EncodeError> {
+        self.close(true)
+    }
+
+    fn begin_list(&mut self, field: Field, len: usize) -> Result<(), EncodeError> {
+        self.open(field, false, len)
+    }
+
+    fn end_list(&mut self) -> Result<(), EncodeError> {
+        self.close(false)
+    }
+
+    fn uint(&mut self, field: Field, value: u64) -> Result<(), EncodeError> {
+        self.key(field)?;
+        self.head(MAJOR_UINT, value)
+    }
+
+    fn int(&mut self, field: Field, value: i64) -> Result<(), EncodeError> {
+        self.key(field)?;
+        if value < 0 {
+            // -1 - n, computed without overflowing on i64::MIN
+            self.head(MAJOR_NEGATIVE, !value as u64)
+        } else {
+            self.head(MAJOR_UINT, value as u64)
+        }
+    }
+
+    fn float(&mut self, field: Field, value: f64) -> Result<(), EncodeError> {
+        self.key(field)?;
+        // Half the size when single precision is lossless
+        let single = value as f32;
+        if single as f64 == value || value.is_nan() {
+            self.out.byte(FLOAT32)?;
+            self.out.bytes(&single.to_be_bytes())
+        } else {
+            self.out.byte(FLOAT64)?;
+            self.out.bytes(&value.to_be_bytes())
+        }
+    }
+
+    fn bool(&mut self, field: Field, value: bool) -> Result<(), EncodeError> {
+        self.key(field)?;
+        self.out.byte(if value { TRUE } else { FALSE })
+    }
+
+    fn str(&mut self, field: Field, value: &str) -> Result<(), EncodeError> {
+        self.key(field)?;
+        self.head(MAJOR_TEXT, value.len() as u64)?;
+        self.out.bytes(value.as_bytes())
+    }
+
+    fn bytes(&mut self, field: Field, value: &[u8]) -> Result<(), EncodeError> {
+        self.key(field)?;
+        self.head(MAJOR_BYTES, value.len() as u64)?;
+        self.out.bytes(value)
+    }
+
+    fn len(&self) -> usize {
+        self.out.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::hex;
+    use super::*;
+
+    /// Single value written by `write` at the root
+    fn value(
+        write: impl FnOnce(&mut CborEncoder) -> Result<(), EncodeError>,
+    ) -> std::string::String {
+        let mut buf = [0u8; 32];
+        let mut e = CborEncoder::new(&mut buf);
+        write(&mut e).unwrap();
+        let len = e.len();
+        hex(&buf[..len])
+    }
+
+    // Examples of RFC 8949 appendix A
+
+    #[test]
+    fn encodes_integers() {
+        let unsigned = [
+            (0, "00"),
+            (23, "17"),
+            (24, "1818"),
+            (100, "1864"),
+            (1000, "1903E8"),
+            (1_000_000, "1A000F4240"),
+            (1_000_000_000_000, "1B000000E8D4A51000"),
+            (u64::MAX, "1BFFFFFFFFFFFFFFFF"),
+        ];
+        for (n, expected) in unsigned {
+            assert_eq!(value(|e| e.uint(Field::NONE, n)), expected, "{n}");
+        }
+        let signed = [
+            (10, "0A"),
+            (-1, "20"),
+            (-10, "29"),
+            (-100, "3863"),
+            (-1000, "3903E7"),
+            (i64::MIN, "3B7FFFFFFFFFFFFFFF"),
+        ];
+        for (n, expected) in signed {
+            assert_eq!(value(|e| e.int(Field::NONE, n)), expected, "{n}");
+        }
+    }
+
+    #[test]
+    fn encodes_floats_in_the_shortest_lossless_precision() {
+        let cases = [
+            (100_000.0, "FA47C35000"),
+            (3.4028234663852886e38, "FA7F7FFFFF"),
+            (1.1, "FB3FF199999999999A"),
+            (-4.1, "FBC010666666666666"),
+            (1.0e300, "FB7E37E43C8800759C"),
+            (f64::INFINITY, "FA7F800000"),
+        ];
+        for (n, expected) in cases {
+            assert_eq!(value(|e| e.float(Field::NONE, n)), expected, "{n}");
+        }
+    }
+
+    #[test]
+    fn encodes_simple_values_and_strings() {
+        assert_eq!(value(|e| e.bool(Field::NONE, false)), "F4");
+        assert_eq!(value(|e| e.bool(Field::NONE, true)), "F5");
+        assert_eq!(value(|e| e.str(Field::NONE, "")), "60");
+        assert_eq!(value(|e| e.str(Field::NONE, "a")), "6161");
+        assert_eq!(value(|e| e.str(Field::NONE, "IETF")), "6449455446");
+        assert_eq!(value(|e| e.str(Field::NONE, "\u{fc}")), "62C3BC");
+        assert_eq!(value(|e| e.bytes(Field::NONE, &[])), "40");
+        assert_eq!(value(|e| e.bytes(Field::NONE, &[1, 2, 3, 4])), "4401020304");
+    }
+
+    #[test]
+    fn encodes_maps_and_arrays() {
+        // {"a": 1, "b": [2, 3]}
+        let map = value(|e| {
+            e.begin_message(Field::NONE, 2)?;
+            e.uint(Field::new(1, "a"), 1)?;
+            e.begin_list(Field::new(2, "b"), 2)?;
+            e.uint(Field::NONE, 2)?;
+            e.uint(Field::NONE, 3)?;
+            e.end_list()?;
+            e.end_message()
+        });
+        assert_eq!(map, "A26161016162820203");
+        // [1, [2, 3], [4, 5]]
+        let nested = value(|e| {
+            e.begin_list(Field::NONE, 3)?;
+            e.uint(Field::NONE, 1)?;
+            for pair in [[2, 3], [4, 5]] {
+                e.begin_list(Field::NONE, 2)?;
+                e.uint(Field::NONE, pair[0])?;
+                e.uint(Field::NONE, pair[1])?;
+                e.end_list()?;
+            }
+            e.end_list()
+        });
+        assert_eq!(nested, "8301820203820405");
+        assert_eq!(value(|e| e.begin_list(Field::NONE, 0)), "80");
+    }
+
+    #[test]
+    fn rejects_unbalanced_and_deep_nesting() {
+        let mut buf = [0u8; 32];
+        let mut e = CborEncoder::new(&mut buf);
+        e.begin_list(Field::NONE, 0).unwrap();
+        assert_eq!(e.end_message(), Err(EncodeError::Unbalanced));
+
+        let mut e = CborEncoder::new(&mut buf);
+        for _ in 0..MAX_DEPTH {
+            e.begin_list(Field::NONE, 1).unwrap();
+        }
+        assert_eq!(e.begin_list(Field::NONE, 1), Err(EncodeError::TooDeep));
+    }
+}
@@ -0,0 +1,141 @@
+//! JSON, bytes are written as uppercase hex strings
+use core::fmt::Write;
+
+use super::{EncodeError, Field, Output, PayloadEncoder, MAX_DEPTH};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Container {
+    Object { first: bool },
+    Array { first: bool },
+}
+
+pub struct JsonEncoder<'a> {
+    out: Output<'a>,
+    stack: heapless::Vec<Container, MAX_DEPTH>,
+}
+
+impl<'a> JsonEncoder<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Self {
+            out: Output::new(buf),
+            stack: heapless::Vec::new(),
+        }
+    }
+
+    /// Separator and key of the next value
+    fn key(&mut self, field: Field) -> Result<(), EncodeError> {
+        let (first, object) = match self.stack.last_mut() {
+            Some(Container::Object { first }) => (core::mem::replace(first, false), true),
+            Some(Container::Array { first }) => (core::mem::replace(first, false), false),
+            None => return Ok(()),
+        };
+        if !first {
+            self.out.byte(b',')?;
+        }
+        if object {
+            self.string(field.name)?;
+            self.out.byte(b':')?;
+        }
+        Ok(())
+    }
+
+    fn string(&mut self, value: &str) -> Result<(), EncodeError> {
+        self.out.byte(b'"')?;
+        for c in value.chars() {
+            match c {
+                '"' => self.out.bytes(b"\\\"")?,
+                '\\' => self.out.bytes(b"\\\\")?,
+                c if (c as u32) < 0x20 => {
+                    write!(self.out, "\\u{:04x}", c as u32).map_err(|_| EncodeError::BufferFull)?
+                }
+                c => {
+                    let mut utf8 = [0u8; 4];
+                    self.out.bytes(c.encode_utf8(&mut utf8).as_bytes())?;
+                }
+            }
+        }
+        self.out.byte(b'"')
+    }
+
+    fn open(&mut self, container: Container, token: u8) -> Result<(), EncodeError> {
+        self.stack
+            .push(container)
+            .map_err(|_| EncodeError::TooDeep)?;
+        self.out.byte(token)
+    }
+
+    fn number(&mut self, args: core::fmt::Arguments) -> Result<(), EncodeError> {
+        self.out
+            .write_fmt(args)
+            .map_err(|_| EncodeError::BufferFull)
+    }
+}
+
+impl PayloadEncoder for JsonEncoder<'_> {
+    fn begin_message(&mut self, field: Field, _len: usize) -> Result<(), EncodeError> {
+        self.key(field)?;
+        self.open(Container::Object { first: true }, b'{')
+    }
+
+    fn end_message(&mut self) -> Result<(), EncodeError> {
+        match self.stack.pop() {
+            Some(Container::Object { .. }) => self.out.byte(b'}'),
+            _ => Err(EncodeError::Unbalanced),
+        }
+    }
+
+    fn begin_list(&mut self, field: Field, _len: usize) -> Result<(), EncodeError> {
+        self.key(field)?;
+        self.open(Container::Array { first: true }, b'[')
+    }
+
+    fn end_list(&mut self) -> Result<(), EncodeError> {
+        match self.stack.pop() {
+            Some(Container::Array { .. }) => self.out.byte(b']'),
+            _ => Err(EncodeError::Unbalanced),
+        }
+    }
+
+    fn uint(&mut self, field: Field, value: u64) -> Result<(), EncodeError> {
+        self.key(field)?;
+        self.number(format_args!("{value}"))
+    }
+
+    fn int(&mut self, field: Field, value: i64) -> Result<(), EncodeError> {
+        self.key(field)?;
+        self.number(format_args!("{value}"))
+    }
+
+    fn float(&mut self, field: Field, value: f64) -> Result<(), EncodeError> {
+        self.key(field)?;
+        if value.is_finite() {
+            self.number(format_args!("{value}"))
+        } else {
+            self.out.bytes(b"null")
+        }
+    }
+
+    fn bool(&mut self, field: Field, value: bool) -> Result<(), EncodeError> {
+        self.key(field)?;
+        self.out
+            .bytes(if value { b"true".as_slice() } else { b"false" })
+    }
+
+    fn str(&mut self, field: Field, value: &str) -> Result<(), EncodeError> {
+        self.key(field)?;
+        self.string(value)
+    }
+
+    fn bytes(&mut self, field: Field, value: &[u8]) -> Result<(), EncodeError> {
+        self.key(field)?;
+        self.out.byte(b'"')?;
+        for byte in value {
+            self.number(format_args!("{byte:02X}"))?;
+        }
+        self.out.byte(b'"')
+    }
+
+    fn len(&self) -> usize {
+        self.out.len()
+    }
+}
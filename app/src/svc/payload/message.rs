@@ -0,0 +1,283 @@
+//! Messages with a payload encoder
+//!
+//! The field names are the keys of the JSON, CBOR and MessagePack maps, the
+//! numbers the Protobuf tags. Keep both stable, bump the schema version of the
+//! message type (`svc::topic::schema`) on any change.
+use super::{Encode, EncodeError, Field, PayloadEncoder};
+use crate::svc::can::CanFrame;
+
+/// CAN frames of one bus
+///
+/// `{"bus":"can0","frames":[{"t_us":<us>,"id":<id>,"ext":<bool>,"data":"<hex>"}]}`
+pub struct CanBatch<'a> {
+    pub bus: &'a str,
+    pub frames: &'a [CanFrame],
+}
+
+impl CanBatch<'_> {
+    const BUS: Field = Field::new(1, "bus");
+    const FRAMES: Field = Field::new(2, "frames");
+    const TIMESTAMP_US: Field = Field::new(1, "t_us");
+    const ID: Field = Field::new(2, "id");
+    const EXTENDED: Field = Field::new(3, "ext");
+    const DATA: Field = Field::new(4, "data");
+}
+
+impl Encode for CanBatch<'_> {
+    fn encode<E: PayloadEncoder>(&self, e: &mut E) -> Result<(), EncodeError> {
+        e.begin_message(Field::NONE, 2)?;
+        e.str(Self::BUS, self.bus)?;
+        e.begin_list(Self::FRAMES, self.frames.len())?;
+        for frame in self.frames {
+            e.begin_message(Field::NONE, 4)?;
+            e.uint(Self::TIMESTAMP_US, frame.timestamp_us)?;
+            e.uint(Self::ID, frame.id as u64)?;
+            e.bool(Self::EXTENDED, frame.extended)?;
+            e.bytes(Self::DATA, frame.payload())?;
+            e.end_message()?;
+        }
+        e.end_list()?;
+        e.end_message()
+    }
+}
+
+/// GNSS position of the trip
+///
+/// `{"device_id":"<id>","trip_id":"<id>","latitude":<deg>,"longitude":<deg>,"timestamp":<unix s>}`
+pub struct Trip<'a> {
+    pub device_id: &'a str,
+    pub trip_id: &'a str,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub timestamp: u64,
+}
+
+impl Trip<'_> {
+    const DEVICE_ID: Field = Field::new(1, "device_id");
+    const TRIP_ID: Field = Field::new(2, "trip_id");
+    const LATITUDE: Field = Field::new(3, "latitude");
+    const LONGITUDE: Field = Field::new(4, "longitude");
+    const TIMESTAMP: Field = Field::new(5, "timestamp");
+}
+
+impl Encode for Trip<'_> {
+    fn encode<E: PayloadEncoder>(&self, e: &mut E) -> Result<(), EncodeError> {
+        e.begin_message(Field::NONE, 5)?;
+        e.str(Self::DEVICE_ID, self.device_id)?;
+        e.str(Self::TRIP_ID, self.trip_id)?;
+        e.float(Self::LATITUDE, self.latitude)?;
+        e.float(Self::LONGITUDE, self.longitude)?;
+        e.uint(Self::TIMESTAMP, self.timestamp)?;
+        e.end_message()
+    }
+}
+
+/// Device health, published after each connection
+///
+/// `{"uptime_s":<s>,"firmware":"<version>","bearer":"<wifi|lte>","queued":<n>,"failures":<n>}`,
+/// `failures` counts the failed connection attempts before this one.
+pub struct Health<'a> {
+    pub uptime_s: u64,
+    pub firmware: &'a str,
+    pub bearer: &'a str,
+    pub queued: u32,
+    pub failures: u32,
+}
+
+impl Health<'_> {
+    const UPTIME_S: Field = Field::new(1, "uptime_s");
+    const FIRMWARE: Field = Field::new(2, "firmware");
+    const BEARER: Field = Field::new(3, "bearer");
+    const QUEUED: Field = Field::new(4, "queued");
+    const FAILURES: Field = Field::new(5, "failures");
+}
+
+impl Encode for Health<'_> {
+    fn encode<E: PayloadEncoder>(&self, e: &mut E) -> Result<(), EncodeError> {
+        e.begin_message(Field::NONE, 5)?;
+        e.uint(Self::UPTIME_S, self.uptime_s)?;
+        e.str(Self::FIRMWARE, self.firmware)?;
+        e.str(Self::BEARER, self.bearer)?;
+        e.uint(Self::QUEUED, self.queued as u64)?;
+        e.uint(Self::FAILURES, self.failures as u64)?;
+        e.end_message()
+    }
+}
+
+/// State change of the device or the vehicle
+///
+/// `{"event":"<name>","from":"<state>","to":"<state>","uptime_ms":<ms>}`
+pub struct Event<'a> {
+    pub event: &'a str,
+    pub from: &'a str,
+    pub to: &'a str,
+    pub uptime_ms: u64,
+}
+
+impl Event<'_> {
+    const EVENT: Field = Field::new(1, "event");
+    const FROM: Field = Field::new(2, "from");
+    const TO: Field = Field::new(3, "to");
+    const UPTIME_MS: Field = Field::new(4, "uptime_ms");
+}
+
+impl Encode for Event<'_> {
+    fn encode<E: PayloadEncoder>(&self, e: &mut E) -> Result<(), EncodeError> {
+        e.begin_message(Field::NONE, 4)?;
+        e.str(Self::EVENT, self.event)?;
+        e.str(Self::FROM, self.from)?;
+        e.str(Self::TO, self.to)?;
+        e.uint(Self::UPTIME_MS, self.uptime_ms)?;
+        e.end_message()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{encode, hex, PayloadFormat};
+    use super::*;
+
+    fn encoded<M: Encode>(format: PayloadFormat, message: &M) -> std::string::String {
+        let mut buf = [0u8; 256];
+        let len = encode(format, message, &mut buf).unwrap();
+        hex(&buf[..len])
+    }
+
+    const EVENT: Event = Event {
+        event: "trip_start",
+        from: "parked",
+        to: "driving",
+        uptime_ms: 1000,
+    };
+
+    #[test]
+    fn event_golden_vectors() {
+        let json = r#"{"event":"trip_start","from":"parked","to":"driving","uptime_ms":1000}"#;
+        assert_eq!(encoded(PayloadFormat::Json, &EVENT), hex(json.as_bytes()));
+        assert_eq!(
+            encoded(PayloadFormat::Cbor, &EVENT),
+            "A4656576656E746A747269705F73746172746466726F6D667061726B656462746F67647269\
+             76696E6769757074696D655F6D731903E8"
+        );
+        assert_eq!(
+            encoded(PayloadFormat::MessagePack, &EVENT),
+            "84A56576656E74AA747269705F7374617274A466726F6DA67061726B6564A2746FA7647269\
+             76696E67A9757074696D655F6D73CD03E8"
+        );
+        assert_eq!(
+            encoded(PayloadFormat::Protobuf, &EVENT),
+            "0A0A747269705F737461727412067061726B65641A0764726976696E6720E807"
+        );
+    }
+
+    #[test]
+    fn can_batch_golden_vectors() {
+        let frames = [
+            CanFrame {
+                id: 0x123,
+                extended: false,
+                len: 2,
+                data: [0xDE, 0xAD, 0, 0, 0, 0, 0, 0],
+                timestamp_us: 1_000_000,
+            },
+            CanFrame {
+                id: 0x18FE_F100,
+                extended: true,
+                len: 8,
+                data: [0, 1, 2, 3, 4, 5, 6, 7],
+                timestamp_us: 1_001_500,
+            },
+        ];
+        let batch = CanBatch {
+            bus: "can0",
+            frames: &frames,
+        };
+        let json = r#"{"bus":"can0","frames":[{"t_us":1000000,"id":291,"ext":false,"data":"DEAD"},{"t_us":1001500,"id":419361024,"ext":true,"data":"0001020304050607"}]}"#;
+        assert_eq!(encoded(PayloadFormat::Json, &batch), hex(json.as_bytes()));
+        assert_eq!(
+            encoded(PayloadFormat::Cbor, &batch),
+            "A2636275736463616E30666672616D657382A464745F75731A000F424062696419012363657874\
+             F4646461746142DEADA464745F75731A000F481C6269641A18FEF10063657874F5646461746148\
+             0001020304050607"
+        );
+        assert_eq!(
+            encoded(PayloadFormat::MessagePack, &batch),
+            "82A3627573A463616E30A66672616D65739284A4745F7573CE000F4240A26964CD0123A36578\
+             74C2A464617461C402DEAD84A4745F7573CE000F481CA26964CE18FEF100A3657874C3A4646174\
+             61C4080001020304050607"
+        );
+        // Repeated nested messages, false written as is
+        assert_eq!(
+            encoded(PayloadFormat::Protobuf, &batch),
+            "0A0463616E30120D08C0843D10A30218002202DEAD1216089C903D1080E2FBC70118012208000102\
+             0304050607"
+        );
+    }
+
+    #[test]
+    fn trip_golden_vectors() {
+        // 1.1 needs double precision, 100000.0 fits a single
+        let trip = Trip {
+            device_id: "dev",
+            trip_id: "t1",
+            latitude: 1.1,
+            longitude: 100_000.0,
+            timestamp: 1_700_000_000,
+        };
+        let json = r#"{"device_id":"dev","trip_id":"t1","latitude":1.1,"longitude":100000,"timestamp":1700000000}"#;
+        assert_eq!(encoded(PayloadFormat::Json, &trip), hex(json.as_bytes()));
+        assert_eq!(
+            encoded(PayloadFormat::Cbor, &trip),
+            "A5696465766963655F69646364657667747269705F6964627431686C61746974756465FB3FF1999999\
+             99999A696C6F6E676974756465FA47C350006974696D657374616D701A6553F100"
+        );
+        assert_eq!(
+            encoded(PayloadFormat::MessagePack, &trip),
+            "85A96465766963655F6964A3646576A7747269705F6964A27431A86C61746974756465CB3FF1999999\
+             99999AA96C6F6E676974756465CA47C35000A974696D657374616D70CE6553F100"
+        );
+        assert_eq!(
+            encoded(PayloadFormat::Protobuf, &trip),
+            "0A0364657612027431199A9999999999F13F2100000000006AF8402880E2CFAA06"
+        );
+    }
+
+    #[test]
+    fn health_golden_vectors() {
+        let health = Health {
+            uptime_s: 3600,
+            firmware: "0.1.0",
+            bearer: "lte",
+            queued: 0,
+            failures: 300,
+        };
+        assert_eq!(
+            encoded(PayloadFormat::Cbor, &health),
+            "A568757074696D655F73190E10686669726D7761726565302E312E3066626561726572636C7465667175\
+             6575656400686661696C7572657319012C"
+        );
+        assert_eq!(
+            encoded(PayloadFormat::MessagePack, &health),
+            "85A8757074696D655F73CD0E10A86669726D77617265A5302E312E30A6626561726572A36C7465A671\
+             756575656400A86661696C75726573CD012C"
+        );
+        assert_eq!(
+            encoded(PayloadFormat::Protobuf, &health),
+            "08901C1205302E312E301A036C7465200028AC02"
+        );
+    }
+
+    #[test]
+    fn reports_a_full_buffer() {
+        let mut buf = [0u8; 16];
+        for format in [
+            PayloadFormat::Json,
+            PayloadFormat::Cbor,
+            PayloadFormat::MessagePack,
+            PayloadFormat::Protobuf,
+        ] {
+            let result = encode(format, &EVENT, &mut buf);
+            assert_eq!(result, Err(EncodeError::BufferFull), "{format:?}");
+        }
+    }
+}
@@ -0,0 +1,201 @@
+//! Payload encoding of the uplink messages
+//!
+//! A message describes its fields once through [`Encode`], each field with a
+//! name for the self-describing formats (JSON, CBOR, MessagePack) and a tag for
+//! Protobuf. The format is chosen per message type in `cfg::topic_cfg`, so the
+//! chatty topics can trade readability for bytes. Binary formats are sent hex
+//! encoded on the AT commands of the LTE modem and decoded by the modem.
+pub mod cbor;
+pub mod json;
+pub mod message;
+pub mod msgpack;
+pub mod protobuf;
+
+use crate::cfg::topic_cfg::{DEFAULT_PAYLOAD_FORMAT, PAYLOAD_FORMATS};
+use crate::svc::topic::MessageType;
+
+/// Deepest nesting of messages and lists
+const MAX_DEPTH: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PayloadFormat {
+    Json,
+    Cbor,
+    MessagePack,
+    Protobuf,
+}
+
+impl PayloadFormat {
+    /// Name reported in the schema manifest
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PayloadFormat::Json => "json",
+            PayloadFormat::Cbor => "cbor",
+            PayloadFormat::MessagePack => "msgpack",
+            PayloadFormat::Protobuf => "protobuf",
+        }
+    }
+
+    /// MQTT 5 content type of the messages
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            PayloadFormat::Json => "application/json",
+            PayloadFormat::Cbor => "application/cbor",
+            PayloadFormat::MessagePack => "application/msgpack",
+            PayloadFormat::Protobuf => "application/x-protobuf",
+        }
+    }
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(PayloadFormat::Json),
+            1 => Some(PayloadFormat::Cbor),
+            2 => Some(PayloadFormat::MessagePack),
+            3 => Some(PayloadFormat::Protobuf),
+            _ => None,
+        }
+    }
+}
+
+/// Format of the messages of type `message`
+///
+/// Only the message types of [`message`] have an encoder, the others are
+/// rendered as JSON whatever the configuration says.
+pub fn format_for(message: MessageType) -> PayloadFormat {
+    match message {
+        MessageType::Can | MessageType::Trip | MessageType::Event | MessageType::Health => {
+            PAYLOAD_FORMATS
+                .iter()
+                .find(|(m, _)| *m == message)
+                .map_or(DEFAULT_PAYLOAD_FORMAT, |(_, format)| *format)
+        }
+        _ => PayloadFormat::Json,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EncodeError {
+    BufferFull,
+    /// Messages and lists nested deeper than supported
+    TooDeep,
+    /// End of a message or list that was not opened
+    Unbalanced,
+}
+
+/// Name and Protobuf tag of a field
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Field {
+    pub tag: u32,
+    pub name: &'static str,
+}
+
+impl Field {
+    /// The root message, and the values of a list
+    pub const NONE: Field = Field { tag: 0, name: "" };
+
+    pub const fn new(tag: u32, name: &'static str) -> Self {
+        Self { tag, name }
+    }
+}
+
+/// Writes the fields of a message in one format
+///
+/// Messages and lists are opened with the number of entries they will hold, as
+/// CBOR and MessagePack write it upfront. Values inside a list take
+/// [`Field::NONE`], Protobuf repeats the tag of the list for each of them.
+pub trait PayloadEncoder {
+    fn begin_message(&mut self, field: Field, len: usize) -> Result<(), EncodeError>;
+    fn end_message(&mut self) -> Result<(), EncodeError>;
+    fn begin_list(&mut self, field: Field, len: usize) -> Result<(), EncodeError>;
+    fn end_list(&mut self) -> Result<(), EncodeError>;
+    fn uint(&mut self, field: Field, value: u64) -> Result<(), EncodeError>;
+    fn int(&mut self, field: Field, value: i64) -> Result<(), EncodeError>;
+    fn float(&mut self, field: Field, value: f64) -> Result<(), EncodeError>;
+    fn bool(&mut self, field: Field, value: bool) -> Result<(), EncodeError>;
+    fn str(&mut self, field: Field, value: &str) -> Result<(), EncodeError>;
+    fn bytes(&mut self, field: Field, value: &[u8]) -> Result<(), EncodeError>;
+    /// Bytes written so far
+    fn len(&self) -> usize;
+}
+
+/// A message that can be written by any [`PayloadEncoder`]
+pub trait Encode {
+    fn encode<E: PayloadEncoder>(&self, encoder: &mut E) -> Result<(), EncodeError>;
+}
+
+/// Encode `message` in `format` into `out`, returns the length of the payload
+pub fn encode<M: Encode>(
+    format: PayloadFormat,
+    message: &M,
+    out: &mut [u8],
+) -> Result<usize, EncodeError> {
+    match format {
+        PayloadFormat::Json => run(&mut json::JsonEncoder::new(out), message),
+        PayloadFormat::Cbor => run(&mut cbor::CborEncoder::new(out), message),
+        PayloadFormat::MessagePack => run(&mut msgpack::MsgPackEncoder::new(out), message),
+        PayloadFormat::Protobuf => run(&mut protobuf::ProtobufEncoder::new(out), message),
+    }
+}
+
+fn run<E: PayloadEncoder, M: Encode>(encoder: &mut E, message: &M) -> Result<usize, EncodeError> {
+    message.encode(encoder)?;
+    Ok(encoder.len())
+}
+
+/// Bytes written into a caller provided buffer
+pub(crate) struct Output<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> Output<'a> {
+    pub(crate) fn new(buf: &'a mut [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.pos
+    }
+
+    pub(crate) fn byte(&mut self, value: u8) -> Result<(), EncodeError> {
+        self.bytes(&[value])
+    }
+
+    pub(crate) fn bytes(&mut self, value: &[u8]) -> Result<(), EncodeError> {
+        let end = self.pos + value.len();
+        self.buf
+            .get_mut(self.pos..end)
+            .ok_or(EncodeError::BufferFull)?
+            .copy_from_slice(value);
+        self.pos = end;
+        Ok(())
+    }
+
+    /// Make room for `len` bytes at `at`, moving what was written after it
+    pub(crate) fn insert(&mut self, at: usize, len: usize) -> Result<(), EncodeError> {
+        if self.pos + len > self.buf.len() {
+            return Err(EncodeError::BufferFull);
+        }
+        self.buf.copy_within(at..self.pos, at + len);
+        self.pos += len;
+        Ok(())
+    }
+
+    pub(crate) fn set(&mut self, at: usize, value: &[u8]) {
+        self.buf[at..at + value.len()].copy_from_slice(value);
+    }
+}
+
+/// Uppercase hex of `bytes`, the golden vectors of the tests are written so
+#[cfg(test)]
+pub(crate) fn hex(bytes: &[u8]) -> std::string::String {
+    let mut out = std::string::String::new();
+    crate::util::hex::encode(&mut out, bytes).unwrap();
+    out
+}
+
+impl core::fmt::Write for Output<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.bytes(s.as_bytes()).map_err(|_| core::fmt::Error)
+    }
+}
@@ -0,0 +1,269 @@
+//! MessagePack, messages are maps keyed by the field names
+use super::{EncodeError, Field, Output, PayloadEncoder, MAX_DEPTH};
+
+pub struct MsgPackEncoder<'a> {
+    out: Output<'a>,
+    /// `true` for a map, `false` for an array
+    stack: heapless::Vec<bool, MAX_DEPTH>,
+}
+
+impl<'a> MsgPackEncoder<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Self {
+            out: Output::new(buf),
+            stack: heapless::Vec::new(),
+        }
+    }
+
+    fn key(&mut self, field: Field) -> Result<(), EncodeError> {
+        if self.stack.last() == Some(&true) {
+            self.string(field.name)?;
+        }
+        Ok(())
+    }
+
+    fn string(&mut self, value: &str) -> Result<(), EncodeError> {
+        let len = value.len();
+        match len {
+            0..=31 => self.out.byte(0xA0 | len as u8)?,
+            32..=0xFF => self.out.bytes(&[0xD9, len as u8])?,
+            0x100..=0xFFFF => {
+                self.out.byte(0xDA)?;
+                self.out.bytes(&(len as u16).to_be_bytes())?;
+            }
+            _ => {
+                self.out.byte(0xDB)?;
+                self.out.bytes(&(len as u32).to_be_bytes())?;
+            }
+        }
+        self.out.bytes(value.as_bytes())
+    }
+
+    fn open(&mut self, field: Field, map: bool, len: usize) -> Result<(), EncodeError> {
+        self.key(field)?;
+        self.stack.push(map).map_err(|_| EncodeError::TooDeep)?;
+        let (fix, len16, len32) = if map {
+            (0x80, 0xDE, 0xDF)
+        } else {
+            (0x90, 0xDC, 0xDD)
+        };
+        match len {
+            0..=15 => self.out.byte(fix | len as u8),
+            16..=0xFFFF => {
+                self.out.byte(len16)?;
+                self.out.bytes(&(len as u16).to_be_bytes())
+            }
+            _ => {
+                self.out.byte(len32)?;
+                self.out.bytes(&(len as u32).to_be_bytes())
+            }
+        }
+    }
+
+    fn close(&mut self, map: bool) -> Result<(), EncodeError> {
+        match self.stack.pop() {
+            Some(kind) if kind == map => Ok(()),
+            _ => Err(EncodeError::Unbalanced),
+        }
+    }
+}
+
+impl PayloadEncoder for MsgPackEncoder<'_> {
+    fn begin_message(&mut self, field: Field, len: usize) -> Result<(), EncodeError> {
+        self.open(field, true, len)
+    }
+
+    fn end_message(&mut self) -> Result<(), EncodeError> {
+        self.close(true)
+    }
+
+    fn begin_list(&mut self, field: Field, len: usize) -> Result<(), EncodeError> {
+        self.open(field, false, len)
+    }
+
+    fn end_list(&mut self) -> Result<(), EncodeError> {
+        self.close(false)
+    }
+
+    fn uint(&mut self, field: Field, value: u64) -> Result<(), EncodeError> {
+        self.key(field)?;
+        match value {
+            0..=0x7F => self.out.byte(value as u8),
+            0x80..=0xFF => self.out.bytes(&[0xCC, value as u8]),
+            0x100..=0xFFFF => {
+                self.out.byte(0xCD)?;
+                self.out.bytes(&(value as u16).to_be_bytes())
+            }
+            0x1_0000..=0xFFFF_FFFF => {
+                self.out.byte(0xCE)?;
+                self.out.bytes(&(value as u32).to_be_bytes())
+            }
+            _ => {
+                self.out.byte(0xCF)?;
+                self.out.bytes(&value.to_be_bytes())
+            }
+        }
+    }
+
+    fn int(&mut self, field: Field, value: i64) -> Result<(), EncodeError> {
+        if value >= 0 {
+            return self.uint(field, value as u64);
+        }
+        self.key(field)?;
+        match value {
+            -32..=-1 => self.out.byte(value as u8),
+            -0x80..=-33 => self.out.bytes(&[0xD0, value as u8]),
+            -0x8000..=-0x81 => {
+                self.out.byte(0xD1)?;
+                self.out.bytes(&(value as i16).to_be_bytes())
+            }
+            -0x8000_0000..=-0x8001 => {
+                self.out.byte(0xD2)?;
+                self.out.bytes(&(value as i32).to_be_bytes())
+            }
+            _ => {
+                self.out.byte(0xD3)?;
+                self.out.bytes(&value.to_be_bytes())
+            }
+        }
+    }
+
+    fn float(&mut self, field: Field, value: f64) -> Result<(), EncodeError> {
+        self.key(field)?;
+        // Half the size when single precision is lossless
+        let single = value as f32;
+        if single as f64 == value || value.is_nan() {
+            self.out.byte(0xCA)?;
+            self.out.bytes(&single.to_be_bytes())
+        } else {
+            self.out.byte(0xCB)?;
+            self.out.bytes(&value.to_be_bytes())
+        }
+    }
+
+    fn bool(&mut self, field: Field, value: bool) -> Result<(), EncodeError> {
+        self.key(field)?;
+        self.out.byte(if value { 0xC3 } else { 0xC2 })
+    }
+
+    fn str(&mut self, field: Field, value: &str) -> Result<(), EncodeError> {
+        self.key(field)?;
+        self.string(value)
+    }
+
+    fn bytes(&mut self, field: Field, value: &[u8]) -> Result<(), EncodeError> {
+        self.key(field)?;
+        let len = value.len();
+        match len {
+            0..=0xFF => self.out.bytes(&[0xC4, len as u8])?,
+            0x100..=0xFFFF => {
+                self.out.byte(0xC5)?;
+                self.out.bytes(&(len as u16).to_be_bytes())?;
+            }
+            _ => {
+                self.out.byte(0xC6)?;
+                self.out.bytes(&(len as u32).to_be_bytes())?;
+            }
+        }
+        self.out.bytes(value)
+    }
+
+    fn len(&self) -> usize {
+        self.out.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::hex;
+    use super::*;
+
+    /// Single value written by `write` at the root
+    fn value(
+        write: impl FnOnce(&mut MsgPackEncoder) -> Result<(), EncodeError>,
+    ) -> std::string::String {
+        let mut buf = [0u8; 64];
+        let mut e = MsgPackEncoder::new(&mut buf);
+        write(&mut e).unwrap();
+        let len = e.len();
+        hex(&buf[..len])
+    }
+
+    // Boundaries of each format family of the MessagePack specification
+
+    #[test]
+    fn encodes_integers_in_the_smallest_format() {
+        let unsigned = [
+            (0, "00"),
+            (0x7F, "7F"),
+            (0x80, "CC80"),
+            (0xFF, "CCFF"),
+            (0x100, "CD0100"),
+            (0xFFFF, "CDFFFF"),
+            (0x1_0000, "CE00010000"),
+            (0xFFFF_FFFF, "CEFFFFFFFF"),
+            (0x1_0000_0000, "CF0000000100000000"),
+        ];
+        for (n, expected) in unsigned {
+            assert_eq!(value(|e| e.uint(Field::NONE, n)), expected, "{n}");
+        }
+        let signed = [
+            (1, "01"),
+            (-1, "FF"),
+            (-32, "E0"),
+            (-33, "D0DF"),
+            (-128, "D080"),
+            (-129, "D1FF7F"),
+            (-32768, "D18000"),
+            (-32769, "D2FFFF7FFF"),
+            (-2_147_483_648, "D280000000"),
+            (-2_147_483_649, "D3FFFFFFFF7FFFFFFF"),
+        ];
+        for (n, expected) in signed {
+            assert_eq!(value(|e| e.int(Field::NONE, n)), expected, "{n}");
+        }
+    }
+
+    #[test]
+    fn encodes_floats_bools_and_binaries() {
+        assert_eq!(value(|e| e.float(Field::NONE, 1.5)), "CA3FC00000");
+        assert_eq!(value(|e| e.float(Field::NONE, 1.1)), "CB3FF199999999999A");
+        assert_eq!(value(|e| e.bool(Field::NONE, false)), "C2");
+        assert_eq!(value(|e| e.bool(Field::NONE, true)), "C3");
+        assert_eq!(value(|e| e.bytes(Field::NONE, &[])), "C400");
+        assert_eq!(value(|e| e.bytes(Field::NONE, &[0xDE, 0xAD])), "C402DEAD");
+    }
+
+    #[test]
+    fn encodes_string_lengths() {
+        assert_eq!(value(|e| e.str(Field::NONE, "")), "A0");
+        let fix = "a".repeat(31);
+        let expected = std::format!("BF{}", "61".repeat(31));
+        assert_eq!(value(|e| e.str(Field::NONE, &fix)), expected);
+        let str8 = "a".repeat(32);
+        let expected = std::format!("D920{}", "61".repeat(32));
+        assert_eq!(value(|e| e.str(Field::NONE, &str8)), expected);
+    }
+
+    #[test]
+    fn encodes_maps_and_arrays() {
+        // {"a": 1, "b": [2, 3]}
+        let map = value(|e| {
+            e.begin_message(Field::NONE, 2)?;
+            e.uint(Field::new(1, "a"), 1)?;
+            e.begin_list(Field::new(2, "b"), 2)?;
+            e.uint(Field::NONE, 2)?;
+            e.uint(Field::NONE, 3)?;
+            e.end_list()?;
+            e.end_message()
+        });
+        assert_eq!(map, "82A16101A162920203");
+        assert_eq!(value(|e| e.begin_list(Field::NONE, 15)), "9F");
+        assert_eq!(value(|e| e.begin_list(Field::NONE, 16)), "DC0010");
+        assert_eq!(value(|e| e.begin_message(Field::NONE, 15)), "8F");
+        assert_eq!(
+            value(|e| e.begin_message(Field::NONE, 0x1_0000)),
+            "DF00010000"
+        );
+    }
+}
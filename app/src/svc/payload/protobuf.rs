@@ -0,0 +1,251 @@
+//! Protocol Buffers, messages are written with the field tags
+//!
+//! Unsigned values are `uint64`, signed ones `sint64` (zigzag), floats `double`
+//! and lists are repeated fields, not packed. The length of a nested message is
+//! only known once it is written, one byte is reserved for it and the message is
+//! moved when the length takes more.
+use super::{EncodeError, Field, Output, PayloadEncoder, MAX_DEPTH};
+
+const WIRE_VARINT: u32 = 0;
+const WIRE_FIXED64: u32 = 1;
+const WIRE_LEN: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Frame {
+    Root,
+    /// Nested message, its body starts at `start`
+    Message {
+        start: usize,
+    },
+    /// Repeated field, each value is written with `tag`
+    List {
+        tag: u32,
+    },
+}
+
+pub struct ProtobufEncoder<'a> {
+    out: Output<'a>,
+    stack: heapless::Vec<Frame, MAX_DEPTH>,
+}
+
+impl<'a> ProtobufEncoder<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Self {
+            out: Output::new(buf),
+            stack: heapless::Vec::new(),
+        }
+    }
+
+    fn varint(&mut self, value: u64) -> Result<(), EncodeError> {
+        let mut buf = [0u8; 10];
+        let len = write_varint(value, &mut buf);
+        self.out.bytes(&buf[..len])
+    }
+
+    fn key(&mut self, field: Field, wire_type: u32) -> Result<(), EncodeError> {
+        let tag = match self.stack.last() {
+            Some(Frame::List { tag }) => *tag,
+            _ => field.tag,
+        };
+        self.varint(((tag << 3) | wire_type) as u64)
+    }
+
+    fn push(&mut self, frame: Frame) -> Result<(), EncodeError> {
+        self.stack.push(frame).map_err(|_| EncodeError::TooDeep)
+    }
+}
+
+impl PayloadEncoder for ProtobufEncoder<'_> {
+    fn begin_message(&mut self, field: Field, _len: usize) -> Result<(), EncodeError> {
+        if self.stack.is_empty() {
+            return self.push(Frame::Root);
+        }
+        self.key(field, WIRE_LEN)?;
+        self.out.byte(0)?;
+        let start = self.out.len();
+        self.push(Frame::Message { start })
+    }
+
+    fn end_message(&mut self) -> Result<(), EncodeError> {
+        match self.stack.pop() {
+            Some(Frame::Root) => Ok(()),
+            Some(Frame::Message { start }) => {
+                let mut buf = [0u8; 10];
+                let len = write_varint((self.out.len() - start) as u64, &mut buf);
+                if len > 1 {
+                    self.out.insert(start, len - 1)?;
+                }
+                self.out.set(start - 1, &buf[..len]);
+                Ok(())
+            }
+            _ => Err(EncodeError::Unbalanced),
+        }
+    }
+
+    fn begin_list(&mut self, field: Field, _len: usize) -> Result<(), EncodeError> {
+        let tag = match self.stack.last() {
+            Some(Frame::List { tag }) => *tag,
+            _ => field.tag,
+        };
+        self.push(Frame::List { tag })
+    }
+
+    fn end_list(&mut self) -> Result<(), EncodeError> {
+        match self.stack.pop() {
+            Some(Frame::List { .. }) => Ok(()),
+            _ => Err(EncodeError::Unbalanced),
+        }
+    }
+
+    fn uint(&mut self, field: Field, value: u64) -> Result<(), EncodeError> {
+        self.key(field, WIRE_VARINT)?;
+        self.varint(value)
+    }
+
+    fn int(&mut self, field: Field, value: i64) -> Result<(), EncodeError> {
+        self.key(field, WIRE_VARINT)?;
+        self.varint(((value << 1) ^ (value >> 63)) as u64)
+    }
+
+    fn float(&mut self, field: Field, value: f64) -> Result<(), EncodeError> {
+        self.key(field, WIRE_FIXED64)?;
+        self.out.bytes(&value.to_le_bytes())
+    }
+
+    fn bool(&mut self, field: Field, value: bool) -> Result<(), EncodeError> {
+        self.uint(field, value as u64)
+    }
+
+    fn str(&mut self, field: Field, value: &str) -> Result<(), EncodeError> {
+        self.bytes(field, value.as_bytes())
+    }
+
+    fn bytes(&mut self, field: Field, value: &[u8]) -> Result<(), EncodeError> {
+        self.key(field, WIRE_LEN)?;
+        self.varint(value.len() as u64)?;
+        self.out.bytes(value)
+    }
+
+    fn len(&self) -> usize {
+        self.out.len()
+    }
+}
+
+fn write_varint(mut value: u64, out: &mut [u8; 10]) -> usize {
+    let mut len = 0;
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            out[len] = byte;
+            return len + 1;
+        }
+        out[len] = byte | 0x80;
+        len += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::hex;
+    use super::*;
+
+    fn message(
+        write: impl FnOnce(&mut ProtobufEncoder) -> Result<(), EncodeError>,
+    ) -> std::string::String {
+        let mut buf = [0u8; 256];
+        let mut e = ProtobufEncoder::new(&mut buf);
+        e.begin_message(Field::NONE, 1).unwrap();
+        write(&mut e).unwrap();
+        e.end_message().unwrap();
+        let len = e.len();
+        hex(&buf[..len])
+    }
+
+    const A: Field = Field::new(1, "a");
+    const B: Field = Field::new(2, "b");
+    const C: Field = Field::new(3, "c");
+
+    // Examples of the Protocol Buffers encoding guide
+
+    #[test]
+    fn encodes_the_guide_examples() {
+        // Test1 { a: 150 }
+        assert_eq!(message(|e| e.uint(A, 150)), "089601");
+        // Test2 { b: "testing" }
+        assert_eq!(message(|e| e.str(B, "testing")), "120774657374696E67");
+        // Test3 { c: Test1 { a: 150 } }
+        let nested = message(|e| {
+            e.begin_message(C, 1)?;
+            e.uint(A, 150)?;
+            e.end_message()
+        });
+        assert_eq!(nested, "1A03089601");
+    }
+
+    #[test]
+    fn encodes_signed_values_zigzag() {
+        let cases = [
+            (0, "0800"),
+            (-1, "0801"),
+            (1, "0802"),
+            (-2, "0803"),
+            (2_147_483_647, "08FEFFFFFF0F"),
+            (-2_147_483_648, "08FFFFFFFF0F"),
+            (i64::MIN, "08FFFFFFFFFFFFFFFFFF01"),
+        ];
+        for (n, expected) in cases {
+            assert_eq!(message(|e| e.int(A, n)), expected, "{n}");
+        }
+        assert_eq!(message(|e| e.uint(A, u64::MAX)), "08FFFFFFFFFFFFFFFFFF01");
+    }
+
+    #[test]
+    fn encodes_doubles_and_bools() {
+        assert_eq!(message(|e| e.float(A, 1.1)), "099A9999999999F13F");
+        assert_eq!(message(|e| e.bool(B, true)), "1001");
+        // Defaults are written, not skipped
+        assert_eq!(message(|e| e.bool(B, false)), "1000");
+    }
+
+    #[test]
+    fn repeats_the_tag_of_a_list() {
+        let list = message(|e| {
+            e.begin_list(B, 2)?;
+            e.uint(Field::NONE, 2)?;
+            e.uint(Field::NONE, 3)?;
+            e.end_list()?;
+            e.begin_list(C, 2)?;
+            for a in [1, 2] {
+                e.begin_message(Field::NONE, 1)?;
+                e.uint(A, a)?;
+                e.end_message()?;
+            }
+            e.end_list()
+        });
+        assert_eq!(list, "100210031A0208011A020802");
+    }
+
+    #[test]
+    fn moves_a_nested_message_longer_than_127_bytes() {
+        let data = [0xAB; 130];
+        let nested = message(|e| {
+            e.begin_message(C, 1)?;
+            e.bytes(A, &data)?;
+            e.end_message()?;
+            e.uint(B, 1)
+        });
+        // 133 bytes of body take a 2-byte length
+        let expected = std::format!("1A85010A8201{}1001", "AB".repeat(130));
+        assert_eq!(nested, expected);
+    }
+
+    #[test]
+    fn rejects_unbalanced_and_full_output() {
+        let mut buf = [0u8; 2];
+        let mut e = ProtobufEncoder::new(&mut buf);
+        e.begin_message(Field::NONE, 1).unwrap();
+        assert_eq!(e.end_list(), Err(EncodeError::Unbalanced));
+        assert_eq!(e.str(A, "ab"), Err(EncodeError::BufferFull));
+    }
+}
@@ -0,0 +1,65 @@
+//! Device presence on the retained status topics
+//!
+//! Each uplink has its own status topic, the `status` topic (`svc::topic`) with
+//! the bearer as last level (`.../status/wifi`, `.../status/lte`): the broker
+//! keeps one session per bearer, and the will of one must not mark the device
+//! offline while the other one is connected. The backend takes the device as
+//! online while any of them is. Both uplinks register the same offline message
+//! as their MQTT Last Will and publish the same retained messages:
+//! `{"status":"online","firmware":"<version>","boot_reason":"<reason>","bearer":"<wifi|lte>"}`
+//! after connecting, `{"status":"offline","reason":"sleep"}` before the modem
+//! sleeps, and the will `{"status":"offline","reason":"lost"}` when the broker
+//! loses the device.
+use core::fmt::Write;
+
+use crate::svc::topic::{self, MessageType, Topic};
+
+/// Version reported in the online message
+pub const FIRMWARE_VERSION: &str = env!("CARGO_PKG_VERSION");
+/// Last Will, published by the broker when the connection drops unexpectedly
+pub const WILL_PAYLOAD: &str = "{\"status\":\"offline\",\"reason\":\"lost\"}";
+/// Published before the modem is put to sleep
+pub const SLEEP_PAYLOAD: &str = "{\"status\":\"offline\",\"reason\":\"sleep\"}";
+pub const STATUS_PAYLOAD_LEN: usize = 192;
+
+/// Uplink carrying the MQTT connection
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Bearer {
+    Wifi,
+    Lte,
+}
+
+impl Bearer {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Bearer::Wifi => "wifi",
+            Bearer::Lte => "lte",
+        }
+    }
+}
+
+/// Retained presence topic of `bearer`
+pub fn status_topic(bearer: Bearer) -> Topic {
+    let mut topic = topic::topic(MessageType::Status);
+    let _ = write!(&mut topic, "/{}", bearer.as_str());
+    topic
+}
+
+/// Retained message published once connected
+pub fn online_payload(bearer: Bearer) -> heapless::String<STATUS_PAYLOAD_LEN> {
+    let mut payload = heapless::String::new();
+    let _ = write!(
+        &mut payload,
+        "{{\"status\":\"online\",\"firmware\":\"{FIRMWARE_VERSION}\""
+    );
+    match esp_hal::reset::reset_reason() {
+        Some(reason) => {
+            let _ = write!(&mut payload, ",\"boot_reason\":\"{reason:?}\"");
+        }
+        None => {
+            let _ = write!(&mut payload, ",\"boot_reason\":\"unknown\"");
+        }
+    }
+    let _ = write!(&mut payload, ",\"bearer\":\"{}\"}}", bearer.as_str());
+    payload
+}
@@ -0,0 +1,142 @@
+//! DER encoding of the certificate signing request and of the device key
+//!
+//! The CSR (PKCS #10) carries the device ID as common name and the P-256
+//! public key, signed with ECDSA-SHA256. The key is kept as a SEC1
+//! `EC PRIVATE KEY`, the form mbedtls reads.
+use crate::util::p256::{SecretKey, Signature};
+use crate::util::sha256;
+
+/// Longest encoded CSR, the common name is at most 64 bytes
+pub const CSR_LEN: usize = 320;
+pub const KEY_LEN: usize = 128;
+
+pub type Der<const N: usize> = heapless::Vec<u8, N>;
+
+const OID_COMMON_NAME: &[u8] = &[0x06, 0x03, 0x55, 0x04, 0x03];
+const OID_EC_PUBLIC_KEY: &[u8] = &[0x06, 0x07, 0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x02, 0x01];
+const OID_PRIME256V1: &[u8] = &[0x06, 0x08, 0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x03, 0x01, 0x07];
+const OID_ECDSA_SHA256: &[u8] = &[0x06, 0x08, 0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x04, 0x03, 0x02];
+
+const SEQUENCE: u8 = 0x30;
+const SET: u8 = 0x31;
+const INTEGER: u8 = 0x02;
+const BIT_STRING: u8 = 0x03;
+const OCTET_STRING: u8 = 0x04;
+const UTF8_STRING: u8 = 0x0C;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CsrError {
+    /// The common name is longer than 64 bytes
+    NameTooLong,
+    /// An encoding does not fit its buffer
+    Overflow,
+}
+
+/// `tag`, length and the concatenated `parts`
+fn tlv<const N: usize>(out: &mut Der<N>, tag: u8, parts: &[&[u8]]) -> Result<(), CsrError> {
+    let len: usize = parts.iter().map(|part| part.len()).sum();
+    let (header, header_len) = match len {
+        0..=0x7F => ([tag, len as u8, 0, 0], 2),
+        0x80..=0xFF => ([tag, 0x81, len as u8, 0], 3),
+        _ => ([tag, 0x82, (len >> 8) as u8, len as u8], 4),
+    };
+    out.extend_from_slice(&header[..header_len])
+        .map_err(|_| CsrError::Overflow)?;
+    for part in parts {
+        out.extend_from_slice(part)
+            .map_err(|_| CsrError::Overflow)?;
+    }
+    Ok(())
+}
+
+/// Unsigned big-endian `value` as an INTEGER
+fn integer<const N: usize>(out: &mut Der<N>, value: &[u8]) -> Result<(), CsrError> {
+    let first = value
+        .iter()
+        .position(|b| *b != 0)
+        .unwrap_or(value.len() - 1);
+    let value = &value[first..];
+    if value[0] & 0x80 != 0 {
+        tlv(out, INTEGER, &[&[0], value])
+    } else {
+        tlv(out, INTEGER, &[value])
+    }
+}
+
+fn signature(sig: &Signature) -> Result<Der<72>, CsrError> {
+    let mut ints = Der::<72>::new();
+    integer(&mut ints, &sig.r)?;
+    integer(&mut ints, &sig.s)?;
+    let mut out = Der::new();
+    tlv(&mut out, SEQUENCE, &[&ints])?;
+    Ok(out)
+}
+
+/// CSR for `common_name` signed with `key`
+pub fn build(key: &SecretKey, common_name: &str) -> Result<Der<CSR_LEN>, CsrError> {
+    if common_name.len() > 64 {
+        return Err(CsrError::NameTooLong);
+    }
+    let public_key = key.public_key();
+
+    let mut attribute = Der::<80>::new();
+    tlv(&mut attribute, UTF8_STRING, &[common_name.as_bytes()])?;
+    let mut name = Der::<96>::new();
+    {
+        let mut atv = Der::<80>::new();
+        tlv(&mut atv, SEQUENCE, &[OID_COMMON_NAME, &attribute])?;
+        let mut rdn = Der::<88>::new();
+        tlv(&mut rdn, SET, &[&atv])?;
+        tlv(&mut name, SEQUENCE, &[&rdn])?;
+    }
+
+    let mut spki = Der::<96>::new();
+    {
+        let mut algorithm = Der::<24>::new();
+        tlv(
+            &mut algorithm,
+            SEQUENCE,
+            &[OID_EC_PUBLIC_KEY, OID_PRIME256V1],
+        )?;
+        let mut bits = Der::<72>::new();
+        tlv(&mut bits, BIT_STRING, &[&[0], &public_key])?;
+        tlv(&mut spki, SEQUENCE, &[&algorithm, &bits])?;
+    }
+
+    // Version 1, no attributes
+    let mut info = Der::<208>::new();
+    tlv(
+        &mut info,
+        SEQUENCE,
+        &[&[0x02, 0x01, 0x00], &name, &spki, &[0xA0, 0x00]],
+    )?;
+    let sig = signature(&key.sign(&sha256::digest(&info)))?;
+
+    let mut algorithm = Der::<12>::new();
+    tlv(&mut algorithm, SEQUENCE, &[OID_ECDSA_SHA256])?;
+    let mut sig_bits = Der::<80>::new();
+    tlv(&mut sig_bits, BIT_STRING, &[&[0], &sig])?;
+    let mut csr = Der::new();
+    tlv(&mut csr, SEQUENCE, &[&info, &algorithm, &sig_bits])?;
+    Ok(csr)
+}
+
+/// SEC1 `ECPrivateKey` with the curve and the public key
+pub fn private_key(key: &SecretKey) -> Result<Der<KEY_LEN>, CsrError> {
+    let mut scalar = Der::<40>::new();
+    tlv(&mut scalar, OCTET_STRING, &[&key.to_bytes()])?;
+    let mut curve = Der::<12>::new();
+    tlv(&mut curve, 0xA0, &[OID_PRIME256V1])?;
+    let mut bits = Der::<72>::new();
+    tlv(&mut bits, BIT_STRING, &[&[0], &key.public_key()])?;
+    let mut public_key = Der::<76>::new();
+    tlv(&mut public_key, 0xA1, &[&bits])?;
+    let mut out = Der::new();
+    tlv(
+        &mut out,
+        SEQUENCE,
+        &[&[0x02, 0x01, 0x01], &scalar, &curve, &public_key],
+    )?;
+    scalar.iter_mut().for_each(|b| *b = 0);
+    Ok(out)
+}
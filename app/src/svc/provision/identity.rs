@@ -0,0 +1,127 @@
+//! Device certificate and private key as stored in flash
+//!
+//! `magic, format, certificate length (LE), key length (LE), certificate, key,
+//! checksum (LE)` in a fixed-size record, both PEM and NUL-terminated as
+//! mbedtls wants them.
+use crate::svc::mem::nvm::{BlockId, Nvm, NvsError};
+use crate::util::fnv::fnv1a;
+
+/// A sector, room for an RSA certificate and key
+pub const IDENTITY_LEN: usize = 4096;
+const MAGIC: u8 = 0x1D;
+const FORMAT: u8 = 1;
+const HEADER_LEN: usize = 6;
+const CHECKSUM_LEN: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IdentityError {
+    /// The certificate and key don't fit in the record
+    TooLarge,
+    Storage,
+}
+
+impl From<NvsError> for IdentityError {
+    fn from(_: NvsError) -> Self {
+        IdentityError::Storage
+    }
+}
+
+pub struct Identity {
+    record: [u8; IDENTITY_LEN],
+    valid: bool,
+}
+
+impl Default for Identity {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Identity {
+    pub const fn new() -> Self {
+        Self {
+            record: [0; IDENTITY_LEN],
+            valid: false,
+        }
+    }
+
+    /// Read the stored identity, `false` when there is none or it is corrupt
+    pub fn load(&mut self, nvm: &mut Nvm) -> bool {
+        self.valid = nvm.nvs_read(BlockId::IdentityId, &mut self.record).is_ok() && self.check();
+        self.valid
+    }
+
+    pub fn store(&self, nvm: &mut Nvm) -> Result<(), IdentityError> {
+        nvm.nvs_write(BlockId::IdentityId, &self.record)?;
+        Ok(())
+    }
+
+    /// Replace the identity, PEM without the terminating NUL
+    pub fn set(&mut self, certificate: &[u8], key: &[u8]) -> Result<(), IdentityError> {
+        let cert_len = certificate.len() + 1;
+        let key_len = key.len() + 1;
+        if HEADER_LEN + cert_len + key_len + CHECKSUM_LEN > IDENTITY_LEN {
+            return Err(IdentityError::TooLarge);
+        }
+        self.record.fill(0);
+        self.record[0] = MAGIC;
+        self.record[1] = FORMAT;
+        self.record[2..4].copy_from_slice(&(cert_len as u16).to_le_bytes());
+        self.record[4..6].copy_from_slice(&(key_len as u16).to_le_bytes());
+        let key_at = HEADER_LEN + cert_len;
+        self.record[HEADER_LEN..key_at - 1].copy_from_slice(certificate);
+        self.record[key_at..key_at + key_len - 1].copy_from_slice(key);
+        let checksum = fnv1a(&self.record[..IDENTITY_LEN - CHECKSUM_LEN]);
+        self.record[IDENTITY_LEN - CHECKSUM_LEN..].copy_from_slice(&checksum.to_le_bytes());
+        self.valid = true;
+        Ok(())
+    }
+
+    /// Certificate PEM with its NUL
+    pub fn certificate(&self) -> Option<&[u8]> {
+        let (cert, _) = self.split()?;
+        Some(cert)
+    }
+
+    /// Private key PEM with its NUL
+    pub fn private_key(&self) -> Option<&[u8]> {
+        let (_, key) = self.split()?;
+        Some(key)
+    }
+
+    fn lengths(&self) -> (usize, usize) {
+        let len = |at: usize| u16::from_le_bytes([self.record[at], self.record[at + 1]]) as usize;
+        (len(2), len(4))
+    }
+
+    fn split(&self) -> Option<(&[u8], &[u8])> {
+        if !self.valid {
+            return None;
+        }
+        let (cert_len, key_len) = self.lengths();
+        let key_at = HEADER_LEN + cert_len;
+        Some((
+            &self.record[HEADER_LEN..key_at],
+            &self.record[key_at..key_at + key_len],
+        ))
+    }
+
+    fn check(&self) -> bool {
+        let stored = u32::from_le_bytes([
+            self.record[IDENTITY_LEN - 4],
+            self.record[IDENTITY_LEN - 3],
+            self.record[IDENTITY_LEN - 2],
+            self.record[IDENTITY_LEN - 1],
+        ]);
+        let (cert_len, key_len) = self.lengths();
+        let key_at = HEADER_LEN + cert_len;
+        self.record[0] == MAGIC
+            && self.record[1] == FORMAT
+            && cert_len > 0
+            && key_len > 0
+            && key_at + key_len + CHECKSUM_LEN <= IDENTITY_LEN
+            && self.record[key_at - 1] == 0
+            && self.record[key_at + key_len - 1] == 0
+            && stored == fnv1a(&self.record[..IDENTITY_LEN - CHECKSUM_LEN])
+    }
+}
@@ -0,0 +1,241 @@
+//! Fleet provisioning by claim
+//!
+//! Units leave the factory with the same claim certificate (`DvtCrtId`). On
+//! the first connection without a device certificate the device makes its own
+//! P-256 key pair and sends a CSR for its device ID on
+//! `$aws/certificates/create-from-csr/json`. The answer carries the signed
+//! certificate and an ownership token, with which the device registers itself
+//! on `$aws/provisioning-templates/<template>/provision/json`. Once registered,
+//! the certificate and key are stored (`identity`) and used for every later
+//! connection.
+//!
+//! These are the fleet provisioning topics of AWS IoT Core, another backend has
+//! to answer the same requests.
+pub mod csr;
+pub mod identity;
+
+use core::cell::RefCell;
+use core::fmt::Write;
+
+use embassy_sync::blocking_mutex::{raw::NoopRawMutex, Mutex};
+use log::warn;
+
+use crate::svc::mqtt::handler::MessageHandler;
+use crate::svc::shadow::object;
+use crate::svc::topic::Topic;
+use crate::util::base64;
+use csr::CsrError;
+use identity::IdentityError;
+
+pub const CREATE_FROM_CSR_TOPIC: &str = "$aws/certificates/create-from-csr/json";
+/// Longest certificate PEM the backend can send
+pub const CERTIFICATE_LEN: usize = 1792;
+/// Longest ownership token, kept as the JSON string received
+pub const TOKEN_LEN: usize = 1024;
+/// Longest answer of the backend
+pub const REPLY_LEN: usize = 2560;
+pub const REQUEST_LEN: usize = 1152;
+
+pub type Request = heapless::String<REQUEST_LEN>;
+pub type ThingName = heapless::String<128>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProvisionError {
+    Csr(CsrError),
+    /// The MQTT connection failed on the way
+    Connection,
+    /// No answer in time
+    Timeout,
+    /// The backend refused the request
+    Rejected,
+    /// An answer without the expected members, or too large
+    InvalidReply,
+    Identity(IdentityError),
+}
+
+impl From<CsrError> for ProvisionError {
+    fn from(e: CsrError) -> Self {
+        ProvisionError::Csr(e)
+    }
+}
+
+impl From<IdentityError> for ProvisionError {
+    fn from(e: IdentityError) -> Self {
+        ProvisionError::Identity(e)
+    }
+}
+
+/// Answers to [`CREATE_FROM_CSR_TOPIC`], `accepted` or `rejected`
+pub fn create_from_csr_filter() -> Topic {
+    let mut filter = Topic::new();
+    let _ = write!(&mut filter, "{CREATE_FROM_CSR_TOPIC}/+");
+    filter
+}
+
+pub fn register_topic(template: &str) -> Topic {
+    let mut topic = Topic::new();
+    let _ = write!(
+        &mut topic,
+        "$aws/provisioning-templates/{template}/provision/json"
+    );
+    topic
+}
+
+/// Answers to [`register_topic`], `accepted` or `rejected`
+pub fn register_filter(template: &str) -> Topic {
+    let mut filter = register_topic(template);
+    let _ = filter.push_str("/+");
+    filter
+}
+
+/// `{"certificateSigningRequest":"<PEM>"}`
+pub fn csr_request(csr: &[u8]) -> Result<Request, ProvisionError> {
+    let mut pem = heapless::String::<512>::new();
+    base64::encode_pem(&mut pem, "CERTIFICATE REQUEST", csr).map_err(|_| CsrError::Overflow)?;
+    let mut out = Request::new();
+    let _ = out.push_str("{\"certificateSigningRequest\":\"");
+    for line in pem.lines() {
+        write!(&mut out, "{line}\\n").map_err(|_| CsrError::Overflow)?;
+    }
+    out.push_str("\"}").map_err(|_| CsrError::Overflow)?;
+    Ok(out)
+}
+
+/// Accepted answer to the CSR
+pub struct CreatedCertificate {
+    /// PEM, escapes resolved
+    pub certificate: heapless::Vec<u8, CERTIFICATE_LEN>,
+    /// JSON string with its quotes, sent back as is
+    pub token: heapless::Vec<u8, TOKEN_LEN>,
+}
+
+pub fn parse_created(payload: &[u8]) -> Result<CreatedCertificate, ProvisionError> {
+    let mut certificate = None;
+    let mut token = None;
+    let members = object::members(payload).map_err(|_| ProvisionError::InvalidReply)?;
+    for member in members {
+        match member.map_err(|_| ProvisionError::InvalidReply)? {
+            ("certificatePem", value) => certificate = unescape(value),
+            ("certificateOwnershipToken", value) if value.starts_with(b"\"") => {
+                token = heapless::Vec::from_slice(value).ok();
+            }
+            _ => {}
+        }
+    }
+    match (certificate, token) {
+        (Some(certificate), Some(token)) => Ok(CreatedCertificate { certificate, token }),
+        _ => Err(ProvisionError::InvalidReply),
+    }
+}
+
+/// `{"certificateOwnershipToken":<token>,"parameters":{"SerialNumber":"<serial>"}}`
+pub fn register_request(token: &[u8], serial: &str) -> Result<Request, ProvisionError> {
+    let token = core::str::from_utf8(token).map_err(|_| ProvisionError::InvalidReply)?;
+    let mut out = Request::new();
+    write!(
+        &mut out,
+        "{{\"certificateOwnershipToken\":{token},\"parameters\":{{\"SerialNumber\":\"{serial}\"}}}}"
+    )
+    .map_err(|_| ProvisionError::InvalidReply)?;
+    Ok(out)
+}
+
+/// Name of the thing the accepted registration created or updated
+pub fn parse_registered(payload: &[u8]) -> Result<ThingName, ProvisionError> {
+    let members = object::members(payload).map_err(|_| ProvisionError::InvalidReply)?;
+    for member in members {
+        if let ("thingName", value) = member.map_err(|_| ProvisionError::InvalidReply)? {
+            let name = unescape::<128>(value).ok_or(ProvisionError::InvalidReply)?;
+            let name = core::str::from_utf8(&name).map_err(|_| ProvisionError::InvalidReply)?;
+            return ThingName::try_from(name).map_err(|_| ProvisionError::InvalidReply);
+        }
+    }
+    Err(ProvisionError::InvalidReply)
+}
+
+/// `errorMessage` of a rejection, as received
+pub fn rejection_reason(payload: &[u8]) -> &str {
+    let reason = object::members(payload).ok().and_then(|mut members| {
+        members.find_map(|member| match member {
+            Ok(("errorMessage", value)) => core::str::from_utf8(value).ok(),
+            _ => None,
+        })
+    });
+    reason.unwrap_or("no reason given")
+}
+
+/// Contents of a raw JSON string value, `None` for anything else
+fn unescape<const N: usize>(value: &[u8]) -> Option<heapless::Vec<u8, N>> {
+    let inner = value.strip_prefix(b"\"")?.strip_suffix(b"\"")?;
+    let mut out = heapless::Vec::new();
+    let mut bytes = inner.iter();
+    while let Some(byte) = bytes.next() {
+        let byte = match byte {
+            b'\\' => match bytes.next()? {
+                b'n' => b'\n',
+                b'r' => b'\r',
+                b't' => b'\t',
+                b'b' => 0x08,
+                b'f' => 0x0C,
+                b'u' => {
+                    // Only ASCII ends up escaped like this in a PEM or a name
+                    let mut code = 0u32;
+                    for _ in 0..4 {
+                        code = code << 4 | (*bytes.next()? as char).to_digit(16)?;
+                    }
+                    u8::try_from(code).ok().filter(u8::is_ascii)?
+                }
+                other @ (b'"' | b'\\' | b'/') => *other,
+                _ => return None,
+            },
+            other => *other,
+        };
+        out.push(byte).ok()?;
+    }
+    Some(out)
+}
+
+/// Answer of the backend to the last request
+pub struct Reply {
+    pub accepted: bool,
+    pub payload: heapless::Vec<u8, REPLY_LEN>,
+}
+
+/// Keeps the answers received on the provisioning topics for the MQTT task
+pub struct ProvisioningHandler {
+    reply: Mutex<NoopRawMutex, RefCell<Option<Reply>>>,
+}
+
+impl Default for ProvisioningHandler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ProvisioningHandler {
+    pub const fn new() -> Self {
+        Self {
+            reply: Mutex::new(RefCell::new(None)),
+        }
+    }
+
+    pub fn take(&self) -> Option<Reply> {
+        self.reply.lock(|reply| reply.borrow_mut().take())
+    }
+}
+
+impl MessageHandler for ProvisioningHandler {
+    fn on_message(&self, topic: &str, payload: &[u8]) {
+        let accepted = match topic.rsplit('/').next() {
+            Some("accepted") => true,
+            Some("rejected") => false,
+            _ => return,
+        };
+        let Ok(payload) = heapless::Vec::from_slice(payload) else {
+            warn!("Provisioning answer of {} bytes too large", payload.len());
+            return;
+        };
+        self.reply
+            .lock(|reply| *reply.borrow_mut() = Some(Reply { accepted, payload }));
+    }
+}
@@ -0,0 +1,452 @@
+//! Remote procedure calls over MQTT
+//!
+//! The backend publishes a request on `<rpc/req topic>/<id>`, for example
+//! `{"method":"read_did","params":{"did":61840},"timeout_ms":2000}`, and gets the
+//! response on `<rpc/res topic>/<id>` as `{"id":"<id>","status":200,"result":{..}}`
+//! or `{"id":"<id>","status":404,"error":"unknown method"}`.
+//!
+//! Requests from both bearers end up in the same inbox and the responses go
+//! through the outbox, whichever bearer is connected delivers them. A request ID
+//! answered recently gets the same response again without running the method a
+//! second time, which covers QoS 1 redeliveries and retries of the backend.
+use core::fmt::Write;
+
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, channel::Channel};
+use embassy_time::{Duration, Instant};
+use log::warn;
+use serde::Deserialize;
+
+use crate::svc::can::filter::{CanFilter, MAX_FILTERS};
+use crate::svc::config::ConfigPatch;
+use crate::svc::mqtt::handler::MessageHandler;
+use crate::svc::topic::{topic, MessageType, Topic};
+use crate::svc::uplink::{Priority, Uplink, UplinkMessage};
+
+/// Longest request ID, a UUID fits
+pub const ID_LEN: usize = 36;
+/// Longest request payload
+pub const REQUEST_LEN: usize = 256;
+/// Longest `result` member of a response
+pub const RESULT_LEN: usize = 288;
+pub const RESPONSE_LEN: usize = 384;
+/// Longest credential chunk of stage_credentials, decoded
+pub const CHUNK_LEN: usize = 120;
+/// Upper bound of the timeout a request can ask for
+pub const MAX_TIMEOUT: Duration = Duration::from_secs(30);
+/// Number of responses kept to answer repeated request IDs
+const RECENT_CALLS: usize = 8;
+
+pub type RpcInbox = Channel<NoopRawMutex, RpcRequest, 4>;
+pub type RequestId = heapless::String<ID_LEN>;
+/// `result` member of a successful response, a JSON object
+pub type Reply = heapless::String<RESULT_LEN>;
+
+/// Request as received, parsed by the RPC task
+#[derive(Debug, Clone)]
+pub struct RpcRequest {
+    pub id: RequestId,
+    pub payload: heapless::Vec<u8, REQUEST_LEN>,
+    /// The time spent in the inbox counts against the timeout
+    pub received_at: Instant,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+    Ok,
+    BadRequest,
+    Forbidden,
+    NotFound,
+    Timeout,
+    TooLarge,
+    Failed,
+    Busy,
+}
+
+impl Status {
+    pub fn code(&self) -> u16 {
+        match self {
+            Status::Ok => 200,
+            Status::BadRequest => 400,
+            Status::Forbidden => 403,
+            Status::NotFound => 404,
+            Status::Timeout => 408,
+            Status::TooLarge => 413,
+            Status::Failed => 500,
+            Status::Busy => 503,
+        }
+    }
+}
+
+/// Why a call did not succeed, the `status` and `error` members of the response
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Failure {
+    pub status: Status,
+    pub reason: &'static str,
+}
+
+impl Failure {
+    pub const fn new(status: Status, reason: &'static str) -> Self {
+        Self { status, reason }
+    }
+}
+
+pub type Outcome = Result<Reply, Failure>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Method {
+    /// Restart the device once the response is out
+    Reboot,
+    /// Current `svc::config` settings
+    GetConfig,
+    /// Update some `svc::config` settings
+    SetConfig,
+    /// UDS ReadDataByIdentifier over ISO-TP
+    ReadDid,
+    /// Transmit one frame on the bus
+    SendCan,
+    /// Start a live CAN session, see `svc::sniff`
+    StartCapture,
+    /// Write a chunk of new TLS credentials, see `svc::credentials`
+    StageCredentials,
+    /// Try the staged credentials and commit them once the broker accepts them
+    RotateCredentials,
+}
+
+impl Method {
+    pub fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "reboot" => Method::Reboot,
+            "get_config" => Method::GetConfig,
+            "set_config" => Method::SetConfig,
+            "read_did" => Method::ReadDid,
+            "send_can" => Method::SendCan,
+            "start_capture" => Method::StartCapture,
+            "stage_credentials" => Method::StageCredentials,
+            "rotate_credentials" => Method::RotateCredentials,
+            _ => return None,
+        })
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Method::Reboot => "reboot",
+            Method::GetConfig => "get_config",
+            Method::SetConfig => "set_config",
+            Method::ReadDid => "read_did",
+            Method::SendCan => "send_can",
+            Method::StartCapture => "start_capture",
+            Method::StageCredentials => "stage_credentials",
+            Method::RotateCredentials => "rotate_credentials",
+        }
+    }
+
+    /// Timeout of a request that does not ask for one
+    pub fn default_timeout(&self) -> Duration {
+        match self {
+            Method::ReadDid => Duration::from_secs(2),
+            // A reconnection of the Wi-Fi uplink
+            Method::RotateCredentials => MAX_TIMEOUT,
+            _ => Duration::from_secs(1),
+        }
+    }
+}
+
+/// Parameters of every method, each one reads its own and ignores the others
+#[derive(Debug, Default, Deserialize)]
+pub struct Params {
+    /// reboot: time left to the uplinks to deliver the response
+    pub delay_ms: Option<u32>,
+    /// set_config
+    pub telemetry_interval_s: Option<u32>,
+    pub trip_interval_s: Option<u32>,
+    pub can_telemetry: Option<bool>,
+    /// read_did: data identifier, request and response CAN IDs
+    pub did: Option<u16>,
+    pub tx_id: Option<u32>,
+    pub rx_id: Option<u32>,
+    /// read_did and send_can: extended CAN IDs
+    pub ext: Option<bool>,
+    /// send_can: CAN ID and hex data
+    pub id: Option<u32>,
+    pub data: Option<heapless::String<16>>,
+    /// start_capture, as in the live session command
+    pub session_id: Option<heapless::String<36>>,
+    pub duration_s: Option<u32>,
+    pub byte_budget: Option<u32>,
+    pub filters: Option<heapless::Vec<CanFilter, MAX_FILTERS>>,
+    /// stage_credentials: `ca`, `cert` or `key`, offset of the chunk in it and
+    /// the chunk in base64, at most [`CHUNK_LEN`] bytes
+    pub part: Option<heapless::String<8>>,
+    pub offset: Option<u32>,
+    pub chunk: Option<heapless::String<160>>,
+    /// rotate_credentials: base64 signature of a new CA bundle, see
+    /// `svc::credentials::trust`
+    pub signature: Option<heapless::String<88>>,
+}
+
+impl Params {
+    pub fn config_patch(&self) -> ConfigPatch {
+        ConfigPatch {
+            telemetry_interval_s: self.telemetry_interval_s,
+            trip_interval_s: self.trip_interval_s,
+            can_telemetry: self.can_telemetry,
+            can_filters: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Envelope {
+    method: heapless::String<24>,
+    params: Option<Params>,
+    timeout_ms: Option<u32>,
+}
+
+/// Parsed request
+#[derive(Debug)]
+pub struct Call {
+    pub method: Method,
+    pub params: Params,
+    pub timeout: Duration,
+}
+
+impl Call {
+    pub fn parse(payload: &[u8]) -> Result<Self, Failure> {
+        let (envelope, _) = serde_json_core::from_slice::<Envelope>(payload)
+            .map_err(|_| Failure::new(Status::BadRequest, "invalid request"))?;
+        let method = Method::from_name(&envelope.method)
+            .ok_or(Failure::new(Status::NotFound, "unknown method"))?;
+        let timeout = envelope.timeout_ms.map_or(method.default_timeout(), |ms| {
+            Duration::from_millis(ms as u64).min(MAX_TIMEOUT)
+        });
+        Ok(Self {
+            method,
+            params: envelope.params.unwrap_or_default(),
+            timeout,
+        })
+    }
+}
+
+/// `{"id":"<id>","status":<code>,"result":<reply>}` or
+/// `{"id":"<id>","status":<code>,"error":"<reason>"}`
+pub fn render_response(id: &str, outcome: &Outcome) -> heapless::String<RESPONSE_LEN> {
+    let mut out = heapless::String::new();
+    let _ = match outcome {
+        Ok(reply) => write!(
+            &mut out,
+            "{{\"id\":\"{id}\",\"status\":{},\"result\":{}}}",
+            Status::Ok.code(),
+            if reply.is_empty() {
+                "{}"
+            } else {
+                reply.as_str()
+            }
+        ),
+        Err(failure) => write!(
+            &mut out,
+            "{{\"id\":\"{id}\",\"status\":{},\"error\":\"{}\"}}",
+            failure.status.code(),
+            failure.reason
+        ),
+    };
+    out
+}
+
+/// Response topic of request `id`
+pub fn response_topic(id: &str) -> Topic {
+    let mut topic = topic(MessageType::RpcResponse);
+    let _ = write!(&mut topic, "/{id}");
+    topic
+}
+
+pub fn response_message(id: &str, response: &str) -> UplinkMessage {
+    UplinkMessage::json(&response_topic(id), response)
+}
+
+/// ID of a request received on `topic`, the last level under the request topic
+///
+/// The response topic is built from it, it must not add levels nor wildcards.
+fn request_id(topic_name: &str) -> Option<RequestId> {
+    let id = topic_name
+        .strip_prefix(topic(MessageType::RpcRequest).as_str())?
+        .strip_prefix('/')?;
+    if id.is_empty() || id.contains(['/', '+', '#', '"', '\\']) {
+        return None;
+    }
+    RequestId::try_from(id).ok()
+}
+
+/// Queue a request received on `topic` for the RPC task
+///
+/// Requests the task can't take are answered right away.
+pub fn submit(inbox: &RpcInbox, uplink: &Uplink, topic_name: &str, payload: &[u8]) {
+    let Some(id) = request_id(topic_name) else {
+        warn!("RPC request on {topic_name} dropped, invalid request ID");
+        return;
+    };
+    let Ok(payload) = heapless::Vec::from_slice(payload) else {
+        reject(
+            uplink,
+            &id,
+            Failure::new(Status::TooLarge, "request too large"),
+        );
+        return;
+    };
+    let request = RpcRequest {
+        id,
+        payload,
+        received_at: Instant::now(),
+    };
+    if let Err(embassy_sync::channel::TrySendError::Full(request)) = inbox.try_send(request) {
+        reject(uplink, &request.id, Failure::new(Status::Busy, "busy"));
+    }
+}
+
+fn reject(uplink: &Uplink, id: &str, failure: Failure) {
+    warn!("RPC request {id} rejected: {}", failure.reason);
+    let response = render_response(id, &Err(failure));
+    if !uplink.try_send(Priority::Normal, response_message(id, &response)) {
+        warn!("Outbox full, RPC response {id} dropped");
+    }
+}
+
+/// Responses of the last requests, by request ID
+pub struct RecentCalls {
+    calls: heapless::Deque<(RequestId, heapless::String<RESPONSE_LEN>), RECENT_CALLS>,
+}
+
+impl Default for RecentCalls {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RecentCalls {
+    pub const fn new() -> Self {
+        Self {
+            calls: heapless::Deque::new(),
+        }
+    }
+
+    pub fn find(&self, id: &str) -> Option<&str> {
+        self.calls
+            .iter()
+            .find(|(call_id, _)| call_id == id)
+            .map(|(_, response)| response.as_str())
+    }
+
+    /// Remember the response of `id`, the oldest one goes when full
+    pub fn insert(&mut self, id: &RequestId, response: &str) {
+        if self.calls.is_full() {
+            let _ = self.calls.pop_front();
+        }
+        let Ok(response) = heapless::String::try_from(response) else {
+            return;
+        };
+        let _ = self.calls.push_back((id.clone(), response));
+    }
+}
+
+/// Hands the requests received on the Wi-Fi connection to the RPC task
+pub struct RpcRequestHandler {
+    inbox: &'static RpcInbox,
+    uplink: &'static Uplink,
+}
+
+impl RpcRequestHandler {
+    pub const fn new(inbox: &'static RpcInbox, uplink: &'static Uplink) -> Self {
+        Self { inbox, uplink }
+    }
+}
+
+impl MessageHandler for RpcRequestHandler {
+    fn on_message(&self, topic: &str, payload: &[u8]) {
+        submit(self.inbox, self.uplink, topic, payload);
+    }
+}
+
+/// Topic filter of the requests, one level per request ID
+pub fn request_filter() -> Topic {
+    let mut filter = topic(MessageType::RpcRequest);
+    let _ = filter.push_str("/+");
+    filter
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(n: usize) -> RequestId {
+        let mut id = RequestId::new();
+        let _ = write!(&mut id, "req-{n}");
+        id
+    }
+
+    fn response(n: usize) -> heapless::String<RESPONSE_LEN> {
+        render_response(&id(n), &Ok(Reply::new()))
+    }
+
+    #[test]
+    fn repeated_request_gets_the_same_response() {
+        let mut recent = RecentCalls::new();
+        assert_eq!(recent.find("req-0"), None);
+        recent.insert(&id(0), &response(0));
+        assert_eq!(
+            recent.find("req-0"),
+            Some(r#"{"id":"req-0","status":200,"result":{}}"#)
+        );
+        assert_eq!(recent.find("req-1"), None);
+        // Only whole IDs match
+        assert_eq!(recent.find("req-"), None);
+    }
+
+    #[test]
+    fn full_window_forgets_the_oldest() {
+        let mut recent = RecentCalls::new();
+        for n in 0..RECENT_CALLS {
+            recent.insert(&id(n), &response(n));
+        }
+        for n in 0..RECENT_CALLS {
+            assert_eq!(recent.find(&id(n)), Some(response(n).as_str()));
+        }
+        recent.insert(&id(RECENT_CALLS), &response(RECENT_CALLS));
+        assert_eq!(recent.find("req-0"), None);
+        for n in 1..=RECENT_CALLS {
+            assert_eq!(recent.find(&id(n)), Some(response(n).as_str()));
+        }
+    }
+
+    #[test]
+    fn evicted_request_runs_again() {
+        let mut recent = RecentCalls::new();
+        for n in 0..=RECENT_CALLS {
+            recent.insert(&id(n), &response(n));
+        }
+        // The replay of req-0 is not recognized, its new response is kept
+        assert_eq!(recent.find("req-0"), None);
+        let failed = render_response("req-0", &Err(Failure::new(Status::Failed, "again")));
+        recent.insert(&id(0), &failed);
+        assert_eq!(recent.find("req-0"), Some(failed.as_str()));
+        assert_eq!(recent.find("req-1"), None);
+        assert_eq!(
+            recent.find(&id(RECENT_CALLS)),
+            Some(response(RECENT_CALLS).as_str())
+        );
+    }
+
+    #[test]
+    fn request_ids() {
+        let base = topic(MessageType::RpcRequest);
+        let on = |id: &str| request_id(&std::format!("{base}/{id}"));
+        assert_eq!(on("4b5c").as_deref(), Some("4b5c"));
+        assert_eq!(
+            on("123e4567-e89b-12d3-a456-426614174000").as_deref(),
+            Some("123e4567-e89b-12d3-a456-426614174000")
+        );
+        for invalid in ["", "a/b", "+", "#", "a\"b", "a\\b", &"x".repeat(ID_LEN + 1)] {
+            assert_eq!(on(invalid), None, "{invalid}");
+        }
+        assert_eq!(request_id("other/topic/4b5c"), None);
+        assert_eq!(request_id(&std::format!("{base}4b5c")), None);
+    }
+}
@@ -0,0 +1,473 @@
+//! Device shadow
+//!
+//! The backend publishes the desired settings, retained, as
+//! `{"version":<n>,"state":{"telemetry_interval_s":10,"can_filters":[..]}}`. The
+//! device applies the keys it supports, persists the result and answers with
+//! `{"version":<n>,"state":{<settings in effect>},"delta":{<rejected keys>}}`,
+//! `delta` holding each rejected key with its desired value. A `null` value puts
+//! the setting back to its default.
+//!
+//! The desired document is subscribed again on every connection and the reported
+//! one published again, so a change made while the device was offline is picked
+//! up from the retained message. A document not newer than the applied version
+//! is only answered with the current report.
+pub mod object;
+
+use core::fmt::Write;
+
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, channel::Channel, signal::Signal};
+use log::warn;
+
+use crate::svc::can::filter::CanFilter;
+use crate::svc::config::{ConfigPatch, DeviceConfig, TelemetryFilters, MAX_TELEMETRY_FILTERS};
+use crate::svc::mqtt::handler::MessageHandler;
+use crate::util::fnv::fnv1a;
+
+/// Longest desired document
+pub const DESIRED_LEN: usize = 512;
+pub const REPORTED_LEN: usize = 768;
+/// Room for the rejected members of the last desired document
+const DELTA_LEN: usize = 384;
+
+/// Size of the persisted shadow, see [`encode_record`]
+pub const RECORD_LEN: usize = 56;
+const RECORD_MAGIC: u8 = 0x5D;
+const RECORD_FORMAT: u8 = 1;
+const RECORD_FILTER_LEN: usize = 9;
+
+pub type DesiredDocument = heapless::Vec<u8, DESIRED_LEN>;
+
+/// Desired documents from both bearers and resynchronization requests
+pub struct ShadowLink {
+    desired: Channel<NoopRawMutex, DesiredDocument, 2>,
+    resync: Signal<NoopRawMutex, ()>,
+}
+
+impl Default for ShadowLink {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ShadowLink {
+    pub const fn new() -> Self {
+        Self {
+            desired: Channel::new(),
+            resync: Signal::new(),
+        }
+    }
+
+    /// Queue a received desired document
+    pub fn submit(&self, payload: &[u8]) {
+        let Ok(doc) = DesiredDocument::from_slice(payload) else {
+            warn!("Desired document of {} bytes too large", payload.len());
+            return;
+        };
+        if self.desired.try_send(doc).is_err() {
+            // The retained document comes again on the next connection
+            warn!("Desired document dropped, shadow busy");
+        }
+    }
+
+    /// A bearer connected, the reported document has to be published again
+    pub fn resync(&self) {
+        self.resync.signal(());
+    }
+
+    pub async fn receive(&self) -> DesiredDocument {
+        self.desired.receive().await
+    }
+
+    pub async fn wait_resync(&self) {
+        self.resync.wait().await
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ShadowError {
+    /// Not a JSON object, or no version or state member
+    InvalidDocument,
+}
+
+/// Applied state of the shadow
+pub struct Shadow {
+    /// Version of the last desired document applied
+    pub version: u32,
+    /// Rejected members of that document, `"key":<value>` separated by commas
+    delta: heapless::String<DELTA_LEN>,
+}
+
+impl Default for Shadow {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Shadow {
+    pub const fn new() -> Self {
+        Self {
+            version: 0,
+            delta: heapless::String::new(),
+        }
+    }
+
+    pub const fn with_version(version: u32) -> Self {
+        Self {
+            version,
+            delta: heapless::String::new(),
+        }
+    }
+
+    /// Apply a desired document to `config`
+    ///
+    /// Returns `Ok(false)` when the document is not newer than the applied one.
+    pub fn apply(&mut self, config: &mut DeviceConfig, doc: &[u8]) -> Result<bool, ShadowError> {
+        let mut version = None;
+        let mut state = None;
+        for member in object::members(doc).map_err(|_| ShadowError::InvalidDocument)? {
+            match member.map_err(|_| ShadowError::InvalidDocument)? {
+                ("version", value) => {
+                    version = serde_json_core::from_slice::<u32>(value)
+                        .ok()
+                        .map(|(v, _)| v);
+                }
+                ("state", value) => state = Some(value),
+                _ => {}
+            }
+        }
+        let (Some(version), Some(state)) = (version, state) else {
+            return Err(ShadowError::InvalidDocument);
+        };
+        if version <= self.version {
+            return Ok(false);
+        }
+
+        self.delta.clear();
+        for member in object::members(state).map_err(|_| ShadowError::InvalidDocument)? {
+            let Ok((key, value)) = member else {
+                warn!("Desired state malformed, the rest of it is ignored");
+                break;
+            };
+            let applied = patch(key, value).is_some_and(|patch| config.apply(&patch).is_ok());
+            if !applied {
+                warn!("Desired setting {key} rejected");
+                self.reject(key, value);
+            }
+        }
+        self.version = version;
+        Ok(true)
+    }
+
+    fn reject(&mut self, key: &str, value: &[u8]) {
+        let Ok(value) = core::str::from_utf8(value) else {
+            return;
+        };
+        let mark = self.delta.len();
+        let separator = if self.delta.is_empty() { "" } else { "," };
+        if write!(&mut self.delta, "{separator}\"{key}\":{value}").is_err() {
+            // Keep the members that fit, the key alone says what was rejected
+            self.delta.truncate(mark);
+            let _ = write!(&mut self.delta, "{separator}\"{key}\":null");
+        }
+    }
+
+    /// `{"version":<n>,"state":{..},"delta":{..}}`
+    pub fn reported(&self, config: &DeviceConfig) -> heapless::String<REPORTED_LEN> {
+        let mut out = heapless::String::new();
+        let _ = write!(&mut out, "{{\"version\":{},\"state\":", self.version);
+        let _ = config.write_json(&mut out);
+        let _ = write!(&mut out, ",\"delta\":{{{}}}}}", self.delta);
+        out
+    }
+}
+
+/// Patch setting `key` to the JSON `value`, `None` for an unknown key or a value
+/// of the wrong type
+fn patch(key: &str, value: &[u8]) -> Option<ConfigPatch> {
+    let reset = value == b"null";
+    let defaults = DeviceConfig::new();
+    let mut patch = ConfigPatch::default();
+    match key {
+        "telemetry_interval_s" => {
+            patch.telemetry_interval_s = Some(if reset {
+                defaults.telemetry_interval_s
+            } else {
+                parse(value)?
+            })
+        }
+        "trip_interval_s" => {
+            patch.trip_interval_s = Some(if reset {
+                defaults.trip_interval_s
+            } else {
+                parse(value)?
+            })
+        }
+        "can_telemetry" => {
+            patch.can_telemetry = Some(if reset {
+                defaults.can_telemetry
+            } else {
+                parse(value)?
+            })
+        }
+        "can_filters" => {
+            patch.can_filters = Some(if reset {
+                defaults.can_filters
+            } else {
+                parse::<TelemetryFilters>(value)?
+            })
+        }
+        _ => return None,
+    }
+    Some(patch)
+}
+
+fn parse<'de, T: serde::Deserialize<'de>>(value: &'de [u8]) -> Option<T> {
+    serde_json_core::from_slice::<T>(value).ok().map(|(v, _)| v)
+}
+
+/// Applied version and settings as stored in flash
+///
+/// `magic, format, version (LE), telemetry_interval_s (LE), trip_interval_s (LE),
+/// flags, filter count, filters, checksum (LE)`, each filter as
+/// `id (LE), mask (LE), flags`.
+pub fn encode_record(version: u32, config: &DeviceConfig) -> [u8; RECORD_LEN] {
+    let mut record = [0u8; RECORD_LEN];
+    record[0] = RECORD_MAGIC;
+    record[1] = RECORD_FORMAT;
+    record[2..6].copy_from_slice(&version.to_le_bytes());
+    record[6..10].copy_from_slice(&config.telemetry_interval_s.to_le_bytes());
+    record[10..14].copy_from_slice(&config.trip_interval_s.to_le_bytes());
+    record[14] = config.can_telemetry as u8;
+    record[15] = config.can_filters.len() as u8;
+    for (idx, filter) in config.can_filters.iter().enumerate() {
+        let at = 16 + idx * RECORD_FILTER_LEN;
+        record[at..at + 4].copy_from_slice(&filter.id.to_le_bytes());
+        record[at + 4..at + 8].copy_from_slice(&filter.mask.unwrap_or(0).to_le_bytes());
+        record[at + 8] = filter.mask.is_some() as u8
+            | (filter.ext.is_some() as u8) << 1
+            | ((filter.ext == Some(true)) as u8) << 2;
+    }
+    let checksum = fnv1a(&record[..RECORD_LEN - 4]);
+    record[RECORD_LEN - 4..].copy_from_slice(&checksum.to_le_bytes());
+    record
+}
+
+/// Counterpart of [`encode_record`], `None` for erased flash or a corrupt record
+pub fn decode_record(record: &[u8; RECORD_LEN]) -> Option<(u32, DeviceConfig)> {
+    let stored = u32::from_le_bytes(record[RECORD_LEN - 4..].try_into().ok()?);
+    if record[0] != RECORD_MAGIC
+        || record[1] != RECORD_FORMAT
+        || stored != fnv1a(&record[..RECORD_LEN - 4])
+    {
+        return None;
+    }
+    let u32_at = |at: usize| {
+        u32::from_le_bytes([record[at], record[at + 1], record[at + 2], record[at + 3]])
+    };
+    let count = record[15] as usize;
+    if count > MAX_TELEMETRY_FILTERS {
+        return None;
+    }
+    let mut config = DeviceConfig {
+        telemetry_interval_s: u32_at(6),
+        trip_interval_s: u32_at(10),
+        can_telemetry: record[14] != 0,
+        can_filters: heapless::Vec::new(),
+    };
+    for idx in 0..count {
+        let at = 16 + idx * RECORD_FILTER_LEN;
+        let flags = record[at + 8];
+        let _ = config.can_filters.push(CanFilter {
+            id: u32_at(at),
+            mask: (flags & 0x01 != 0).then(|| u32_at(at + 4)),
+            ext: (flags & 0x02 != 0).then_some(flags & 0x04 != 0),
+        });
+    }
+    Some((u32_at(2), config))
+}
+
+/// Hands the desired documents received on the Wi-Fi connection to the shadow task
+pub struct ShadowDesiredHandler {
+    link: &'static ShadowLink,
+}
+
+impl ShadowDesiredHandler {
+    pub const fn new(link: &'static ShadowLink) -> Self {
+        Self { link }
+    }
+}
+
+impl MessageHandler for ShadowDesiredHandler {
+    fn on_message(&self, _topic: &str, payload: &[u8]) {
+        self.link.submit(payload);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(
+        shadow: &mut Shadow,
+        config: &mut DeviceConfig,
+        doc: &str,
+    ) -> Result<bool, ShadowError> {
+        shadow.apply(config, doc.as_bytes())
+    }
+
+    #[test]
+    fn applies_newer_versions_only() {
+        let mut shadow = Shadow::new();
+        let mut config = DeviceConfig::new();
+        let doc = r#"{"version":5,"state":{"telemetry_interval_s":10}}"#;
+        assert_eq!(apply(&mut shadow, &mut config, doc), Ok(true));
+        assert_eq!(shadow.version, 5);
+        assert_eq!(config.telemetry_interval_s, 10);
+
+        // The retained document again, then an older one
+        for stale in [
+            r#"{"version":5,"state":{"telemetry_interval_s":20}}"#,
+            r#"{"version":4,"state":{"telemetry_interval_s":30}}"#,
+            r#"{"version":0,"state":{"trip_interval_s":30}}"#,
+        ] {
+            assert_eq!(apply(&mut shadow, &mut config, stale), Ok(false));
+        }
+        assert_eq!(shadow.version, 5);
+        assert_eq!(config.telemetry_interval_s, 10);
+        assert_eq!(config.trip_interval_s, 0);
+
+        let doc = r#"{"state":{"telemetry_interval_s":20},"version":6}"#;
+        assert_eq!(apply(&mut shadow, &mut config, doc), Ok(true));
+        assert_eq!(config.telemetry_interval_s, 20);
+    }
+
+    #[test]
+    fn restored_version_rejects_older_documents() {
+        let mut shadow = Shadow::with_version(9);
+        let mut config = DeviceConfig::new();
+        let doc = r#"{"version":8,"state":{"can_telemetry":false}}"#;
+        assert_eq!(apply(&mut shadow, &mut config, doc), Ok(false));
+        assert!(config.can_telemetry);
+    }
+
+    #[test]
+    fn invalid_documents() {
+        let mut shadow = Shadow::with_version(1);
+        let mut config = DeviceConfig::new();
+        for doc in [
+            "",
+            "[]",
+            r#"{"state":{}}"#,
+            r#"{"version":2}"#,
+            r#"{"version":-2,"state":{}}"#,
+            r#"{"version":"2","state":{}}"#,
+            r#"{"version":2,"state":{}"#,
+        ] {
+            assert_eq!(
+                apply(&mut shadow, &mut config, doc),
+                Err(ShadowError::InvalidDocument),
+                "{doc}"
+            );
+        }
+        assert_eq!(shadow.version, 1);
+        assert_eq!(config, DeviceConfig::new());
+    }
+
+    #[test]
+    fn partial_delta_reports_the_rejected_keys() {
+        let mut shadow = Shadow::new();
+        let mut config = DeviceConfig::new();
+        let doc = r#"{"version":3,"state":{
+            "telemetry_interval_s": 15,
+            "trip_interval_s": 999999,
+            "can_telemetry": "yes",
+            "sampling": {"rate": 2},
+            "can_filters": [{"id":256,"mask":1792,"ext":false}]
+        }}"#;
+        assert_eq!(apply(&mut shadow, &mut config, doc), Ok(true));
+        assert_eq!(config.telemetry_interval_s, 15);
+        assert_eq!(config.trip_interval_s, 0);
+        assert!(config.can_telemetry);
+        assert_eq!(config.can_filters.len(), 1);
+        assert_eq!(
+            shadow.reported(&config),
+            r#"{"version":3,"state":{"telemetry_interval_s":15,"trip_interval_s":0,"can_telemetry":true,"can_filters":[{"id":256,"mask":1792,"ext":false}]},"delta":{"trip_interval_s":999999,"can_telemetry":"yes","sampling":{"rate": 2}}}"#
+        );
+
+        // The next document starts a new delta
+        let doc = r#"{"version":4,"state":{"can_telemetry":false}}"#;
+        assert_eq!(apply(&mut shadow, &mut config, doc), Ok(true));
+        assert!(!config.can_telemetry);
+        assert!(shadow.reported(&config).ends_with(r#","delta":{}}"#));
+    }
+
+    #[test]
+    fn null_resets_to_the_default() {
+        let mut shadow = Shadow::new();
+        let mut config = DeviceConfig::new();
+        let doc = r#"{"version":1,"state":{"trip_interval_s":60,"can_telemetry":false}}"#;
+        assert_eq!(apply(&mut shadow, &mut config, doc), Ok(true));
+        let doc = r#"{"version":2,"state":{"trip_interval_s":null}}"#;
+        assert_eq!(apply(&mut shadow, &mut config, doc), Ok(true));
+        assert_eq!(config.trip_interval_s, 0);
+        assert!(!config.can_telemetry);
+    }
+
+    #[test]
+    fn oversized_rejection_keeps_the_key() {
+        let mut shadow = Shadow::new();
+        let mut config = DeviceConfig::new();
+        let long = "x".repeat(DELTA_LEN);
+        let doc = std::format!(
+            r#"{{"version":1,"state":{{"mode":"fast","label":"{long}","telemetry_interval_s":5}}}}"#
+        );
+        assert_eq!(apply(&mut shadow, &mut config, &doc), Ok(true));
+        assert_eq!(config.telemetry_interval_s, 5);
+        assert!(shadow
+            .reported(&config)
+            .ends_with(r#","delta":{"mode":"fast","label":null}}"#));
+    }
+
+    #[test]
+    fn malformed_state_keeps_the_members_before() {
+        let mut shadow = Shadow::new();
+        let mut config = DeviceConfig::new();
+        let doc = r#"{"version":1,"state":{"telemetry_interval_s":5,"trip_interval_s" 6}}"#;
+        assert_eq!(apply(&mut shadow, &mut config, doc), Ok(true));
+        assert_eq!(config.telemetry_interval_s, 5);
+        assert_eq!(config.trip_interval_s, 0);
+    }
+
+    #[test]
+    fn record_round_trip() {
+        let mut config = DeviceConfig::new();
+        config.telemetry_interval_s = 30;
+        config.can_telemetry = false;
+        for filter in [
+            CanFilter {
+                id: 0x100,
+                mask: Some(0x700),
+                ext: Some(false),
+            },
+            CanFilter {
+                id: 0x18FE_F100,
+                mask: None,
+                ext: Some(true),
+            },
+            CanFilter {
+                id: 0x7E8,
+                mask: None,
+                ext: None,
+            },
+        ] {
+            config.can_filters.push(filter).unwrap();
+        }
+        let record = encode_record(7, &config);
+        assert_eq!(decode_record(&record), Some((7, config)));
+
+        let mut torn = record;
+        torn[6] ^= 1;
+        assert_eq!(decode_record(&torn), None);
+        assert_eq!(decode_record(&[0xFF; RECORD_LEN]), None);
+    }
+}
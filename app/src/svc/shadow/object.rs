@@ -0,0 +1,147 @@
+//! Members of a JSON object with their values left unparsed
+//!
+//! The shadow documents carry keys the device does not know and has to report
+//! back, which a typed deserializer would silently drop. Values are returned as
+//! raw slices and parsed one by one.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScanError {
+    /// Not an object, or a member is malformed
+    Malformed,
+}
+
+/// Iterator over the `(key, raw value)` pairs of the object in `buf`
+pub struct Members<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    done: bool,
+}
+
+pub fn members(buf: &[u8]) -> Result<Members<'_>, ScanError> {
+    let mut scan = Members {
+        buf,
+        pos: 0,
+        done: false,
+    };
+    scan.skip_whitespace();
+    scan.expect(b'{')?;
+    scan.skip_whitespace();
+    if scan.peek() == Some(b'}') {
+        scan.done = true;
+    }
+    Ok(scan)
+}
+
+impl<'a> Members<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.buf.get(self.pos).copied()
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), ScanError> {
+        if self.peek() != Some(byte) {
+            return Err(ScanError::Malformed);
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t' | b'\r' | b'\n')) {
+            self.pos += 1;
+        }
+    }
+
+    /// Past the closing quote of the string starting at `pos`
+    fn skip_string(&mut self) -> Result<(), ScanError> {
+        self.expect(b'"')?;
+        loop {
+            match self.peek().ok_or(ScanError::Malformed)? {
+                b'"' => {
+                    self.pos += 1;
+                    return Ok(());
+                }
+                b'\\' => self.pos += 2,
+                _ => self.pos += 1,
+            }
+        }
+    }
+
+    fn skip_value(&mut self) -> Result<(), ScanError> {
+        match self.peek().ok_or(ScanError::Malformed)? {
+            b'"' => self.skip_string(),
+            b'{' | b'[' => {
+                let mut depth = 0usize;
+                loop {
+                    match self.peek().ok_or(ScanError::Malformed)? {
+                        b'"' => {
+                            self.skip_string()?;
+                            continue;
+                        }
+                        b'{' | b'[' => depth += 1,
+                        b'}' | b']' => {
+                            depth -= 1;
+                            if depth == 0 {
+                                self.pos += 1;
+                                return Ok(());
+                            }
+                        }
+                        _ => {}
+                    }
+                    self.pos += 1;
+                }
+            }
+            _ => {
+                let start = self.pos;
+                while !matches!(
+                    self.peek(),
+                    None | Some(b',' | b'}' | b']' | b' ' | b'\t' | b'\r' | b'\n')
+                ) {
+                    self.pos += 1;
+                }
+                if self.pos == start {
+                    return Err(ScanError::Malformed);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn member(&mut self) -> Result<(&'a str, &'a [u8]), ScanError> {
+        let key_start = self.pos + 1;
+        self.skip_string()?;
+        let key = core::str::from_utf8(&self.buf[key_start..self.pos - 1])
+            .map_err(|_| ScanError::Malformed)?;
+        self.skip_whitespace();
+        self.expect(b':')?;
+        self.skip_whitespace();
+        let value_start = self.pos;
+        self.skip_value()?;
+        let value = &self.buf[value_start..self.pos];
+        self.skip_whitespace();
+        match self.peek() {
+            Some(b',') => {
+                self.pos += 1;
+                self.skip_whitespace();
+            }
+            Some(b'}') => self.done = true,
+            _ => return Err(ScanError::Malformed),
+        }
+        Ok((key, value))
+    }
+}
+
+impl<'a> Iterator for Members<'a> {
+    type Item = Result<(&'a str, &'a [u8]), ScanError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let res = self.member();
+        if res.is_err() {
+            // Nothing after a malformed member can be trusted
+            self.done = true;
+        }
+        Some(res)
+    }
+}
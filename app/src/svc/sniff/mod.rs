@@ -0,0 +1,259 @@
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::{raw::NoopRawMutex, Mutex};
+use embassy_time::{Duration, Instant};
+use log::warn;
+use serde::Deserialize;
+
+use crate::svc::can::filter::{CanFilter, FilterSet, MAX_FILTERS};
+use crate::svc::mqtt::handler::MessageHandler;
+
+/// Session length used when the command does not specify one
+pub const DEFAULT_SESSION_SECS: u32 = 60;
+/// Hard upper bound of a session, whatever the cloud asks for
+pub const MAX_SESSION_SECS: u32 = 900;
+/// Byte budget used when the command does not specify one
+pub const DEFAULT_BYTE_BUDGET: u32 = 256 * 1024;
+/// Hard upper bound of the byte budget, whatever the cloud asks for
+pub const MAX_BYTE_BUDGET: u32 = 8 * 1024 * 1024;
+
+pub type LiveSessionCell = Mutex<NoopRawMutex, RefCell<LiveSession>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SniffAction {
+    Start,
+    Stop,
+}
+
+/// Live session command sent by the cloud
+///
+/// `{"action":"start","session_id":"abc","duration_s":60,"byte_budget":100000,
+///   "filters":[{"id":256,"mask":1792,"ext":false}]}`
+/// `{"action":"stop"}`
+#[derive(Debug, Deserialize)]
+pub struct SniffCommand {
+    pub action: SniffAction,
+    pub session_id: Option<heapless::String<36>>,
+    pub duration_s: Option<u32>,
+    pub byte_budget: Option<u32>,
+    pub filters: Option<heapless::Vec<CanFilter, MAX_FILTERS>>,
+}
+
+impl SniffCommand {
+    pub fn parse(payload: &[u8]) -> Result<Self, SniffError> {
+        serde_json_core::from_slice::<SniffCommand>(payload)
+            .map(|(cmd, _)| cmd)
+            .map_err(|_| SniffError::InvalidCommand)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SniffError {
+    InvalidCommand,
+    NotActive,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StopReason {
+    Requested,
+    Timeout,
+    BudgetExhausted,
+    Replaced,
+}
+
+impl StopReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StopReason::Requested => "requested",
+            StopReason::Timeout => "timeout",
+            StopReason::BudgetExhausted => "budget",
+            StopReason::Replaced => "replaced",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SessionStats {
+    pub frames_sent: u32,
+    pub frames_dropped: u32,
+    pub bytes_sent: u32,
+}
+
+/// Session lifecycle notifications, reported to the cloud by the streamer
+#[derive(Debug, Clone)]
+pub enum SessionEvent {
+    Started {
+        id: heapless::String<36>,
+        duration_s: u32,
+        byte_budget: u32,
+        filters: usize,
+    },
+    Stopped {
+        id: heapless::String<36>,
+        reason: StopReason,
+        stats: SessionStats,
+    },
+}
+
+struct ActiveSession {
+    id: heapless::String<36>,
+    deadline: Instant,
+    byte_budget: u32,
+    stats: SessionStats,
+}
+
+/// State of the live CAN sniffing session
+///
+/// Shared between the CAN receiver, which asks whether a frame has to be captured,
+/// and the streamer, which uplinks captured frames and enforces the limits.
+pub struct LiveSession {
+    active: Option<ActiveSession>,
+    filters: FilterSet,
+    events: heapless::Deque<SessionEvent, 4>,
+}
+
+impl Default for LiveSession {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LiveSession {
+    pub const fn new() -> Self {
+        Self {
+            active: None,
+            filters: FilterSet::new(),
+            events: heapless::Deque::new(),
+        }
+    }
+
+    /// Apply a command coming from the cloud
+    pub fn apply(&mut self, cmd: SniffCommand, now: Instant) -> Result<(), SniffError> {
+        match cmd.action {
+            SniffAction::Start => {
+                if self.active.is_some() {
+                    self.stop(StopReason::Replaced);
+                }
+                let duration_s = cmd
+                    .duration_s
+                    .unwrap_or(DEFAULT_SESSION_SECS)
+                    .clamp(1, MAX_SESSION_SECS);
+                let byte_budget = cmd
+                    .byte_budget
+                    .unwrap_or(DEFAULT_BYTE_BUDGET)
+                    .min(MAX_BYTE_BUDGET);
+                let id = cmd.session_id.unwrap_or_default();
+                self.filters = cmd
+                    .filters
+                    .map(|filters| FilterSet::from_slice(&filters))
+                    .unwrap_or_default();
+                self.push_event(SessionEvent::Started {
+                    id: id.clone(),
+                    duration_s,
+                    byte_budget,
+                    filters: self.filters.len(),
+                });
+                self.active = Some(ActiveSession {
+                    id,
+                    deadline: now + Duration::from_secs(duration_s as u64),
+                    byte_budget,
+                    stats: SessionStats::default(),
+                });
+                Ok(())
+            }
+            SniffAction::Stop => {
+                if self.active.is_none() {
+                    return Err(SniffError::NotActive);
+                }
+                self.stop(StopReason::Requested);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn session_id(&self) -> Option<heapless::String<36>> {
+        self.active.as_ref().map(|s| s.id.clone())
+    }
+
+    /// Whether a received frame has to be captured
+    pub fn accepts(&self, id: u32, extended: bool) -> bool {
+        self.active.is_some() && self.filters.matches(id, extended)
+    }
+
+    /// A captured frame could not be queued
+    pub fn record_drop(&mut self) {
+        if let Some(session) = self.active.as_mut() {
+            session.stats.frames_dropped = session.stats.frames_dropped.saturating_add(1);
+        }
+    }
+
+    /// Account uplinked frames, stops the session when the byte budget is spent
+    pub fn record_sent(&mut self, frames: u32, bytes: u32) {
+        let exhausted = match self.active.as_mut() {
+            Some(session) => {
+                session.stats.frames_sent = session.stats.frames_sent.saturating_add(frames);
+                session.stats.bytes_sent = session.stats.bytes_sent.saturating_add(bytes);
+                session.stats.bytes_sent >= session.byte_budget
+            }
+            None => false,
+        };
+        if exhausted {
+            self.stop(StopReason::BudgetExhausted);
+        }
+    }
+
+    /// Stop the session once its deadline has passed
+    pub fn poll_expiry(&mut self, now: Instant) {
+        if matches!(&self.active, Some(session) if now >= session.deadline) {
+            self.stop(StopReason::Timeout);
+        }
+    }
+
+    pub fn pop_event(&mut self) -> Option<SessionEvent> {
+        self.events.pop_front()
+    }
+
+    fn stop(&mut self, reason: StopReason) {
+        if let Some(session) = self.active.take() {
+            self.filters.clear();
+            self.push_event(SessionEvent::Stopped {
+                id: session.id,
+                reason,
+                stats: session.stats,
+            });
+        }
+    }
+
+    fn push_event(&mut self, event: SessionEvent) {
+        if self.events.is_full() {
+            let _ = self.events.pop_front();
+        }
+        let _ = self.events.push_back(event);
+    }
+}
+
+/// Entry point for live session commands received from the cloud
+pub fn handle_command(session: &LiveSessionCell, payload: &[u8]) -> Result<(), SniffError> {
+    let cmd = SniffCommand::parse(payload)?;
+    session.lock(|s| s.borrow_mut().apply(cmd, Instant::now()))
+}
+
+/// Applies the live session commands received on the MQTT command topic
+pub struct SniffCommandHandler {
+    session: &'static LiveSessionCell,
+}
+
+impl SniffCommandHandler {
+    pub const fn new(session: &'static LiveSessionCell) -> Self {
+        Self { session }
+    }
+}
+
+impl MessageHandler for SniffCommandHandler {
+    fn on_message(&self, topic: &str, payload: &[u8]) {
+        if let Err(e) = handle_command(self.session, payload) {
+            warn!("Live session command on {topic} rejected: {e:?}");
+        }
+    }
+}
@@ -0,0 +1,532 @@
+//! Sparkplug B edge node
+//!
+//! The device is an edge node of [`SPARKPLUG_GROUP_ID`] with a single device, the
+//! vehicle, whose metrics are decoded from the CAN bus. After each connection
+//! the node is born with NBIRTH, and its NDEATH is the MQTT Last Will, both
+//! carrying the same `bdSeq` so the host can pair them. The vehicle is then born
+//! with DBIRTH and reports by exception with DDATA. Every message but NDEATH
+//! carries a sequence number wrapping at 256, NBIRTH starts over at 0. A host
+//! asks for a new birth with the `Node Control/Rebirth` metric of an NCMD.
+pub mod proto;
+
+use core::cell::Cell;
+
+use embassy_time::Instant;
+use log::info;
+
+use crate::cfg::sparkplug_cfg::{SPARKPLUG_DEVICE_ID, SPARKPLUG_EDGE_NODE_ID, SPARKPLUG_GROUP_ID};
+use crate::svc::ev::BatteryCell;
+use crate::svc::mqtt::handler::MessageHandler;
+use crate::svc::payload::{self, EncodeError, PayloadFormat};
+use crate::svc::presence::FIRMWARE_VERSION;
+use crate::svc::topic::Topic;
+use crate::svc::vehicle::VehicleCell;
+use proto::{DataType, Metric, Payload, Value};
+
+pub const NAMESPACE: &str = "spBv1.0";
+/// Largest encoded payload, a DBIRTH with every metric
+pub const PAYLOAD_LEN: usize = 640;
+pub const BD_SEQ: &str = "bdSeq";
+pub const REBIRTH: &str = "Node Control/Rebirth";
+/// Alias of [`REBIRTH`], hosts may send the command by alias
+const REBIRTH_ALIAS: u64 = 1;
+/// Aliases of the device metrics start there
+const DEVICE_ALIAS_BASE: u64 = 100;
+
+/// Metrics of the vehicle, in the order of [`sample`]
+pub const DEVICE_METRICS: [(&str, DataType); 12] = [
+    ("Vehicle/State", DataType::String),
+    ("Vehicle/Charging", DataType::Boolean),
+    ("CAN/Frame Rate", DataType::UInt32),
+    ("Engine/RPM", DataType::Double),
+    ("Engine/Gear", DataType::Double),
+    ("Battery/SoC", DataType::Double),
+    ("Battery/Pack Voltage", DataType::Double),
+    ("Battery/Pack Current", DataType::Double),
+    ("Battery/Cell Temperature Min", DataType::Double),
+    ("Battery/Cell Temperature Max", DataType::Double),
+    ("Battery/Cell Voltage Min", DataType::Double),
+    ("Battery/Cell Voltage Max", DataType::Double),
+];
+
+pub type DeviceValues = [Value; DEVICE_METRICS.len()];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MessageKind {
+    NBirth,
+    NDeath,
+    NCmd,
+    DBirth,
+    DData,
+}
+
+impl MessageKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MessageKind::NBirth => "NBIRTH",
+            MessageKind::NDeath => "NDEATH",
+            MessageKind::NCmd => "NCMD",
+            MessageKind::DBirth => "DBIRTH",
+            MessageKind::DData => "DDATA",
+        }
+    }
+
+    fn is_device(&self) -> bool {
+        matches!(self, MessageKind::DBirth | MessageKind::DData)
+    }
+}
+
+/// `spBv1.0/<group>/<kind>/<edge node>[/<device>]`
+pub fn topic(kind: MessageKind) -> Topic {
+    let mut topic = Topic::new();
+    for level in [
+        NAMESPACE,
+        SPARKPLUG_GROUP_ID,
+        kind.as_str(),
+        SPARKPLUG_EDGE_NODE_ID,
+    ] {
+        if !topic.is_empty() {
+            let _ = topic.push('/');
+        }
+        let _ = topic.push_str(level);
+    }
+    if kind.is_device() {
+        let _ = topic.push('/');
+        let _ = topic.push_str(SPARKPLUG_DEVICE_ID);
+    }
+    topic
+}
+
+/// Payload timestamp in milliseconds
+///
+/// Sparkplug expects UTC, the device has no wall clock yet and sends its uptime.
+pub fn now_ms() -> u64 {
+    Instant::now().as_millis()
+}
+
+/// Current values of [`DEVICE_METRICS`]
+pub fn sample(vehicle: &VehicleCell, battery: &BatteryCell) -> DeviceValues {
+    let now = Instant::now();
+    let double = |value: Option<f32>| value.map_or(Value::Null, |v| Value::Double(v as f64));
+    let (state, charging, frame_rate, rpm, gear) = vehicle.lock(|v| {
+        let v = v.borrow();
+        (
+            v.state(),
+            v.charging(now),
+            v.frame_rate(),
+            v.engine_rpm(now),
+            v.gear(now),
+        )
+    });
+    let bms = battery
+        .lock(|b| b.borrow().snapshot(now))
+        .unwrap_or_default();
+    [
+        Value::Str(state.as_str()),
+        charging.map_or(Value::Null, Value::Bool),
+        Value::UInt(frame_rate as u64),
+        double(rpm),
+        double(gear),
+        double(bms.soc),
+        double(bms.pack_voltage),
+        double(bms.pack_current),
+        double(bms.cell_temp_min),
+        double(bms.cell_temp_max),
+        double(bms.cell_voltage_min),
+        double(bms.cell_voltage_max),
+    ]
+}
+
+/// Birth and death certificates, sequence numbers and report by exception
+pub struct EdgeNode {
+    /// Birth/death sequence of the current connection, `None` before the first
+    bd_seq: Option<u8>,
+    seq: u8,
+    /// Values in the last DBIRTH or DDATA, `None` until the device is born
+    published: Option<DeviceValues>,
+}
+
+impl Default for EdgeNode {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EdgeNode {
+    pub const fn new() -> Self {
+        Self {
+            bd_seq: None,
+            seq: 0,
+            published: None,
+        }
+    }
+
+    /// Start a new MQTT connection, `bdSeq` moves to the next value
+    pub fn begin_session(&mut self) {
+        self.bd_seq = Some(self.bd_seq.map_or(0, |s| s.wrapping_add(1)));
+        self.published = None;
+    }
+
+    pub fn bd_seq(&self) -> u8 {
+        self.bd_seq.unwrap_or(0)
+    }
+
+    fn bd_seq_metric(&self) -> Metric<'static> {
+        Metric {
+            name: Some(BD_SEQ),
+            alias: None,
+            datatype: DataType::UInt64,
+            value: Value::UInt(self.bd_seq() as u64),
+        }
+    }
+
+    fn next_seq(&mut self) -> u8 {
+        let seq = self.seq;
+        self.seq = self.seq.wrapping_add(1);
+        seq
+    }
+
+    /// NDEATH, registered as the Last Will of the connection
+    pub fn death(&self, out: &mut [u8]) -> Result<usize, EncodeError> {
+        let metrics = [self.bd_seq_metric()];
+        encode(now_ms(), &metrics, None, out)
+    }
+
+    /// NBIRTH, the sequence starts over
+    pub fn node_birth(&mut self, out: &mut [u8]) -> Result<usize, EncodeError> {
+        self.seq = 0;
+        let metrics = [
+            self.bd_seq_metric(),
+            Metric {
+                name: Some(REBIRTH),
+                alias: Some(REBIRTH_ALIAS),
+                datatype: DataType::Boolean,
+                value: Value::Bool(false),
+            },
+            Metric {
+                name: Some("Properties/Firmware"),
+                alias: None,
+                datatype: DataType::String,
+                value: Value::Str(FIRMWARE_VERSION),
+            },
+        ];
+        let seq = self.next_seq();
+        encode(now_ms(), &metrics, Some(seq), out)
+    }
+
+    /// DBIRTH, every metric with its name and alias
+    pub fn device_birth(
+        &mut self,
+        values: &DeviceValues,
+        out: &mut [u8],
+    ) -> Result<usize, EncodeError> {
+        let mut metrics: heapless::Vec<Metric, { DEVICE_METRICS.len() }> = heapless::Vec::new();
+        for (idx, ((name, datatype), value)) in DEVICE_METRICS.iter().zip(values).enumerate() {
+            let _ = metrics.push(Metric {
+                name: Some(*name),
+                alias: Some(DEVICE_ALIAS_BASE + idx as u64),
+                datatype: *datatype,
+                value: *value,
+            });
+        }
+        let seq = self.next_seq();
+        let len = encode(now_ms(), &metrics, Some(seq), out)?;
+        self.published = Some(*values);
+        Ok(len)
+    }
+
+    /// DDATA with the metrics that changed since the last report, by alias,
+    /// `None` when nothing changed or the device is not born
+    pub fn device_data(
+        &mut self,
+        values: &DeviceValues,
+        out: &mut [u8],
+    ) -> Result<Option<usize>, EncodeError> {
+        let Some(published) = self.published.as_mut() else {
+            return Ok(None);
+        };
+        let mut metrics: heapless::Vec<Metric, { DEVICE_METRICS.len() }> = heapless::Vec::new();
+        for (idx, (value, last)) in values.iter().zip(published.iter()).enumerate() {
+            if value != last {
+                let _ = metrics.push(Metric {
+                    name: None,
+                    alias: Some(DEVICE_ALIAS_BASE + idx as u64),
+                    datatype: DEVICE_METRICS[idx].1,
+                    value: *value,
+                });
+            }
+        }
+        if metrics.is_empty() {
+            return Ok(None);
+        }
+        // Sequence taken only once the message is sure to be sent
+        let seq = self.seq;
+        let len = encode(now_ms(), &metrics, Some(seq), out)?;
+        self.seq = self.seq.wrapping_add(1);
+        *published = *values;
+        Ok(Some(len))
+    }
+}
+
+fn encode(
+    timestamp: u64,
+    metrics: &[Metric],
+    seq: Option<u8>,
+    out: &mut [u8],
+) -> Result<usize, EncodeError> {
+    let payload = Payload {
+        timestamp,
+        metrics,
+        seq,
+    };
+    payload::encode(PayloadFormat::Protobuf, &payload, out)
+}
+
+/// Subscribed to NCMD, records the rebirth requests of the host
+pub struct RebirthHandler {
+    requested: Cell<bool>,
+}
+
+impl Default for RebirthHandler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RebirthHandler {
+    pub const fn new() -> Self {
+        Self {
+            requested: Cell::new(false),
+        }
+    }
+
+    /// Whether a rebirth was requested since the last call
+    pub fn take(&self) -> bool {
+        self.requested.replace(false)
+    }
+}
+
+impl MessageHandler for RebirthHandler {
+    fn on_message(&self, topic: &str, payload: &[u8]) {
+        let rebirth = proto::metrics(payload).any(|m| {
+            (m.name == Some(REBIRTH) || m.alias == Some(REBIRTH_ALIAS))
+                && m.boolean_value == Some(true)
+        });
+        if rebirth {
+            info!("Sparkplug rebirth requested on {topic}");
+            self.requested.set(true);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::proto::{FieldReader, WireValue};
+    use super::*;
+    use crate::svc::payload::hex;
+
+    /// Hex of the payload after its timestamp, the uptime of the test run
+    fn after_timestamp(payload: &[u8]) -> std::string::String {
+        assert_eq!(payload[0], 0x08);
+        let varint_end = payload[1..].iter().position(|b| b & 0x80 == 0).unwrap();
+        hex(&payload[varint_end + 2..])
+    }
+
+    fn seq(payload: &[u8]) -> Option<u64> {
+        FieldReader::new(payload).find_map(|f| match f.unwrap() {
+            (3, WireValue::Varint(seq)) => Some(seq),
+            _ => None,
+        })
+    }
+
+    fn bd_seq_of(payload: &[u8]) -> u64 {
+        // bdSeq is the first metric, its value the last field
+        let Some(Ok((2, WireValue::Bytes(metric)))) = FieldReader::new(payload).nth(1) else {
+            panic!("no metric");
+        };
+        match FieldReader::new(metric).last() {
+            Some(Ok((11, WireValue::Varint(value)))) => value,
+            other => panic!("bdSeq value {other:?}"),
+        }
+    }
+
+    fn values() -> DeviceValues {
+        let mut values = [Value::Null; DEVICE_METRICS.len()];
+        values[0] = Value::Str("running");
+        values[1] = Value::Bool(false);
+        values[2] = Value::UInt(250);
+        values[3] = Value::Double(1500.0);
+        values[5] = Value::Double(80.5);
+        values
+    }
+
+    #[test]
+    fn topics() {
+        assert_eq!(
+            topic(MessageKind::NBirth),
+            std::format!("spBv1.0/telematics/NBIRTH/{SPARKPLUG_EDGE_NODE_ID}").as_str()
+        );
+        assert_eq!(
+            topic(MessageKind::DData),
+            std::format!("spBv1.0/telematics/DDATA/{SPARKPLUG_EDGE_NODE_ID}/vehicle").as_str()
+        );
+    }
+
+    #[test]
+    fn node_birth_golden_vector() {
+        let mut node = EdgeNode::new();
+        node.begin_session();
+        let mut out = [0u8; PAYLOAD_LEN];
+        let len = node.node_birth(&mut out).unwrap();
+        let firmware = hex(FIRMWARE_VERSION.as_bytes());
+        let firmware_metric = std::format!(
+            "0A1350726F706572746965732F4669726D77617265200C7A{:02X}{firmware}",
+            FIRMWARE_VERSION.len()
+        );
+        let expected = std::format!(
+            "120B0A05626453657120085800\
+             121C0A144E6F646520436F6E74726F6C2F526562697274681001200B7000\
+             12{:02X}{firmware_metric}\
+             1800",
+            firmware_metric.len() / 2
+        );
+        assert_eq!(after_timestamp(&out[..len]), expected);
+    }
+
+    #[test]
+    fn device_birth_golden_vector() {
+        let mut node = EdgeNode::new();
+        node.begin_session();
+        let mut out = [0u8; PAYLOAD_LEN];
+        node.node_birth(&mut out).unwrap();
+        let len = node.device_birth(&values(), &mut out).unwrap();
+        assert_eq!(
+            after_timestamp(&out[..len]),
+            "121C0A0D56656869636C652F53746174651064200C7A0772756E6E696E67\
+             12180A1056656869636C652F4368617267696E671065200B7000\
+             12170A0E43414E2F4672616D6520526174651066200750FA01\
+             12190A0A456E67696E652F52504D1067200A690000000000709740\
+             12130A0B456E67696E652F476561721068200A3801\
+             121A0A0B426174746572792F536F431069200A690000000000205440\
+             121C0A14426174746572792F5061636B20566F6C74616765106A200A3801\
+             121C0A14426174746572792F5061636B2043757272656E74106B200A3801\
+             12240A1C426174746572792F43656C6C2054656D7065726174757265204D696E106C200A3801\
+             12240A1C426174746572792F43656C6C2054656D7065726174757265204D6178106D200A3801\
+             12200A18426174746572792F43656C6C20566F6C74616765204D696E106E200A3801\
+             12200A18426174746572792F43656C6C20566F6C74616765204D6178106F200A3801\
+             1801"
+        );
+    }
+
+    #[test]
+    fn device_data_carries_the_changes_by_alias() {
+        let mut node = EdgeNode::new();
+        node.begin_session();
+        let mut out = [0u8; PAYLOAD_LEN];
+        let mut values = values();
+        assert_eq!(node.device_data(&values, &mut out), Ok(None));
+        node.node_birth(&mut out).unwrap();
+        node.device_birth(&values, &mut out).unwrap();
+        assert_eq!(node.device_data(&values, &mut out), Ok(None));
+
+        values[3] = Value::Double(1800.0);
+        values[4] = Value::Double(3.0);
+        let len = node.device_data(&values, &mut out).unwrap().unwrap();
+        assert_eq!(
+            after_timestamp(&out[..len]),
+            "120D1067200A690000000000209C40\
+             120D1068200A690000000000000840\
+             1802"
+        );
+        assert_eq!(node.device_data(&values, &mut out), Ok(None));
+    }
+
+    #[test]
+    fn death_pairs_with_the_birth() {
+        let mut node = EdgeNode::new();
+        let mut out = [0u8; PAYLOAD_LEN];
+        for expected in 0..3 {
+            node.begin_session();
+            let len = node.death(&mut out).unwrap();
+            assert_eq!(bd_seq_of(&out[..len]), expected);
+            assert_eq!(seq(&out[..len]), None);
+            let len = node.node_birth(&mut out).unwrap();
+            assert_eq!(bd_seq_of(&out[..len]), expected);
+        }
+        node.begin_session();
+        let len = node.death(&mut out).unwrap();
+        assert_eq!(after_timestamp(&out[..len]), "120B0A05626453657120085803");
+    }
+
+    #[test]
+    fn bd_seq_wraps_at_256() {
+        let mut node = EdgeNode::new();
+        for _ in 0..256 {
+            node.begin_session();
+        }
+        assert_eq!(node.bd_seq(), 255);
+        node.begin_session();
+        assert_eq!(node.bd_seq(), 0);
+        let mut out = [0u8; PAYLOAD_LEN];
+        let len = node.death(&mut out).unwrap();
+        assert_eq!(bd_seq_of(&out[..len]), 0);
+    }
+
+    #[test]
+    fn seq_wraps_and_starts_over_with_the_birth() {
+        let mut node = EdgeNode::new();
+        node.begin_session();
+        let mut out = [0u8; PAYLOAD_LEN];
+        let len = node.node_birth(&mut out).unwrap();
+        assert_eq!(seq(&out[..len]), Some(0));
+        let mut values = values();
+        let len = node.device_birth(&values, &mut out).unwrap();
+        assert_eq!(seq(&out[..len]), Some(1));
+        for expected in (2..256).chain(0..2) {
+            values[2] = Value::UInt(expected);
+            let len = node.device_data(&values, &mut out).unwrap().unwrap();
+            assert_eq!(seq(&out[..len]), Some(expected));
+        }
+
+        // A new session is born with seq 0 again
+        node.begin_session();
+        let len = node.node_birth(&mut out).unwrap();
+        assert_eq!(seq(&out[..len]), Some(0));
+        // and the device has to be born again before it reports
+        assert_eq!(node.device_data(&values, &mut out), Ok(None));
+    }
+
+    #[test]
+    fn rebirth_by_name_or_alias() {
+        let handler = RebirthHandler::new();
+        let command = |metric: Metric| {
+            let mut out = [0u8; 64];
+            let len = encode(0, &[metric], None, &mut out).unwrap();
+            handler.on_message("spBv1.0/telematics/NCMD/node", &out[..len]);
+        };
+        command(Metric {
+            name: Some(REBIRTH),
+            alias: None,
+            datatype: DataType::Boolean,
+            value: Value::Bool(true),
+        });
+        assert!(handler.take());
+        assert!(!handler.take());
+        command(Metric {
+            name: None,
+            alias: Some(REBIRTH_ALIAS),
+            datatype: DataType::Boolean,
+            value: Value::Bool(true),
+        });
+        assert!(handler.take());
+        command(Metric {
+            name: Some(REBIRTH),
+            alias: None,
+            datatype: DataType::Boolean,
+            value: Value::Bool(false),
+        });
+        assert!(!handler.take());
+        handler.on_message("spBv1.0/telematics/NCMD/node", &[0x12, 0x7F]);
+        assert!(!handler.take());
+    }
+}
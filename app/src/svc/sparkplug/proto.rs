@@ -0,0 +1,212 @@
+//! Sparkplug B payload, the `org.eclipse.tahu.protobuf.Payload` message
+//!
+//! Only the fields the edge node uses are written: the payload timestamp,
+//! metrics and sequence number, and for each metric its name, alias, datatype
+//! and value. Commands are read back with a bounds-checked field reader.
+use crate::svc::payload::{Encode, EncodeError, Field, PayloadEncoder};
+
+/// Sparkplug B datatypes used by the edge node
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DataType {
+    UInt32 = 7,
+    UInt64 = 8,
+    Double = 10,
+    Boolean = 11,
+    String = 12,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    /// Not known, sent with `is_null`
+    Null,
+    Bool(bool),
+    UInt(u64),
+    Double(f64),
+    Str(&'static str),
+}
+
+pub struct Metric<'a> {
+    /// Left out once the host knows the alias
+    pub name: Option<&'a str>,
+    pub alias: Option<u64>,
+    pub datatype: DataType,
+    pub value: Value,
+}
+
+impl Metric<'_> {
+    const NAME: Field = Field::new(1, "name");
+    const ALIAS: Field = Field::new(2, "alias");
+    const DATATYPE: Field = Field::new(4, "datatype");
+    const IS_NULL: Field = Field::new(7, "is_null");
+    const INT_VALUE: Field = Field::new(10, "int_value");
+    const LONG_VALUE: Field = Field::new(11, "long_value");
+    const DOUBLE_VALUE: Field = Field::new(13, "double_value");
+    const BOOLEAN_VALUE: Field = Field::new(14, "boolean_value");
+    const STRING_VALUE: Field = Field::new(15, "string_value");
+}
+
+impl Encode for Metric<'_> {
+    fn encode<E: PayloadEncoder>(&self, e: &mut E) -> Result<(), EncodeError> {
+        let len = 2 + self.name.is_some() as usize + self.alias.is_some() as usize;
+        e.begin_message(Field::NONE, len)?;
+        if let Some(name) = self.name {
+            e.str(Self::NAME, name)?;
+        }
+        if let Some(alias) = self.alias {
+            e.uint(Self::ALIAS, alias)?;
+        }
+        e.uint(Self::DATATYPE, self.datatype as u64)?;
+        match self.value {
+            Value::Null => e.bool(Self::IS_NULL, true)?,
+            Value::Bool(value) => e.bool(Self::BOOLEAN_VALUE, value)?,
+            Value::UInt(value) if self.datatype == DataType::UInt64 => {
+                e.uint(Self::LONG_VALUE, value)?
+            }
+            Value::UInt(value) => e.uint(Self::INT_VALUE, value)?,
+            Value::Double(value) => e.float(Self::DOUBLE_VALUE, value)?,
+            Value::Str(value) => e.str(Self::STRING_VALUE, value)?,
+        }
+        e.end_message()
+    }
+}
+
+pub struct Payload<'a> {
+    /// Milliseconds, see [`super::now_ms`]
+    pub timestamp: u64,
+    pub metrics: &'a [Metric<'a>],
+    /// Left out of NDEATH
+    pub seq: Option<u8>,
+}
+
+impl Payload<'_> {
+    const TIMESTAMP: Field = Field::new(1, "timestamp");
+    const METRICS: Field = Field::new(2, "metrics");
+    const SEQ: Field = Field::new(3, "seq");
+}
+
+impl Encode for Payload<'_> {
+    fn encode<E: PayloadEncoder>(&self, e: &mut E) -> Result<(), EncodeError> {
+        e.begin_message(Field::NONE, 2 + self.seq.is_some() as usize)?;
+        e.uint(Self::TIMESTAMP, self.timestamp)?;
+        e.begin_list(Self::METRICS, self.metrics.len())?;
+        for metric in self.metrics {
+            metric.encode(e)?;
+        }
+        e.end_list()?;
+        if let Some(seq) = self.seq {
+            e.uint(Self::SEQ, seq as u64)?;
+        }
+        e.end_message()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DecodeError {
+    Truncated,
+    /// Wire type 3 and 4 (groups) or reserved
+    UnsupportedWireType,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WireValue<'a> {
+    Varint(u64),
+    Fixed64(u64),
+    Bytes(&'a [u8]),
+    Fixed32(u32),
+}
+
+/// Fields of a protobuf message, in wire order
+pub struct FieldReader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> FieldReader<'a> {
+    pub const fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    fn varint(&mut self) -> Result<u64, DecodeError> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let (&byte, rest) = self.buf.split_first().ok_or(DecodeError::Truncated)?;
+            self.buf = rest;
+            value |= ((byte & 0x7F) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(DecodeError::Truncated)
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        if len > self.buf.len() {
+            return Err(DecodeError::Truncated);
+        }
+        let (head, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(head)
+    }
+
+    fn field(&mut self) -> Result<(u32, WireValue<'a>), DecodeError> {
+        let key = self.varint()?;
+        let tag = (key >> 3) as u32;
+        let value = match key & 0x07 {
+            0 => WireValue::Varint(self.varint()?),
+            1 => WireValue::Fixed64(u64::from_le_bytes(self.take(8)?.try_into().unwrap())),
+            2 => {
+                let len = self.varint()?;
+                WireValue::Bytes(self.take(len.try_into().unwrap_or(usize::MAX))?)
+            }
+            5 => WireValue::Fixed32(u32::from_le_bytes(self.take(4)?.try_into().unwrap())),
+            _ => return Err(DecodeError::UnsupportedWireType),
+        };
+        Ok((tag, value))
+    }
+}
+
+impl<'a> Iterator for FieldReader<'a> {
+    type Item = Result<(u32, WireValue<'a>), DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buf.is_empty() {
+            return None;
+        }
+        let res = self.field();
+        if res.is_err() {
+            // Nothing after a malformed field can be trusted
+            self.buf = &[];
+        }
+        Some(res)
+    }
+}
+
+/// Metric of a received payload, the fields a command needs
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ReceivedMetric<'a> {
+    pub name: Option<&'a str>,
+    pub alias: Option<u64>,
+    pub boolean_value: Option<bool>,
+}
+
+/// Metrics of a received payload, skipping the malformed ones
+pub fn metrics(payload: &[u8]) -> impl Iterator<Item = ReceivedMetric<'_>> {
+    FieldReader::new(payload)
+        .map_while(Result::ok)
+        .filter_map(|(tag, value)| match (tag, value) {
+            (2, WireValue::Bytes(body)) => decode_metric(body).ok(),
+            _ => None,
+        })
+}
+
+fn decode_metric(body: &[u8]) -> Result<ReceivedMetric<'_>, DecodeError> {
+    let mut metric = ReceivedMetric::default();
+    for field in FieldReader::new(body) {
+        match field? {
+            (1, WireValue::Bytes(name)) => metric.name = core::str::from_utf8(name).ok(),
+            (2, WireValue::Varint(alias)) => metric.alias = Some(alias),
+            (14, WireValue::Varint(value)) => metric.boolean_value = Some(value != 0),
+            _ => {}
+        }
+    }
+    Ok(metric)
+}
@@ -0,0 +1,156 @@
+//! Reconnection policy of the Wi-Fi MQTT uplink
+//!
+//! A failed connection attempt is retried after a jittered exponential backoff, so
+//! a fleet knocked off by the same broker outage does not come back in lockstep.
+//! Consecutive failures are counted per stage of the connection and trip an
+//! escalation: the Wi-Fi link is reset when the network path keeps failing, the
+//! uplink steps aside for LTE when the broker keeps failing.
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, signal::Signal};
+use embassy_time::{Duration, Instant};
+
+use crate::cfg::mqtt_cfg::{
+    RECONNECT_BACKOFF_BASE, RECONNECT_BACKOFF_MAX, RECONNECT_CIRCUIT_OPEN, RECONNECT_ESCALATE_AFTER,
+};
+
+/// Raised by the MQTT task to have the Wi-Fi task drop and re-establish the link
+pub type WifiResetSignal = Signal<NoopRawMutex, ()>;
+
+/// Step of a connection attempt
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stage {
+    Dns,
+    Tcp,
+    Tls,
+    Mqtt,
+}
+
+impl Stage {
+    const COUNT: usize = 4;
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// What to do once a stage failed too many times in a row
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Escalation {
+    /// Drop the Wi-Fi association and connect again, DHCP included
+    ResetWifi,
+    /// Stop trying for a while and leave the uplink to the LTE bearer
+    SwitchBearer,
+}
+
+/// Exponential backoff with jitter
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    attempt: u32,
+    /// xorshift state, jitter does not need a cryptographic source
+    seed: u32,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Self {
+            base,
+            max,
+            attempt: 0,
+            seed: (Instant::now().as_ticks() as u32) | 1,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+
+    /// `base * 2^attempt` capped at `max`, then a random point in its upper half
+    pub fn next_delay(&mut self) -> Duration {
+        let factor = 1u64 << self.attempt.min(16);
+        let ceiling = (self.base.as_millis() * factor).min(self.max.as_millis());
+        self.attempt = self.attempt.saturating_add(1);
+        let half = ceiling / 2;
+        Duration::from_millis(half + self.random() as u64 % (half + 1))
+    }
+
+    fn random(&mut self) -> u32 {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 17;
+        self.seed ^= self.seed << 5;
+        self.seed
+    }
+}
+
+/// Outcome of a failed attempt
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Retry {
+    /// Wait that long before the next attempt
+    pub delay: Duration,
+    pub escalation: Option<Escalation>,
+}
+
+/// Backoff and circuit breaker over the stages of a connection
+pub struct ConnectionSupervisor {
+    backoff: Backoff,
+    /// Consecutive failures, per stage
+    failures: [u8; Stage::COUNT],
+    /// Failures since the last successful connection, all stages
+    total_failures: u32,
+}
+
+impl Default for ConnectionSupervisor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ConnectionSupervisor {
+    pub fn new() -> Self {
+        Self {
+            backoff: Backoff::new(RECONNECT_BACKOFF_BASE, RECONNECT_BACKOFF_MAX),
+            failures: [0; Stage::COUNT],
+            total_failures: 0,
+        }
+    }
+
+    /// Record a failed attempt at `stage`
+    ///
+    /// Once a stage failed [`RECONNECT_ESCALATE_AFTER`] times in a row the retry
+    /// comes with an escalation and the stage count starts over. Broker failures
+    /// also open the circuit: the retry waits [`RECONNECT_CIRCUIT_OPEN`].
+    pub fn on_failure(&mut self, stage: Stage) -> Retry {
+        self.total_failures = self.total_failures.saturating_add(1);
+        let count = &mut self.failures[stage.index()];
+        *count = count.saturating_add(1);
+        if *count < RECONNECT_ESCALATE_AFTER {
+            return Retry {
+                delay: self.backoff.next_delay(),
+                escalation: None,
+            };
+        }
+
+        *count = 0;
+        match stage {
+            // The network path is broken, the broker may well be fine
+            Stage::Dns | Stage::Tcp => Retry {
+                delay: self.backoff.next_delay(),
+                escalation: Some(Escalation::ResetWifi),
+            },
+            Stage::Tls | Stage::Mqtt => Retry {
+                delay: RECONNECT_CIRCUIT_OPEN,
+                escalation: Some(Escalation::SwitchBearer),
+            },
+        }
+    }
+
+    /// The broker accepted the connection, everything starts over
+    pub fn on_connected(&mut self) {
+        self.backoff.reset();
+        self.failures = [0; Stage::COUNT];
+        self.total_failures = 0;
+    }
+
+    pub fn total_failures(&self) -> u32 {
+        self.total_failures
+    }
+}
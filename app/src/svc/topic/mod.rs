@@ -0,0 +1,180 @@
+//! MQTT topic namespace
+//!
+//! Every topic is rendered from a template of `cfg::topic_cfg` with the
+//! placeholders `{device}` (MQTT client ID), `{dir}` (`client` from the device,
+//! `server` to it), `{type}` (message type), `{version}` (schema version as
+//! `v<n>`), `{bus}` and `{signal}`. A level left empty by placeholders without a
+//! value is dropped, so `.../{type}/{bus}` renders `.../can/can0` with a bus and
+//! `.../can` without one.
+pub mod schema;
+
+use core::fmt::Write;
+
+use crate::cfg::net_cfg::MQTT_CLIENT_ID;
+use crate::cfg::topic_cfg::{TOPIC_OVERRIDES, TOPIC_TEMPLATE};
+
+pub const TOPIC_LEN: usize = 128;
+
+pub type Topic = heapless::String<TOPIC_LEN>;
+
+/// Kinds of messages exchanged with the backend, each with its own schema
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MessageType {
+    Can,
+    Trip,
+    Event,
+    Status,
+    Health,
+    Sniff,
+    SniffStatus,
+    EvCharge,
+    EvCurve,
+    Schema,
+    SniffCommand,
+    RpcRequest,
+    RpcResponse,
+    ShadowDesired,
+    ShadowReported,
+}
+
+impl MessageType {
+    pub const ALL: [MessageType; 15] = [
+        MessageType::Can,
+        MessageType::Trip,
+        MessageType::Event,
+        MessageType::Status,
+        MessageType::Health,
+        MessageType::Sniff,
+        MessageType::SniffStatus,
+        MessageType::EvCharge,
+        MessageType::EvCurve,
+        MessageType::Schema,
+        MessageType::SniffCommand,
+        MessageType::RpcRequest,
+        MessageType::RpcResponse,
+        MessageType::ShadowDesired,
+        MessageType::ShadowReported,
+    ];
+
+    /// Value of the `{type}` placeholder
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MessageType::Can => "can",
+            MessageType::Trip => "trip",
+            MessageType::Event => "event",
+            MessageType::Status => "status",
+            MessageType::Health => "health",
+            MessageType::Sniff | MessageType::SniffCommand => "sniff",
+            MessageType::SniffStatus => "sniff/status",
+            MessageType::EvCharge => "ev/charge",
+            MessageType::EvCurve => "ev/curve",
+            MessageType::Schema => "schema",
+            MessageType::RpcRequest => "rpc/req",
+            MessageType::RpcResponse => "rpc/res",
+            MessageType::ShadowDesired => "shadow/desired",
+            MessageType::ShadowReported => "shadow/reported",
+        }
+    }
+
+    pub fn direction(&self) -> Direction {
+        match self {
+            MessageType::SniffCommand | MessageType::RpcRequest | MessageType::ShadowDesired => {
+                Direction::Server
+            }
+            _ => Direction::Client,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    /// Published by the device
+    Client,
+    /// Published by the backend, subscribed by the device
+    Server,
+}
+
+impl Direction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Direction::Client => "client",
+            Direction::Server => "server",
+        }
+    }
+}
+
+/// Values of the optional placeholders
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TopicParams<'p> {
+    pub bus: Option<&'p str>,
+    pub signal: Option<&'p str>,
+}
+
+/// Topic of `message` without bus nor signal
+pub fn topic(message: MessageType) -> Topic {
+    topic_with(message, &TopicParams::default())
+}
+
+pub fn topic_with(message: MessageType, params: &TopicParams) -> Topic {
+    let template = TOPIC_OVERRIDES
+        .iter()
+        .find(|(m, _)| *m == message)
+        .map_or(TOPIC_TEMPLATE, |(_, template)| *template);
+    render(template, message, params)
+}
+
+fn render(template: &str, message: MessageType, params: &TopicParams) -> Topic {
+    let mut topic = Topic::new();
+    for (idx, level) in template.split('/').enumerate() {
+        let mut rendered = Topic::new();
+        let mut has_placeholder = false;
+        let mut rest = level;
+        while let Some(start) = rest.find('{') {
+            let Some(len) = rest[start..].find('}') else {
+                break;
+            };
+            let _ = rendered.push_str(&rest[..start]);
+            let name = &rest[start + 1..start + len];
+            has_placeholder = true;
+            match name {
+                "device" => {
+                    let _ = rendered.push_str(MQTT_CLIENT_ID);
+                }
+                "dir" => {
+                    let _ = rendered.push_str(message.direction().as_str());
+                }
+                "type" => {
+                    let _ = rendered.push_str(message.as_str());
+                }
+                "version" => {
+                    let _ = write!(&mut rendered, "v{}", schema::schema(message).version);
+                }
+                "bus" => push_value(&mut rendered, params.bus),
+                "signal" => push_value(&mut rendered, params.signal),
+                // Unknown placeholders are kept as written
+                _ => {
+                    let _ = rendered.push_str(&rest[start..start + len + 1]);
+                }
+            }
+            rest = &rest[start + len + 1..];
+        }
+        let _ = rendered.push_str(rest);
+
+        if rendered.is_empty() && has_placeholder {
+            continue;
+        }
+        if idx > 0 {
+            let _ = topic.push('/');
+        }
+        let _ = topic.push_str(&rendered);
+    }
+    topic
+}
+
+/// Bus and signal names come from configuration, they must not add levels nor
+/// wildcards to the topic
+fn push_value(out: &mut Topic, value: Option<&str>) {
+    for c in value.unwrap_or_default().chars() {
+        let _ = out.push(if matches!(c, '/' | '+' | '#') { '_' } else { c });
+    }
+}
@@ -0,0 +1,61 @@
+//! Payload schema registry
+//!
+//! Each message type has a named schema with a version. The version is bumped on
+//! any payload change the backend has to know about, and the whole registry is
+//! published retained on the `schema` topic after connecting, so the backend can
+//! route the topics and decode each one with the right format.
+use core::fmt::Write;
+
+use super::MessageType;
+use crate::svc::payload;
+
+pub const MANIFEST_LEN: usize = 1536;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Schema {
+    pub name: &'static str,
+    pub version: u16,
+}
+
+pub const fn schema(message: MessageType) -> Schema {
+    let (name, version) = match message {
+        MessageType::Can => ("tcu.can.batch", 2),
+        MessageType::Trip => ("tcu.trip", 1),
+        MessageType::Event => ("tcu.event", 1),
+        MessageType::Status => ("tcu.status", 1),
+        MessageType::Health => ("tcu.health", 1),
+        MessageType::Sniff => ("tcu.sniff.batch", 1),
+        MessageType::SniffStatus => ("tcu.sniff.status", 1),
+        MessageType::EvCharge => ("tcu.ev.charge", 1),
+        MessageType::EvCurve => ("tcu.ev.curve", 1),
+        MessageType::Schema => ("tcu.schema", 1),
+        MessageType::SniffCommand => ("tcu.sniff.command", 1),
+        MessageType::RpcRequest => ("tcu.rpc.request", 1),
+        MessageType::RpcResponse => ("tcu.rpc.response", 1),
+        MessageType::ShadowDesired => ("tcu.shadow.desired", 1),
+        MessageType::ShadowReported => ("tcu.shadow.reported", 1),
+    };
+    Schema { name, version }
+}
+
+/// Every message type with its schema and payload format, as
+/// `{"schemas":[{"type":"<type>","dir":"<client|server>","name":"<name>","version":<n>,"format":"<format>"}]}`
+pub fn manifest_payload() -> heapless::String<MANIFEST_LEN> {
+    let mut payload = heapless::String::new();
+    let _ = payload.push_str("{\"schemas\":[");
+    for (idx, message) in MessageType::ALL.iter().enumerate() {
+        let schema = schema(*message);
+        let _ = write!(
+            &mut payload,
+            "{}{{\"type\":\"{}\",\"dir\":\"{}\",\"name\":\"{}\",\"version\":{},\"format\":\"{}\"}}",
+            if idx > 0 { "," } else { "" },
+            message.as_str(),
+            message.direction().as_str(),
+            schema.name,
+            schema.version,
+            payload::format_for(*message).as_str()
+        );
+    }
+    let _ = payload.push_str("]}");
+    payload
+}
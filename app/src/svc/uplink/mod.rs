@@ -0,0 +1,241 @@
+pub mod outbox;
+#[cfg(feature = "spill")]
+pub mod spill;
+
+use core::cell::{Cell, RefCell};
+
+use embassy_sync::{
+    blocking_mutex::{raw::NoopRawMutex, Mutex},
+    channel::Channel,
+    signal::Signal,
+};
+use embassy_time::Instant;
+use log::warn;
+
+use outbox::{Outbox, QueuedMessage, OUTBOX_LEN};
+
+use crate::svc::payload::{self, Encode, EncodeError, PayloadFormat};
+
+/// Maximum topic length accepted by the uplinks (limited by AT+QMTPUB)
+pub const UPLINK_TOPIC_LEN: usize = 128;
+/// Maximum payload length accepted by the uplinks (limited by AT+QMTPUB)
+pub const UPLINK_PAYLOAD_LEN: usize = 1024;
+/// Outbox length above which messages are spilled to flash
+pub const SPILL_HIGH_WATERMARK: usize = OUTBOX_LEN - 4;
+/// Outbox length below which spilled messages are read back
+pub const SPILL_LOW_WATERMARK: usize = OUTBOX_LEN / 2;
+
+/// A message ready to be published by whichever uplink (Wi-Fi or LTE) is active
+#[derive(Debug, Clone)]
+pub struct UplinkMessage {
+    pub topic: heapless::String<UPLINK_TOPIC_LEN>,
+    pub payload: heapless::Vec<u8, UPLINK_PAYLOAD_LEN>,
+    pub format: PayloadFormat,
+}
+
+impl UplinkMessage {
+    /// JSON message rendered by the caller
+    pub fn json(topic: &str, payload: &str) -> Self {
+        let mut msg = Self {
+            topic: heapless::String::new(),
+            payload: heapless::Vec::new(),
+            format: PayloadFormat::Json,
+        };
+        let _ = msg.topic.push_str(topic);
+        let _ = msg.payload.extend_from_slice(payload.as_bytes());
+        msg
+    }
+
+    /// `message` encoded in `format`
+    pub fn encode<M: Encode>(
+        topic: &str,
+        format: PayloadFormat,
+        message: &M,
+    ) -> Result<Self, EncodeError> {
+        let mut msg = Self {
+            topic: heapless::String::new(),
+            payload: heapless::Vec::new(),
+            format,
+        };
+        let _ = msg.topic.push_str(topic);
+        let _ = msg.payload.resize(UPLINK_PAYLOAD_LEN, 0);
+        let len = payload::encode(format, message, &mut msg.payload)?;
+        msg.payload.truncate(len);
+        Ok(msg)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Priority {
+    /// Live session traffic, always drained first
+    Live,
+    /// Regular telemetry and events
+    Normal,
+}
+
+/// Outbound message queues shared between producers and the uplink tasks
+///
+/// Producers push pre-rendered messages with a priority, the uplink tasks drain
+/// the live queue before touching the normal one. Normal messages wait in the
+/// [`Outbox`] while no uplink is connected, and can be spilled to flash by the
+/// `outbox_spill` task when it runs.
+pub struct Uplink {
+    live: Channel<NoopRawMutex, UplinkMessage, 4>,
+    normal: Mutex<NoopRawMutex, RefCell<Outbox>>,
+    /// Messages currently spilled to flash
+    spilled: Mutex<NoopRawMutex, Cell<usize>>,
+    pending: Signal<NoopRawMutex, ()>,
+    spill: Signal<NoopRawMutex, ()>,
+}
+
+impl Default for Uplink {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Uplink {
+    pub const fn new() -> Self {
+        Self {
+            live: Channel::new(),
+            normal: Mutex::new(RefCell::new(Outbox::new())),
+            spilled: Mutex::new(Cell::new(0)),
+            pending: Signal::new(),
+            spill: Signal::new(),
+        }
+    }
+
+    /// Queue a message
+    ///
+    /// Live messages wait for room in their queue, normal messages never wait:
+    /// the least important queued message is dropped when the outbox is full.
+    pub async fn send(&self, priority: Priority, msg: UplinkMessage) {
+        match priority {
+            Priority::Live => self.live.send(msg).await,
+            Priority::Normal => {
+                self.push_normal(msg, false);
+            }
+        }
+        self.pending.signal(());
+    }
+
+    /// Queue a message without waiting, the message is dropped if the queue is full
+    pub fn try_send(&self, priority: Priority, msg: UplinkMessage) -> bool {
+        let res = match priority {
+            Priority::Live => self.live.try_send(msg).is_ok(),
+            Priority::Normal => self.push_normal(msg, false),
+        };
+        if res {
+            self.pending.signal(());
+        }
+        res
+    }
+
+    /// Put back a message an uplink failed to publish, ahead of the others
+    pub fn retry(&self, priority: Priority, msg: UplinkMessage) {
+        match priority {
+            // Live traffic is worthless once late
+            Priority::Live => {}
+            Priority::Normal => {
+                self.push_normal(msg, true);
+            }
+        }
+    }
+
+    /// Next message to publish with its priority, live traffic first
+    pub fn try_next(&self) -> Option<(Priority, UplinkMessage)> {
+        if let Ok(msg) = self.live.try_receive() {
+            return Some((Priority::Live, msg));
+        }
+        let (msg, len) = self.normal.lock(|o| {
+            let mut outbox = o.borrow_mut();
+            (outbox.pop(Instant::now()), outbox.len())
+        });
+        if len <= SPILL_LOW_WATERMARK && self.spilled() > 0 {
+            self.spill.signal(());
+        }
+        msg.map(|msg| (Priority::Normal, msg))
+    }
+
+    /// Whether live traffic is waiting, regular telemetry should back off meanwhile
+    pub fn live_pending(&self) -> bool {
+        !self.live.is_empty()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.live.is_empty() && self.normal.lock(|o| o.borrow().is_empty()) && self.spilled() == 0
+    }
+
+    /// Wait until something is queued in RAM
+    ///
+    /// Spilled messages do not count, [`Uplink::restore`] signals them once the
+    /// `outbox_spill` task read them back.
+    pub async fn wait(&self) {
+        if self.live.is_empty() && self.outbox_len() == 0 {
+            self.pending.wait().await;
+        }
+    }
+
+    /// Number of messages in the RAM outbox
+    pub fn outbox_len(&self) -> usize {
+        self.normal.lock(|o| o.borrow().len())
+    }
+
+    pub fn spilled(&self) -> usize {
+        self.spilled.lock(|s| s.get())
+    }
+
+    pub fn set_spilled(&self, count: usize) {
+        self.spilled.lock(|s| s.set(count));
+    }
+
+    /// Wait until the outbox crosses a spill watermark
+    pub async fn wait_spill(&self) {
+        self.spill.wait().await;
+    }
+
+    /// Take the message that would be published last, to spill it
+    pub fn take_for_spill(&self) -> Option<QueuedMessage> {
+        self.normal.lock(|o| o.borrow_mut().take_last())
+    }
+
+    /// Put back a message read from flash
+    #[allow(clippy::result_large_err)]
+    pub fn restore(&self, entry: QueuedMessage) {
+        let res = self
+            .normal
+            .lock(|o| o.borrow_mut().restore(entry, Instant::now()));
+        if let Ok(Some(dropped)) | Err(dropped) = res {
+            warn!("Dropping queued message for {}", dropped.msg.topic);
+        }
+        self.pending.signal(());
+    }
+
+    /// Returns `false` when `msg` itself was dropped
+    fn push_normal(&self, msg: UplinkMessage, front: bool) -> bool {
+        let now = Instant::now();
+        let (res, len) = self.normal.lock(|o| {
+            let mut outbox = o.borrow_mut();
+            let res = if front {
+                outbox.push_front(msg, now)
+            } else {
+                outbox.push(msg, now)
+            };
+            (res, outbox.len())
+        });
+        if len >= SPILL_HIGH_WATERMARK {
+            self.spill.signal(());
+        }
+        match res {
+            Ok(None) => true,
+            Ok(Some(dropped)) => {
+                warn!("Outbox full, dropping message for {}", dropped.msg.topic);
+                true
+            }
+            Err(rejected) => {
+                warn!("Dropping message for {}", rejected.msg.topic);
+                false
+            }
+        }
+    }
+}
@@ -0,0 +1,188 @@
+//! Outbound queue surviving disconnections
+//!
+//! Messages wait here until an uplink publishes them, ordered by the priority of
+//! their topic then by arrival. Expired messages are dropped, and when the queue
+//! is full the least important message goes first.
+// Without a heap the message left out goes back to the caller by value
+#![allow(clippy::result_large_err)]
+use embassy_time::{Duration, Instant};
+
+use super::UplinkMessage;
+use crate::cfg::uplink_cfg::{OUTBOX_DEFAULT_POLICY, OUTBOX_TOPIC_POLICIES};
+
+/// Messages kept in RAM
+pub const OUTBOX_LEN: usize = 24;
+
+/// How messages published on topics ending with `suffix` are queued
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TopicPolicy {
+    pub suffix: &'static str,
+    /// Lower is published first
+    pub priority: u8,
+    /// Dropped when not published within that delay
+    pub ttl: Option<Duration>,
+}
+
+impl TopicPolicy {
+    pub fn for_topic(topic: &str) -> Self {
+        OUTBOX_TOPIC_POLICIES
+            .iter()
+            .find(|p| topic.ends_with(p.suffix))
+            .copied()
+            .unwrap_or(OUTBOX_DEFAULT_POLICY)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct QueuedMessage {
+    /// Arrival order
+    pub seq: u32,
+    pub priority: u8,
+    pub expires_at: Option<Instant>,
+    pub msg: UplinkMessage,
+}
+
+impl QueuedMessage {
+    pub fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at.is_some_and(|at| now >= at)
+    }
+
+    /// Sort key, the smallest is published first
+    fn rank(&self) -> (u8, u32) {
+        (self.priority, self.seq)
+    }
+}
+
+pub struct Outbox {
+    entries: heapless::Vec<QueuedMessage, OUTBOX_LEN>,
+    /// Starts in the middle so messages sent again can be put in front
+    next_seq: u32,
+}
+
+impl Default for Outbox {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Outbox {
+    pub const fn new() -> Self {
+        Self {
+            entries: heapless::Vec::new(),
+            next_seq: u32::MAX / 2,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Queue `msg` behind the messages of the same priority
+    ///
+    /// Returns the message dropped to make room, if any, or `msg` back when it is
+    /// expired or less important than everything queued.
+    pub fn push(
+        &mut self,
+        msg: UplinkMessage,
+        now: Instant,
+    ) -> Result<Option<QueuedMessage>, QueuedMessage> {
+        let seq = self.next_seq;
+        self.next_seq = self.next_seq.wrapping_add(1);
+        self.insert(Self::queued(seq, msg, now), now)
+    }
+
+    /// Queue `msg` ahead of everything of the same priority, for a message an
+    /// uplink failed to publish
+    pub fn push_front(
+        &mut self,
+        msg: UplinkMessage,
+        now: Instant,
+    ) -> Result<Option<QueuedMessage>, QueuedMessage> {
+        let seq = self
+            .entries
+            .iter()
+            .map(|e| e.seq)
+            .min()
+            .unwrap_or(self.next_seq)
+            .wrapping_sub(1);
+        self.insert(Self::queued(seq, msg, now), now)
+    }
+
+    /// Put back a message taken with [`Outbox::take_last`]
+    pub fn restore(
+        &mut self,
+        entry: QueuedMessage,
+        now: Instant,
+    ) -> Result<Option<QueuedMessage>, QueuedMessage> {
+        self.insert(entry, now)
+    }
+
+    /// Next message to publish, expired messages are dropped on the way
+    pub fn pop(&mut self, now: Instant) -> Option<UplinkMessage> {
+        self.purge_expired(now);
+        let idx = self
+            .entries
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, e)| e.rank())
+            .map(|(idx, _)| idx)?;
+        Some(self.entries.swap_remove(idx).msg)
+    }
+
+    /// Remove the message that would be published last
+    pub fn take_last(&mut self) -> Option<QueuedMessage> {
+        let idx = self.last_idx()?;
+        Some(self.entries.swap_remove(idx))
+    }
+
+    /// Drop the expired messages, returns how many were dropped
+    pub fn purge_expired(&mut self, now: Instant) -> usize {
+        let before = self.entries.len();
+        self.entries.retain(|e| !e.is_expired(now));
+        before - self.entries.len()
+    }
+
+    fn queued(seq: u32, msg: UplinkMessage, now: Instant) -> QueuedMessage {
+        let policy = TopicPolicy::for_topic(&msg.topic);
+        QueuedMessage {
+            seq,
+            priority: policy.priority,
+            expires_at: policy.ttl.map(|ttl| now + ttl),
+            msg,
+        }
+    }
+
+    fn insert(
+        &mut self,
+        entry: QueuedMessage,
+        now: Instant,
+    ) -> Result<Option<QueuedMessage>, QueuedMessage> {
+        if entry.is_expired(now) {
+            return Err(entry);
+        }
+        if self.entries.is_full() && self.purge_expired(now) == 0 {
+            let Some(last) = self.last_idx() else {
+                return Err(entry);
+            };
+            if entry.rank() > self.entries[last].rank() {
+                return Err(entry);
+            }
+            let dropped = self.entries.swap_remove(last);
+            self.entries.push(entry)?;
+            return Ok(Some(dropped));
+        }
+        self.entries.push(entry).map(|_| None)
+    }
+
+    fn last_idx(&self) -> Option<usize> {
+        self.entries
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, e)| e.rank())
+            .map(|(idx, _)| idx)
+    }
+}
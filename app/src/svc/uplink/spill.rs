@@ -0,0 +1,152 @@
+//! Ring of outbox messages on the external W25Q128 flash
+//!
+//! Each message takes a 2 KiB slot: a 20 bytes header (magic, priority, topic
+//! length, payload format, payload length, sequence number, expiry in ticks)
+//! followed by the topic and the payload. The read and write positions live in
+//! RAM, the region is considered empty after a reboot as the expiry ticks are
+//! meaningless by then.
+use embassy_time::Instant;
+use log::warn;
+
+use super::outbox::QueuedMessage;
+use super::{UplinkMessage, UPLINK_PAYLOAD_LEN, UPLINK_TOPIC_LEN};
+use crate::cfg::uplink_cfg::{OUTBOX_SPILL_BASE, OUTBOX_SPILL_SLOTS};
+use crate::hal::flash::W25Q128FVSG;
+use crate::svc::payload::PayloadFormat;
+
+const SLOT_SIZE: u32 = 2048;
+const SECTOR_SIZE: u32 = 4096;
+const PAGE_SIZE: u32 = 256;
+const HEADER_LEN: usize = 20;
+const MAGIC: u8 = 0xA5;
+/// Expiry value of the messages that never expire
+const NO_EXPIRY: u64 = u64::MAX;
+
+pub struct FlashSpill<'d> {
+    flash: W25Q128FVSG<'d>,
+    /// Next slot to read
+    head: u32,
+    /// Next slot to write
+    tail: u32,
+    count: u32,
+}
+
+impl<'d> FlashSpill<'d> {
+    pub fn new(flash: W25Q128FVSG<'d>) -> Self {
+        Self {
+            flash,
+            head: 0,
+            tail: 0,
+            count: 0,
+        }
+    }
+
+    pub async fn init(&mut self) {
+        self.flash.init().await;
+    }
+
+    pub fn len(&self) -> usize {
+        self.count as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// One slot stays free: writing the first slot of a sector erases its
+    /// neighbour, which must not hold an unread message
+    pub fn is_full(&self) -> bool {
+        self.count + 1 >= OUTBOX_SPILL_SLOTS
+    }
+
+    /// Write `entry` to the next slot, `false` when the region is full
+    pub async fn push(&mut self, entry: &QueuedMessage) -> bool {
+        if self.is_full() {
+            return false;
+        }
+        let mut record = [0xFFu8; HEADER_LEN + UPLINK_TOPIC_LEN + UPLINK_PAYLOAD_LEN];
+        let topic = entry.msg.topic.as_bytes();
+        let payload = &entry.msg.payload;
+        let expiry = entry.expires_at.map_or(NO_EXPIRY, |at| at.as_ticks());
+        record[0] = MAGIC;
+        record[1] = entry.priority;
+        record[2] = topic.len() as u8;
+        record[3] = entry.msg.format as u8;
+        record[4..6].copy_from_slice(&(payload.len() as u16).to_le_bytes());
+        record[8..12].copy_from_slice(&entry.seq.to_le_bytes());
+        record[12..20].copy_from_slice(&expiry.to_le_bytes());
+        record[HEADER_LEN..HEADER_LEN + topic.len()].copy_from_slice(topic);
+        let end = HEADER_LEN + topic.len() + payload.len();
+        record[HEADER_LEN + topic.len()..end].copy_from_slice(payload);
+
+        let address = self.slot_address(self.tail);
+        if address % SECTOR_SIZE == 0 {
+            self.flash.erase_sector(address).await;
+        }
+        // Page program does not cross page boundaries
+        let mut offset = 0;
+        while offset < end {
+            let len = (PAGE_SIZE as usize).min(end - offset);
+            self.flash
+                .write_data(address + offset as u32, &record[offset..offset + len])
+                .await;
+            offset += len;
+        }
+
+        self.tail = (self.tail + 1) % OUTBOX_SPILL_SLOTS;
+        self.count += 1;
+        true
+    }
+
+    /// Read back the oldest slot, skipping the ones that do not hold a valid record
+    pub async fn pop(&mut self) -> Option<QueuedMessage> {
+        while self.count > 0 {
+            let address = self.slot_address(self.head);
+            self.head = (self.head + 1) % OUTBOX_SPILL_SLOTS;
+            self.count -= 1;
+
+            let mut header = [0u8; HEADER_LEN];
+            self.flash.read_data(address, &mut header).await;
+            let topic_len = header[2] as usize;
+            let payload_len = u16::from_le_bytes([header[4], header[5]]) as usize;
+            let format = PayloadFormat::from_u8(header[3]);
+            if header[0] != MAGIC
+                || topic_len > UPLINK_TOPIC_LEN
+                || payload_len > UPLINK_PAYLOAD_LEN
+                || format.is_none()
+            {
+                warn!("Invalid spilled message at {:#X}", address);
+                continue;
+            }
+
+            let mut data = [0u8; UPLINK_TOPIC_LEN + UPLINK_PAYLOAD_LEN];
+            let data = &mut data[..topic_len + payload_len];
+            self.flash
+                .read_data(address + HEADER_LEN as u32, data)
+                .await;
+            let Ok(topic) = core::str::from_utf8(&data[..topic_len]) else {
+                warn!("Invalid spilled topic at {:#X}", address);
+                continue;
+            };
+            let mut msg = UplinkMessage {
+                topic: heapless::String::new(),
+                payload: heapless::Vec::new(),
+                format: format.unwrap_or(PayloadFormat::Json),
+            };
+            let _ = msg.topic.push_str(topic);
+            let _ = msg.payload.extend_from_slice(&data[topic_len..]);
+            let expiry = u64::from_le_bytes(header[12..20].try_into().unwrap());
+            return Some(QueuedMessage {
+                seq: u32::from_le_bytes(header[8..12].try_into().unwrap()),
+                priority: header[1],
+                expires_at: (expiry != NO_EXPIRY).then(|| Instant::from_ticks(expiry)),
+                msg,
+            });
+        }
+        None
+    }
+
+    fn slot_address(&self, slot: u32) -> u32 {
+        OUTBOX_SPILL_BASE + slot * SLOT_SIZE
+    }
+}
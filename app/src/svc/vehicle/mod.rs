@@ -0,0 +1,264 @@
+//! Vehicle state detection
+//!
+//! The state is derived from bus activity, the optional signals configured in
+//! `cfg::vehicle_cfg` and the GNSS speed. A candidate state has to hold for
+//! [`DEBOUNCE`] before it is reported, so a single burst of frames or a GNSS jump
+//! does not flip the state back and forth.
+use core::cell::RefCell;
+
+use embassy_sync::blocking_mutex::{raw::NoopRawMutex, Mutex};
+use embassy_time::{Duration, Instant};
+
+use crate::cfg::vehicle_cfg::{CHARGING_SIGNAL, ENGINE_RPM_SIGNAL, GEAR_SIGNAL};
+use crate::svc::can::signal::SignalDef;
+use crate::svc::can::CanFrame;
+
+pub type VehicleCell = Mutex<NoopRawMutex, RefCell<Vehicle>>;
+
+/// Bus silent for that long means the vehicle is off
+const BUS_SILENCE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Below that frame rate only a few ECUs are awake (accessory position)
+const ACCESSORY_MAX_FRAME_RATE: u32 = 20;
+/// Engine speed above which the engine is considered running
+const RUNNING_MIN_RPM: f32 = 400.0;
+/// GNSS speed above which the vehicle is considered moving
+const MOVING_MIN_SPEED_KMH: f32 = 5.0;
+/// Signals and GNSS speed older than that are ignored
+const INPUT_MAX_AGE: Duration = Duration::from_secs(3);
+/// How long a candidate state has to hold before it is reported
+const DEBOUNCE: Duration = Duration::from_secs(2);
+/// Frame rate measurement window
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VehicleState {
+    Off,
+    Accessory,
+    IgnitionOn,
+    Running,
+    Charging,
+}
+
+impl VehicleState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            VehicleState::Off => "off",
+            VehicleState::Accessory => "accessory",
+            VehicleState::IgnitionOn => "ignition_on",
+            VehicleState::Running => "running",
+            VehicleState::Charging => "charging",
+        }
+    }
+}
+
+/// How the uplinks behave in a given vehicle state
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReportPolicy {
+    /// Minimum interval between two CAN telemetry messages
+    pub telemetry_interval: Duration,
+    /// Minimum interval between two trip (GNSS) messages
+    pub trip_interval: Duration,
+    /// Whether the modem may enter sleep mode between two publications
+    pub allow_sleep: bool,
+}
+
+impl ReportPolicy {
+    pub const fn for_state(state: VehicleState) -> Self {
+        let (telemetry_s, trip_s, allow_sleep) = match state {
+            VehicleState::Off => (300, 900, true),
+            VehicleState::Accessory => (30, 120, false),
+            VehicleState::IgnitionOn => (5, 30, false),
+            VehicleState::Running => (1, 5, false),
+            VehicleState::Charging => (10, 300, false),
+        };
+        Self {
+            telemetry_interval: Duration::from_secs(telemetry_s),
+            trip_interval: Duration::from_secs(trip_s),
+            allow_sleep,
+        }
+    }
+}
+
+/// Last GNSS position, in decimal degrees
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Position {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+/// A reported state change
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Transition {
+    pub from: VehicleState,
+    pub to: VehicleState,
+    pub at: Instant,
+}
+
+/// Last value of an input and when it was received
+#[derive(Debug, Clone, Copy)]
+struct Sample<T> {
+    value: T,
+    at: Instant,
+}
+
+impl<T: Copy> Sample<T> {
+    fn fresh(sample: &Option<Self>, now: Instant) -> Option<T> {
+        sample
+            .as_ref()
+            .filter(|s| now.saturating_duration_since(s.at) <= INPUT_MAX_AGE)
+            .map(|s| s.value)
+    }
+}
+
+/// Inputs and state machine, fed by the CAN receiver and the GNSS reader
+pub struct Vehicle {
+    state: VehicleState,
+    candidate: VehicleState,
+    candidate_since: Instant,
+    last_frame_at: Option<Instant>,
+    window_start: Instant,
+    window_frames: u32,
+    frame_rate: u32,
+    rpm: Option<Sample<f32>>,
+    gear: Option<Sample<f32>>,
+    charging: Option<Sample<bool>>,
+    speed_kmh: Option<Sample<f32>>,
+    position: Option<Position>,
+}
+
+impl Default for Vehicle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Vehicle {
+    pub const fn new() -> Self {
+        Self {
+            state: VehicleState::Off,
+            candidate: VehicleState::Off,
+            candidate_since: Instant::from_ticks(0),
+            last_frame_at: None,
+            window_start: Instant::from_ticks(0),
+            window_frames: 0,
+            frame_rate: 0,
+            rpm: None,
+            gear: None,
+            charging: None,
+            speed_kmh: None,
+            position: None,
+        }
+    }
+
+    pub fn state(&self) -> VehicleState {
+        self.state
+    }
+
+    pub fn policy(&self) -> ReportPolicy {
+        ReportPolicy::for_state(self.state)
+    }
+
+    /// Account for a received frame and decode the configured signals it carries
+    pub fn on_frame(&mut self, frame: &CanFrame, now: Instant) {
+        self.last_frame_at = Some(now);
+        self.window_frames = self.window_frames.saturating_add(1);
+
+        let decode = |signal: &Option<SignalDef>| signal.as_ref().and_then(|s| s.decode(frame));
+        if let Some(value) = decode(&ENGINE_RPM_SIGNAL) {
+            self.rpm = Some(Sample { value, at: now });
+        }
+        if let Some(value) = decode(&GEAR_SIGNAL) {
+            self.gear = Some(Sample { value, at: now });
+        }
+        if let Some(value) = decode(&CHARGING_SIGNAL) {
+            self.charging = Some(Sample {
+                value: value != 0.0,
+                at: now,
+            });
+        }
+    }
+
+    /// Engine speed in rpm, `None` when not configured or not received lately
+    pub fn engine_rpm(&self, now: Instant) -> Option<f32> {
+        Sample::fresh(&self.rpm, now)
+    }
+
+    pub fn gear(&self, now: Instant) -> Option<f32> {
+        Sample::fresh(&self.gear, now)
+    }
+
+    pub fn charging(&self, now: Instant) -> Option<bool> {
+        Sample::fresh(&self.charging, now)
+    }
+
+    /// Frames per second received over the last rate window
+    pub fn frame_rate(&self) -> u32 {
+        self.frame_rate
+    }
+
+    /// Last known position, kept when the fix is lost
+    pub fn position(&self) -> Option<Position> {
+        self.position
+    }
+
+    /// Valid fix reported by the GNSS receiver, speed over ground in km/h
+    pub fn on_gnss_fix(&mut self, position: Position, speed_kmh: f32, now: Instant) {
+        self.position = Some(position);
+        self.speed_kmh = Some(Sample {
+            value: speed_kmh,
+            at: now,
+        });
+    }
+
+    /// Re-evaluate the state, returns the transition once a new state is confirmed
+    pub fn update(&mut self, now: Instant) -> Option<Transition> {
+        let window = now.saturating_duration_since(self.window_start);
+        if window >= RATE_WINDOW {
+            self.frame_rate = (self.window_frames as u64 * 1000 / window.as_millis().max(1)) as u32;
+            self.window_frames = 0;
+            self.window_start = now;
+        }
+
+        let candidate = self.evaluate(now);
+        if candidate != self.candidate {
+            self.candidate = candidate;
+            self.candidate_since = now;
+        }
+        if self.candidate == self.state
+            || now.saturating_duration_since(self.candidate_since) < DEBOUNCE
+        {
+            return None;
+        }
+        let transition = Transition {
+            from: self.state,
+            to: self.candidate,
+            at: now,
+        };
+        self.state = self.candidate;
+        Some(transition)
+    }
+
+    fn evaluate(&self, now: Instant) -> VehicleState {
+        if Sample::fresh(&self.charging, now) == Some(true) {
+            return VehicleState::Charging;
+        }
+        let engine_running =
+            Sample::fresh(&self.rpm, now).is_some_and(|rpm| rpm >= RUNNING_MIN_RPM);
+        let in_gear = Sample::fresh(&self.gear, now).is_some_and(|gear| gear != 0.0);
+        let moving =
+            Sample::fresh(&self.speed_kmh, now).is_some_and(|speed| speed >= MOVING_MIN_SPEED_KMH);
+        if engine_running || in_gear || moving {
+            return VehicleState::Running;
+        }
+        let bus_active = self
+            .last_frame_at
+            .is_some_and(|at| now.saturating_duration_since(at) < BUS_SILENCE_TIMEOUT);
+        if !bus_active {
+            VehicleState::Off
+        } else if self.frame_rate < ACCESSORY_MAX_FRAME_RATE {
+            VehicleState::Accessory
+        } else {
+            VehicleState::IgnitionOn
+        }
+    }
+}
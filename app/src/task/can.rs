@@ -1,22 +1,36 @@
 use embassy_sync::{blocking_mutex::raw::NoopRawMutex, channel::Channel};
-use embedded_can::{Frame, Id};
-use esp_hal::twai::TwaiRx;
-use log::{error, info};
-
-#[derive(Debug)]
-#[allow(dead_code)]
-pub struct CanFrame {
-    pub id: u32,
-    pub len: u8,
-    pub data: [u8; 8],
-}
+use embassy_time::Instant;
+use embedded_can::{ExtendedId, Frame, Id, StandardId};
+use esp_hal::twai::{EspTwaiFrame, TwaiRx, TwaiTx};
+use log::{error, info, warn};
+
+use crate::svc::can::isotp::DiagLink;
+pub use crate::svc::can::CanFrame;
+use crate::svc::ev::BatteryCell;
+use crate::svc::sniff::LiveSessionCell;
+use crate::svc::vehicle::VehicleCell;
+
+/// Bitrate the TWAI controller is started with, keep in sync with `main`
+pub const CAN_BITRATE_BPS: u32 = 250_000;
 
 pub type TwaiOutbox = Channel<NoopRawMutex, CanFrame, 16>;
+/// Frames captured for the live session, sized to absorb bursts at full bus load
+pub type SniffOutbox = Channel<NoopRawMutex, CanFrame, 64>;
+/// Frames captured for the wireless CAN adapter link
+pub type CaptureOutbox = Channel<NoopRawMutex, CanFrame, 64>;
+/// Frames waiting to be transmitted on the bus
+pub type CanTxInbox = Channel<NoopRawMutex, CanFrame, 8>;
 
 #[embassy_executor::task]
 pub async fn can_receiver(
     mut rx: TwaiRx<'static, esp_hal::Async>,
     channel: &'static TwaiOutbox,
+    sniff: &'static SniffOutbox,
+    session: &'static LiveSessionCell,
+    capture: &'static CaptureOutbox,
+    vehicle: &'static VehicleCell,
+    battery: &'static BatteryCell,
+    diag: &'static DiagLink,
 ) -> ! {
     info!("Hello Can Rx Task !!\r");
     loop {
@@ -24,24 +38,44 @@ pub async fn can_receiver(
 
         match frame {
             Ok(frame) => {
-                // repeat the frame back
-                match frame.id() {
-                    // ION doesn't work with StandardId
-                    Id::Standard(_id) => {}
-                    Id::Extended(id) => {
-                        let mut data = [0u8; 8];
-                        data[0..frame.data().len()].copy_from_slice(frame.data());
-
-                        // if id.as_raw() == MQTT_CAN_PACKET {
-                        info!("Receive MQTT CAN packet");
-                        // Try to send can frame to wifi task without blocking
-                        let _ = channel.try_send(CanFrame {
-                            id: id.as_raw(),
-                            len: frame.dlc() as u8,
-                            data,
-                        });
-                        // }
+                let (id, extended) = match frame.id() {
+                    Id::Standard(id) => (id.as_raw() as u32, false),
+                    Id::Extended(id) => (id.as_raw(), true),
+                };
+                let mut data = [0u8; 8];
+                data[0..frame.data().len()].copy_from_slice(frame.data());
+                let can_frame = CanFrame {
+                    id,
+                    extended,
+                    len: frame.dlc() as u8,
+                    data,
+                    timestamp_us: Instant::now().as_micros(),
+                };
+
+                let now = Instant::now();
+                vehicle.lock(|v| v.borrow_mut().on_frame(&can_frame, now));
+                battery.lock(|b| b.borrow_mut().on_frame(&can_frame, now));
+                // Responses of the ECU queried by a diagnostic request, if any
+                diag.offer(&can_frame);
+
+                // Nobody drains the capture queue while no host is connected
+                let _ = capture.try_send(can_frame.clone());
+
+                // Live session sees the raw bus, standard frames included
+                session.lock(|s| {
+                    let mut s = s.borrow_mut();
+                    if s.accepts(id, extended) && sniff.try_send(can_frame.clone()).is_err() {
+                        s.record_drop();
                     }
+                });
+
+                // ION doesn't work with StandardId
+                if extended {
+                    // if id.as_raw() == MQTT_CAN_PACKET {
+                    info!("Receive MQTT CAN packet");
+                    // Try to send can frame to wifi task without blocking
+                    let _ = channel.try_send(can_frame);
+                    // }
                 }
             }
             Err(e) => {
@@ -50,3 +84,26 @@ pub async fn can_receiver(
         }
     }
 }
+
+#[embassy_executor::task]
+pub async fn can_transmitter(
+    mut tx: TwaiTx<'static, esp_hal::Async>,
+    inbox: &'static CanTxInbox,
+) -> ! {
+    info!("Hello Can Tx Task !!\r");
+    loop {
+        let frame = inbox.receive().await;
+        let id = if frame.extended {
+            ExtendedId::new(frame.id).map(Id::Extended)
+        } else {
+            StandardId::new(frame.id as u16).map(Id::Standard)
+        };
+        let Some(twai_frame) = id.and_then(|id| EspTwaiFrame::new(id, frame.payload())) else {
+            warn!("Dropping invalid CAN frame {:08X}", frame.id);
+            continue;
+        };
+        if let Err(e) = tx.transmit_async(&twai_frame).await {
+            error!("TWAI transmit error: {e:?}\r");
+        }
+    }
+}
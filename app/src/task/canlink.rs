@@ -0,0 +1,155 @@
+use embassy_net::{tcp::TcpSocket, IpAddress, IpEndpoint, Stack};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use esp_hal::efuse::Efuse;
+use log::{error, info, warn};
+
+use crate::cfg::canlink_cfg::*;
+use crate::svc::canlink::{self, LinkContext, LinkSession};
+use crate::task::can::{CanTxInbox, CaptureOutbox, CAN_BITRATE_BPS};
+
+/// Close the link when the host has been silent for that long
+const IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+/// How long to wait for host bytes before forwarding captured frames again
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// Wireless CAN adapter: serves the captured bus traffic over GVRET (SavvyCAN)
+/// or SLCAN (`slcand` through a TCP bridge) to the hosts of `CAN_LINK_PEERS`,
+/// and transmits their frames when `CAN_LINK_TRANSMIT` allows it
+#[embassy_executor::task]
+pub async fn can_link_server(
+    stack: &'static Stack<'static>,
+    capture: &'static CaptureOutbox,
+    can_tx: &'static CanTxInbox,
+) -> ! {
+    let mut rx_buffer = [0; 1024];
+    let mut tx_buffer = [0; 4096];
+    let mac = Efuse::mac_address();
+    let serial = u16::from_be_bytes([mac[4], mac[5]]);
+
+    loop {
+        //wait until wifi connected
+        while stack.config_v4().is_none() {
+            Timer::after(Duration::from_millis(500)).await;
+        }
+
+        let mut socket = TcpSocket::new(*stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(IDLE_TIMEOUT));
+        info!("CAN link: listening on TCP port {CAN_LINK_PORT}");
+        if let Err(e) = socket.accept(CAN_LINK_PORT).await {
+            error!("CAN link: accept failed: {e:?}");
+            continue;
+        }
+        // The address was lost meanwhile
+        let Some(config) = stack.config_v4() else {
+            socket.abort();
+            continue;
+        };
+        let subnet = (
+            config.address.address().octets(),
+            config.address.prefix_len(),
+        );
+        let peer = socket.remote_endpoint();
+        let allowed = match peer {
+            Some(IpEndpoint {
+                addr: IpAddress::Ipv4(addr),
+                ..
+            }) => canlink::peer_allowed(addr.octets(), subnet, CAN_LINK_PEERS),
+            _ => false,
+        };
+        if !allowed {
+            warn!("CAN link: host {peer:?} refused, not an allowed network");
+            socket.abort();
+            let _ = socket.flush().await;
+            Timer::after(CAN_LINK_REFUSE_DELAY).await;
+            continue;
+        }
+        info!("CAN link: host connected from {peer:?}");
+
+        // Frames captured while nobody was listening are stale
+        while capture.try_receive().is_ok() {}
+
+        let mut session = LinkSession::new(CAN_LINK_TRANSMIT);
+        let mut input = [0u8; 256];
+        let mut output: heapless::Vec<u8, 512> = heapless::Vec::new();
+        'link: loop {
+            match with_timeout(POLL_INTERVAL, socket.read(&mut input)).await {
+                Ok(Ok(0)) => break 'link,
+                Ok(Ok(len)) => {
+                    let host = &input[..len];
+                    if feed(&mut session, host, serial, can_tx, &mut socket, &mut output)
+                        .await
+                        .is_err()
+                    {
+                        break 'link;
+                    }
+                }
+                Ok(Err(e)) => {
+                    warn!("CAN link: read error: {e:?}");
+                    break 'link;
+                }
+                Err(_) => {}
+            }
+
+            if session.is_streaming() {
+                while let Ok(frame) = capture.try_receive() {
+                    if !session.encode_frame(&frame, &mut output) {
+                        if write_all(&mut socket, &mut output).await.is_err() {
+                            break 'link;
+                        }
+                        let _ = session.encode_frame(&frame, &mut output);
+                    }
+                }
+            }
+            if write_all(&mut socket, &mut output).await.is_err() {
+                break 'link;
+            }
+        }
+
+        info!("CAN link: host disconnected");
+        socket.close();
+        let _ = socket.flush().await;
+    }
+}
+
+/// Hand bytes of the host to the session, queueing the frames it asks to
+/// transmit and writing its answers
+async fn feed<const N: usize>(
+    session: &mut LinkSession,
+    bytes: &[u8],
+    serial: u16,
+    can_tx: &CanTxInbox,
+    socket: &mut TcpSocket<'_>,
+    output: &mut heapless::Vec<u8, N>,
+) -> Result<(), embassy_net::tcp::Error> {
+    let ctx = LinkContext {
+        timestamp_us: Instant::now().as_micros() as u32,
+        bitrate: CAN_BITRATE_BPS,
+        serial,
+    };
+    for byte in bytes {
+        if let Some(frame) = session.on_byte(*byte, &ctx, output) {
+            if can_tx.try_send(frame).is_err() {
+                warn!("CAN link: transmit queue full, frame dropped");
+            }
+        }
+        if output.len() > output.capacity() - 32 {
+            write_all(socket, output).await?;
+        }
+    }
+    Ok(())
+}
+
+async fn write_all<const N: usize>(
+    socket: &mut TcpSocket<'_>,
+    buf: &mut heapless::Vec<u8, N>,
+) -> Result<(), embassy_net::tcp::Error> {
+    let mut sent = 0;
+    while sent < buf.len() {
+        match socket.write(&buf[sent..]).await? {
+            0 => return Err(embassy_net::tcp::Error::ConnectionReset),
+            len => sent += len,
+        }
+    }
+    buf.clear();
+    Ok(())
+}
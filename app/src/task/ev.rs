@@ -0,0 +1,140 @@
+use core::fmt::Write;
+
+use embassy_time::{Duration, Instant, Timer};
+use log::{info, warn};
+
+use crate::svc::ev::{BatteryCell, ChargeEvent, ChargeTracker};
+use crate::svc::topic::{topic, MessageType};
+use crate::svc::uplink::{Priority, Uplink, UplinkMessage, UPLINK_PAYLOAD_LEN};
+use crate::svc::vehicle::{Position, VehicleCell, VehicleState};
+
+/// How often the battery snapshot is sampled
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Tracks the charging sessions and publishes their summaries and charge curves
+///
+/// Session start and summary go to `.../client/ev/charge` as
+/// `{"session":<n>,"state":"started","soc":<%>,"lat":<deg>,"lon":<deg>}` and
+/// `{"session":<n>,"state":"finished","start_soc":<%>,"end_soc":<%>,"energy_kwh":<kWh>,
+/// "peak_power_kw":<kW>,"duration_s":<s>,"lat":<deg>,"lon":<deg>}`, the curve points
+/// to `.../client/ev/curve` as `{"session":<n>,"minute":<n>,"soc":<%>,"voltage":<V>,
+/// "current":<A>,"power_kw":<kW>,"cell_t_min":<degC>,"cell_t_max":<degC>,
+/// "cell_v_min":<V>,"cell_v_max":<V>}`. Values not available are `null`.
+#[embassy_executor::task]
+pub async fn ev_monitor(
+    battery: &'static BatteryCell,
+    vehicle: &'static VehicleCell,
+    uplink: &'static Uplink,
+) -> ! {
+    let charge_topic = topic(MessageType::EvCharge);
+    let curve_topic = topic(MessageType::EvCurve);
+
+    let mut tracker = ChargeTracker::new();
+    loop {
+        Timer::after(SAMPLE_INTERVAL).await;
+        let now = Instant::now();
+        let snapshot = battery.lock(|b| b.borrow().snapshot(now));
+        let (charging, position) = vehicle.lock(|v| {
+            let v = v.borrow();
+            (v.state() == VehicleState::Charging, v.position())
+        });
+        let Some(event) = tracker.update(now, snapshot, charging, position) else {
+            continue;
+        };
+
+        match &event {
+            ChargeEvent::Started { session, .. } => info!("Charging session {session} started"),
+            ChargeEvent::Finished(summary) => info!(
+                "Charging session {} finished: {} kWh in {} s",
+                summary.session,
+                summary.energy_kwh,
+                summary.duration.as_secs()
+            ),
+            ChargeEvent::Curve { .. } => {}
+        }
+        let topic = match &event {
+            ChargeEvent::Curve { .. } => &curve_topic,
+            _ => &charge_topic,
+        };
+        let mut payload: heapless::String<UPLINK_PAYLOAD_LEN> = heapless::String::new();
+        if render_event(&mut payload, &event).is_err() {
+            warn!("Charging event does not fit the uplink payload");
+            continue;
+        }
+
+        uplink
+            .send(Priority::Normal, UplinkMessage::json(topic, &payload))
+            .await;
+    }
+}
+
+fn render_event(
+    out: &mut heapless::String<UPLINK_PAYLOAD_LEN>,
+    event: &ChargeEvent,
+) -> core::fmt::Result {
+    match event {
+        ChargeEvent::Started {
+            session,
+            soc,
+            position,
+        } => {
+            write!(out, "{{\"session\":{session},\"state\":\"started\"")?;
+            write_opt(out, "soc", *soc)?;
+            write_position(out, *position)?;
+        }
+        ChargeEvent::Curve {
+            session,
+            minute,
+            battery,
+        } => {
+            write!(out, "{{\"session\":{session},\"minute\":{minute}")?;
+            write_opt(out, "soc", battery.soc)?;
+            write_opt(out, "voltage", battery.pack_voltage)?;
+            write_opt(out, "current", battery.pack_current)?;
+            write_opt(out, "power_kw", battery.power_kw())?;
+            write_opt(out, "cell_t_min", battery.cell_temp_min)?;
+            write_opt(out, "cell_t_max", battery.cell_temp_max)?;
+            write_opt(out, "cell_v_min", battery.cell_voltage_min)?;
+            write_opt(out, "cell_v_max", battery.cell_voltage_max)?;
+        }
+        ChargeEvent::Finished(summary) => {
+            write!(
+                out,
+                "{{\"session\":{},\"state\":\"finished\"",
+                summary.session
+            )?;
+            write_opt(out, "start_soc", summary.start_soc)?;
+            write_opt(out, "end_soc", summary.end_soc)?;
+            write!(
+                out,
+                ",\"energy_kwh\":{:.3},\"peak_power_kw\":{:.2},\"duration_s\":{}",
+                summary.energy_kwh,
+                summary.peak_power_kw,
+                summary.duration.as_secs()
+            )?;
+            write_position(out, summary.position)?;
+        }
+    }
+    out.push('}').map_err(|_| core::fmt::Error)
+}
+
+fn write_opt(
+    out: &mut heapless::String<UPLINK_PAYLOAD_LEN>,
+    key: &str,
+    value: Option<f32>,
+) -> core::fmt::Result {
+    match value {
+        Some(value) => write!(out, ",\"{key}\":{value:.2}"),
+        None => write!(out, ",\"{key}\":null"),
+    }
+}
+
+fn write_position(
+    out: &mut heapless::String<UPLINK_PAYLOAD_LEN>,
+    position: Option<Position>,
+) -> core::fmt::Result {
+    match position {
+        Some(p) => write!(out, ",\"lat\":{:.6},\"lon\":{:.6}", p.latitude, p.longitude),
+        None => write!(out, ",\"lat\":null,\"lon\":null"),
+    }
+}
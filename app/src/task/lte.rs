@@ -2,9 +2,11 @@ use core::{fmt::Debug, fmt::Write, str::FromStr};
 
 use atat::{
     asynch::{AtatClient, Client},
-    AtatIngress, DefaultDigester, Ingress, UrcChannel,
+    AtatIngress, DefaultDigester, Ingress, UrcChannel, UrcSubscription,
 };
 
+use embassy_futures::select::{select, Either};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
 use esp_hal::{
     gpio::Output,
     uart::{UartRx, UartTx},
@@ -12,14 +14,30 @@ use esp_hal::{
 };
 use esp_println::print;
 use log::{debug, error, info, trace, warn};
-use serde::{Deserialize, Serialize};
 
 use crate::svc::atcmd::general::*;
 use crate::svc::atcmd::response::*;
 use crate::svc::atcmd::Urc;
+use crate::svc::clock::{parse_modem_time, ClockCell, TimeSource};
+use crate::svc::config::{report_policy, ConfigCell};
+use crate::svc::credentials::{Credentials, Part, RotationLink};
+use crate::svc::mem::nvm::Nvm;
+use crate::svc::payload::{
+    self,
+    message::{Health, Trip},
+};
+use crate::svc::presence::{self, Bearer};
+use crate::svc::rpc::{self, RpcInbox};
+use crate::svc::shadow::{ShadowLink, DESIRED_LEN};
+use crate::svc::topic::{schema, topic, MessageType, Topic};
+use crate::svc::uplink::{Priority, Uplink, UplinkMessage};
+use crate::svc::vehicle::{Position, VehicleCell};
 
+use crate::cfg::mqtt_cfg::CREDENTIALS_RETRY;
 use crate::cfg::net_cfg::*;
+use crate::cfg::time_cfg::{MODEM_NTP_SERVER, MODEM_NTP_TIMEOUT};
 
+use crate::util::hex;
 use crate::util::time::utc_date_to_unix_timestamp;
 
 const REGISTERED_HOME: u8 = 1;
@@ -28,14 +46,11 @@ const REGISTRATION_DENIED: u8 = 3;
 const REGISTRATION_FAILED: u8 = 4;
 const REGISTERED_ROAMING: u8 = 5;
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct TripData {
-    device_id: heapless::String<36>,
-    trip_id: heapless::String<36>,
-    latitude: f64,
-    longitude: f64,
-    timestamp: u64,
-}
+const KNOTS_TO_KMH: f64 = 1.852;
+/// Longest AT+QMTPUBEX payload
+const PUBLISH_LEN: usize = 1024;
+/// Time the modem UART needs after DTR is pulled low to leave sleep mode
+const MODEM_WAKEUP_DELAY: Duration = Duration::from_millis(100);
 
 #[derive(Debug)]
 enum State {
@@ -57,72 +72,232 @@ enum State {
     ErrorConnection,
 }
 
+/// Read the last RMC sentence, a valid fix also feeds the vehicle state with the
+/// speed over ground
+async fn retrieve_gnss_fix(
+    client: &mut Client<'static, UartTx<'static, Async>, 1024>,
+    vehicle: &VehicleCell,
+    clock: &ClockCell,
+) -> Option<GpsData> {
+    match client.send(&RetrieveGpsRmc).await {
+        Ok(res) => {
+            info!("GPS RMC data received: {res:?}");
+            if res.status == 'A' {
+                let utc_ms = utc_date_to_unix_timestamp(&res.utc, &res.date);
+                set_clock(clock, TimeSource::Gnss, utc_ms);
+                let position = Position {
+                    latitude: nmea_to_degrees(res.latitude, res.latitude_direction == 'S'),
+                    longitude: nmea_to_degrees(res.longitude, res.longtitude_direction == 'W'),
+                };
+                let speed_kmh = (res.spkm * KNOTS_TO_KMH) as f32;
+                vehicle.lock(|v| {
+                    v.borrow_mut()
+                        .on_gnss_fix(position, speed_kmh, Instant::now())
+                });
+            }
+            Some(res)
+        }
+        Err(e) => {
+            warn!("Failed to retrieve GPS data: {e:?}");
+            None
+        }
+    }
+}
+
+/// AT+QLTS, the time of the cell in GMT, empty until the network sent it
+async fn retrieve_network_time(
+    client: &mut Client<'static, UartTx<'static, Async>, 1024>,
+    clock: &ClockCell,
+) {
+    match client.send(&GetNetworkNitzTime { mode: 1 }).await {
+        Ok(res) => match parse_modem_time(&res.time_and_dst, false) {
+            Some(unix_ms) => set_clock(clock, TimeSource::Nitz, unix_ms),
+            None => info!("Quectel: no network time: {res:?}"),
+        },
+        Err(e) => warn!("Failed to retrieve the network time: {e:?}"),
+    }
+}
+
+/// AT+QNTP over the activated context, the time comes in local time with the
+/// +QNTP URC
+async fn synchronize_modem_ntp(
+    client: &mut Client<'static, UartTx<'static, Async>, 1024>,
+    urc_channel: &'static UrcChannel<Urc, 8, 3>,
+    clock: &ClockCell,
+) {
+    let Ok(mut subscriber) = urc_channel.subscribe() else {
+        return;
+    };
+    let Ok(server) = heapless::String::from_str(MODEM_NTP_SERVER) else {
+        return;
+    };
+    let request = GetNetworkNtpTime {
+        context_id: 1,
+        server,
+    };
+    if !check_result(client.send(&request).await) {
+        return;
+    }
+    let result = with_timeout(MODEM_NTP_TIMEOUT, async {
+        loop {
+            if let Urc::NtpTime(res) = subscriber.next_message_pure().await {
+                return res;
+            }
+        }
+    })
+    .await;
+    match result {
+        Ok(res) if res.err == 0 => match parse_modem_time(&res.time, true) {
+            Some(unix_ms) => set_clock(clock, TimeSource::ModemNtp, unix_ms),
+            None => warn!("Quectel: unreadable NTP time: {res:?}"),
+        },
+        Ok(res) => warn!("Quectel: NTP synchronization failed: {res:?}"),
+        Err(_) => warn!("Quectel: NTP synchronization timed out"),
+    }
+}
+
+fn set_clock(clock: &ClockCell, source: TimeSource, unix_ms: u64) {
+    match clock.lock(|c| c.borrow_mut().set(source, unix_ms, Instant::now())) {
+        Some(correction) => debug!("Clock set from {source:?}, {correction} ms off"),
+        None => debug!("Clock kept, {source:?} less trusted than the last source"),
+    }
+}
+
+/// Convert a NMEA `(d)ddmm.mmmm` coordinate to signed decimal degrees
+fn nmea_to_degrees(value: f64, negative: bool) -> f64 {
+    let degrees = ((value as u64 / 100) as f64) + ((value % 100.0f64) / 60.0f64);
+    if negative {
+        -degrees
+    } else {
+        degrees
+    }
+}
+
 async fn handle_publish_mqtt_data(
     client: &mut Client<'static, UartTx<'static, Async>, 1024>,
     mqtt_client_id: &str,
+    res: &GpsData,
 ) -> bool {
-    let mut mqtt_topic: heapless::String<128> = heapless::String::new();
-    let mut payload: heapless::String<1024> = heapless::String::new();
-    let mut deserialized: [u8; 1024] = [0u8; 1024];
+    let mqtt_topic = topic(MessageType::Trip);
+    let format = payload::format_for(MessageType::Trip);
+    let trip = Trip {
+        device_id: mqtt_client_id,
+        trip_id: mqtt_client_id,
+        latitude: nmea_to_degrees(res.latitude, res.latitude_direction == 'S'),
+        longitude: nmea_to_degrees(res.longitude, res.longtitude_direction == 'W'),
+        timestamp: utc_date_to_unix_timestamp(&res.utc, &res.date),
+    };
 
-    writeln!(
-        &mut mqtt_topic,
-        "channels/{mqtt_client_id}/messages/client/trip"
-    )
-    .unwrap();
+    let mut encoded = [0u8; 256];
+    let Ok(len) = payload::encode(format, &trip, &mut encoded) else {
+        error!("Failed to encode trip data");
+        return false;
+    };
+    info!("MQTT payload: {len} bytes");
+    publish(client, &mqtt_topic, &encoded[..len], false).await
+}
 
-    match client.send(&RetrieveGpsRmc).await {
-        Ok(res) => {
-            info!("GPS RMC data received: {res:?}");
-            let timestamp = utc_date_to_unix_timestamp(&res.utc, &res.date);
-            let mut device_id = heapless::String::new();
-            let mut trip_id = heapless::String::new();
-            write!(&mut trip_id, "{mqtt_client_id}").unwrap();
-            write!(&mut device_id, "{mqtt_client_id}").unwrap();
-
-            let trip_data = TripData {
-                device_id,
-                trip_id,
-                latitude: ((res.latitude as u64 / 100) as f64)
-                    + ((res.latitude % 100.0f64) / 60.0f64),
-                longitude: ((res.longitude as u64 / 100) as f64)
-                    + ((res.longitude % 100.0f64) / 60.0f64),
-                timestamp,
-            };
+/// Publish the messages queued for the uplink, live session traffic first
+///
+/// Every AT+QMTPUBEX is answered with a +QMTPUBEX URC, `drain_urcs` runs between
+/// them so the URC queue never fills up. It returns `false` once the modem
+/// reported the link down, the rest of the outbox then waits for the next
+/// connection.
+async fn handle_publish_uplink_messages(
+    client: &mut Client<'static, UartTx<'static, Async>, 1024>,
+    uplink: &Uplink,
+    mut drain_urcs: impl FnMut() -> bool,
+) -> bool {
+    while drain_urcs() {
+        let Some((priority, msg)) = uplink.try_next() else {
+            return true;
+        };
+        if msg.payload.len() > PUBLISH_LEN {
+            warn!(
+                "Dropping uplink message for {}, too large for AT+QMTPUBEX",
+                msg.topic
+            );
+            continue;
+        }
+        if !publish(client, &msg.topic, &msg.payload, false).await {
+            uplink.retry(priority, msg);
+            return false;
+        }
+    }
+    false
+}
 
-            if let Ok(len) = serde_json_core::to_slice(&trip_data, &mut deserialized) {
-                let single_quote = core::str::from_utf8(&deserialized[..len])
-                    .unwrap_or_default()
-                    .replace('\"', "'");
+/// Retained message, the presence on the status topic (see `svc::presence`) or
+/// the schema manifest
+async fn publish_retained(
+    client: &mut Client<'static, UartTx<'static, Async>, 1024>,
+    topic: &Topic,
+    payload: &str,
+) -> bool {
+    publish(client, topic, payload.as_bytes(), true).await
+}
 
-                if payload.push_str(&single_quote).is_err() {
-                    error!("Payload buffer overflow");
-                    return false;
-                }
+/// AT+QMTPUBEX in data mode, the payload follows the prompt as is
+///
+/// Retained messages are sent with QoS 1, the others with QoS 0.
+async fn publish(
+    client: &mut Client<'static, UartTx<'static, Async>, 1024>,
+    topic: &str,
+    payload: &[u8],
+    retained: bool,
+) -> bool {
+    let mut topic_arg: heapless::String<128> = heapless::String::new();
+    if topic_arg.push_str(topic).is_err() {
+        error!("Topic too long: {topic}");
+        return false;
+    }
+    let Ok(payload) = heapless::Vec::from_slice(payload) else {
+        error!("Payload too long for {topic}");
+        return false;
+    };
+    let command = MqttPublishExtended {
+        tcp_connect_id: 0,
+        msg_id: retained as u16,
+        qos: retained as u8,
+        retain: retained as u8,
+        topic: topic_arg,
+        length: payload.len() as u16,
+    };
+    check_result(client.send(&command).await)
+        && check_result(client.send(&MqttPublishPayload { payload }).await)
+}
 
-                info!("MQTT payload: {payload}");
-                check_result(
-                    client
-                        .send(&MqttPublishExtended {
-                            tcp_connect_id: 0,
-                            msg_id: 0,
-                            qos: 0,
-                            retain: 0,
-                            topic: mqtt_topic,
-                            payload,
-                        })
-                        .await,
-                )
-            } else {
-                error!("Failed to serialize trip data");
-                false
-            }
-        }
-        Err(e) => {
-            warn!("Failed to retrieve GPS data: {e:?}");
-            false
+/// AT+QMTCFG="dataformat", payloads are published as sent and reported hex
+/// encoded
+///
+/// A JSON payload would not survive the quoted +QMTRECV argument.
+async fn set_data_format(client: &mut Client<'static, UartTx<'static, Async>, 1024>) -> bool {
+    check_result(
+        client
+            .send(&MqttConfig {
+                name: heapless::String::from_str("dataformat").unwrap(),
+                param_1: Some(0),
+                param_2: Some(0),
+                param_3: Some(1),
+            })
+            .await,
+    )
+}
+
+/// Copy `text` into an AT string argument with the `\HH` escapes of V.250
+///
+/// A bare `"` would end the argument, and AT+QMTCFG="will" has no data mode to
+/// send the JSON of the last will as is.
+fn at_escaped<const N: usize>(text: &str) -> Option<heapless::String<N>> {
+    let mut out = heapless::String::new();
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\22").ok()?,
+            '\\' => out.push_str("\\5C").ok()?,
+            c => out.push(c).ok()?,
         }
     }
+    Some(out)
 }
 
 fn check_result<T>(res: Result<T, atat::Error>) -> bool
@@ -141,6 +316,81 @@ where
     }
 }
 
+/// AT+QMTSUB with QoS 1, the result comes as a +QMTSUB URC
+async fn subscribe(
+    client: &mut Client<'static, UartTx<'static, Async>, 1024>,
+    msg_id: u16,
+    filter: &str,
+) -> bool {
+    let Ok(topic) = heapless::String::try_from(filter) else {
+        error!("Topic filter too long: {filter}");
+        return false;
+    };
+    check_result(
+        client
+            .send(&MqttSubscribe {
+                tcp_connect_id: 0,
+                msg_id,
+                topic,
+                qos: 1,
+            })
+            .await,
+    )
+}
+
+/// URCs reported while connected: messages of the subscribed topics and the
+/// subscription results
+///
+/// Returns `false` on +QMTSTAT, the modem closed the MQTT link.
+fn handle_urc(urc: Urc, inbox: &RpcInbox, shadow: &ShadowLink, uplink: &Uplink) -> bool {
+    match urc {
+        Urc::MqttReceive(msg) => {
+            let Some(payload) = hex::decode::<DESIRED_LEN>(&msg.payload) else {
+                warn!(
+                    "Dropping message received on {}, invalid payload",
+                    msg.topic
+                );
+                return true;
+            };
+            if msg.topic.as_str() == topic(MessageType::ShadowDesired).as_str() {
+                shadow.submit(&payload);
+            } else {
+                rpc::submit(inbox, uplink, &msg.topic, &payload);
+            }
+        }
+        Urc::MqttSubscribe(res) if res.result != 0 => {
+            error!("Quectel: subscription failed: {res:?}");
+        }
+        Urc::MqttPublish(res) if res.result == 2 => {
+            error!("Quectel: publish failed: {res:?}");
+        }
+        Urc::MqttStatus(status) if status.tcpconnect_id == 0 => {
+            error!("Quectel: MQTT link closed, error {}", status.err);
+            return false;
+        }
+        other => debug!("Ignoring URC: {other:?}"),
+    }
+    true
+}
+
+/// Handle the URCs received so far, `false` once the modem closed the MQTT link
+fn drain_urcs(
+    urcs: &mut Option<UrcSubscription<'static, Urc, 8, 3>>,
+    inbox: &RpcInbox,
+    shadow: &ShadowLink,
+    uplink: &Uplink,
+) -> bool {
+    let Some(subscriber) = urcs.as_mut() else {
+        return true;
+    };
+    while let Some(urc) = subscriber.try_next_message_pure() {
+        if !handle_urc(urc, inbox, shadow, uplink) {
+            return false;
+        }
+    }
+    true
+}
+
 async fn reset_modem(pen: &mut Output<'static>) {
     pen.set_low(); // Power down the modem
     embassy_time::Timer::after(embassy_time::Duration::from_secs(1)).await;
@@ -150,7 +400,7 @@ async fn reset_modem(pen: &mut Output<'static>) {
 
 pub async fn upload_mqtt_cert_files(
     client: &mut Client<'static, UartTx<'static, Async>, 1024>,
-    urc_channel: &'static UrcChannel<Urc, 128, 3>,
+    urc_channel: &'static UrcChannel<Urc, 8, 3>,
     ca_chain: &[u8],
     certificate: &[u8],
     private_key: &[u8],
@@ -195,85 +445,31 @@ pub async fn upload_mqtt_cert_files(
             name: heapless::String::from_str("dvt.key").unwrap(),
         })
         .await;
-    // Upload CA cert
     info!("Quectel: Upload MQTT certs to quectel");
-    let _ = raw_data.extend_from_slice(&ca_chain[0..1024]);
-    let _ = client
-        .send(&FileUpl {
-            name: heapless::String::from_str("crt.pem").unwrap(),
-            size: 2574,
-        })
-        .await;
-    let _ = client
-        .send(&SendRawData {
-            raw_data: raw_data.clone(),
-            len: 1024,
-        })
-        .await;
-    raw_data.clear();
-    let _ = raw_data.extend_from_slice(&ca_chain[1024..2048]);
-    let _ = client
-        .send(&SendRawData {
-            raw_data: raw_data.clone(),
-            len: 1024,
-        })
-        .await;
-    raw_data.clear();
-    let _ = raw_data.extend_from_slice(&ca_chain[2048..]);
-    let _ = client
-        .send(&SendRawData {
-            raw_data: raw_data.clone(),
-            len: 526,
-        })
-        .await;
-    embassy_time::Timer::after(embassy_time::Duration::from_secs(1)).await;
-    // Upload client cert
-    let _ = client
-        .send(&FileUpl {
-            name: heapless::String::from_str("dvt.crt").unwrap(),
-            size: 1268,
-        })
-        .await;
-    raw_data.clear();
-    let _ = raw_data.extend_from_slice(&certificate[0..1024]);
-    let _ = client
-        .send(&SendRawData {
-            raw_data: raw_data.clone(),
-            len: 1024,
-        })
-        .await;
-    raw_data.clear();
-    let _ = raw_data.extend_from_slice(&certificate[1024..]);
-    let _ = client
-        .send(&SendRawData {
-            raw_data: raw_data.clone(),
-            len: 244,
-        })
-        .await;
-    embassy_time::Timer::after(embassy_time::Duration::from_secs(1)).await;
-    // Upload client key
-    let _ = client
-        .send(&FileUpl {
-            name: heapless::String::from_str("dvt.key").unwrap(),
-            size: 1678,
-        })
-        .await;
-    raw_data.clear();
-    let _ = raw_data.extend_from_slice(&private_key[0..1024]);
-    let _ = client
-        .send(&SendRawData {
-            raw_data: raw_data.clone(),
-            len: 1024,
-        })
-        .await;
-    raw_data.clear();
-    let _ = raw_data.extend_from_slice(&private_key[1024..]);
-    let _ = client
-        .send(&SendRawData {
-            raw_data: raw_data.clone(),
-            len: 654,
-        })
-        .await;
+    for (name, data) in [
+        ("crt.pem", ca_chain),
+        ("dvt.crt", certificate),
+        ("dvt.key", private_key),
+    ] {
+        let _ = client
+            .send(&FileUpl {
+                name: heapless::String::from_str(name).unwrap(),
+                size: data.len() as u32,
+            })
+            .await;
+        // Any length, sent in pieces the UART buffer takes
+        for chunk in data.chunks(1024) {
+            raw_data.clear();
+            let _ = raw_data.extend_from_slice(chunk);
+            let _ = client
+                .send(&SendRawData {
+                    raw_data: raw_data.clone(),
+                    len: chunk.len(),
+                })
+                .await;
+        }
+        embassy_time::Timer::after(embassy_time::Duration::from_secs(1)).await;
+    }
 
     info!("Quectel: set MQTTS configuration");
     let _ = client
@@ -409,7 +605,7 @@ pub enum MqttConnectError {
 
 pub async fn open_mqtt_connection(
     client: &mut Client<'static, UartTx<'static, Async>, 1024>,
-    urc_channel: &'static UrcChannel<Urc, 128, 3>,
+    urc_channel: &'static UrcChannel<Urc, 8, 3>,
 ) -> Result<(), MqttConnectError> {
     // Create server string safely
     let server = heapless::String::from_str(MQTT_SERVER_NAME)
@@ -467,20 +663,53 @@ pub async fn open_mqtt_connection(
 
 pub async fn connect_mqtt_broker(
     client: &mut Client<'static, UartTx<'static, Async>, 1024>,
-    urc_channel: &'static UrcChannel<Urc, 128, 3>,
+    urc_channel: &'static UrcChannel<Urc, 8, 3>,
 ) -> Result<(), MqttConnectError> {
     const MAX_RETRIES: usize = 3;
     const RESPONSE_TIMEOUT: embassy_time::Duration = embassy_time::Duration::from_secs(30);
-    const CLIENT_ID: &str = "telematics-control-unit";
 
     // Create credentials with proper error handling
     let username = heapless::String::<64>::from_str(MQTT_USR_NAME)
         .map_err(|_| MqttConnectError::StringConversion)?;
     let password = heapless::String::<64>::from_str(MQTT_USR_NAME) // Note: Same as username - is this intentional?
         .map_err(|_| MqttConnectError::StringConversion)?;
-    let client_id = heapless::String::<23>::from_str(CLIENT_ID)
+    // Stable per device for the persistent session, distinct from the Wi-Fi
+    // client so that both bearers can be connected at once
+    let mut client_id = heapless::String::<64>::new();
+    write!(&mut client_id, "{MQTT_CLIENT_ID}-lte")
         .map_err(|_| MqttConnectError::StringConversion)?;
 
+    if !check_result(
+        client
+            .send(&MqttConfig {
+                name: heapless::String::from_str("session").unwrap(),
+                param_1: Some(0),
+                param_2: Some(0),
+                param_3: None,
+            })
+            .await,
+    ) {
+        warn!("Quectel: persistent session not configured");
+    }
+
+    // Same last will as the Wi-Fi path, on the status topic of this bearer only
+    let will_msg = at_escaped(presence::WILL_PAYLOAD).ok_or(MqttConnectError::StringConversion)?;
+    if !check_result(
+        client
+            .send(&MqttConfigWill {
+                name: heapless::String::from_str("will").unwrap(),
+                tcp_connect_id: 0,
+                will_flag: 1,
+                will_qos: 1,
+                will_retain: 1,
+                will_topic: presence::status_topic(Bearer::Lte),
+                will_msg,
+            })
+            .await,
+    ) {
+        warn!("Quectel: last will not configured");
+    }
+
     // Send connect command with retries
     for attempt in 1..=MAX_RETRIES {
         info!("MQTT connect attempt {attempt}/{MAX_RETRIES}");
@@ -545,19 +774,41 @@ pub async fn connect_mqtt_broker(
 pub async fn quectel_tx_handler(
     mut client: Client<'static, UartTx<'static, Async>, 1024>,
     mut pen: Output<'static>,
-    mut _dtr: Output<'static>,
-    urc_channel: &'static UrcChannel<Urc, 128, 3>,
+    mut dtr: Output<'static>,
+    urc_channel: &'static UrcChannel<Urc, 8, 3>,
+    uplink: &'static Uplink,
+    vehicle: &'static VehicleCell,
+    rpc_inbox: &'static RpcInbox,
+    shadow: &'static ShadowLink,
+    config: &'static ConfigCell,
+    clock: &'static ClockCell,
+    rotation: &'static RotationLink,
 ) -> ! {
     let mut state: State = State::ResetHardware;
-    let ca_chain = include_str!("../../cert/crt.pem").as_bytes();
-    let certificate = include_str!("../../cert/dvt.crt").as_bytes();
-    let private_key = include_str!("../../cert/dvt.key").as_bytes();
+    // Subscribed while connected to the broker only, a subscriber nobody reads
+    // fills the URC queue and stalls the AT responses behind it
+    let mut urcs = None;
+    let mut last_trip: Option<Instant> = None;
+    let status_topic = presence::status_topic(Bearer::Lte);
+    let schema_topic = topic(MessageType::Schema);
+    let health_topic = topic(MessageType::Health);
+    let desired_topic = topic(MessageType::ShadowDesired);
+    // Failed attempts since the broker last accepted the connection
+    let mut failures: u32 = 0;
+    // Read from flash before every upload to the modem
+    let mut nvm = Nvm::init();
+    let mut credentials = Credentials::new();
 
     loop {
+        if !matches!(state, State::MqttPublishData) {
+            urcs = None;
+        }
         match state {
             State::ResetHardware => {
                 // 0: Reset Hardware
                 info!("Quectel: Reset Hardware");
+                // DTR low keeps the modem awake
+                dtr.set_low();
                 reset_modem(&mut pen).await;
                 state = State::DisableEchoMode;
             }
@@ -618,17 +869,27 @@ pub async fn quectel_tx_handler(
                         })
                         .await,
                 ) {
+                    // Sleep is only entered while DTR is high, see MqttPublishData
+                    if !check_result(client.send(&ConfigureSleepMode { mode: 1 }).await) {
+                        warn!("Quectel: sleep mode not available");
+                    }
                     state = State::UploadMqttCert;
                 }
             }
             State::UploadMqttCert => {
+                let now = clock.lock(|c| c.borrow().now_secs());
+                if let Err(e) = credentials.load(&mut nvm, false, now) {
+                    error!("Quectel: no usable TLS credentials, not connecting: {e:?}");
+                    Timer::after(CREDENTIALS_RETRY).await;
+                    continue;
+                }
                 info!("Quectel: Upload Files");
                 let res: bool = upload_mqtt_cert_files(
                     &mut client,
                     urc_channel,
-                    ca_chain,
-                    certificate,
-                    private_key,
+                    credentials.pem(Part::CaChain),
+                    credentials.pem(Part::Certificate),
+                    credentials.pem(Part::PrivateKey),
                 )
                 .await;
                 state = if res {
@@ -641,6 +902,9 @@ pub async fn quectel_tx_handler(
             State::CheckNetworkRegistration => {
                 info!("Quectel: Check Network Registration");
                 let res = check_network_registration(&mut client).await;
+                if res {
+                    retrieve_network_time(&mut client, clock).await;
+                }
                 state = if res {
                     State::MqttOpenConnection
                 } else {
@@ -657,6 +921,7 @@ pub async fn quectel_tx_handler(
                     }
                     Err(e) => {
                         error!("Failed to open MQTT connection: {e:?}");
+                        failures = failures.saturating_add(1);
                     }
                 }
             }
@@ -665,25 +930,168 @@ pub async fn quectel_tx_handler(
                 match connect_mqtt_broker(&mut client, urc_channel).await {
                     Ok(_) => {
                         info!("MQTT connection established");
+                        if !publish_retained(
+                            &mut client,
+                            &status_topic,
+                            &presence::online_payload(Bearer::Lte),
+                        )
+                        .await
+                        {
+                            error!("Failed to publish online status");
+                        }
+                        let manifest = schema::manifest_payload();
+                        if manifest.len() > PUBLISH_LEN {
+                            // Beyond AT+QMTPUBEX, the Wi-Fi uplink publishes it
+                            warn!("Schema manifest too large for the modem, not published");
+                        } else if !publish_retained(&mut client, &schema_topic, &manifest).await {
+                            error!("Failed to publish the schema manifest");
+                        }
+                        // Received payloads hex encoded from now on
+                        if !set_data_format(&mut client).await {
+                            error!("Quectel: data format not set");
+                        }
+                        // Before subscribing, the retained desired document comes right away
+                        urcs = urc_channel.subscribe().ok();
+                        if urcs.is_none() {
+                            error!("Quectel: no URC subscriber left, messages will be missed");
+                        }
+                        if !subscribe(&mut client, 1, &rpc::request_filter()).await {
+                            error!("Failed to subscribe to RPC requests");
+                        }
+                        // The retained desired document follows, see `svc::shadow`
+                        if subscribe(&mut client, 2, &desired_topic).await {
+                            shadow.resync();
+                        } else {
+                            error!("Failed to subscribe to the desired shadow");
+                        }
+                        let health = Health {
+                            uptime_s: Instant::now().as_secs(),
+                            firmware: presence::FIRMWARE_VERSION,
+                            bearer: Bearer::Lte.as_str(),
+                            queued: (uplink.outbox_len() + uplink.spilled()) as u32,
+                            failures,
+                        };
+                        failures = 0;
+                        // The cell did not give the time, nor did anything better
+                        let source = clock.lock(|c| c.borrow().source());
+                        if source.is_none_or(|source| source == TimeSource::ModemNtp) {
+                            synchronize_modem_ntp(&mut client, urc_channel, clock).await;
+                        }
+                        let format = payload::format_for(MessageType::Health);
+                        match UplinkMessage::encode(&health_topic, format, &health) {
+                            Ok(msg) => {
+                                if !uplink.try_send(Priority::Normal, msg) {
+                                    warn!("Outbox full, health report dropped");
+                                }
+                            }
+                            Err(e) => error!("Failed to encode the health report: {e:?}"),
+                        }
                         state = State::MqttPublishData;
                     }
                     Err(e) => {
                         error!("MQTT connection failed: {e:?}");
+                        failures = failures.saturating_add(1);
                     }
                 }
             }
             State::MqttPublishData => {
+                // The modem reports a lost link with +QMTSTAT, the queued
+                // messages are published once connected again
+                if !drain_urcs(&mut urcs, rpc_inbox, shadow, uplink) {
+                    failures = failures.saturating_add(1);
+                    state = State::MqttOpenConnection;
+                    continue;
+                }
+                if rotation.take_committed() {
+                    info!("Quectel: credentials rotated, connecting again with them");
+                    let _ = client.send(&MqttDisconnect { tcp_connect_id: 0 }).await;
+                    state = State::UploadMqttCert;
+                    continue;
+                }
+                let policy = report_policy(vehicle, config);
+                let trip_due = last_trip.is_none_or(|at| at.elapsed() >= policy.trip_interval);
+                if policy.allow_sleep && !trip_due && uplink.is_empty() {
+                    // Let the modem sleep until the next trip or a queued message,
+                    // RPC requests received meanwhile are reported once it is awake
+                    let remaining = last_trip.map_or(Duration::from_ticks(0), |at| {
+                        policy
+                            .trip_interval
+                            .checked_sub(at.elapsed())
+                            .unwrap_or(Duration::from_ticks(0))
+                    });
+                    if !publish_retained(&mut client, &status_topic, presence::SLEEP_PAYLOAD).await
+                    {
+                        warn!("Failed to publish sleep status");
+                    }
+                    dtr.set_high();
+                    let wakeup = with_timeout(remaining, uplink.wait());
+                    let connected = match urcs.as_mut() {
+                        // The modem wakes up for URCs, a request queues its response
+                        Some(subscriber) => {
+                            let requests = async {
+                                loop {
+                                    let urc = subscriber.next_message_pure().await;
+                                    if !handle_urc(urc, rpc_inbox, shadow, uplink) {
+                                        break;
+                                    }
+                                }
+                            };
+                            matches!(select(wakeup, requests).await, Either::First(_))
+                        }
+                        None => {
+                            let _ = wakeup.await;
+                            true
+                        }
+                    };
+                    dtr.set_low();
+                    Timer::after(MODEM_WAKEUP_DELAY).await;
+                    if !connected {
+                        failures = failures.saturating_add(1);
+                        state = State::MqttOpenConnection;
+                        continue;
+                    }
+                    if !publish_retained(
+                        &mut client,
+                        &status_topic,
+                        &presence::online_payload(Bearer::Lte),
+                    )
+                    .await
+                    {
+                        warn!("Failed to publish online status");
+                    }
+                    continue;
+                }
+
                 info!("Quectel: Publishing MQTT Data");
-                if handle_publish_mqtt_data(&mut client, MQTT_CLIENT_ID).await {
-                    info!("MQTT data published successfully");
-                    // Transition to next state or maintain publishing state
-                    state = State::MqttPublishData;
-                } else {
-                    error!("MQTT publish failed");
+                let mut connected = true;
+                let drain = || {
+                    connected = drain_urcs(&mut urcs, rpc_inbox, shadow, uplink);
+                    connected
+                };
+                if !handle_publish_uplink_messages(&mut client, uplink, drain).await {
+                    error!("MQTT publish of queued messages failed");
+                }
+                if !connected {
+                    failures = failures.saturating_add(1);
+                    state = State::MqttOpenConnection;
+                    continue;
+                }
+                // The GNSS speed feeds the vehicle state, keep reading it while awake
+                if trip_due || !policy.allow_sleep {
+                    let fix = retrieve_gnss_fix(&mut client, vehicle, clock).await;
+                    if let (true, Some(fix)) = (trip_due, fix) {
+                        if handle_publish_mqtt_data(&mut client, MQTT_CLIENT_ID, &fix).await {
+                            info!("MQTT data published successfully");
+                            last_trip = Some(Instant::now());
+                        } else {
+                            error!("MQTT publish failed");
+                        }
+                    }
                 }
             }
             State::ErrorConnection => {
                 error!("System in error state - attempting recovery");
+                failures = failures.saturating_add(1);
                 embassy_time::Timer::after(embassy_time::Duration::from_secs(5)).await;
                 state = State::ResetHardware;
             }
@@ -695,7 +1103,7 @@ pub async fn quectel_tx_handler(
 
 #[embassy_executor::task]
 pub async fn quectel_rx_handler(
-    mut ingress: Ingress<'static, DefaultDigester<Urc>, Urc, 1024, 128, 3>,
+    mut ingress: Ingress<'static, DefaultDigester<Urc>, Urc, 1024, 8, 3>,
     mut reader: UartRx<'static, Async>,
 ) -> ! {
     ingress.read_from(&mut reader).await
@@ -1,7 +1,16 @@
 // src/task/mod.rs
 pub mod can;
+pub mod canlink;
+pub mod ev;
 pub mod lte;
 pub mod mqtt;
 #[cfg(feature = "ota")]
 pub mod ota;
+#[cfg(feature = "spill")]
+pub mod outbox;
+pub mod rpc;
+pub mod shadow;
+pub mod sniff;
+pub mod sntp;
+pub mod vehicle;
 pub mod wifi;
@@ -1,62 +1,189 @@
-use embassy_net::{
-    tcp::{ConnectError, TcpSocket},
-    Ipv4Address, Stack,
-};
-use embassy_time::{Duration, Timer};
+use embassy_futures::select::{select, Either};
+use embassy_net::{tcp::TcpSocket, IpAddress, IpEndpoint, Stack};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use embedded_io_async::{Read, Write};
 use esp_hal::peripherals::{RSA, SHA};
 use esp_mbedtls::{asynch::Session, Certificates, Mode, Tls, TlsVersion, X509};
 use esp_println::println;
-use log::{error, info};
+use log::{error, info, warn};
 
-use crate::svc::{dns::DnsBuilder, mqtt::MqttClient};
+use crate::svc::clock::ClockCell;
+use crate::svc::cloud::Profile;
+use crate::svc::config::{report_policy, ConfigCell};
+use crate::svc::credentials::probe::{Presented, Probe, ProbeError};
+use crate::svc::credentials::{staging, CredentialError, Credentials, RotationLink};
+use crate::svc::mem::nvm::Nvm;
+use crate::svc::mqtt::{
+    inflight::InFlightWindow, packet::ProtocolVersion, ConnectRefused, MqttClient, MqttClientError,
+    PublishOptions,
+};
+use crate::svc::payload::{
+    self,
+    message::{CanBatch, Event, Health},
+};
+use crate::svc::presence::{self, Bearer};
+use crate::svc::provision::{
+    self, csr, identity::Identity, ProvisionError, ProvisioningHandler, Reply,
+};
+use crate::svc::rpc::{self, RpcInbox, RpcRequestHandler};
+use crate::svc::shadow::{ShadowDesiredHandler, ShadowLink};
+use crate::svc::sniff::{LiveSessionCell, SniffCommandHandler};
+use crate::svc::sparkplug::{self, EdgeNode, MessageKind, RebirthHandler};
+use crate::svc::supervisor::{ConnectionSupervisor, Escalation, Stage, WifiResetSignal};
+use crate::svc::topic::{schema, topic, topic_with, MessageType, TopicParams};
+use crate::svc::uplink::{outbox::TopicPolicy, Priority, Uplink, UplinkMessage};
+use crate::svc::{dns::resolver::Resolver, ev::BatteryCell, vehicle::VehicleCell};
 
-use crate::cfg::net_cfg::*;
+use crate::cfg::mqtt_cfg::{
+    CREDENTIALS_RETRY, DELIVERY_TIMEOUT, MQTT_SESSION_EXPIRY_SECS, RECONNECT_BACKOFF_BASE,
+};
+use crate::cfg::net_cfg::MQTT_CLIENT_ID;
+use crate::cfg::provision_cfg::{
+    FLEET_PROVISIONING, PROVISIONING_RETRY, PROVISIONING_TEMPLATE, PROVISIONING_TIMEOUT,
+};
+use crate::cfg::sparkplug_cfg::{SPARKPLUG_ENABLED, SPARKPLUG_SCAN_INTERVAL};
+use crate::cfg::topic_cfg::CAN_BUS_NAME;
+use crate::cfg::trust_cfg::PROBE_TIMEOUT;
 use crate::task::can::TwaiOutbox;
+use crate::util::{base64, hex};
 
 #[embassy_executor::task]
 pub async fn mqtt_handler(
     stack: &'static Stack<'static>,
     channel: &'static TwaiOutbox,
+    uplink: &'static Uplink,
+    vehicle: &'static VehicleCell,
+    battery: &'static BatteryCell,
+    live_session: &'static LiveSessionCell,
+    rpc_inbox: &'static RpcInbox,
+    shadow: &'static ShadowLink,
+    config: &'static ConfigCell,
+    clock: &'static ClockCell,
+    rotation: &'static RotationLink,
+    wifi_reset: &'static WifiResetSignal,
+    key_seed: [u8; 32],
+    dns_seed: [u8; 32],
     mut sha: SHA,
     mut rsa: RSA,
 ) {
+    let profile = match Profile::from_config() {
+        Ok(profile) => profile,
+        Err(e) => {
+            error!("Invalid cloud profile, Wi-Fi uplink disabled: {e:?}");
+            return;
+        }
+    };
+    if let Some(alpn) = profile.alpn {
+        // esp-mbedtls has no way to offer ALPN protocols yet, the broker would
+        // refuse the connection on this port
+        error!("ALPN {alpn} not supported by the TLS session, Wi-Fi uplink disabled");
+        return;
+    }
+    info!("Cloud profile: {profile:?}");
     let mut rx_buffer = [0; 4096];
     let mut tx_buffer = [0; 4096];
     let tls = Tls::new(&mut sha).unwrap().with_hardware_rsa(&mut rsa);
-
-    //wait until wifi connected
-    loop {
-        if stack.is_link_up() {
-            break;
+    let sniff_handler = SniffCommandHandler::new(live_session);
+    let rpc_handler = RpcRequestHandler::new(rpc_inbox, uplink);
+    let rpc_filter = rpc::request_filter();
+    let desired_handler = ShadowDesiredHandler::new(shadow);
+    let desired_topic = topic(MessageType::ShadowDesired);
+    // Survives reconnections, unacknowledged messages are sent again
+    let mut inflight = InFlightWindow::new();
+    let status_topic = presence::status_topic(Bearer::Wifi);
+    let schema_topic = topic(MessageType::Schema);
+    let schema_manifest = schema::manifest_payload();
+    let sniff_topic = topic(MessageType::SniffCommand);
+    let can_topic = topic_with(
+        MessageType::Can,
+        &TopicParams {
+            bus: Some(CAN_BUS_NAME),
+            ..Default::default()
+        },
+    );
+    let can_format = payload::format_for(MessageType::Can);
+    let health_topic = topic(MessageType::Health);
+    let health_format = payload::format_for(MessageType::Health);
+    // Sparkplug B edge node, see `cfg::sparkplug_cfg`
+    let mut edge_node = EdgeNode::new();
+    let rebirth = RebirthHandler::new();
+    let ncmd_topic = sparkplug::topic(MessageKind::NCmd);
+    let ndeath_topic = sparkplug::topic(MessageKind::NDeath);
+    let mut death = [0u8; 64];
+    // TLS credentials, see `svc::credentials`
+    let mut nvm = Nvm::init();
+    let mut credentials = Credentials::new();
+    if staging::state(&mut nvm) == Some(staging::State::Committing) {
+        // Accepted by the broker before a restart
+        match credentials.load(&mut nvm, true, None) {
+            Ok(()) => commit(&credentials, &mut nvm, rotation),
+            Err(e) => {
+                error!("Rotated credentials unreadable, dropped: {e:?}");
+                let _ = staging::clear(&mut nvm);
+            }
         }
-        Timer::after(Duration::from_millis(500)).await;
     }
+    let provisioning_handler = ProvisioningHandler::new();
+    // Downgraded for good once the broker refuses MQTT 5
+    let mut protocol = profile.protocol;
 
-    println!("Waiting to get IP address...");
+    // Keeps the broker's address across reconnections, see `svc::dns`
+    let mut resolver = Resolver::new(dns_seed);
+    let mut supervisor = ConnectionSupervisor::new();
+    // Set by the supervisor after a failed attempt
+    let mut retry_in = Duration::from_ticks(0);
     loop {
-        if let Some(config) = stack.config_v4() {
-            println!("Got IP: {}", config.address); //dhcp IP address
-            break;
-        }
-        Timer::after(Duration::from_millis(500)).await;
-    }
-    loop {
-        Timer::after(Duration::from_millis(1_000)).await;
+        Timer::after(retry_in).await;
+        retry_in = RECONNECT_BACKOFF_BASE;
+        wait_for_network(stack).await;
 
-        let remote_endpoint = if let Ok(endpoint) = dns_query(stack).await {
-            endpoint
-        } else {
+        // A new SAS token on every connection
+        let now = clock.lock(|c| c.borrow().now_secs());
+        let password = match profile.password(now) {
+            Ok(password) => password,
+            Err(e) => {
+                warn!("No MQTT credentials yet: {e:?}");
+                retry_in = Duration::from_secs(10);
+                continue;
+            }
+        };
+        // Staged credentials stand in for the current ones until the broker
+        // accepts or refuses them
+        let trial = staging::state(&mut nvm) == Some(staging::State::Staged);
+        rotation.take_staged();
+        if let Err(e) = credentials.load(&mut nvm, trial, now) {
+            if trial {
+                error!("Staged credentials unusable: {e:?}");
+                retry_in = roll_back(&mut nvm, rotation, e);
+            } else {
+                error!("No usable TLS credentials, not connecting: {e:?}");
+                retry_in = CREDENTIALS_RETRY;
+            }
             continue;
+        }
+        let remote_endpoint = match resolver.resolve(*stack, profile.host()).await {
+            Ok(addr) => IpEndpoint::new(IpAddress::Ipv4(addr), profile.port),
+            Err(e) => {
+                error!("Failed to resolve the broker: {e:?}");
+                retry_in = on_failure(&mut supervisor, Stage::Dns, wifi_reset);
+                continue;
+            }
         };
         println!("Establish TCP connection to broker {:?}", remote_endpoint);
         let mut socket = TcpSocket::new(*stack, &mut rx_buffer, &mut tx_buffer);
-        socket.connect(remote_endpoint).await.unwrap();
+        if let Err(e) = socket.connect(remote_endpoint).await {
+            error!("TCP connection to {remote_endpoint:?} failed: {e:?}");
+            // The broker may have moved, resolve it again
+            resolver.forget(profile.host());
+            retry_in = on_failure(&mut supervisor, Stage::Tcp, wifi_reset);
+            continue;
+        }
+        // The factory certificate doubles as claim certificate
+        let provisioning = FLEET_PROVISIONING && !credentials.is_identity() && !trial;
         let certificates = Certificates {
-            ca_chain: X509::pem(concat!(include_str!("../../cert/crt.pem"), "\0").as_bytes()).ok(),
-            certificate: X509::pem(concat!(include_str!("../../cert/dvt.crt"), "\0").as_bytes())
-                .ok(),
-            private_key: X509::pem(concat!(include_str!("../../cert/dvt.key"), "\0").as_bytes())
-                .ok(),
+            ca_chain: X509::pem(credentials.ca_chain()).ok(),
+            certificate: X509::pem(credentials.certificate()).ok(),
+            private_key: X509::pem(credentials.private_key()).ok(),
             password: None,
         };
 
@@ -64,91 +191,623 @@ pub async fn mqtt_handler(
         let session = Session::new(
             socket,
             Mode::Client {
-                servername: MQTT_CSTR_SERVER_NAME,
+                servername: profile.server_name(),
             },
             TlsVersion::Tls1_3,
             certificates,
             tls.reference(),
-        )
-        .unwrap();
-        println!("Establishing MQTT client connection ...");
-        let mut mqtt_client = MqttClient::new(MQTT_CLIENT_ID, session);
-        mqtt_client
-            .connect(
+        );
+        let mut session = match session {
+            Ok(session) => session,
+            Err(e) => {
+                error!("Failed to set up the TLS session: {e:?}");
+                retry_in = if credentials.is_trial() {
+                    roll_back(&mut nvm, rotation, CredentialError::Rejected)
+                } else {
+                    on_failure(&mut supervisor, Stage::Tls, wifi_reset)
+                };
+                continue;
+            }
+        };
+        if let Err(e) = session.connect().await {
+            error!("TLS handshake with {remote_endpoint:?} failed: {e:?}");
+            // The buffers go to the probe of the broker's certificate
+            drop(session);
+            report_untrusted(
+                stack,
                 remote_endpoint,
-                60,
-                Some(MQTT_USR_NAME),
-                Some(&MQTT_USR_PASS),
+                profile.server_name(),
+                &mut rx_buffer,
+                &mut tx_buffer,
+                uplink,
             )
+            .await;
+            retry_in = if credentials.is_trial() {
+                roll_back(&mut nvm, rotation, CredentialError::Rejected)
+            } else {
+                on_failure(&mut supervisor, Stage::Tls, wifi_reset)
+            };
+            continue;
+        }
+        println!("Establishing MQTT client connection ...");
+        let death_len = if SPARKPLUG_ENABLED && !provisioning {
+            edge_node.begin_session();
+            edge_node.death(&mut death).unwrap_or(0)
+        } else {
+            0
+        };
+        let mut mqtt_client = MqttClient::new(profile.client_id(), session, &mut inflight);
+        // Keeps the sniff subscription and QoS 1 messages across outages
+        mqtt_client.set_clean_session(provisioning);
+        mqtt_client.set_session_expiry(MQTT_SESSION_EXPIRY_SECS);
+        mqtt_client.set_protocol(protocol);
+        if provisioning {
+            // Not online as this device yet, no will to announce
+        } else if SPARKPLUG_ENABLED {
+            // The host pairs it with the NBIRTH of this connection by bdSeq
+            mqtt_client.set_last_will(
+                &ndeath_topic,
+                &death[..death_len],
+                mqttrust::QoS::AtLeastOnce,
+                false,
+            );
+        } else {
+            mqtt_client.set_last_will(
+                &status_topic,
+                presence::WILL_PAYLOAD.as_bytes(),
+                mqttrust::QoS::AtLeastOnce,
+                true,
+            );
+        }
+        if let Err(e) = mqtt_client
+            .connect(60, profile.username(), password.as_deref())
             .await
-            .unwrap();
-        println!("Establishing MQTT client connection OK");
-        loop {
-            if let Ok(frame) = channel.try_receive() {
-                use core::fmt::Write;
-                let mut frame_str: heapless::String<80> = heapless::String::new();
-                let mut mqtt_topic: heapless::String<80> = heapless::String::new();
-                writeln!(
-                    &mut frame_str,
-                    "{{\"id\": \"{:08X}\", \"len\": {}, \"data\": \"{:02X?}\"}}",
-                    frame.id, frame.len, frame.data
-                )
-                .unwrap();
-                writeln!(
-                    &mut mqtt_topic,
-                    "channels/{MQTT_CLIENT_ID}/messages/client/can"
-                )
-                .unwrap();
-                if let Err(e) = mqtt_client
-                    .publish(&mqtt_topic, frame_str.as_bytes(), mqttrust::QoS::AtMostOnce)
+        {
+            error!("MQTT connection failed: {e:?}");
+            let refused_version = matches!(
+                e,
+                MqttClientError::ConnectionRefused(ConnectRefused::UnacceptableProtocolVersion)
+            );
+            if refused_version && protocol == ProtocolVersion::V5 {
+                info!("MQTT 5 not supported by the broker, using MQTT 3.1.1");
+                protocol = ProtocolVersion::V311;
+            }
+            mqtt_client.disconnect().await;
+            let _ = mqtt_client.into_transport().close().await;
+            // Refused for anything but the protocol version, down to the staged credentials
+            let refused = matches!(e, MqttClientError::ConnectionRefused(_));
+            retry_in = if credentials.is_trial() && refused && !refused_version {
+                roll_back(&mut nvm, rotation, CredentialError::Rejected)
+            } else {
+                on_failure(&mut supervisor, Stage::Mqtt, wifi_reset)
+            };
+            continue;
+        }
+        supervisor.on_connected();
+        if credentials.is_trial() {
+            commit(&credentials, &mut nvm, rotation);
+        }
+        if provisioning {
+            info!("Connected with the claim certificate, provisioning the device");
+            let res = provision(&mut mqtt_client, &provisioning_handler, &key_seed, &mut nvm).await;
+            mqtt_client.disconnect().await;
+            let _ = mqtt_client.into_transport().close().await;
+            match res {
+                Ok(()) => {
+                    info!("Device certificate provisioned");
+                    // The LTE uplink takes it as well
+                    rotation.report(Ok(()));
+                    retry_in = Duration::from_ticks(0);
+                }
+                Err(e) => {
+                    error!("Fleet provisioning failed: {e:?}");
+                    retry_in = PROVISIONING_RETRY;
+                }
+            }
+            continue;
+        }
+        let failures = supervisor.total_failures();
+        // Connect again before the broker drops us with expired credentials
+        let renew_at = profile.renew_after().map(|after| Instant::now() + after);
+        println!(
+            "Establishing MQTT client connection OK ({:?})",
+            mqtt_client.protocol()
+        );
+        let online = presence::online_payload(Bearer::Wifi);
+        if let Err(e) = mqtt_client
+            .publish_retained(&status_topic, online.as_bytes(), mqttrust::QoS::AtLeastOnce)
+            .await
+        {
+            error!("Failed to publish online status: {e:?}");
+        }
+        if let Err(e) = mqtt_client
+            .publish_retained(
+                &schema_topic,
+                schema_manifest.as_bytes(),
+                mqttrust::QoS::AtLeastOnce,
+            )
+            .await
+        {
+            error!("Failed to publish the schema manifest: {e:?}");
+        }
+        let health = Health {
+            uptime_s: Instant::now().as_secs(),
+            firmware: presence::FIRMWARE_VERSION,
+            bearer: Bearer::Wifi.as_str(),
+            queued: (uplink.outbox_len() + uplink.spilled()) as u32,
+            failures,
+        };
+        // Goes through the outbox like the telemetry, see its topic policy
+        match UplinkMessage::encode(&health_topic, health_format, &health) {
+            Ok(msg) => {
+                if !uplink.try_send(Priority::Normal, msg) {
+                    warn!("Outbox full, health report dropped");
+                }
+            }
+            Err(e) => error!("Failed to encode the health report: {e:?}"),
+        }
+        if profile.custom_topics() {
+            if let Err(e) = mqtt_client
+                .subscribe(&sniff_topic, mqttrust::QoS::AtLeastOnce, &sniff_handler)
+                .await
+            {
+                error!("Failed to subscribe to live session commands: {e:?}");
+            }
+            if let Err(e) = mqtt_client
+                .subscribe(&rpc_filter, mqttrust::QoS::AtLeastOnce, &rpc_handler)
+                .await
+            {
+                error!("Failed to subscribe to RPC requests: {e:?}");
+            }
+            // The retained desired document follows, see `svc::shadow`
+            match mqtt_client
+                .subscribe(&desired_topic, mqttrust::QoS::AtLeastOnce, &desired_handler)
+                .await
+            {
+                Ok(()) => shadow.resync(),
+                Err(e) => error!("Failed to subscribe to the desired shadow: {e:?}"),
+            }
+        } else {
+            shadow.resync();
+        }
+        let mut last_scan = Instant::now();
+        if SPARKPLUG_ENABLED {
+            // Commands first, a rebirth request must not be missed after NBIRTH
+            if let Err(e) = mqtt_client
+                .subscribe(&ncmd_topic, mqttrust::QoS::AtMostOnce, &rebirth)
+                .await
+            {
+                error!("Failed to subscribe to Sparkplug node commands: {e:?}");
+            }
+            rebirth.take();
+            let values = sparkplug::sample(vehicle, battery);
+            if let Err(e) = publish_births(&mut mqtt_client, &mut edge_node, &values).await {
+                error!("Failed to publish the Sparkplug births: {e:?}");
+            }
+        }
+        let mut last_telemetry: Option<Instant> = None;
+        'connected: loop {
+            if renew_at.is_some_and(|at| Instant::now() >= at) {
+                info!("MQTT credentials about to expire, connecting again");
+                mqtt_client.disconnect().await;
+                retry_in = Duration::from_ticks(0);
+                break 'connected;
+            }
+            if rotation.take_staged() {
+                info!("Credentials staged, connecting again to try them");
+                mqtt_client.disconnect().await;
+                retry_in = Duration::from_ticks(0);
+                break 'connected;
+            }
+            // Queued messages first, live session traffic ahead of everything else
+            while let Some((priority, msg)) = uplink.try_next() {
+                // Events must reach the broker, live traffic favours latency
+                let qos = match priority {
+                    Priority::Live => mqttrust::QoS::AtMostOnce,
+                    Priority::Normal => mqttrust::QoS::AtLeastOnce,
+                };
+                // A message the outbox would have dropped is not worth delivering late
+                let options = PublishOptions {
+                    message_expiry_secs: TopicPolicy::for_topic(&msg.topic)
+                        .ttl
+                        .map(|ttl| ttl.as_secs() as u32),
+                    content_type: Some(msg.format.content_type()),
+                    ..Default::default()
+                };
+                let delivery = match mqtt_client
+                    .publish_with_options(&msg.topic, &msg.payload, qos, &options)
                     .await
                 {
-                    error!("Failed to publish MQTT packet: {e:?}");
-                    break;
+                    Ok(delivery) => delivery,
+                    Err(e) => {
+                        error!("Failed to publish MQTT packet: {e:?}");
+                        // Queued again for whichever uplink connects first
+                        if !e.is_message_error() {
+                            uplink.retry(priority, msg);
+                        }
+                        break 'connected;
+                    }
+                };
+                // The outbox lets go of a message once the broker has it
+                match mqtt_client.wait_delivery(delivery, DELIVERY_TIMEOUT).await {
+                    Ok(()) => {}
+                    Err(e) if e.is_message_error() => {
+                        warn!("Broker refused the message on {}: {e:?}", msg.topic);
+                    }
+                    Err(e) => {
+                        error!("Message on {} not acknowledged: {e:?}", msg.topic);
+                        // The outbox sends it again, not the in-flight window
+                        mqtt_client.abandon(delivery);
+                        uplink.retry(priority, msg);
+                        break 'connected;
+                    }
+                }
+            }
+            // Regular telemetry backs off while a live session is streaming and
+            // follows the reporting interval of the vehicle state or the configured one
+            let interval = report_policy(vehicle, config).telemetry_interval;
+            let due = last_telemetry.is_none_or(|at| at.elapsed() >= interval);
+            if due && !uplink.live_pending() {
+                // Only the most recent frame the configuration selects is worth reporting
+                let mut latest = None;
+                while let Ok(frame) = channel.try_receive() {
+                    if config.lock(|c| c.borrow().telemetry_accepts(frame.id, frame.extended)) {
+                        latest = Some(frame);
+                    }
+                }
+                if let Some(frame) = latest {
+                    last_telemetry = Some(Instant::now());
+                    let batch = CanBatch {
+                        bus: CAN_BUS_NAME,
+                        frames: core::slice::from_ref(&frame),
+                    };
+                    let mut encoded = [0u8; 128];
+                    match payload::encode(can_format, &batch, &mut encoded) {
+                        Ok(len) => {
+                            let options = PublishOptions {
+                                content_type: Some(can_format.content_type()),
+                                ..Default::default()
+                            };
+                            if let Err(e) = mqtt_client
+                                .publish_with_options(
+                                    &can_topic,
+                                    &encoded[..len],
+                                    mqttrust::QoS::AtMostOnce,
+                                    &options,
+                                )
+                                .await
+                            {
+                                error!("Failed to publish MQTT packet: {e:?}");
+                                break;
+                            }
+                            info!("MQTT sent OK");
+                        }
+                        Err(e) => error!("Failed to encode the CAN frame: {e:?}"),
+                    }
+                }
+            }
+            if SPARKPLUG_ENABLED {
+                let res = if rebirth.take() {
+                    let values = sparkplug::sample(vehicle, battery);
+                    publish_births(&mut mqtt_client, &mut edge_node, &values).await
+                } else if last_scan.elapsed() >= SPARKPLUG_SCAN_INTERVAL {
+                    last_scan = Instant::now();
+                    let values = sparkplug::sample(vehicle, battery);
+                    publish_device_data(&mut mqtt_client, &mut edge_node, &values).await
+                } else {
+                    Ok(())
+                };
+                if let Err(e) = res {
+                    error!("Sparkplug publish failed: {e:?}");
+                    break 'connected;
                 }
-                println!("{frame_str}");
-                info!("MQTT sent OK");
             }
-            mqtt_client.poll().await;
-            Timer::after_secs(1).await;
+            if let Err(e) = mqtt_client.poll().await {
+                error!("MQTT connection lost: {e:?}");
+                break 'connected;
+            }
+            // Wait for queued messages or for the broker, only the read is raced,
+            // the acknowledgements are written once it has completed
+            let read = with_timeout(Duration::from_secs(1), mqtt_client.read_packets());
+            let Either::Second(Ok(read)) = select(uplink.wait(), read).await else {
+                continue;
+            };
+            let handled = match read {
+                Ok(()) => mqtt_client.handle_packets().await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = handled {
+                error!("MQTT connection lost: {e:?}");
+                break 'connected;
+            }
         }
     }
 }
 
-pub async fn dns_query(
-    stack: &'static Stack<'static>,
-) -> Result<embassy_net::IpEndpoint, ConnectError> {
-    let mut rx_buffer = [0; 4096];
-    let mut tx_buffer = [0; 4096];
-    let mut socket = TcpSocket::new(*stack, &mut rx_buffer, &mut tx_buffer);
-    socket.set_timeout(Some(embassy_time::Duration::from_secs(10)));
-    let mut buffer = [0; 512];
-    let dns_ip = Ipv4Address::new(8, 8, 8, 8);
-    let remote_endpoint = embassy_net::IpEndpoint {
-        addr: embassy_net::IpAddress::Ipv4(dns_ip),
-        port: 53,
+/// Make the credentials of the trial the current ones
+fn commit(credentials: &Credentials, nvm: &mut Nvm, rotation: &RotationLink) {
+    match credentials.commit(nvm) {
+        Ok(()) => {
+            info!("Rotated credentials committed");
+            rotation.report(Ok(()));
+        }
+        Err(e) => {
+            error!("Failed to commit the rotated credentials: {e:?}");
+            let _ = staging::clear(nvm);
+            rotation.report(Err(e));
+        }
+    }
+}
+
+/// Drop the staged credentials after they failed with `reason`, returns how
+/// long to wait before connecting with the current ones
+fn roll_back(nvm: &mut Nvm, rotation: &RotationLink, reason: CredentialError) -> Duration {
+    warn!("Staged credentials failed, rolling back");
+    if let Err(e) = staging::clear(nvm) {
+        error!("Failed to drop the staged credentials: {e:?}");
+    }
+    rotation.report(Err(reason));
+    Duration::from_ticks(0)
+}
+
+/// Log and report the certificate the broker presented after a failed
+/// handshake, as a `tls_untrusted` event with its SHA-256 fingerprint
+async fn report_untrusted(
+    stack: &Stack<'_>,
+    remote_endpoint: IpEndpoint,
+    server_name: &str,
+    rx_buffer: &mut [u8],
+    tx_buffer: &mut [u8],
+    uplink: &Uplink,
+) {
+    let probe = probe_certificate(stack, remote_endpoint, server_name, rx_buffer, tx_buffer);
+    let presented = match with_timeout(PROBE_TIMEOUT, probe).await {
+        Ok(Ok(presented)) => presented,
+        Ok(Err(e)) => {
+            warn!("Could not get the broker's certificate: {e:?}");
+            return;
+        }
+        Err(_) => {
+            warn!("Timed out getting the broker's certificate");
+            return;
+        }
     };
-    socket.connect(remote_endpoint).await?;
-    let dns_builder = DnsBuilder::build(MQTT_SERVER_NAME);
-    socket.write(&dns_builder.query_data()).await.unwrap();
-
-    let size = socket.read(&mut buffer).await.unwrap();
-    let broker_ip = if size > 2 {
-        if let Ok(ips) = DnsBuilder::parse_dns_response(&buffer[2..size]) {
-            info!("broker IP: {}.{}.{}.{}", ips[0], ips[1], ips[2], ips[3]);
-            ips
-        } else {
-            return Err(ConnectError::NoRoute);
+    let mut fingerprint = heapless::String::<64>::new();
+    let _ = hex::encode(&mut fingerprint, &presented.fingerprint);
+    let mut pin = heapless::String::<44>::new();
+    if let Some(key) = presented.pin {
+        let _ = base64::encode(&mut pin, &key);
+    }
+    warn!("Broker presented certificate SHA-256 {fingerprint}, key pin-sha256 \"{pin}\"");
+    let event = Event {
+        event: "tls_untrusted",
+        from: server_name,
+        to: &fingerprint,
+        uptime_ms: Instant::now().as_millis(),
+    };
+    let format = payload::format_for(MessageType::Event);
+    match UplinkMessage::encode(&topic(MessageType::Event), format, &event) {
+        Ok(msg) => {
+            if !uplink.try_send(Priority::Normal, msg) {
+                warn!("Outbox full, untrusted certificate event dropped");
+            }
+        }
+        Err(e) => warn!("Failed to encode the untrusted certificate event: {e:?}"),
+    }
+}
+
+/// Send a TLS 1.2 `ClientHello` and read the broker's certificate off the
+/// handshake
+async fn probe_certificate(
+    stack: &Stack<'_>,
+    remote_endpoint: IpEndpoint,
+    server_name: &str,
+    rx_buffer: &mut [u8],
+    tx_buffer: &mut [u8],
+) -> Result<Presented, ProbeError> {
+    let hello = Probe::client_hello(server_name)?;
+    let mut socket = TcpSocket::new(*stack, rx_buffer, tx_buffer);
+    socket
+        .connect(remote_endpoint)
+        .await
+        .map_err(|_| ProbeError::Closed)?;
+    socket
+        .write_all(&hello)
+        .await
+        .map_err(|_| ProbeError::Closed)?;
+    let mut probe = Probe::new();
+    let mut buffer = [0; 512];
+    let presented = loop {
+        match socket.read(&mut buffer).await {
+            Ok(0) | Err(_) => break Err(ProbeError::Closed),
+            Ok(len) => match probe.feed(&buffer[..len]) {
+                Ok(Some(presented)) => break Ok(presented),
+                Ok(None) => {}
+                Err(e) => break Err(e),
+            },
         }
-    } else {
-        return Err(ConnectError::NoRoute);
     };
+    // Nothing to say past the certificate
+    socket.abort();
+    presented
+}
+
+/// Get a certificate for a key made from `seed`, register the device and store both
+async fn provision<'a, T: Read + Write>(
+    client: &mut MqttClient<'a, T>,
+    handler: &'a ProvisioningHandler,
+    seed: &[u8; 32],
+    nvm: &mut Nvm,
+) -> Result<(), ProvisionError> {
+    let key = crate::util::p256::SecretKey::from_seed(seed);
+    let request = provision::csr_request(&csr::build(&key, MQTT_CLIENT_ID)?)?;
+    for filter in [
+        provision::create_from_csr_filter(),
+        provision::register_filter(PROVISIONING_TEMPLATE),
+    ] {
+        client
+            .subscribe(&filter, mqttrust::QoS::AtLeastOnce, handler)
+            .await
+            .map_err(|_| ProvisionError::Connection)?;
+    }
+    handler.take();
 
-    let broker_ipv4 = Ipv4Address::new(broker_ip[0], broker_ip[1], broker_ip[2], broker_ip[3]);
+    client
+        .publish(
+            provision::CREATE_FROM_CSR_TOPIC,
+            request.as_bytes(),
+            mqttrust::QoS::AtLeastOnce,
+        )
+        .await
+        .map_err(|_| ProvisionError::Connection)?;
+    let reply = wait_reply(client, handler).await?;
+    let created = provision::parse_created(&reply.payload)?;
 
-    let remote_endpoint = embassy_net::IpEndpoint {
-        addr: embassy_net::IpAddress::Ipv4(broker_ipv4),
-        port: MQTT_SERVER_PORT,
-    };
-    Ok(remote_endpoint)
+    let request = provision::register_request(&created.token, MQTT_CLIENT_ID)?;
+    client
+        .publish(
+            &provision::register_topic(PROVISIONING_TEMPLATE),
+            request.as_bytes(),
+            mqttrust::QoS::AtLeastOnce,
+        )
+        .await
+        .map_err(|_| ProvisionError::Connection)?;
+    let reply = wait_reply(client, handler).await?;
+    let thing_name = provision::parse_registered(&reply.payload)?;
+    if thing_name != MQTT_CLIENT_ID {
+        warn!("Registered as thing {thing_name}, not as the client ID {MQTT_CLIENT_ID}");
+    }
+
+    let mut key_pem = heapless::String::<320>::new();
+    crate::util::base64::encode_pem(&mut key_pem, "EC PRIVATE KEY", &csr::private_key(&key)?)
+        .map_err(|_| csr::CsrError::Overflow)?;
+    let mut identity = Identity::new();
+    identity.set(&created.certificate, key_pem.as_bytes())?;
+    identity.store(nvm)?;
+    Ok(())
+}
+
+/// Next answer on the provisioning topics, a rejection is an error
+async fn wait_reply<T: Read + Write>(
+    client: &mut MqttClient<'_, T>,
+    handler: &ProvisioningHandler,
+) -> Result<Reply, ProvisionError> {
+    let deadline = Instant::now() + PROVISIONING_TIMEOUT;
+    loop {
+        if let Some(reply) = handler.take() {
+            if !reply.accepted {
+                error!(
+                    "Provisioning request rejected: {}",
+                    provision::rejection_reason(&reply.payload)
+                );
+                return Err(ProvisionError::Rejected);
+            }
+            return Ok(reply);
+        }
+        if Instant::now() >= deadline {
+            return Err(ProvisionError::Timeout);
+        }
+        client
+            .receive(Duration::from_secs(1))
+            .await
+            .map_err(|_| ProvisionError::Connection)?;
+    }
+}
+
+/// NBIRTH then DBIRTH, after connecting and when the host asks for a rebirth
+async fn publish_births<T: Read + Write>(
+    client: &mut MqttClient<'_, T>,
+    node: &mut EdgeNode,
+    values: &sparkplug::DeviceValues,
+) -> Result<(), MqttClientError> {
+    let mut payload = [0u8; sparkplug::PAYLOAD_LEN];
+    match node.node_birth(&mut payload) {
+        Ok(len) => {
+            let topic = sparkplug::topic(MessageKind::NBirth);
+            client
+                .publish(&topic, &payload[..len], mqttrust::QoS::AtMostOnce)
+                .await?;
+        }
+        Err(e) => error!("Failed to encode NBIRTH: {e:?}"),
+    }
+    match node.device_birth(values, &mut payload) {
+        Ok(len) => {
+            let topic = sparkplug::topic(MessageKind::DBirth);
+            client
+                .publish(&topic, &payload[..len], mqttrust::QoS::AtMostOnce)
+                .await?;
+        }
+        Err(e) => error!("Failed to encode DBIRTH: {e:?}"),
+    }
+    info!("Sparkplug node born, bdSeq {}", node.bd_seq());
+    Ok(())
+}
+
+/// DDATA with the metrics that changed since the last report
+async fn publish_device_data<T: Read + Write>(
+    client: &mut MqttClient<'_, T>,
+    node: &mut EdgeNode,
+    values: &sparkplug::DeviceValues,
+) -> Result<(), MqttClientError> {
+    let mut payload = [0u8; sparkplug::PAYLOAD_LEN];
+    match node.device_data(values, &mut payload) {
+        Ok(Some(len)) => {
+            let topic = sparkplug::topic(MessageKind::DData);
+            client
+                .publish(&topic, &payload[..len], mqttrust::QoS::AtMostOnce)
+                .await?;
+        }
+        Ok(None) => {}
+        Err(e) => error!("Failed to encode DDATA: {e:?}"),
+    }
+    Ok(())
+}
+
+/// Wait for the Wi-Fi link and a DHCP lease
+async fn wait_for_network(stack: &Stack<'_>) {
+    while !stack.is_link_up() {
+        Timer::after(Duration::from_millis(500)).await;
+    }
+    if stack.config_v4().is_none() {
+        println!("Waiting to get IP address...");
+        loop {
+            if let Some(config) = stack.config_v4() {
+                println!("Got IP: {}", config.address); //dhcp IP address
+                break;
+            }
+            Timer::after(Duration::from_millis(500)).await;
+        }
+    }
+}
+
+/// Record a failed connection attempt and escalate when the supervisor says so,
+/// returns how long to wait before the next attempt
+fn on_failure(
+    supervisor: &mut ConnectionSupervisor,
+    stage: Stage,
+    wifi_reset: &WifiResetSignal,
+) -> Duration {
+    let retry = supervisor.on_failure(stage);
+    match retry.escalation {
+        Some(Escalation::ResetWifi) => {
+            warn!("Repeated {:?} failures, resetting the Wi-Fi link", stage);
+            wifi_reset.signal(());
+        }
+        Some(Escalation::SwitchBearer) => {
+            warn!(
+                "Repeated {:?} failures, leaving the uplink to LTE for {} s",
+                stage,
+                retry.delay.as_secs()
+            );
+        }
+        None => {
+            info!(
+                "MQTT connection attempt failed ({:?}, {} in a row), retrying in {} ms",
+                stage,
+                supervisor.total_failures(),
+                retry.delay.as_millis()
+            );
+        }
+    }
+    retry.delay
 }
@@ -0,0 +1,43 @@
+use embassy_time::{with_timeout, Duration, Instant};
+use log::{info, warn};
+
+use crate::hal::flash::W25Q128FVSG;
+use crate::svc::uplink::spill::FlashSpill;
+use crate::svc::uplink::{Uplink, SPILL_HIGH_WATERMARK, SPILL_LOW_WATERMARK};
+
+/// Watermarks are checked at least that often
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Moves outbox messages to the external flash during long outages and reads
+/// them back once the uplinks drain the outbox
+#[embassy_executor::task]
+pub async fn outbox_spill(uplink: &'static Uplink, flash: W25Q128FVSG<'static>) -> ! {
+    let mut spill = FlashSpill::new(flash);
+    spill.init().await;
+    info!("Outbox spill to external flash enabled");
+
+    loop {
+        let _ = with_timeout(CHECK_INTERVAL, uplink.wait_spill()).await;
+
+        while uplink.outbox_len() > SPILL_HIGH_WATERMARK && !spill.is_full() {
+            let Some(entry) = uplink.take_for_spill() else {
+                break;
+            };
+            spill.push(&entry).await;
+        }
+        if spill.is_full() && uplink.outbox_len() > SPILL_HIGH_WATERMARK {
+            warn!("Outbox spill region full");
+        }
+
+        while uplink.outbox_len() < SPILL_LOW_WATERMARK {
+            let Some(entry) = spill.pop().await else {
+                break;
+            };
+            if entry.is_expired(Instant::now()) {
+                continue;
+            }
+            uplink.restore(entry);
+        }
+        uplink.set_spilled(spill.len());
+    }
+}
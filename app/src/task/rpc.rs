@@ -0,0 +1,322 @@
+use core::fmt::Write;
+
+use embassy_time::{with_timeout, Duration, Timer};
+use log::{info, warn};
+
+use crate::svc::can::isotp::{self, DiagLink, Progress, Reassembler};
+use crate::svc::config::ConfigCell;
+use crate::svc::credentials::trust::{self, TrustError};
+use crate::svc::credentials::{staging, CredentialError, Part, RotationLink};
+use crate::svc::mem::nvm::Nvm;
+use crate::svc::rpc::{
+    self, Call, Failure, Method, Outcome, Params, RecentCalls, Reply, RpcInbox, Status, CHUNK_LEN,
+};
+use crate::svc::sniff::{LiveSessionCell, SniffAction, SniffCommand};
+use crate::svc::uplink::{Priority, Uplink};
+use crate::task::can::{CanFrame, CanTxInbox};
+use crate::util::{base64, hex};
+
+/// Delay between the reboot response and the restart when the request does not ask for one
+const DEFAULT_REBOOT_DELAY: Duration = Duration::from_secs(2);
+const MAX_REBOOT_DELAY_MS: u32 = 60_000;
+/// ECU addressed by read_did unless the request names one, the usual OBD pair
+const DEFAULT_DIAG_TX_ID: u32 = 0x7E0;
+const DEFAULT_DIAG_RX_ID: u32 = 0x7E8;
+
+const UDS_READ_DATA_BY_ID: u8 = 0x22;
+const UDS_NEGATIVE_RESPONSE: u8 = 0x7F;
+const UDS_RESPONSE_PENDING: u8 = 0x78;
+
+/// Runs the requests received by both bearers, one at a time
+///
+/// A repeated request ID is answered from the recent calls, so the method runs
+/// once. The reboot waits for the response to go out, the recent calls do not
+/// survive it and a request repeated after the restart runs again.
+#[embassy_executor::task]
+pub async fn rpc_server(
+    inbox: &'static RpcInbox,
+    uplink: &'static Uplink,
+    config: &'static ConfigCell,
+    session: &'static LiveSessionCell,
+    can_tx: &'static CanTxInbox,
+    diag: &'static DiagLink,
+    rotation: &'static RotationLink,
+) -> ! {
+    let mut recent = RecentCalls::new();
+    let mut nvm = Nvm::init();
+    loop {
+        let request = inbox.receive().await;
+        if let Some(response) = recent.find(&request.id) {
+            info!(
+                "RPC request {} repeated, sending the same response",
+                request.id
+            );
+            uplink
+                .send(
+                    Priority::Normal,
+                    rpc::response_message(&request.id, response),
+                )
+                .await;
+            continue;
+        }
+
+        let mut reboot_after = None;
+        let outcome = match Call::parse(&request.payload) {
+            Ok(call) => {
+                info!("RPC request {}: {}", request.id, call.method.as_str());
+                let left = call
+                    .timeout
+                    .checked_sub(request.received_at.elapsed())
+                    .unwrap_or(Duration::from_ticks(0));
+                let outcome = if left == Duration::from_ticks(0) {
+                    Err(Failure::new(Status::Timeout, "expired in queue"))
+                } else {
+                    let res = with_timeout(
+                        left,
+                        dispatch(&call, config, session, can_tx, diag, &mut nvm, rotation),
+                    )
+                    .await
+                    .unwrap_or(Err(Failure::new(Status::Timeout, "timed out")));
+                    // A timed out read_did leaves the receiver listening
+                    diag.stop();
+                    res
+                };
+                if call.method == Method::Reboot && outcome.is_ok() {
+                    reboot_after = Some(reboot_delay(&call.params));
+                }
+                outcome
+            }
+            Err(failure) => Err(failure),
+        };
+        if let Err(failure) = &outcome {
+            warn!("RPC request {} failed: {}", request.id, failure.reason);
+        }
+
+        let response = rpc::render_response(&request.id, &outcome);
+        recent.insert(&request.id, &response);
+        uplink
+            .send(
+                Priority::Normal,
+                rpc::response_message(&request.id, &response),
+            )
+            .await;
+
+        if let Some(delay) = reboot_after {
+            warn!("Rebooting in {} ms on RPC request", delay.as_millis());
+            Timer::after(delay).await;
+            esp_hal::reset::software_reset();
+        }
+    }
+}
+
+async fn dispatch(
+    call: &Call,
+    config: &ConfigCell,
+    session: &LiveSessionCell,
+    can_tx: &CanTxInbox,
+    diag: &DiagLink,
+    nvm: &mut Nvm,
+    rotation: &RotationLink,
+) -> Outcome {
+    let params = &call.params;
+    let mut reply = Reply::new();
+    match call.method {
+        Method::Reboot => {
+            let _ = write!(
+                &mut reply,
+                "{{\"delay_ms\":{}}}",
+                reboot_delay(params).as_millis()
+            );
+        }
+        Method::GetConfig => {
+            let _ = config.lock(|c| c.borrow().write_json(&mut reply));
+        }
+        Method::SetConfig => {
+            config
+                .lock(|c| c.borrow_mut().apply(&params.config_patch()))
+                .map_err(|_| Failure::new(Status::BadRequest, "setting out of range"))?;
+            let _ = config.lock(|c| c.borrow().write_json(&mut reply));
+        }
+        Method::ReadDid => return read_did(params, can_tx, diag).await,
+        Method::SendCan => send_can(params, can_tx)?,
+        Method::StartCapture => {
+            let cmd = SniffCommand {
+                action: SniffAction::Start,
+                session_id: params.session_id.clone(),
+                duration_s: params.duration_s,
+                byte_budget: params.byte_budget,
+                filters: params.filters.clone(),
+            };
+            session
+                .lock(|s| s.borrow_mut().apply(cmd, embassy_time::Instant::now()))
+                .map_err(|_| Failure::new(Status::Failed, "capture not started"))?;
+            let id = session
+                .lock(|s| s.borrow().session_id())
+                .unwrap_or_default();
+            let _ = write!(&mut reply, "{{\"session_id\":\"{id}\"}}");
+        }
+        Method::StageCredentials => {
+            let part = params
+                .part
+                .as_deref()
+                .and_then(Part::from_name)
+                .ok_or(Failure::new(Status::BadRequest, "unknown part"))?;
+            let chunk = params
+                .chunk
+                .as_deref()
+                .and_then(base64::decode::<CHUNK_LEN>)
+                .ok_or(Failure::new(Status::BadRequest, "invalid chunk"))?;
+            let offset = params.offset.unwrap_or(0) as usize;
+            let received = staging::write(nvm, part, offset, &chunk).map_err(|e| match e {
+                staging::StagingError::OutOfOrder => {
+                    Failure::new(Status::BadRequest, "chunk out of order")
+                }
+                staging::StagingError::TooLarge => {
+                    Failure::new(Status::TooLarge, "credential too large")
+                }
+                staging::StagingError::Busy => Failure::new(Status::Busy, "rotation in progress"),
+                _ => Failure::new(Status::Failed, "storage error"),
+            })?;
+            let _ = write!(
+                &mut reply,
+                "{{\"part\":\"{}\",\"received\":{received}}}",
+                part.as_str()
+            );
+        }
+        Method::RotateCredentials => {
+            trust::verify_staged(nvm, params.signature.as_deref()).map_err(|e| match e {
+                TrustError::Unsigned => {
+                    Failure::new(Status::BadRequest, "CA bundle needs a signature")
+                }
+                TrustError::BadSignature => {
+                    Failure::new(Status::Forbidden, "invalid CA bundle signature")
+                }
+                TrustError::Storage => Failure::new(Status::Failed, "storage error"),
+            })?;
+            staging::seal(nvm).map_err(|e| match e {
+                staging::StagingError::Empty => Failure::new(Status::BadRequest, "nothing staged"),
+                staging::StagingError::Busy => Failure::new(Status::Busy, "rotation in progress"),
+                _ => Failure::new(Status::Failed, "storage error"),
+            })?;
+            // A timeout leaves them staged, tried on the next Wi-Fi connection
+            rotation.stage();
+            rotation.wait_outcome().await.map_err(|e| match e {
+                CredentialError::Rejected => {
+                    Failure::new(Status::Failed, "refused by the broker, rolled back")
+                }
+                CredentialError::Missing(_) | CredentialError::Invalid(_) => {
+                    Failure::new(Status::BadRequest, "unreadable credentials, rolled back")
+                }
+                CredentialError::Expired(_) => Failure::new(
+                    Status::BadRequest,
+                    "certificate out of its validity, rolled back",
+                ),
+                CredentialError::KeyMismatch => Failure::new(
+                    Status::BadRequest,
+                    "key does not match the certificate, rolled back",
+                ),
+                CredentialError::Unpinned => Failure::new(
+                    Status::BadRequest,
+                    "no pinned CA in the bundle, rolled back",
+                ),
+                CredentialError::TooLarge => {
+                    Failure::new(Status::TooLarge, "credentials too large")
+                }
+                CredentialError::Storage => Failure::new(Status::Failed, "storage error"),
+            })?;
+            let _ = reply.push_str("{\"committed\":true}");
+        }
+    }
+    Ok(reply)
+}
+
+fn reboot_delay(params: &Params) -> Duration {
+    params.delay_ms.map_or(DEFAULT_REBOOT_DELAY, |ms| {
+        Duration::from_millis(ms.min(MAX_REBOOT_DELAY_MS) as u64)
+    })
+}
+
+fn send_can(params: &Params, can_tx: &CanTxInbox) -> Result<(), Failure> {
+    let id = params
+        .id
+        .ok_or(Failure::new(Status::BadRequest, "missing id"))?;
+    let extended = params.ext.unwrap_or(false);
+    let max_id = if extended { 0x1FFF_FFFF } else { 0x7FF };
+    if id > max_id {
+        return Err(Failure::new(Status::BadRequest, "invalid id"));
+    }
+    let bytes = hex::decode::<8>(params.data.as_deref().unwrap_or_default())
+        .ok_or(Failure::new(Status::BadRequest, "invalid data"))?;
+    let mut data = [0u8; 8];
+    data[..bytes.len()].copy_from_slice(&bytes);
+    let frame = CanFrame {
+        id,
+        extended,
+        len: bytes.len() as u8,
+        data,
+        timestamp_us: embassy_time::Instant::now().as_micros(),
+    };
+    can_tx
+        .try_send(frame)
+        .map_err(|_| Failure::new(Status::Busy, "CAN transmit queue full"))
+}
+
+/// UDS ReadDataByIdentifier, `{"did":<did>,"data":"<hex>"}` on success
+async fn read_did(params: &Params, can_tx: &CanTxInbox, diag: &DiagLink) -> Outcome {
+    let did = params
+        .did
+        .ok_or(Failure::new(Status::BadRequest, "missing did"))?;
+    let tx_id = params.tx_id.unwrap_or(DEFAULT_DIAG_TX_ID);
+    let rx_id = params.rx_id.unwrap_or(DEFAULT_DIAG_RX_ID);
+    let extended = params.ext.unwrap_or(false);
+    let [did_hi, did_lo] = did.to_be_bytes();
+    let request = isotp::single_frame(tx_id, extended, &[UDS_READ_DATA_BY_ID, did_hi, did_lo])
+        .ok_or(Failure::new(Status::BadRequest, "invalid request"))?;
+
+    diag.listen(rx_id, extended);
+    can_tx.send(request).await;
+    let mut reassembler = Reassembler::new();
+    loop {
+        let frame = diag.receive().await;
+        match reassembler.on_frame(frame.payload()) {
+            Ok(Progress::Pending) => {}
+            Ok(Progress::FlowControl) => can_tx.send(isotp::flow_control(tx_id, extended)).await,
+            Ok(Progress::Complete) => match reassembler.message() {
+                // The ECU needs more time, the final response follows
+                [UDS_NEGATIVE_RESPONSE, UDS_READ_DATA_BY_ID, UDS_RESPONSE_PENDING] => {}
+                [UDS_NEGATIVE_RESPONSE, UDS_READ_DATA_BY_ID, nrc, ..] => {
+                    return Err(Failure::new(Status::Failed, negative_response(*nrc)));
+                }
+                [service, hi, lo, data @ ..]
+                    if *service == UDS_READ_DATA_BY_ID + 0x40 && [*hi, *lo] == [did_hi, did_lo] =>
+                {
+                    let mut reply = Reply::new();
+                    let _ = write!(&mut reply, "{{\"did\":{did},\"data\":\"");
+                    hex::encode(&mut reply, data)
+                        .and_then(|_| reply.write_str("\"}"))
+                        .map_err(|_| Failure::new(Status::Failed, "response too large"))?;
+                    return Ok(reply);
+                }
+                // Response to another request of another tester
+                _ => {}
+            },
+            Err(isotp::IsoTpError::TooLong) => {
+                return Err(Failure::new(Status::Failed, "response too large"));
+            }
+            Err(_) => return Err(Failure::new(Status::Failed, "ISO-TP error")),
+        }
+    }
+}
+
+/// Meaning of the common UDS negative response codes
+fn negative_response(nrc: u8) -> &'static str {
+    match nrc {
+        0x11 => "service not supported",
+        0x13 => "incorrect message length",
+        0x14 => "response too long",
+        0x22 => "conditions not correct",
+        0x31 => "request out of range",
+        0x33 => "security access denied",
+        _ => "negative response",
+    }
+}
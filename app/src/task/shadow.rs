@@ -0,0 +1,64 @@
+use embassy_futures::select::{select, Either};
+use log::{error, info, warn};
+
+use crate::svc::config::ConfigCell;
+use crate::svc::mem::nvm::{BlockId, Nvm};
+use crate::svc::shadow::{self, Shadow, ShadowLink, RECORD_LEN};
+use crate::svc::topic::{topic, MessageType};
+use crate::svc::uplink::{Priority, Uplink, UplinkMessage};
+
+/// Applies the desired documents and publishes the reported one
+///
+/// The settings stored by the last run are restored first, the reported document
+/// is published after each desired document and whenever a bearer connects.
+#[embassy_executor::task]
+pub async fn shadow_sync(
+    link: &'static ShadowLink,
+    config: &'static ConfigCell,
+    uplink: &'static Uplink,
+) -> ! {
+    let reported_topic = topic(MessageType::ShadowReported);
+    let mut nvm = Nvm::init();
+    let mut record = [0u8; RECORD_LEN];
+    let stored = nvm
+        .nvs_read(BlockId::ShadowId, &mut record)
+        .ok()
+        .and_then(|_| shadow::decode_record(&record));
+    let mut shadow = match stored {
+        Some((version, settings)) => {
+            info!("Shadow version {version} restored");
+            config.lock(|c| *c.borrow_mut() = settings);
+            Shadow::with_version(version)
+        }
+        None => {
+            info!("No stored shadow, default settings");
+            Shadow::new()
+        }
+    };
+
+    loop {
+        if let Either::First(doc) = select(link.receive(), link.wait_resync()).await {
+            let mut settings = config.lock(|c| c.borrow().clone());
+            match shadow.apply(&mut settings, &doc) {
+                Ok(true) => {
+                    info!("Shadow version {} applied", shadow.version);
+                    let record = shadow::encode_record(shadow.version, &settings);
+                    config.lock(|c| *c.borrow_mut() = settings);
+                    if let Err(e) = nvm.nvs_write(BlockId::ShadowId, &record) {
+                        error!("Failed to store the shadow: {e:?}");
+                    }
+                }
+                Ok(false) => info!("Shadow version {} already applied", shadow.version),
+                Err(e) => warn!("Desired document ignored: {e:?}"),
+            }
+        }
+
+        let reported = config.lock(|c| shadow.reported(&c.borrow()));
+        uplink
+            .send(
+                Priority::Normal,
+                UplinkMessage::json(&reported_topic, &reported),
+            )
+            .await;
+    }
+}
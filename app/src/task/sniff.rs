@@ -0,0 +1,135 @@
+use core::fmt::Write;
+
+use embassy_time::{with_timeout, Duration, Instant};
+use log::{info, warn};
+
+use crate::svc::sniff::{LiveSessionCell, SessionEvent};
+use crate::svc::topic::{topic, MessageType};
+use crate::svc::uplink::{Priority, Uplink, UplinkMessage, UPLINK_PAYLOAD_LEN};
+use crate::task::can::{CanFrame, SniffOutbox};
+
+/// Frames per uplink message, keeps a full batch below the uplink payload limit
+const MAX_BATCH_FRAMES: usize = 14;
+/// Longest time a captured frame waits before being uplinked
+const FLUSH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Streams the frames captured by the live session to the active uplink
+///
+/// Frames are batched as
+/// `{"session":"<id>","seq":<n>,"frames":[{"t":<ms>,"i":"<hex id>","x":<0|1>,"d":"<hex data>"}]}`
+#[embassy_executor::task]
+pub async fn sniff_streamer(
+    sniff: &'static SniffOutbox,
+    session: &'static LiveSessionCell,
+    uplink: &'static Uplink,
+) -> ! {
+    let data_topic = topic(MessageType::Sniff);
+    let status_topic = topic(MessageType::SniffStatus);
+
+    let mut batch: heapless::Vec<CanFrame, MAX_BATCH_FRAMES> = heapless::Vec::new();
+    let mut first_at = Instant::now();
+    let mut seq: u32 = 0;
+
+    loop {
+        let wait = if batch.is_empty() {
+            Duration::from_secs(1)
+        } else {
+            FLUSH_INTERVAL
+                .checked_sub(first_at.elapsed())
+                .unwrap_or(Duration::from_ticks(0))
+        };
+        if let Ok(frame) = with_timeout(wait, sniff.receive()).await {
+            if batch.is_empty() {
+                first_at = Instant::now();
+            }
+            let _ = batch.push(frame);
+        }
+
+        session.lock(|s| s.borrow_mut().poll_expiry(Instant::now()));
+
+        let flush = batch.is_full() || (!batch.is_empty() && first_at.elapsed() >= FLUSH_INTERVAL);
+        if flush {
+            match session.lock(|s| s.borrow().session_id()) {
+                Some(id) => {
+                    let mut payload: heapless::String<UPLINK_PAYLOAD_LEN> = heapless::String::new();
+                    if render_batch(&mut payload, &id, seq, &batch).is_ok() {
+                        let bytes = payload.len() as u32;
+                        uplink
+                            .send(Priority::Live, UplinkMessage::json(&data_topic, &payload))
+                            .await;
+                        seq = seq.wrapping_add(1);
+                        session.lock(|s| s.borrow_mut().record_sent(batch.len() as u32, bytes));
+                    } else {
+                        warn!("Live session batch does not fit the uplink payload");
+                    }
+                }
+                None => {
+                    // Session ended while the batch was filling up
+                    seq = 0;
+                }
+            }
+            batch.clear();
+        }
+
+        while let Some(event) = session.lock(|s| s.borrow_mut().pop_event()) {
+            let mut payload: heapless::String<UPLINK_PAYLOAD_LEN> = heapless::String::new();
+            match &event {
+                SessionEvent::Started {
+                    id,
+                    duration_s,
+                    byte_budget,
+                    filters,
+                } => {
+                    info!(
+                        "Live session {id} started for {duration_s}s, budget {byte_budget} bytes"
+                    );
+                    seq = 0;
+                    let _ = write!(
+                        &mut payload,
+                        "{{\"session\":\"{id}\",\"state\":\"started\",\"duration_s\":{duration_s},\"byte_budget\":{byte_budget},\"filters\":{filters}}}"
+                    );
+                }
+                SessionEvent::Stopped { id, reason, stats } => {
+                    info!("Live session {id} stopped: {}", reason.as_str());
+                    let _ = write!(
+                        &mut payload,
+                        "{{\"session\":\"{id}\",\"state\":\"stopped\",\"reason\":\"{}\",\"frames\":{},\"dropped\":{},\"bytes\":{}}}",
+                        reason.as_str(),
+                        stats.frames_sent,
+                        stats.frames_dropped,
+                        stats.bytes_sent
+                    );
+                }
+            }
+            uplink
+                .send(Priority::Live, UplinkMessage::json(&status_topic, &payload))
+                .await;
+        }
+    }
+}
+
+fn render_batch(
+    out: &mut heapless::String<UPLINK_PAYLOAD_LEN>,
+    id: &str,
+    seq: u32,
+    frames: &[CanFrame],
+) -> core::fmt::Result {
+    write!(out, "{{\"session\":\"{id}\",\"seq\":{seq},\"frames\":[")?;
+    for (i, frame) in frames.iter().enumerate() {
+        if i > 0 {
+            out.push(',').map_err(|_| core::fmt::Error)?;
+        }
+        write!(
+            out,
+            "{{\"t\":{},\"i\":\"{:X}\",\"x\":{},\"d\":\"",
+            frame.timestamp_us / 1000,
+            frame.id,
+            frame.extended as u8
+        )?;
+        for byte in frame.payload() {
+            write!(out, "{byte:02X}")?;
+        }
+        out.push_str("\"}").map_err(|_| core::fmt::Error)?;
+    }
+    out.push_str("]}").map_err(|_| core::fmt::Error)
+}
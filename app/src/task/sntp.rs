@@ -0,0 +1,122 @@
+use embassy_net::{
+    udp::{PacketMetadata, UdpSocket},
+    IpAddress, IpEndpoint, Stack,
+};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use log::{info, warn};
+
+use crate::cfg::time_cfg::{NTP_INTERVAL, NTP_RETRY, NTP_SERVERS, NTP_TIMEOUT};
+use crate::svc::clock::sntp::{self, SntpError, PACKET_LEN};
+use crate::svc::clock::{ClockCell, TimeSource};
+use crate::svc::dns::{resolver::Resolver, DnsError};
+use crate::util::random::Nonces;
+
+const NTP_PORT: u16 = 123;
+/// Source ports are picked above it
+const EPHEMERAL_PORTS: u16 = 49152;
+
+#[derive(Debug)]
+enum SyncError {
+    Dns(DnsError),
+    Sntp(SntpError),
+    Network,
+    Timeout,
+}
+
+/// Sets the wall clock with SNTP whenever the Wi-Fi uplink has an address
+#[embassy_executor::task]
+pub async fn sntp_client(
+    stack: &'static Stack<'static>,
+    clock: &'static ClockCell,
+    seed: [u8; 32],
+) -> ! {
+    let mut nonces = Nonces::new(seed);
+    let mut resolver = Resolver::new(nonces.next());
+    loop {
+        while stack.config_v4().is_none() {
+            Timer::after(Duration::from_secs(1)).await;
+        }
+        let mut synced = false;
+        for server in NTP_SERVERS {
+            match sync(*stack, &mut resolver, &mut nonces, server).await {
+                Ok((unix_ms, at)) => {
+                    match clock.lock(|c| c.borrow_mut().set(TimeSource::Ntp, unix_ms, at)) {
+                        Some(correction) => clock.lock(|c| {
+                            let c = c.borrow();
+                            info!("Clock set from {server}, {correction} ms off");
+                            if let Some(now) = c.now_utc() {
+                                info!("UTC {now}");
+                            }
+                            if let Some(drift) = c.drift_ppb() {
+                                info!("Clock drift {drift} ppb");
+                            }
+                        }),
+                        None => info!("Clock kept, GNSS set it more recently than {server}"),
+                    }
+                    synced = true;
+                    break;
+                }
+                Err(e) => warn!("SNTP with {server} failed: {e:?}"),
+            }
+        }
+        Timer::after(if synced { NTP_INTERVAL } else { NTP_RETRY }).await;
+    }
+}
+
+/// UTC from `server` and the `Instant` it was at
+async fn sync(
+    stack: Stack<'_>,
+    resolver: &mut Resolver,
+    nonces: &mut Nonces,
+    server: &str,
+) -> Result<(u64, Instant), SyncError> {
+    let addr = resolver
+        .resolve(stack, server)
+        .await
+        .map_err(SyncError::Dns)?;
+    let random = nonces.next();
+    let port = EPHEMERAL_PORTS + u16::from_be_bytes([random[0], random[1]]) % 16384;
+    let mut nonce = [0; 8];
+    nonce.copy_from_slice(&random[2..10]);
+    let server = IpEndpoint::new(IpAddress::Ipv4(addr), NTP_PORT);
+
+    let mut rx_meta = [PacketMetadata::EMPTY; 2];
+    let mut rx_buffer = [0; 2 * PACKET_LEN];
+    let mut tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut tx_buffer = [0; PACKET_LEN];
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    socket.bind(port).map_err(|_| SyncError::Network)?;
+    let sent = Instant::now();
+    socket
+        .send_to(&sntp::request(nonce), server)
+        .await
+        .map_err(|_| SyncError::Network)?;
+    let exchange = async {
+        let mut reply = [0; PACKET_LEN];
+        loop {
+            let (len, meta) = socket
+                .recv_from(&mut reply)
+                .await
+                .map_err(|_| SyncError::Network)?;
+            let received = Instant::now();
+            // Stray or spoofed, keep waiting for the reply
+            if meta.endpoint != server {
+                continue;
+            }
+            match sntp::parse_reply(&reply[..len], nonce, sent.as_millis(), received.as_millis()) {
+                Err(SntpError::Unrelated) => continue,
+                Ok(unix_ms) => return Ok((unix_ms, received)),
+                Err(e) => return Err(SyncError::Sntp(e)),
+            }
+        }
+    };
+    with_timeout(NTP_TIMEOUT, exchange)
+        .await
+        .unwrap_or(Err(SyncError::Timeout))
+}
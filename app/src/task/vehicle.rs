@@ -0,0 +1,43 @@
+use embassy_time::{Duration, Instant, Timer};
+use log::{info, warn};
+
+use crate::svc::payload::{self, message::Event};
+use crate::svc::topic::{topic, MessageType};
+use crate::svc::uplink::{Priority, Uplink, UplinkMessage};
+use crate::svc::vehicle::VehicleCell;
+
+/// How often the vehicle state is re-evaluated
+const UPDATE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Tracks the vehicle state and publishes its changes
+///
+/// Events are sent as [`Event`] named `vehicle_state`, in the payload format
+/// of the event topic.
+#[embassy_executor::task]
+pub async fn vehicle_monitor(vehicle: &'static VehicleCell, uplink: &'static Uplink) -> ! {
+    let event_topic = topic(MessageType::Event);
+    let format = payload::format_for(MessageType::Event);
+
+    loop {
+        Timer::after(UPDATE_INTERVAL).await;
+        let Some(transition) = vehicle.lock(|v| v.borrow_mut().update(Instant::now())) else {
+            continue;
+        };
+        info!(
+            "Vehicle state: {} -> {}",
+            transition.from.as_str(),
+            transition.to.as_str()
+        );
+
+        let event = Event {
+            event: "vehicle_state",
+            from: transition.from.as_str(),
+            to: transition.to.as_str(),
+            uptime_ms: transition.at.as_millis(),
+        };
+        match UplinkMessage::encode(&event_topic, format, &event) {
+            Ok(msg) => uplink.send(Priority::Normal, msg).await,
+            Err(e) => warn!("Failed to encode the vehicle state event: {e:?}"),
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use embassy_futures::select::{select, Either};
 use embassy_net::Runner;
 use embassy_time::{Duration, Timer};
 use esp_println::println;
@@ -7,9 +8,13 @@ use esp_wifi::wifi::{
 };
 
 use crate::cfg::net_cfg::{WIFI_PSWD, WIFI_SSID};
+use crate::svc::supervisor::WifiResetSignal;
 
 #[embassy_executor::task]
-pub async fn connection(mut controller: WifiController<'static>) {
+pub async fn connection(
+    mut controller: WifiController<'static>,
+    wifi_reset: &'static WifiResetSignal,
+) {
     println!("INFO - Start the connection task");
     println!(
         "INFO - Device capabilities: {:?}",
@@ -21,8 +26,13 @@ pub async fn connection(mut controller: WifiController<'static>) {
         .unwrap();
     loop {
         if esp_wifi::wifi::wifi_state() == WifiState::StaConnected {
-            // wait until we're no longer connected
-            controller.wait_for_event(WifiEvent::StaDisconnected).await;
+            // wait until we're no longer connected, or the MQTT uplink gives up on the link
+            let disconnected = controller.wait_for_event(WifiEvent::StaDisconnected);
+            if let Either::Second(()) = select(disconnected, wifi_reset.wait()).await {
+                println!("INFO - Reset the WIFI");
+                let _ = controller.disconnect_async().await;
+                let _ = controller.stop_async().await;
+            }
             Timer::after(Duration::from_millis(5000)).await
         }
         if !matches!(controller.is_started(), Ok(true)) {
@@ -38,7 +48,11 @@ pub async fn connection(mut controller: WifiController<'static>) {
         println!("INFO - Connecting the WIFI...");
 
         match controller.connect_async().await {
-            Ok(_) => println!("INFO - Wifi connected!"),
+            Ok(_) => {
+                println!("INFO - Wifi connected!");
+                // Raised while the link was down, the new link gets its chance
+                wifi_reset.reset();
+            }
             Err(e) => {
                 println!("ERROR - Failed to connect to wifi: {e:?}");
                 Timer::after(Duration::from_millis(5000)).await
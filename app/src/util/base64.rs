@@ -0,0 +1,122 @@
+/// Standard base64 alphabet, padded
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Append `bytes` in base64
+pub fn encode<W: core::fmt::Write>(out: &mut W, bytes: &[u8]) -> core::fmt::Result {
+    for chunk in bytes.chunks(3) {
+        let b = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for idx in 0..4 {
+            if idx <= chunk.len() {
+                out.write_char(ALPHABET[(n >> (18 - 6 * idx) & 0x3F) as usize] as char)?;
+            } else {
+                out.write_char('=')?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Decode padded base64
+///
+/// `None` on a length that isn't a multiple of 4, a character out of the
+/// alphabet, misplaced padding or more than `N` bytes.
+pub fn decode<const N: usize>(text: &str) -> Option<heapless::Vec<u8, N>> {
+    let chars = text.as_bytes();
+    if !chars.len().is_multiple_of(4) {
+        return None;
+    }
+    let mut out = heapless::Vec::new();
+    let quads = chars.len() / 4;
+    for (idx, quad) in chars.chunks(4).enumerate() {
+        if push_quad(quad, &mut out)? && idx + 1 != quads {
+            return None;
+        }
+    }
+    Some(out)
+}
+
+/// Decode 4 characters into `out`, `Some(true)` when padded, which ends the text
+fn push_quad<const N: usize>(quad: &[u8], out: &mut heapless::Vec<u8, N>) -> Option<bool> {
+    let padding = quad.iter().rev().take_while(|c| **c == b'=').count();
+    if padding > 2 {
+        return None;
+    }
+    let mut n = 0u32;
+    for c in &quad[..4 - padding] {
+        n = n << 6 | sextet(*c)? as u32;
+    }
+    n <<= 6 * padding as u32;
+    let bytes = n.to_be_bytes();
+    out.extend_from_slice(&bytes[1..4 - padding]).ok()?;
+    Some(padding > 0)
+}
+
+fn sextet(c: u8) -> Option<u8> {
+    match c {
+        b'A'..=b'Z' => Some(c - b'A'),
+        b'a'..=b'z' => Some(c - b'a' + 26),
+        b'0'..=b'9' => Some(c - b'0' + 52),
+        b'+' => Some(62),
+        b'/' => Some(63),
+        _ => None,
+    }
+}
+
+/// Append `der` as PEM with `label`, 64 characters a line
+pub fn encode_pem<W: core::fmt::Write>(out: &mut W, label: &str, der: &[u8]) -> core::fmt::Result {
+    writeln!(out, "-----BEGIN {label}-----")?;
+    for line in der.chunks(48) {
+        encode(out, line)?;
+        out.write_char('\n')?;
+    }
+    writeln!(out, "-----END {label}-----")
+}
+
+/// Decode the first PEM block of `text` into `out`, returns its label and the
+/// text after it
+///
+/// `None` when there is no block, or it is cut short, isn't base64 or holds
+/// more than `N` bytes. Line breaks may fall anywhere in the base64.
+pub fn decode_pem<'a, const N: usize>(
+    text: &'a [u8],
+    out: &mut heapless::Vec<u8, N>,
+) -> Option<(&'a str, &'a [u8])> {
+    let label_at = find(text, b"-----BEGIN ")? + 11;
+    let label_len = find(&text[label_at..], b"-----")?;
+    let label = core::str::from_utf8(&text[label_at..label_at + label_len]).ok()?;
+    let body_at = label_at + label_len + 5;
+    let body_len = find(&text[body_at..], b"-----END ")?;
+    let rest = text[body_at + body_len + 9..]
+        .strip_prefix(label.as_bytes())?
+        .strip_prefix(b"-----")?;
+    out.clear();
+    let mut quad = [0u8; 4];
+    let mut filled = 0;
+    let mut ended = false;
+    for c in text[body_at..body_at + body_len]
+        .iter()
+        .filter(|c| !c.is_ascii_whitespace())
+    {
+        if ended {
+            return None;
+        }
+        quad[filled] = *c;
+        filled += 1;
+        if filled == 4 {
+            ended = push_quad(&quad, out)?;
+            filled = 0;
+        }
+    }
+    (filled == 0).then_some((label, rest))
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
@@ -0,0 +1,6 @@
+/// FNV-1a, enough to tell a written record from a torn or erased one
+pub fn fnv1a(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811C_9DC5, |hash, byte| {
+        (hash ^ *byte as u32).wrapping_mul(0x0100_0193)
+    })
+}
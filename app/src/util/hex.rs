@@ -0,0 +1,32 @@
+/// Decode a string of hex digit pairs, either case
+///
+/// `None` on an odd length, a non hex digit or more than `N` bytes.
+pub fn decode<const N: usize>(hex: &str) -> Option<heapless::Vec<u8, N>> {
+    let digits = hex.as_bytes();
+    if !digits.len().is_multiple_of(2) {
+        return None;
+    }
+    let mut out = heapless::Vec::new();
+    for pair in digits.chunks(2) {
+        let byte = (nibble(pair[0])? << 4) | nibble(pair[1])?;
+        out.push(byte).ok()?;
+    }
+    Some(out)
+}
+
+/// Append `bytes` as uppercase hex digit pairs
+pub fn encode<W: core::fmt::Write>(out: &mut W, bytes: &[u8]) -> core::fmt::Result {
+    for byte in bytes {
+        write!(out, "{byte:02X}")?;
+    }
+    Ok(())
+}
+
+fn nibble(digit: u8) -> Option<u8> {
+    match digit {
+        b'0'..=b'9' => Some(digit - b'0'),
+        b'a'..=b'f' => Some(digit - b'a' + 10),
+        b'A'..=b'F' => Some(digit - b'A' + 10),
+        _ => None,
+    }
+}
@@ -1,3 +1,9 @@
+pub mod base64;
+pub mod fnv;
+pub mod hex;
 pub mod log;
 pub mod no_std_prelude;
+pub mod p256;
+pub mod random;
+pub mod sha256;
 pub mod time;
@@ -0,0 +1,224 @@
+/// ECDSA over NIST P-256, on top of the `p256` crate
+///
+/// Enough for the device to make its own key pair and sign a CSR with it, and
+/// to check the signature of a trust bundle, the TLS stack takes over
+/// afterwards. The arithmetic on secrets is constant-time and nonces are
+/// derived from the key and the digest (RFC 6979), signing needs no random
+/// numbers.
+use p256::ecdsa::signature::hazmat::{PrehashSigner, PrehashVerifier};
+use p256::ecdsa::{self, SigningKey};
+
+use crate::util::sha256::{self, DIGEST_LEN};
+
+pub const SCALAR_LEN: usize = 32;
+/// `04 || x || y`
+pub const PUBLIC_KEY_LEN: usize = 65;
+
+/// ECDSA signature, `r` and `s` big-endian
+pub struct Signature {
+    pub r: [u8; SCALAR_LEN],
+    pub s: [u8; SCALAR_LEN],
+}
+
+/// Private key, cleared when dropped
+pub struct SecretKey {
+    key: SigningKey,
+}
+
+impl SecretKey {
+    /// Key derived from 32 random bytes, hashed until it falls in `[1, n)`
+    pub fn from_seed(seed: &[u8; 32]) -> Self {
+        let mut counter = 0u8;
+        loop {
+            let mut sha = sha256::Sha256::new();
+            sha.update(seed);
+            sha.update(&[counter]);
+            if let Some(key) = Self::from_bytes(&sha.finalize()) {
+                return key;
+            }
+            counter = counter.wrapping_add(1);
+        }
+    }
+
+    /// `None` when the scalar is 0 or not below the group order
+    pub fn from_bytes(bytes: &[u8; SCALAR_LEN]) -> Option<Self> {
+        let key = SigningKey::from_bytes(bytes.into()).ok()?;
+        Some(Self { key })
+    }
+
+    pub fn to_bytes(&self) -> [u8; SCALAR_LEN] {
+        self.key.to_bytes().into()
+    }
+
+    /// Uncompressed public key, `04 || x || y`
+    pub fn public_key(&self) -> [u8; PUBLIC_KEY_LEN] {
+        let point = self.key.verifying_key().to_encoded_point(false);
+        let mut out = [0u8; PUBLIC_KEY_LEN];
+        out.copy_from_slice(point.as_bytes());
+        out
+    }
+
+    /// Sign a SHA-256 digest
+    pub fn sign(&self, digest: &[u8; DIGEST_LEN]) -> Signature {
+        // Only fails for digests shorter than half the scalar
+        let signature: ecdsa::Signature = self
+            .key
+            .sign_prehash(digest)
+            .expect("a SHA-256 digest is long enough");
+        let (r, s) = signature.split_bytes();
+        Signature {
+            r: r.into(),
+            s: s.into(),
+        }
+    }
+}
+
+/// Public key of someone else, checked to be on the curve
+pub struct VerifyingKey {
+    key: ecdsa::VerifyingKey,
+}
+
+impl VerifyingKey {
+    /// Uncompressed `04 || x || y`, `None` off the curve
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != PUBLIC_KEY_LEN || bytes[0] != 0x04 {
+            return None;
+        }
+        let key = ecdsa::VerifyingKey::from_sec1_bytes(bytes).ok()?;
+        Some(Self { key })
+    }
+
+    /// Check a signature of a SHA-256 digest
+    pub fn verify(&self, digest: &[u8; DIGEST_LEN], signature: &Signature) -> bool {
+        ecdsa::Signature::from_scalars(signature.r, signature.s)
+            .is_ok_and(|signature| self.key.verify_prehash(digest, &signature).is_ok())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::hex;
+
+    fn bytes<const N: usize>(digits: &str) -> [u8; N] {
+        hex::decode::<N>(digits).unwrap().into_array().unwrap()
+    }
+
+    /// RFC 6979 A.2.5, P-256 with SHA-256
+    const RFC6979_KEY: &str = "C9AFA9D845BA75166B5C215767B1D6934E50C3DB36E89B127B8A622B120F6721";
+    const RFC6979_PUBLIC: &str =
+        "0460FED4BA255A9D31C961EB74C6356D68C049B8923B61FA6CE669622E60F29FB6\
+                                  7903FE1008B8BC99A41AE9E95628BC64F2F1B20C2D7E9F5177A3C294D4462299";
+
+    /// FIPS 186-4 SigGen, P-256 with SHA-256: `d`, `Qx || Qy`, SHA-256 of the
+    /// message, `r`, `s`
+    const CAVP: [[&str; 5]; 2] = [
+        [
+            "519B423D715F8B581F4FA8EE59F4771A5B44C8130B4E3EACCA54A56DDA72B464",
+            "1CCBE91C075FC7F4F033BFA248DB8FCCD3565DE94BBFB12F3C59FF46C271BF83\
+             CE4014C68811F9A21A1FDB2C0E6113E06DB7CA93B7404E78DC7CCD5CA89A4CA9",
+            "44ACF6B7E36C1342C2C5897204FE09504E1E2EFB1A900377DBC4E7A6A133EC56",
+            "F3AC8061B514795B8843E3D6629527ED2AFD6B1F6A555A7ACABB5E6F79C8C2AC",
+            "8BF77819CA05A6B2786C76262BF7371CEF97B218E96F175A3CCDDA2ACC058903",
+        ],
+        [
+            "0F56DB78CA460B055C500064824BED999A25AAF48EBB519AC201537B85479813",
+            "E266DDFDC12668DB30D4CA3E8F7749432C416044F2D2B8C10BF3D4012AEFFA8A\
+             BFA86404A2E9FFE67D47C587EF7A97A7F456B863B4D02CFC6928973AB5B1CB39",
+            "9B2DB89CB0E8FA3CC7608B4D6CC1DEC0114E0B9FF4080BEA12B134F489AB2BBC",
+            "976D3A4E9D23326DC0BAA9FA560B7C4E53F42864F508483A6473B6A11079B2DB",
+            "1B766E9CEB71BA6C01DCD46E0AF462CD4CFA652AE5017D4555B8EEEFE36E1932",
+        ],
+    ];
+
+    #[test]
+    fn signs_rfc6979_vectors() {
+        let key = SecretKey::from_bytes(&bytes(RFC6979_KEY)).unwrap();
+        assert_eq!(key.public_key(), bytes::<PUBLIC_KEY_LEN>(RFC6979_PUBLIC));
+        for (message, r, s) in [
+            (
+                "sample",
+                "EFD48B2AACB6A8FD1140DD9CD45E81D69D2C877B56AAF991C34D0EA84EAF3716",
+                "F7CB1C942D657C41D436C7A1B6E29F65F3E900DBB9AFF4064DC4AB2F843ACDA8",
+            ),
+            (
+                "test",
+                "F1ABB023518351CD71D881567B1EA663ED3EFCF6C5132B354F28D3B0B7D38367",
+                "019F4113742A2B14BD25926B49C649155F267E60D3814B4C0CC84250E46F0083",
+            ),
+        ] {
+            let digest = sha256::digest(message.as_bytes());
+            let signature = key.sign(&digest);
+            assert_eq!(signature.r, bytes::<SCALAR_LEN>(r), "{message}");
+            assert_eq!(signature.s, bytes::<SCALAR_LEN>(s), "{message}");
+            let public = VerifyingKey::from_bytes(&key.public_key()).unwrap();
+            assert!(public.verify(&digest, &signature));
+        }
+    }
+
+    #[test]
+    fn derives_and_verifies_cavp_vectors() {
+        for [d, q, m, r, s] in CAVP {
+            let key = SecretKey::from_bytes(&bytes(d)).unwrap();
+            assert_eq!(key.public_key()[1..], bytes::<64>(q));
+            let public = VerifyingKey::from_bytes(&key.public_key()).unwrap();
+            let digest = bytes(m);
+            let signature = Signature {
+                r: bytes(r),
+                s: bytes(s),
+            };
+            assert!(public.verify(&digest, &signature));
+
+            let mut tampered = digest;
+            tampered[31] ^= 1;
+            assert!(!public.verify(&tampered, &signature));
+            let swapped = Signature {
+                r: signature.s,
+                s: signature.r,
+            };
+            assert!(!public.verify(&digest, &swapped));
+        }
+    }
+
+    #[test]
+    fn rejects_out_of_range_values() {
+        let order =
+            bytes::<SCALAR_LEN>("FFFFFFFF00000000FFFFFFFFFFFFFFFFBCE6FAADA7179E84F3B9CAC2FC632551");
+        assert!(SecretKey::from_bytes(&[0; SCALAR_LEN]).is_none());
+        assert!(SecretKey::from_bytes(&order).is_none());
+
+        let key = SecretKey::from_bytes(&bytes(RFC6979_KEY)).unwrap();
+        let public = VerifyingKey::from_bytes(&key.public_key()).unwrap();
+        let digest = sha256::digest(b"sample");
+        let signature = key.sign(&digest);
+        for (r, s) in [
+            ([0; SCALAR_LEN], signature.s),
+            (signature.r, [0; SCALAR_LEN]),
+            (order, signature.s),
+            (signature.r, order),
+        ] {
+            assert!(!public.verify(&digest, &Signature { r, s }));
+        }
+
+        // Off the curve, compressed, truncated
+        let mut off_curve = key.public_key();
+        off_curve[64] ^= 1;
+        assert!(VerifyingKey::from_bytes(&off_curve).is_none());
+        let mut compressed = [0u8; 33];
+        compressed[0] = 0x02;
+        compressed[1..].copy_from_slice(&key.public_key()[1..33]);
+        assert!(VerifyingKey::from_bytes(&compressed).is_none());
+        assert!(VerifyingKey::from_bytes(&key.public_key()[..64]).is_none());
+    }
+
+    #[test]
+    fn seeds_give_the_same_key() {
+        let seed = [0x5A; 32];
+        let key = SecretKey::from_seed(&seed);
+        let mut sha = sha256::Sha256::new();
+        sha.update(&seed);
+        sha.update(&[0]);
+        assert_eq!(key.to_bytes(), sha.finalize());
+        assert_eq!(SecretKey::from_seed(&seed).to_bytes(), key.to_bytes());
+    }
+}
@@ -0,0 +1,24 @@
+//! Unpredictable bytes for protocol nonces, from a TRNG seed
+//!
+//! SHA-256 of the seed and a counter: the hardware RNG is read once at boot and
+//! the tasks draw from their own generator without sharing it.
+use super::sha256::{self, DIGEST_LEN};
+
+pub struct Nonces {
+    seed: [u8; 32],
+    counter: u32,
+}
+
+impl Nonces {
+    pub const fn new(seed: [u8; 32]) -> Self {
+        Self { seed, counter: 0 }
+    }
+
+    pub fn next(&mut self) -> [u8; DIGEST_LEN] {
+        self.counter = self.counter.wrapping_add(1);
+        let mut input = [0u8; 36];
+        input[..32].copy_from_slice(&self.seed);
+        input[32..].copy_from_slice(&self.counter.to_be_bytes());
+        sha256::digest(&input)
+    }
+}
@@ -0,0 +1,151 @@
+/// SHA-256 and HMAC-SHA256 in software, on top of the `sha2` and `hmac` crates
+///
+/// The SHA peripheral belongs to the TLS stack, this one is for the few digests
+/// computed outside of it.
+use hmac::{Hmac, Mac};
+use sha2::Digest;
+
+pub struct Sha256 {
+    inner: sha2::Sha256,
+}
+
+pub const DIGEST_LEN: usize = 32;
+
+impl Default for Sha256 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sha256 {
+    pub fn new() -> Self {
+        Self {
+            inner: sha2::Sha256::new(),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.inner.update(data);
+    }
+
+    pub fn finalize(self) -> [u8; DIGEST_LEN] {
+        self.inner.finalize().into()
+    }
+}
+
+pub fn digest(data: &[u8]) -> [u8; DIGEST_LEN] {
+    sha2::Sha256::digest(data).into()
+}
+
+/// HMAC-SHA256 of the concatenated `parts`
+pub fn hmac(key: &[u8], parts: &[&[u8]]) -> [u8; DIGEST_LEN] {
+    let mut mac = <Hmac<sha2::Sha256>>::new_from_slice(key).expect("HMAC takes keys of any length");
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::hex;
+
+    fn bytes<const N: usize>(digits: &str) -> heapless::Vec<u8, N> {
+        hex::decode(digits).unwrap()
+    }
+
+    fn digest_of(digits: &str) -> [u8; DIGEST_LEN] {
+        bytes::<DIGEST_LEN>(digits).into_array().unwrap()
+    }
+
+    /// FIPS 180-4 examples (NIST CSRC), and the long message of FIPS 180-2
+    #[test]
+    fn fips_180_4_vectors() {
+        assert_eq!(
+            digest(b""),
+            digest_of("E3B0C44298FC1C149AFBF4C8996FB92427AE41E4649B934CA495991B7852B855")
+        );
+        assert_eq!(
+            digest(b"abc"),
+            digest_of("BA7816BF8F01CFEA414140DE5DAE2223B00361A396177A9CB410FF61F20015AD")
+        );
+        assert_eq!(
+            digest(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+            digest_of("248D6A61D20638B8E5C026930C3E6039A33CE45964FF2167F6ECEDD419DB06C1")
+        );
+        let mut sha = Sha256::new();
+        for _ in 0..1000 {
+            sha.update(&[b'a'; 1000]);
+        }
+        assert_eq!(
+            sha.finalize(),
+            digest_of("CDC76E5C9914FB9281A1C7E284D73E67F1809A48A497200E046D39CCC7112CD0")
+        );
+    }
+
+    #[test]
+    fn hashes_split_updates() {
+        let message = b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq";
+        for split in 0..message.len() {
+            let mut sha = Sha256::new();
+            sha.update(&message[..split]);
+            sha.update(&message[split..]);
+            assert_eq!(sha.finalize(), digest(message), "split at {split}");
+        }
+    }
+
+    /// RFC 4231 test cases 1 to 7, the truncated output of case 5 compared on
+    /// its first 16 bytes
+    #[test]
+    fn rfc_4231_vectors() {
+        let long_key = [0xAA; 131];
+        let cases: [(&[u8], &[u8], &str); 7] = [
+            (
+                &[0x0B; 20],
+                b"Hi There",
+                "B0344C61D8DB38535CA8AFCEAF0BF12B881DC200C9833DA726E9376C2E32CFF7",
+            ),
+            (
+                b"Jefe",
+                b"what do ya want for nothing?",
+                "5BDCC146BF60754E6A042426089575C75A003F089D2739839DEC58B964EC3843",
+            ),
+            (
+                &[0xAA; 20],
+                &[0xDD; 50],
+                "773EA91E36800E46854DB8EBD09181A72959098B3EF8C122D9635514CED565FE",
+            ),
+            (
+                &bytes::<25>("0102030405060708090A0B0C0D0E0F10111213141516171819"),
+                &[0xCD; 50],
+                "82558A389A443C0EA4CC819899F2083A85F0FAA3E578F8077A2E3FF46729665B",
+            ),
+            (
+                &[0x0C; 20],
+                b"Test With Truncation",
+                "A3B6167473100EE06E0C796C2955552B",
+            ),
+            (
+                &long_key,
+                b"Test Using Larger Than Block-Size Key - Hash Key First",
+                "60E431591EE0B67F0D8A26AACBF5B77F8E0BC6213728C5140546040F0EE37F54",
+            ),
+            (
+                &long_key,
+                b"This is a test using a larger than block-size key and a larger than \
+                  block-size data. The key needs to be hashed before being used by the \
+                  HMAC algorithm.",
+                "9B09FFA71B942FCB27635FBCD5B0E944BFDC63644F0713938A7F51535C3A35E2",
+            ),
+        ];
+        for (case, (key, data, expected)) in cases.iter().enumerate() {
+            let expected = bytes::<DIGEST_LEN>(expected);
+            let mac = hmac(key, &[data]);
+            assert_eq!(mac[..expected.len()], expected, "case {}", case + 1);
+            // Parts are hashed as one message
+            let (head, tail) = data.split_at(data.len() / 2);
+            assert_eq!(hmac(key, &[head, tail]), mac, "case {}", case + 1);
+        }
+    }
+}
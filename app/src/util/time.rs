@@ -45,3 +45,90 @@ pub fn utc_date_to_unix_timestamp(utc: &[u8], date: &[u8]) -> u64 {
 
     timestamp
 }
+
+/// Seconds since the Unix epoch of a UTC date and time, `None` out of range
+pub fn civil_to_unix_secs(
+    year: u16,
+    month: u8,
+    day: u8,
+    hour: u8,
+    minute: u8,
+    second: u8,
+) -> Option<u64> {
+    let leap = is_leap_year(year) as usize;
+    if year < 1970
+        || !(1..=12).contains(&month)
+        || day == 0
+        || day as u16 > DAYS_IN_MONTH[leap][month as usize - 1]
+        || hour > 23
+        || minute > 59
+        || second > 60
+    {
+        return None;
+    }
+    let mut days = (1970..year)
+        .map(|y| if is_leap_year(y) { 366 } else { 365 })
+        .sum::<u64>();
+    days += DAYS_IN_MONTH[leap][..month as usize - 1]
+        .iter()
+        .map(|d| *d as u64)
+        .sum::<u64>();
+    days += day as u64 - 1;
+    Some(days * 86400 + hour as u64 * 3600 + minute as u64 * 60 + second as u64)
+}
+
+/// UTC date and time, broken down
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UtcTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub millis: u16,
+}
+
+impl UtcTime {
+    pub fn from_unix_ms(unix_ms: u64) -> Self {
+        let secs = unix_ms / 1000;
+        let mut days = secs / 86400;
+        let mut year = 1970;
+        loop {
+            let len = if is_leap_year(year) { 366 } else { 365 };
+            if days < len {
+                break;
+            }
+            days -= len;
+            year += 1;
+        }
+        let mut month = 0;
+        for len in DAYS_IN_MONTH[is_leap_year(year) as usize] {
+            if days < len as u64 {
+                break;
+            }
+            days -= len as u64;
+            month += 1;
+        }
+        Self {
+            year,
+            month: month + 1,
+            day: days as u8 + 1,
+            hour: (secs / 3600 % 24) as u8,
+            minute: (secs / 60 % 60) as u8,
+            second: (secs % 60) as u8,
+            millis: (unix_ms % 1000) as u16,
+        }
+    }
+}
+
+/// ISO 8601, `2024-05-06T22:10:00.000Z`
+impl core::fmt::Display for UtcTime {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second, self.millis
+        )
+    }
+}
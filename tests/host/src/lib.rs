@@ -0,0 +1,130 @@
+//! Host build of the firmware modules that do not touch the hardware
+//!
+//! The sources are taken from `app/src` as they are, so the `#[cfg(test)]`
+//! cases they hold run with `cargo test` on the development machine. Each
+//! module is placed at the path the firmware knows it by.
+#![no_std]
+
+#[cfg(test)]
+extern crate std;
+
+#[cfg(test)]
+mod mosquitto;
+
+#[path = "../../../app/src/util/base64.rs"]
+pub mod base64;
+#[path = "../../../app/src/svc/can/filter.rs"]
+pub mod can_filter;
+#[path = "../../../app/src/svc/can/frame.rs"]
+pub mod can_frame;
+#[path = "../../../app/src/svc/can/signal.rs"]
+pub mod can_signal;
+#[path = "../../../app/src/svc/canlink/mod.rs"]
+pub mod canlink;
+#[path = "../../../app/src/svc/clock/mod.rs"]
+pub mod clock;
+#[path = "../../../app/src/svc/cloud/mod.rs"]
+pub mod cloud;
+#[path = "../../../app/src/cfg/cloud_cfg.rs"]
+pub mod cloud_cfg;
+#[path = "../../../app/src/svc/config/mod.rs"]
+pub mod config;
+#[path = "../../../app/src/svc/credentials/mod.rs"]
+pub mod credentials;
+#[path = "../../../app/src/svc/dns/message.rs"]
+pub mod dns_message;
+#[path = "../../../app/src/svc/ev/mod.rs"]
+pub mod ev;
+#[path = "../../../app/src/util/fnv.rs"]
+pub mod fnv;
+#[path = "../../../app/src/util/hex.rs"]
+pub mod hex;
+#[path = "../../../app/src/svc/mqtt/mod.rs"]
+pub mod mqtt;
+#[path = "../../../app/src/cfg/mqtt_cfg.rs"]
+pub mod mqtt_cfg;
+#[path = "../../../app/src/cfg/net_cfg.rs"]
+pub mod net_cfg;
+pub mod nvm;
+#[path = "../../../app/src/util/p256.rs"]
+pub mod p256;
+// Only public here, the firmware never exports the encoder trait
+#[allow(clippy::len_without_is_empty)]
+#[path = "../../../app/src/svc/payload/mod.rs"]
+pub mod payload;
+#[path = "../../../app/src/svc/provision/identity.rs"]
+pub mod provision_identity;
+#[path = "../../../app/src/svc/rpc/mod.rs"]
+pub mod rpc;
+#[path = "../../../app/src/util/sha256.rs"]
+pub mod sha256;
+#[path = "../../../app/src/svc/shadow/mod.rs"]
+pub mod shadow;
+#[path = "../../../app/src/svc/sparkplug/mod.rs"]
+pub mod sparkplug;
+#[path = "../../../app/src/cfg/sparkplug_cfg.rs"]
+pub mod sparkplug_cfg;
+// The NMEA date conversion predates the host build and its lints
+#[allow(clippy::manual_is_multiple_of, clippy::needless_range_loop)]
+#[path = "../../../app/src/util/time.rs"]
+pub mod time;
+#[path = "../../../app/src/cfg/time_cfg.rs"]
+pub mod time_cfg;
+#[path = "../../../app/src/svc/topic/mod.rs"]
+pub mod topic;
+#[path = "../../../app/src/cfg/topic_cfg.rs"]
+pub mod topic_cfg;
+#[path = "../../../app/src/cfg/trust_cfg.rs"]
+pub mod trust_cfg;
+#[path = "../../../app/src/svc/uplink/mod.rs"]
+pub mod uplink;
+#[path = "../../../app/src/cfg/uplink_cfg.rs"]
+pub mod uplink_cfg;
+#[path = "../../../app/src/svc/vehicle/mod.rs"]
+pub mod vehicle;
+#[path = "../../../app/src/cfg/vehicle_cfg.rs"]
+pub mod vehicle_cfg;
+
+pub mod cfg {
+    pub use crate::{
+        cloud_cfg, mqtt_cfg, net_cfg, sparkplug_cfg, time_cfg, topic_cfg, trust_cfg, uplink_cfg,
+        vehicle_cfg,
+    };
+}
+
+pub mod svc {
+    pub mod can {
+        pub use crate::can_filter as filter;
+        pub use crate::can_frame::CanFrame;
+        pub use crate::can_signal as signal;
+    }
+    pub use crate::{
+        canlink, clock, cloud, config, credentials, ev, mqtt, payload, rpc, shadow, sparkplug,
+        topic, uplink, vehicle,
+    };
+
+    /// Without the resolver, which needs the network stack
+    pub mod dns {
+        pub use crate::dns_message as message;
+        pub use crate::dns_message::DnsError;
+    }
+
+    /// In RAM, see `nvm`
+    pub mod mem {
+        pub use crate::nvm;
+    }
+
+    /// Only the stored identity, the CSR needs the hardware random generator
+    pub mod provision {
+        pub use crate::provision_identity as identity;
+    }
+
+    /// Only the version, the status reads the reset reason from the chip
+    pub mod presence {
+        pub const FIRMWARE_VERSION: &str = env!("CARGO_PKG_VERSION");
+    }
+}
+
+pub mod util {
+    pub use crate::{base64, fnv, hex, p256, sha256, time};
+}
@@ -0,0 +1,302 @@
+//! `MqttClient` against a Mosquitto broker on loopback
+//!
+//! The broker is taken from `MQTT_TEST_BROKER`, `127.0.0.1:1883` by default,
+//! and must accept anonymous clients (`listener 1883` with
+//! `allow_anonymous true`). Each test is skipped when nothing listens there.
+use core::cell::RefCell;
+use core::future::poll_fn;
+use core::task::Poll;
+use std::io::{ErrorKind as IoErrorKind, Read as _, Write as _};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::string::{String, ToString};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration as StdDuration;
+use std::vec::Vec;
+use std::{eprintln, format};
+
+use embassy_time::{Duration, Instant, MockDriver};
+use embedded_io_async::{ErrorKind, ErrorType, Read, Write};
+use mqttrust::QoS;
+
+use crate::mqtt::handler::MessageHandler;
+use crate::mqtt::inflight::InFlightWindow;
+use crate::mqtt::packet::ProtocolVersion;
+use crate::mqtt::script::run;
+use crate::mqtt::{MqttClient, MqttClientError, PublishOptions};
+
+/// Time the mock clock moves forward on each read finding nothing to deliver,
+/// the real time the read waited
+const READ_STEP: StdDuration = StdDuration::from_millis(10);
+/// How long a test waits for the broker to route its messages back
+const ROUTE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Blocking TCP connection driving the mock clock at the pace of real time
+struct Loopback {
+    stream: TcpStream,
+}
+
+impl Loopback {
+    fn connect(broker: SocketAddr) -> Self {
+        let stream = TcpStream::connect(broker).expect("broker went away");
+        stream.set_read_timeout(Some(READ_STEP)).unwrap();
+        stream.set_nodelay(true).unwrap();
+        Self { stream }
+    }
+}
+
+impl ErrorType for Loopback {
+    type Error = ErrorKind;
+}
+
+impl Read for Loopback {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, ErrorKind> {
+        poll_fn(|_| match self.stream.read(buf) {
+            Ok(len) => Poll::Ready(Ok(len)),
+            // The executor polls again right away, the clock drives the timeouts
+            Err(e) if matches!(e.kind(), IoErrorKind::WouldBlock | IoErrorKind::TimedOut) => {
+                MockDriver::get().advance(Duration::from_millis(READ_STEP.as_millis() as u64));
+                Poll::Pending
+            }
+            Err(_) => Poll::Ready(Err(ErrorKind::ConnectionReset)),
+        })
+        .await
+    }
+}
+
+impl Write for Loopback {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, ErrorKind> {
+        self.stream
+            .write(buf)
+            .map_err(|_| ErrorKind::ConnectionReset)
+    }
+}
+
+/// Address of the broker, `None` when nothing listens there
+fn broker() -> Option<SocketAddr> {
+    let addr = std::env::var("MQTT_TEST_BROKER").unwrap_or_else(|_| "127.0.0.1:1883".into());
+    let broker = addr.to_socket_addrs().ok()?.next()?;
+    match TcpStream::connect_timeout(&broker, StdDuration::from_millis(500)) {
+        Ok(_) => Some(broker),
+        Err(_) => {
+            eprintln!("No MQTT broker on {addr}, skipped");
+            None
+        }
+    }
+}
+
+/// Topic no other run of the tests publishes on
+fn unique_topic(name: &str) -> String {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    format!(
+        "telematic/test/{}-{}/{name}",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+#[derive(Default)]
+struct Collector {
+    messages: RefCell<Vec<(String, Vec<u8>)>>,
+}
+
+impl Collector {
+    fn count(&self) -> usize {
+        self.messages.borrow().len()
+    }
+
+    fn payloads(&self) -> Vec<Vec<u8>> {
+        self.messages
+            .borrow()
+            .iter()
+            .map(|(_, payload)| payload.clone())
+            .collect()
+    }
+}
+
+impl MessageHandler for Collector {
+    fn on_message(&self, topic: &str, payload: &[u8]) {
+        self.messages
+            .borrow_mut()
+            .push((topic.to_string(), payload.to_vec()));
+    }
+}
+
+/// Handle inbound traffic until `collector` holds `count` messages
+async fn receive_until<T: Read + Write>(
+    client: &mut MqttClient<'_, T>,
+    collector: &Collector,
+    count: usize,
+) {
+    let start = Instant::now();
+    while collector.count() < count {
+        assert!(
+            start.elapsed() < ROUTE_TIMEOUT,
+            "{} of {count} messages received",
+            collector.count()
+        );
+        client.receive(Duration::from_millis(100)).await.unwrap();
+    }
+}
+
+/// Publish with each QoS to a topic the client subscribed to, the broker
+/// routes every message back
+async fn round_trip(broker: SocketAddr, protocol: ProtocolVersion) {
+    let topic = unique_topic("echo");
+    let filter = format!("{topic}/#");
+    let client_id = unique_topic("client").replace('/', "-");
+    let collector = Collector::default();
+    let mut inflight = InFlightWindow::new();
+    let mut client = MqttClient::new(&client_id, Loopback::connect(broker), &mut inflight);
+    client.set_protocol(protocol);
+    client.connect(30, None, None).await.unwrap();
+    client
+        .subscribe(&filter, QoS::ExactlyOnce, &collector)
+        .await
+        .unwrap();
+
+    let sub_topic = format!("{topic}/a");
+    for (qos, payload) in [
+        (QoS::AtMostOnce, &b"qos0"[..]),
+        (QoS::AtLeastOnce, b"qos1"),
+        (QoS::ExactlyOnce, b"qos2"),
+    ] {
+        // Published twice, the second one with the topic alias under MQTT 5
+        for _ in 0..2 {
+            let delivery = client.publish(&sub_topic, payload, qos).await.unwrap();
+            client.wait_delivery(delivery, ROUTE_TIMEOUT).await.unwrap();
+        }
+    }
+    receive_until(&mut client, &collector, 6).await;
+    let mut payloads = collector.payloads();
+    payloads.sort();
+    assert_eq!(
+        payloads,
+        [b"qos0", b"qos0", b"qos1", b"qos1", b"qos2", b"qos2"]
+    );
+    assert!(collector
+        .messages
+        .borrow()
+        .iter()
+        .all(|(received, _)| *received == sub_topic));
+
+    client.disconnect().await;
+    assert!(!client.is_connected());
+}
+
+#[test]
+fn round_trips_with_mqtt_311() {
+    let Some(broker) = broker() else { return };
+    run(round_trip(broker, ProtocolVersion::V311));
+}
+
+#[test]
+fn round_trips_with_mqtt_5() {
+    let Some(broker) = broker() else { return };
+    run(round_trip(broker, ProtocolVersion::V5));
+}
+
+#[test]
+fn keeps_retained_messages() {
+    let Some(broker) = broker() else { return };
+    run(async {
+        let topic = unique_topic("retained");
+        let publisher_id = unique_topic("publisher").replace('/', "-");
+        let mut inflight = InFlightWindow::new();
+        let mut publisher =
+            MqttClient::new(&publisher_id, Loopback::connect(broker), &mut inflight);
+        publisher.connect(30, None, None).await.unwrap();
+        let delivery = publisher
+            .publish_retained(&topic, b"kept", QoS::AtLeastOnce)
+            .await
+            .unwrap();
+        publisher
+            .wait_delivery(delivery, ROUTE_TIMEOUT)
+            .await
+            .unwrap();
+
+        // A later subscriber gets the retained message
+        let subscriber_id = unique_topic("subscriber").replace('/', "-");
+        let collector = Collector::default();
+        let mut inflight = InFlightWindow::new();
+        let mut subscriber =
+            MqttClient::new(&subscriber_id, Loopback::connect(broker), &mut inflight);
+        subscriber.connect(30, None, None).await.unwrap();
+        subscriber
+            .subscribe(&topic, QoS::AtLeastOnce, &collector)
+            .await
+            .unwrap();
+        receive_until(&mut subscriber, &collector, 1).await;
+        assert_eq!(collector.payloads(), [b"kept"]);
+
+        // An empty retained message clears it for the next runs
+        let options = PublishOptions {
+            retain: true,
+            ..Default::default()
+        };
+        publisher
+            .publish_with_options(&topic, b"", QoS::AtMostOnce, &options)
+            .await
+            .unwrap();
+        publisher.disconnect().await;
+        subscriber.disconnect().await;
+    });
+}
+
+#[test]
+fn broker_publishes_the_last_will() {
+    let Some(broker) = broker() else { return };
+    run(async {
+        let topic = unique_topic("status");
+        let watcher_id = unique_topic("watcher").replace('/', "-");
+        let collector = Collector::default();
+        let mut inflight = InFlightWindow::new();
+        let mut watcher = MqttClient::new(&watcher_id, Loopback::connect(broker), &mut inflight);
+        watcher.connect(30, None, None).await.unwrap();
+        watcher
+            .subscribe(&topic, QoS::AtLeastOnce, &collector)
+            .await
+            .unwrap();
+
+        let device_id = unique_topic("device").replace('/', "-");
+        let mut inflight = InFlightWindow::new();
+        let mut device = MqttClient::new(&device_id, Loopback::connect(broker), &mut inflight);
+        device.set_last_will(&topic, b"{\"status\":\"offline\"}", QoS::AtLeastOnce, false);
+        device.connect(30, None, None).await.unwrap();
+        // Gone without a DISCONNECT
+        drop(device.into_transport());
+
+        receive_until(&mut watcher, &collector, 1).await;
+        assert_eq!(collector.payloads(), [b"{\"status\":\"offline\"}"]);
+        watcher.disconnect().await;
+    });
+}
+
+#[test]
+fn reports_a_closed_connection() {
+    let Some(broker) = broker() else { return };
+    run(async {
+        let client_id = unique_topic("taken").replace('/', "-");
+        let mut inflight = InFlightWindow::new();
+        let mut first = MqttClient::new(&client_id, Loopback::connect(broker), &mut inflight);
+        first.connect(30, None, None).await.unwrap();
+
+        // The broker drops the first session for a second one with its client ID
+        let mut inflight = InFlightWindow::new();
+        let mut second = MqttClient::new(&client_id, Loopback::connect(broker), &mut inflight);
+        second.connect(30, None, None).await.unwrap();
+
+        let start = Instant::now();
+        let result = loop {
+            assert!(start.elapsed() < ROUTE_TIMEOUT, "first session still open");
+            if let Err(e) = first.receive(Duration::from_millis(100)).await {
+                break e;
+            }
+        };
+        assert!(matches!(
+            result,
+            MqttClientError::ConnectionClosed | MqttClientError::Io
+        ));
+        assert!(!first.is_connected());
+        second.disconnect().await;
+    });
+}
@@ -0,0 +1,86 @@
+//! Stand-in for `svc::mem::nvm` on the development machine
+//!
+//! Same blocks and the same API as the firmware, kept in RAM. Every block
+//! starts erased, all ones, like a fresh flash.
+use crate::svc::credentials::staging::STAGING_LEN;
+use crate::svc::provision::identity::IDENTITY_LEN;
+use crate::svc::shadow::RECORD_LEN;
+
+#[derive(Copy, Clone)]
+pub enum BlockId {
+    CrtPemId = 0x0,
+    DvtCrtId = 0x1,
+    DvtKeyId = 0x2,
+    ShadowId = 0x3,
+    IdentityId = 0x4,
+    StagingId = 0x5,
+}
+
+#[derive(Debug)]
+pub enum NvsError {
+    IdInvalid,
+    LenInvalid,
+    WriteErr,
+    ReadErr,
+}
+
+const SIZES: [usize; 6] = [
+    0x1000,
+    0x1000,
+    0x1000,
+    RECORD_LEN,
+    IDENTITY_LEN,
+    STAGING_LEN,
+];
+const TOTAL: usize = 3 * 0x1000 + RECORD_LEN + IDENTITY_LEN + STAGING_LEN;
+
+pub struct Nvm {
+    /// The blocks one after the other
+    storage: [u8; TOTAL],
+}
+
+impl Nvm {
+    pub fn init() -> Self {
+        Self {
+            storage: [0xFF; TOTAL],
+        }
+    }
+
+    pub fn nvs_write(&mut self, id: BlockId, buf: &[u8]) -> Result<(), NvsError> {
+        if buf.len() != SIZES[id as usize] {
+            return Err(NvsError::LenInvalid);
+        }
+        self.nvs_write_at(id, 0, buf)
+    }
+
+    pub fn nvs_write_at(&mut self, id: BlockId, offset: usize, buf: &[u8]) -> Result<(), NvsError> {
+        self.span(id, offset, buf.len())?.copy_from_slice(buf);
+        Ok(())
+    }
+
+    pub fn nvs_read_at(
+        &mut self,
+        id: BlockId,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> Result<(), NvsError> {
+        buf.copy_from_slice(self.span(id, offset, buf.len())?);
+        Ok(())
+    }
+
+    pub fn nvs_read(&mut self, id: BlockId, buf: &mut [u8]) -> Result<(), NvsError> {
+        if buf.len() != SIZES[id as usize] {
+            return Err(NvsError::LenInvalid);
+        }
+        self.nvs_read_at(id, 0, buf)
+    }
+
+    fn span(&mut self, id: BlockId, offset: usize, len: usize) -> Result<&mut [u8], NvsError> {
+        let id = id as usize;
+        if offset + len > SIZES[id] {
+            return Err(NvsError::LenInvalid);
+        }
+        let start = SIZES[..id].iter().sum::<usize>() + offset;
+        Ok(&mut self.storage[start..start + len])
+    }
+}
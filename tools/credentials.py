@@ -0,0 +1,54 @@
+#!/usr/bin/env python3
+"""Build the factory credentials image of a device.
+
+The CA chain, device certificate and private key go into the first three
+sectors of the `nvs` partition, each as a record the firmware reads with
+`svc::credentials::store`: magic 0xCE, format 1, length (u16 LE), FNV-1a of
+the material (u32 LE), then the material, PEM or DER.
+
+    python3 tools/credentials.py app/cert/crt.pem dev42.crt dev42.key -o dev42.bin
+    espflash write-bin 0x9000 dev42.bin
+"""
+import argparse
+import struct
+import sys
+
+NVS_OFFSET = 0x9000
+SECTOR = 0x1000
+HEADER_LEN = 8
+# Buffers of the firmware, `CA_LEN`, `CERT_LEN` and `KEY_LEN` less the NUL
+LIMITS = (4079, 2047, 2047)
+
+
+def fnv1a(data: bytes) -> int:
+    h = 0x811C9DC5
+    for byte in data:
+        h = ((h ^ byte) * 0x01000193) & 0xFFFFFFFF
+    return h
+
+
+def record(path: str, limit: int) -> bytes:
+    with open(path, "rb") as f:
+        material = f.read()
+    if len(material) > limit:
+        sys.exit(f"{path}: {len(material)} bytes, at most {limit} fit")
+    header = struct.pack("<BBHI", 0xCE, 1, len(material), fnv1a(material))
+    return (header + material).ljust(SECTOR, b"\xff")
+
+
+def main() -> None:
+    parser = argparse.ArgumentParser(description=__doc__.splitlines()[0])
+    parser.add_argument("ca_chain")
+    parser.add_argument("certificate")
+    parser.add_argument("private_key")
+    parser.add_argument("-o", "--output", required=True)
+    args = parser.parse_args()
+    paths = (args.ca_chain, args.certificate, args.private_key)
+    image = b"".join(record(path, limit) for path, limit in zip(paths, LIMITS))
+    with open(args.output, "wb") as f:
+        f.write(image)
+    print(f"espflash write-bin {NVS_OFFSET:#x} {args.output}")
+
+
+if __name__ == "__main__":
+    main()